@@ -0,0 +1,45 @@
+//! The large script shared by the benchmarks.
+
+/// A node exercising the common parts of the syntax, repeated to build a large script.
+const NODE: &str = r#"title: Node{n}
+tags: generated
+---
+Guard: Halt! Who goes there? #line:guard{n}
+<<set $visits{n} to ($visits{n} ?? 0) + 1>>
+<<if $visits{n} > 2 and not visited("Node{n}")>>
+    Guard: You again. You've been here {$visits{n}} times.
+<<elseif $gold >= 10>>
+    Guard: Spare some coin?
+<<else>>
+    Guard: Move along.
+<<endif>>
+<<play_sound guard_grunt 0.5>>
+Where do you want to go?
+-> Bribe the guard <<if $gold > 5>> #bribe
+    <<set $gold to $gold - 5>>
+    Guard: Thank you kindly.
+-> Walk away
+    You walk away slowly.
+[[Onwards|Node{next}]]
+[[Back|Node{n}]]
+[[Back to the hub|Hub]]
+===
+"#;
+
+const HUB: &str = r#"title: Hub
+---
+Welcome back.
+===
+"#;
+
+/// A script of the given number of generated nodes, each linking to the next, plus
+/// the hub they all link back to.
+pub fn large_script(nodes: usize) -> String {
+    let mut source = HUB.to_string();
+    for n in 0..nodes {
+        source += &NODE
+            .replace("{next}", &((n + 1) % nodes).to_string())
+            .replace("{n}", &n.to_string());
+    }
+    source
+}
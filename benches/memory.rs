@@ -0,0 +1,40 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use yarn_spool::YarnEngine;
+
+mod common;
+
+/// Tracks the number of bytes currently allocated, to report the memory retained by a
+/// loaded script.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Reports the memory retained by an engine that has loaded the large script.
+fn main() {
+    let source = common::large_script(1000);
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(&source).unwrap();
+    let retained = ALLOCATED.load(Ordering::Relaxed) - before;
+    println!(
+        "loading {} KB of source retains {} KB",
+        source.len() / 1024,
+        retained / 1024
+    );
+}
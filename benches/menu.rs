@@ -0,0 +1,49 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use yarn_spool::{VariableName, YarnEngine, YarnEntry};
+
+/// A menu node the player returns to after every choice, whose options interpolate
+/// several variables that rarely change.
+const MENU: &str = r#"title: Menu
+---
+Shopkeeper: What will it be, {$name}? You have {$gold} gold.
+-> Buy a {$item1} for {$price1} gold, leaving {$gold - $price1}
+    [[Menu]]
+-> Buy a {$item2} for {$price2} gold, leaving {$gold - $price2}
+    [[Menu]]
+-> Buy a {$item3} for {$price3} gold, leaving {$gold - $price3}
+    [[Menu]]
+-> Buy a {$item4} for {$price4} gold, leaving {$gold - $price4}
+    [[Menu]]
+-> Sell your {$item1} for {$price1 / 2} gold
+    [[Menu]]
+-> Sell your {$item2} for {$price2 / 2} gold
+    [[Menu]]
+-> Ask about the {$item3} and the {$item4}
+    [[Menu]]
+-> Leave {$name == "stranger" ? "quietly" : "with a wave"}
+    [[Menu]]
+===
+"#;
+
+fn present_menu(c: &mut Criterion) {
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(MENU).unwrap();
+    engine.set_variable(VariableName("name".to_string()), "stranger");
+    engine.set_variable(VariableName("gold".to_string()), 100.0);
+    for (n, item) in ["sword", "shield", "lantern", "rope"].iter().enumerate() {
+        let n = n + 1;
+        engine.set_variable(VariableName(format!("item{}", n)), *item);
+        engine.set_variable(VariableName(format!("price{}", n)), 10.0 * n as f32);
+    }
+    engine.activate("Menu".into());
+
+    c.bench_function("menu/present", |b| {
+        b.iter(|| match engine.next() {
+            Some(YarnEntry::Choose { .. }) => engine.choose(0).unwrap(),
+            entry => panic!("expected the menu, found {:?}", entry),
+        })
+    });
+}
+
+criterion_group!(benches, present_menu);
+criterion_main!(benches);
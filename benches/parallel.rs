@@ -0,0 +1,36 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rayon::ThreadPoolBuilder;
+use yarn_spool::YarnEngine;
+
+mod common;
+
+/// Loads the large script on thread pools of increasing size, to show how parsing
+/// node bodies in parallel scales.
+fn load_on_pools(c: &mut Criterion) {
+    let source = common::large_script(1000);
+    let mut group = c.benchmark_group("parallel");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    for threads in [1, 2, 4, 8] {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("threads", threads),
+            &source,
+            |b, source| {
+                // The engine must be dropped on the thread that created it.
+                b.iter(|| {
+                    pool.install(|| {
+                        let mut engine = YarnEngine::new();
+                        engine.load_from_string(source).unwrap();
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, load_on_pools);
+criterion_main!(benches);
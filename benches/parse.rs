@@ -0,0 +1,21 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use yarn_spool::YarnEngine;
+
+mod common;
+
+fn load_large_script(c: &mut Criterion) {
+    let source = common::large_script(1000);
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.bench_function("load_from_string", |b| {
+        b.iter(|| {
+            let mut engine = YarnEngine::new();
+            engine.load_from_string(&source).unwrap();
+            engine
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, load_large_script);
+criterion_main!(benches);
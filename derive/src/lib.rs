@@ -0,0 +1,222 @@
+//! Derive macros for yarn-spool, enabled with its `derive` feature.
+
+use proc_macro::{Delimiter, Spacing, TokenStream, TokenTree};
+
+/// Derive `yarn_spool::YarnVariables` for a struct with named fields, mapping each
+/// field to the Yarn variable of the same name. Field types must implement
+/// `Clone`, `IntoValue` and `FromValue`.
+///
+/// `#[yarn(rename = "name")]` maps a field to a differently named variable, and
+/// `#[yarn(skip)]` leaves a field out; skipped fields are filled with
+/// `Default::default()` when extracting.
+///
+/// ```
+/// use yarn_spool::{VariableName, YarnEngine, YarnVariables};
+///
+/// #[derive(Debug, PartialEq, YarnVariables)]
+/// struct Save {
+///     gold: u32,
+///     #[yarn(rename = "met_the_king")]
+///     met_king: bool,
+///     #[yarn(skip)]
+///     playtime: f32,
+/// }
+///
+/// let mut engine = YarnEngine::new();
+/// let save = Save { gold: 10, met_king: true, playtime: 3.5 };
+/// save.apply_to(&mut engine);
+/// assert_eq!(engine.get_variable_as::<bool>(&VariableName("met_the_king".into())), Ok(true));
+/// assert_eq!(Save::extract_from(&engine), Ok(Save { playtime: 0.0, ..save }));
+/// ```
+///
+/// Fields of types with no Yarn equivalent are rejected:
+///
+/// ```compile_fail
+/// use yarn_spool::YarnVariables;
+///
+/// #[derive(YarnVariables)]
+/// struct Save {
+///     inventory: Vec<String>,
+/// }
+/// ```
+#[proc_macro_derive(YarnVariables, attributes(yarn))]
+pub fn derive_yarn_variables(input: TokenStream) -> TokenStream {
+    let result = parse_struct(input).and_then(|(name, fields)| expand(&name, &fields));
+    match result {
+        Ok(output) => output,
+        Err(message) => format!("compile_error!({:?});", message).parse().unwrap(),
+    }
+}
+
+struct Field {
+    name: String,
+    ty: String,
+    /// The name of the Yarn variable, or `None` if the field is skipped.
+    variable: Option<String>,
+}
+
+/// Find the name and named fields of the struct being derived.
+fn parse_struct(input: TokenStream) -> Result<(String, Vec<Field>), String> {
+    let mut tokens = input.into_iter();
+    let mut name = None;
+    while let Some(token) = tokens.next() {
+        match token {
+            TokenTree::Ident(ident) if ident.to_string() == "struct" => {
+                name = tokens.next().map(|name| name.to_string());
+                break;
+            }
+            TokenTree::Ident(ident) if ident.to_string() == "enum" => break,
+            _ => {}
+        }
+    }
+    let name = name.ok_or("YarnVariables can only be derived for structs")?;
+    match tokens.next() {
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Brace => {
+            parse_fields(group.stream()).map(|fields| (name, fields))
+        }
+        Some(TokenTree::Punct(punct)) if punct.as_char() == '<' => {
+            Err("YarnVariables cannot be derived for generic structs".to_string())
+        }
+        _ => Err("YarnVariables can only be derived for structs with named fields".to_string()),
+    }
+}
+
+fn parse_fields(stream: TokenStream) -> Result<Vec<Field>, String> {
+    let mut fields = vec![];
+    let mut tokens = stream.into_iter().peekable();
+    while tokens.peek().is_some() {
+        let mut variable = Some(());
+        let mut rename = None;
+        // Attributes, including doc comments.
+        while let Some(TokenTree::Punct(punct)) = tokens.peek() {
+            if punct.as_char() != '#' {
+                break;
+            }
+            tokens.next();
+            if let Some(TokenTree::Group(group)) = tokens.next() {
+                let mut attr = group.stream().into_iter();
+                if attr.next().map(|t| t.to_string()).as_deref() != Some("yarn") {
+                    continue;
+                }
+                match attr.next() {
+                    Some(TokenTree::Group(args)) => {
+                        let args = args.stream().into_iter().collect::<Vec<_>>();
+                        match &args[..] {
+                            [TokenTree::Ident(skip)] if skip.to_string() == "skip" => {
+                                variable = None
+                            }
+                            [TokenTree::Ident(key), TokenTree::Punct(eq), TokenTree::Literal(value)]
+                                if key.to_string() == "rename" && eq.as_char() == '=' =>
+                            {
+                                rename = Some(parse_string(&value.to_string())?);
+                            }
+                            _ => {
+                                return Err("expected #[yarn(rename = \"...\")] or #[yarn(skip)]"
+                                    .to_string())
+                            }
+                        }
+                    }
+                    _ => {
+                        return Err(
+                            "expected #[yarn(rename = \"...\")] or #[yarn(skip)]".to_string()
+                        )
+                    }
+                }
+            }
+        }
+        // Visibility.
+        if let Some(TokenTree::Ident(ident)) = tokens.peek() {
+            if ident.to_string() == "pub" {
+                tokens.next();
+                if let Some(TokenTree::Group(group)) = tokens.peek() {
+                    if group.delimiter() == Delimiter::Parenthesis {
+                        tokens.next();
+                    }
+                }
+            }
+        }
+        let name = match tokens.next() {
+            Some(TokenTree::Ident(ident)) => ident.to_string(),
+            _ => return Err("expected a field name".to_string()),
+        };
+        match tokens.next() {
+            Some(TokenTree::Punct(punct)) if punct.as_char() == ':' => {}
+            _ => return Err(format!("expected a type for field `{}`", name)),
+        }
+        // The type extends to the next comma outside of angle brackets.
+        let mut ty = TokenStream::new();
+        let mut depth = 0;
+        let mut arrow = false;
+        for token in tokens.by_ref() {
+            if let TokenTree::Punct(ref punct) = token {
+                match punct.as_char() {
+                    ',' if depth == 0 => break,
+                    '<' => depth += 1,
+                    '>' if !arrow => depth -= 1,
+                    _ => {}
+                }
+                arrow = punct.as_char() == '-' && punct.spacing() == Spacing::Joint;
+            } else {
+                arrow = false;
+            }
+            ty.extend(Some(token));
+        }
+        let variable = variable.map(|()| rename.unwrap_or_else(|| name.clone()));
+        fields.push(Field {
+            ty: ty.to_string(),
+            name,
+            variable,
+        });
+    }
+    Ok(fields)
+}
+
+/// The contents of a string literal, which may not contain escapes.
+fn parse_string(literal: &str) -> Result<String, String> {
+    literal
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .filter(|s| !s.contains('\\'))
+        .map(|s| s.to_string())
+        .ok_or_else(|| format!("expected a string, found {}", literal))
+}
+
+fn expand(name: &str, fields: &[Field]) -> Result<TokenStream, String> {
+    let mut apply = String::new();
+    let mut extract = String::new();
+    for field in fields {
+        match field.variable {
+            Some(ref variable) => {
+                apply += &format!(
+                    "engine.set_variable(::yarn_spool::VariableName({:?}.to_string()), \
+                     ::yarn_spool::IntoValue::into_value(::std::clone::Clone::clone(&self.{})));",
+                    variable, field.name
+                );
+                extract += &format!(
+                    "{}: engine.get_variable_as::<{}>(&::yarn_spool::VariableName({:?}.to_string()))?,",
+                    field.name, field.ty, variable
+                );
+            }
+            None => extract += &format!("{}: ::std::default::Default::default(),", field.name),
+        }
+    }
+    format!(
+        "#[allow(unused_variables)]
+        impl ::yarn_spool::YarnVariables for {name} {{
+            fn apply_to<Ctx: 'static>(&self, engine: &mut ::yarn_spool::YarnEngine<Ctx>) {{
+                {apply}
+            }}
+
+            fn extract_from<Ctx: 'static>(
+                engine: &::yarn_spool::YarnEngine<Ctx>,
+            ) -> ::std::result::Result<Self, ::yarn_spool::TypeError> {{
+                ::std::result::Result::Ok({name} {{ {extract} }})
+            }}
+        }}",
+        name = name,
+        apply = apply,
+        extract = extract,
+    )
+    .parse()
+    .map_err(|err| format!("failed to generate YarnVariables: {:?}", err))
+}
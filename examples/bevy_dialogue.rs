@@ -0,0 +1,78 @@
+//! Runs the dwarf conversation from `simple.yarn` in a headless Bevy app, printing
+//! each line and reading choices from the terminal.
+
+use bevy::app::{App, AppExit, Startup, Update};
+use bevy::asset::{AssetPlugin, AssetServer, Assets, Handle};
+use bevy::ecs::event::{EventReader, EventWriter};
+use bevy::ecs::resource::Resource;
+use bevy::ecs::system::{Commands, NonSendMut, Res};
+use bevy::MinimalPlugins;
+use std::io::{self, BufRead};
+use yarn_spool::{
+    ChooseEvent, ChooseSelection, EndEvent, NodeName, SayEvent, YarnDialogue, YarnDialoguePlugin,
+    YarnFileAsset,
+};
+
+#[derive(Resource)]
+struct Script(Handle<YarnFileAsset>);
+
+fn main() {
+    App::new()
+        .add_plugins((
+            MinimalPlugins,
+            AssetPlugin {
+                file_path: "examples".to_string(),
+                ..AssetPlugin::default()
+            },
+            YarnDialoguePlugin,
+        ))
+        .add_systems(Startup, load_script)
+        .add_systems(Update, (start_conversation, present))
+        .run();
+}
+
+fn load_script(mut commands: Commands, server: Res<AssetServer>) {
+    commands.insert_resource(Script(server.load("simple.yarn")));
+}
+
+fn start_conversation(
+    mut commands: Commands,
+    script: Option<Res<Script>>,
+    files: Res<Assets<YarnFileAsset>>,
+    mut dialogue: NonSendMut<YarnDialogue>,
+) {
+    let file = match script.and_then(|script| files.get(&script.0).cloned()) {
+        Some(file) => file,
+        None => return,
+    };
+    commands.remove_resource::<Script>();
+    dialogue.load(&file).unwrap();
+    dialogue.activate(NodeName::from("dwarf"));
+}
+
+fn present(
+    mut dialogue: NonSendMut<YarnDialogue>,
+    mut say: EventReader<SayEvent>,
+    mut choose: EventReader<ChooseEvent>,
+    mut end: EventReader<EndEvent>,
+    mut selections: EventWriter<ChooseSelection>,
+    mut exit: EventWriter<AppExit>,
+) {
+    for SayEvent(line) in say.read() {
+        println!("{}", line.plain_text());
+        dialogue.proceed();
+    }
+    for event in choose.read() {
+        println!("{}", event.text);
+        for (i, choice) in event.choices.iter().enumerate() {
+            println!("  {}. {}", i + 1, choice.trim());
+        }
+        let mut input = String::new();
+        io::stdin().lock().read_line(&mut input).unwrap();
+        let choice = input.trim().parse::<usize>().unwrap_or(1).max(1) - 1;
+        selections.write(ChooseSelection(choice));
+    }
+    if end.read().next().is_some() {
+        exit.write(AppExit::Success);
+    }
+}
@@ -4,7 +4,6 @@ extern crate yarn_spool;
 use easycurses::*;
 use std::cell::{Cell, RefCell};
 use std::fs;
-use std::iter::repeat;
 use std::rc::Rc;
 use std::thread::sleep;
 use std::time::Duration;
@@ -77,7 +76,7 @@ fn main() {
         let top_of_loop = Instant::now();
         // Gather/process any pending input
         while let Some(input) = easy.get_input() {
-            let is_game = &*state.phase.borrow() == &Phase::Game;
+            let is_game = *state.phase.borrow() == Phase::Game;
             if is_game {
                 let (xdiff, ydiff) = match input {
                     Input::KeyLeft => (-1, 0),
@@ -88,15 +87,14 @@ fn main() {
                 };
 
                 // if input == Input::Character('q') {
-                //     engine.activate(NodeName("dwarf".to_string()));
+                //     engine.activate(NodeName::from("dwarf"));
                 // }
                 if xdiff != 0 || ydiff != 0 {
                     if x + xdiff == dwarf_x && y + ydiff == dwarf_y {
-                        engine.activate(NodeName("dwarf".to_string()));
+                        engine.activate(NodeName::from("dwarf"));
                         if let Some(entry) = engine.next() {
-                            match entry {
-                                YarnEntry::Say(s) => *state.phase.borrow_mut() = Phase::Dialogue(s),
-                                _ => {}
+                            if let YarnEntry::Say(s) = entry {
+                                *state.phase.borrow_mut() = Phase::Dialogue(s.into_plain_text());
                             }
                         } else {
                             *state.phase.borrow_mut() = Phase::Game;
@@ -111,11 +109,13 @@ fn main() {
                     engine.choose(0).unwrap();
                     if let Some(entry) = engine.next() {
                         match entry {
-                            YarnEntry::Say(s) => *state.phase.borrow_mut() = Phase::Dialogue(s),
-                            YarnEntry::Choose { text, choices } => {
+                            YarnEntry::Say(s) => {
+                                *state.phase.borrow_mut() = Phase::Dialogue(s.into_plain_text())
+                            }
+                            YarnEntry::Choose { text, .. } => {
                                 *state.phase.borrow_mut() = Phase::Dialogue(text)
                             }
-                            YarnEntry::EndConversation => {
+                            YarnEntry::EndConversation(_) => {
                                 *state.phase.borrow_mut() = Phase::Game
                             }
                             _ => {}
@@ -126,11 +126,13 @@ fn main() {
                     engine.choose(1).unwrap();
                     if let Some(entry) = engine.next() {
                         match entry {
-                            YarnEntry::Say(s) => *state.phase.borrow_mut() = Phase::Dialogue(s),
-                            YarnEntry::Choose { text, choices } => {
+                            YarnEntry::Say(s) => {
+                                *state.phase.borrow_mut() = Phase::Dialogue(s.into_plain_text())
+                            }
+                            YarnEntry::Choose { text, .. } => {
                                 *state.phase.borrow_mut() = Phase::Dialogue(text)
                             }
-                            YarnEntry::EndConversation => {
+                            YarnEntry::EndConversation(_) => {
                                 *state.phase.borrow_mut() = Phase::Game
                             }
                             _ => {}
@@ -140,11 +142,13 @@ fn main() {
                 if input == Input::Character('x') {
                     if let Some(entry) = engine.next() {
                         match entry {
-                            YarnEntry::Say(s) => *state.phase.borrow_mut() = Phase::Dialogue(s),
-                            YarnEntry::Choose { text, choices } => {
+                            YarnEntry::Say(s) => {
+                                *state.phase.borrow_mut() = Phase::Dialogue(s.into_plain_text())
+                            }
+                            YarnEntry::Choose { text, .. } => {
                                 *state.phase.borrow_mut() = Phase::Dialogue(text)
                             }
-                            YarnEntry::EndConversation => {
+                            YarnEntry::EndConversation(_) => {
                                 *state.phase.borrow_mut() = Phase::Game
                             }
                             _ => {}
@@ -174,11 +178,11 @@ fn main() {
         easy.move_xy(x, y);
         easy.print_char('@');
 
-        if let &Phase::Dialogue(ref s) = &*state.phase.borrow() {
+        if let Phase::Dialogue(ref s) = *state.phase.borrow() {
             let mut draw_y = 4;
             let draw_x = col_count / 3;
             easy.move_xy(draw_x, draw_y);
-            let border: String = repeat('#').take(col_count as usize / 3).collect();
+            let border = "#".repeat(col_count as usize / 3);
             easy.print(&border);
 
             draw_y -= 1;
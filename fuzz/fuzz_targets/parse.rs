@@ -0,0 +1,26 @@
+//! Load arbitrary source and run whatever parses, which must never panic.
+//!
+//! Run with `cargo fuzz run parse` from the repository root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use yarn_spool::{ChoicePolicy, YarnEngine};
+
+fuzz_target!(|source: &str| {
+    let mut engine = YarnEngine::new();
+    if engine.load_from_string(source).is_err() {
+        return;
+    }
+    engine.set_step_budget(1_000);
+    engine.validate();
+    engine.extract_lines();
+    let titles = engine
+        .nodes()
+        .iter()
+        .map(|node| node.title.clone())
+        .collect::<Vec<_>>();
+    for title in titles {
+        let _ = engine.run_node(&title, ChoicePolicy::FirstAvailable);
+    }
+});
@@ -0,0 +1,196 @@
+use crate::engine::{NodeName, Say, Value, YarnEngine, YarnEntry};
+use crate::error::YarnError;
+use bevy::app::{App, Plugin, Update};
+use bevy::asset::io::Reader;
+use bevy::asset::{Asset, AssetApp, AssetLoader, LoadContext};
+use bevy::ecs::event::{Event, EventReader, EventWriter};
+use bevy::ecs::system::NonSendMut;
+use bevy::log::{error, warn};
+use bevy::reflect::TypePath;
+
+/// The source of a `.yarn` file, loaded by the `AssetServer` once
+/// `YarnDialoguePlugin` is added. Files that fail to parse are not loaded, and
+/// the `AssetServer` reports why.
+#[derive(Asset, TypePath, Clone, Debug)]
+pub struct YarnFileAsset {
+    name: String,
+    source: String,
+}
+
+impl YarnFileAsset {
+    /// The path the file was loaded from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The Yarn source of the file.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+/// Loads `.yarn` files as `YarnFileAsset`s, rejecting those that cannot be parsed
+/// with the default `ParseOptions`.
+#[derive(Default)]
+pub struct YarnFileLoader;
+
+impl AssetLoader for YarnFileLoader {
+    type Asset = YarnFileAsset;
+    type Settings = ();
+    type Error = YarnError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<YarnFileAsset, YarnError> {
+        let name = load_context.path().display().to_string();
+        let mut bytes = vec![];
+        reader
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(|err| YarnError::Io(format!("failed to read `{}`: {}", name, err)))?;
+        let source = String::from_utf8(bytes)
+            .map_err(|err| YarnError::Io(format!("failed to read `{}`: {}", name, err)))?;
+        YarnEngine::new().load_from_string_named(&name, &source)?;
+        Ok(YarnFileAsset { name, source })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["yarn"]
+    }
+}
+
+/// A line of dialogue to present, sent when the conversation reaches a
+/// `YarnEntry::Say`. The conversation waits for `YarnDialogue::proceed`.
+#[derive(Event, Clone, Debug)]
+pub struct SayEvent(pub Say);
+
+/// A line of dialogue with options to present, sent when the conversation reaches a
+/// `YarnEntry::Choose`. The conversation waits for a `ChooseSelection`.
+#[derive(Event, Clone, Debug)]
+pub struct ChooseEvent {
+    pub text: String,
+    pub choices: Vec<String>,
+    /// The option `YarnEngine::choose_default` would select.
+    pub default_choice: Option<usize>,
+}
+
+/// An action to perform, sent when the conversation reaches a `YarnEntry::Command`.
+/// The conversation continues without waiting, unless
+/// `YarnEngine::set_commands_require_proceed` is enabled.
+#[derive(Event, Clone, Debug)]
+pub struct CommandEvent {
+    pub action: String,
+}
+
+/// Sent when the conversation ends, with the value of the `<<return value>>` that
+/// ended it, if any. Also sent without a value when the conversation fails.
+#[derive(Event, Clone, Debug)]
+pub struct EndEvent(pub Option<Value>);
+
+/// Sent by the game to select an option of the last `ChooseEvent`.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ChooseSelection(pub usize);
+
+/// The engine running the game's dialogue, inserted as a non-send resource by
+/// `YarnDialoguePlugin` since callbacks registered with the engine must run on the
+/// main thread. Each update, the active conversation runs until it reaches a line,
+/// a choice or its end, sending the corresponding events.
+#[derive(Default)]
+pub struct YarnDialogue {
+    pub engine: YarnEngine,
+    running: bool,
+}
+
+impl YarnDialogue {
+    /// Load the nodes of a `YarnFileAsset` into the engine.
+    pub fn load(&mut self, file: &YarnFileAsset) -> Result<(), YarnError> {
+        self.engine.load_from_string_named(&file.name, &file.source)
+    }
+
+    /// Begin a conversation at the given node, sending its first events on the next
+    /// update.
+    pub fn activate(&mut self, node: NodeName) {
+        self.engine.activate(node);
+        self.running = true;
+    }
+
+    /// Continue the conversation after a `SayEvent`, or after a `CommandEvent` when
+    /// commands require acknowledgement.
+    pub fn proceed(&mut self) {
+        self.engine.proceed();
+        self.running = true;
+    }
+}
+
+/// Registers `YarnFileAsset` with its loader, inserts the `YarnDialogue` resource
+/// and adds the events and the system that runs the conversation. Requires
+/// Bevy's `AssetPlugin`.
+pub struct YarnDialoguePlugin;
+
+impl Plugin for YarnDialoguePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<YarnFileAsset>()
+            .init_asset_loader::<YarnFileLoader>()
+            .insert_non_send_resource(YarnDialogue::default())
+            .add_event::<SayEvent>()
+            .add_event::<ChooseEvent>()
+            .add_event::<CommandEvent>()
+            .add_event::<EndEvent>()
+            .add_event::<ChooseSelection>()
+            .add_systems(Update, run_dialogue);
+    }
+}
+
+fn run_dialogue(
+    mut dialogue: NonSendMut<YarnDialogue>,
+    mut selections: EventReader<ChooseSelection>,
+    mut say: EventWriter<SayEvent>,
+    mut choose: EventWriter<ChooseEvent>,
+    mut command: EventWriter<CommandEvent>,
+    mut end: EventWriter<EndEvent>,
+) {
+    for &ChooseSelection(choice) in selections.read() {
+        match dialogue.engine.choose(choice) {
+            Ok(()) => dialogue.running = true,
+            Err(err) => warn!("ignoring dialogue choice {}: {}", choice, err),
+        }
+    }
+    while dialogue.running {
+        match dialogue.engine.try_next_with(&mut ()) {
+            Ok(Some(YarnEntry::Say(line))) => {
+                say.write(SayEvent(line));
+                dialogue.running = false;
+            }
+            Ok(Some(YarnEntry::Choose {
+                text,
+                choices,
+                default_choice,
+                ..
+            })) => {
+                choose.write(ChooseEvent {
+                    text,
+                    choices,
+                    default_choice,
+                });
+                dialogue.running = false;
+            }
+            Ok(Some(YarnEntry::Command { action })) => {
+                command.write(CommandEvent { action });
+            }
+            Ok(Some(YarnEntry::Error(report))) => warn!("dialogue error: {}", report),
+            Ok(Some(YarnEntry::EndConversation(value))) => {
+                end.write(EndEvent(value));
+                dialogue.running = false;
+            }
+            Ok(None) => dialogue.running = false,
+            Err(err) => {
+                error!("dialogue failed: {}", err);
+                end.write(EndEvent(None));
+                dialogue.running = false;
+            }
+        }
+    }
+}
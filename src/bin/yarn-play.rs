@@ -0,0 +1,185 @@
+//! A minimal terminal player for Yarn scripts.
+//!
+//! ```text
+//! yarn-play <script.yarn> [node] [--var name=value]... [--seed n] [--transcript file]
+//! ```
+//!
+//! Lines of dialogue are printed as they are reached, commands are printed in
+//! brackets, and options are numbered and selected by entering their number.
+
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::process;
+use yarn_spool::{NodeName, Value, VariableName, YarnEngine, YarnEntry};
+
+const USAGE: &str =
+    "usage: yarn-play <script.yarn> [node] [--var name=value]... [--seed n] [--transcript file]";
+
+struct Options {
+    script: String,
+    node: String,
+    variables: Vec<(String, Value)>,
+    seed: Option<u64>,
+    transcript: Option<String>,
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut positional = vec![];
+    let mut variables = vec![];
+    let mut seed = None;
+    let mut transcript = None;
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().ok_or(format!("missing value for {}", flag));
+        match arg.as_str() {
+            "--var" => {
+                let assignment = value("--var")?;
+                let (name, value) = assignment
+                    .split_once('=')
+                    .ok_or(format!("expected name=value, found `{}`", assignment))?;
+                variables.push((name.trim_start_matches('$').to_string(), parse_value(value)));
+            }
+            "--seed" => {
+                let n = value("--seed")?;
+                seed = Some(n.parse().map_err(|_| format!("invalid seed `{}`", n))?);
+            }
+            "--transcript" => transcript = Some(value("--transcript")?),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let script = positional.next().ok_or("missing script")?;
+    let node = positional.next().unwrap_or_else(|| "Start".to_string());
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument `{}`", extra));
+    }
+    Ok(Options {
+        script,
+        node,
+        variables,
+        seed,
+        transcript,
+    })
+}
+
+/// Interpret a `--var` value as a number or boolean if possible, and a string
+/// otherwise.
+fn parse_value(value: &str) -> Value {
+    match value {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => match value.parse() {
+            Ok(n) => Value::Number(n),
+            Err(_) => Value::String(value.to_string()),
+        },
+    }
+}
+
+/// Writes the player's output to stdout and, if requested, a transcript file.
+struct Output {
+    transcript: Option<File>,
+}
+
+impl Output {
+    fn line(&mut self, text: &str) {
+        println!("{}", text);
+        self.record(text);
+    }
+
+    /// Record text in the transcript only, such as the selections read from stdin.
+    fn record(&mut self, text: &str) {
+        if let Some(ref mut file) = self.transcript {
+            if writeln!(file, "{}", text).is_err() {
+                eprintln!("error: failed to write transcript");
+                self.transcript = None;
+            }
+        }
+    }
+}
+
+fn main() {
+    let options = match parse_options(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+    process::exit(match play(options) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            1
+        }
+    });
+}
+
+fn play(options: Options) -> Result<(), String> {
+    let mut engine = YarnEngine::new();
+    if let Some(seed) = options.seed {
+        engine.set_seed(seed);
+    }
+    engine
+        .load_from_file(&options.script)
+        .map_err(|err| err.to_string())?;
+    for (name, value) in options.variables {
+        engine.set_variable(VariableName(name), value);
+    }
+    let node = NodeName::from(options.node);
+    if engine.node(&node).is_none() {
+        return Err(format!("in `{}`: unknown node `{}`", options.script, node));
+    }
+    let transcript = match options.transcript {
+        Some(path) => Some(
+            File::create(&path).map_err(|err| format!("failed to create `{}`: {}", path, err))?,
+        ),
+        None => None,
+    };
+    let mut output = Output { transcript };
+    let stdin = io::stdin();
+    let mut input = stdin.lock().lines();
+
+    engine.activate(node);
+    loop {
+        let entry =
+            engine
+                .try_next_with(&mut ())
+                .map_err(|err| match engine.node_trail().last() {
+                    Some(node) => format!("in node `{}`: {}", node, err),
+                    None => err.to_string(),
+                })?;
+        match entry {
+            Some(YarnEntry::Say(line)) => output.line(line.plain_text()),
+            Some(YarnEntry::Choose { text, choices, .. }) => {
+                output.line(&text);
+                for (index, choice) in choices.iter().enumerate() {
+                    output.line(&format!("  {}. {}", index + 1, choice));
+                }
+                let selection = loop {
+                    print!("> ");
+                    let _ = io::stdout().flush();
+                    let line = match input.next() {
+                        Some(Ok(line)) => line,
+                        _ => return Err("no selection made".to_string()),
+                    };
+                    match line.trim().parse::<usize>() {
+                        Ok(n) if n >= 1 && n <= choices.len() => {
+                            output.record(&format!("> {}", n));
+                            break n - 1;
+                        }
+                        _ => println!("enter a number from 1 to {}", choices.len()),
+                    }
+                };
+                engine
+                    .choose(selection)
+                    .map_err(|err| format!("failed to select option {}: {}", selection + 1, err))?;
+            }
+            Some(YarnEntry::Command { action }) => {
+                output.line(&format!("[{}]", action));
+                engine.proceed();
+            }
+            Some(YarnEntry::Error(err)) => eprintln!("error: {}", err),
+            Some(YarnEntry::EndConversation(_)) | None => return Ok(()),
+        }
+    }
+}
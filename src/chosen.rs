@@ -0,0 +1,72 @@
+use crate::engine::{Choice, ChoiceKind, NodeName, Step, Value};
+use std::collections::HashMap;
+use std::ptr;
+
+/// The number of times each option has been selected, by node and by the option's
+/// position among all of the node's options in source order.
+pub(crate) type ChoiceCounts = HashMap<(NodeName, usize), u32>;
+
+/// Collect the options in the given steps in source order, including those nested
+/// in inline options and conditionals.
+fn collect_options<'a>(steps: &'a [Step], options: &mut Vec<&'a Choice>) {
+    for step in steps {
+        match step {
+            Step::Dialogue(_, choices, _) => {
+                for choice in choices {
+                    options.push(choice);
+                    if let ChoiceKind::Inline(ref steps) = choice.kind {
+                        collect_options(steps, options);
+                    }
+                }
+            }
+            Step::Conditional(_, if_steps, else_ifs, else_steps) => {
+                collect_options(if_steps, options);
+                for (_, steps) in else_ifs {
+                    collect_options(steps, options);
+                }
+                collect_options(else_steps, options);
+            }
+            Step::Command(..)
+            | Step::Assign(..)
+            | Step::Jump(..)
+            | Step::Assert(..)
+            | Step::Log(..)
+            | Step::Declare(..)
+            | Step::Const(..)
+            | Step::Enum(..)
+            | Step::LineGroup(..)
+            | Step::Return(..)
+            | Step::Unknown(..) => {}
+        }
+    }
+}
+
+/// The position of each of the given options among the options of the node whose
+/// steps are given. The options must belong to one of the node's steps.
+pub(crate) fn positions(steps: &[Step], choices: &[Choice]) -> Vec<usize> {
+    let mut options = vec![];
+    collect_options(steps, &mut options);
+    choices
+        .iter()
+        .map(|choice| {
+            let position = options.iter().position(|option| ptr::eq(*option, choice));
+            position.expect("the option belongs to the node")
+        })
+        .collect()
+}
+
+/// The position of an option given to `chosen()` as either its position or the ID
+/// from its `#line:` tag.
+pub(crate) fn find(steps: &[Step], option: &Value) -> Option<usize> {
+    match option {
+        Value::Number(n) if *n >= 0. && n.fract() == 0. => Some(*n as usize),
+        Value::String(id) => {
+            let id = id.trim();
+            let tag = format!("line:{}", id.strip_prefix("line:").unwrap_or(id));
+            let mut options = vec![];
+            collect_options(steps, &mut options);
+            options.iter().position(|option| option.tags.contains(&tag))
+        }
+        _ => None,
+    }
+}
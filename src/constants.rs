@@ -0,0 +1,120 @@
+use crate::engine::{ChoiceKind, Expr, OptionCase, Step, Term, Value};
+use crate::error::YarnError;
+use std::collections::HashMap;
+
+/// Collect the `<<const>>` steps in the given steps: each constant and its value.
+pub(crate) fn collect_constants(steps: &[Step], constants: &mut Vec<(String, Value)>) {
+    for step in steps {
+        match step {
+            Step::Const(name, value) => constants.push((name.clone(), value.clone())),
+            Step::Dialogue(_, choices, _) => {
+                for choice in choices {
+                    if let ChoiceKind::Inline(ref steps) = choice.kind {
+                        collect_constants(steps, constants);
+                    }
+                }
+            }
+            Step::Conditional(_, if_steps, else_ifs, else_steps) => {
+                collect_constants(if_steps, constants);
+                for (_, steps) in else_ifs {
+                    collect_constants(steps, constants);
+                }
+                collect_constants(else_steps, constants);
+            }
+            Step::Command(..)
+            | Step::Assign(..)
+            | Step::Jump(..)
+            | Step::Assert(..)
+            | Step::Log(..)
+            | Step::Declare(..)
+            | Step::Enum(..)
+            | Step::LineGroup(..)
+            | Step::Return(..)
+            | Step::Unknown(..) => {}
+        }
+    }
+}
+
+/// Check that the given steps only refer to constants that are defined, and never
+/// assign to one.
+pub(crate) fn check_constants(
+    steps: &[Step],
+    constants: &HashMap<String, Value>,
+) -> Result<(), YarnError> {
+    let check = |expr: &Expr| check_expr(expr, constants);
+    for step in steps {
+        match step {
+            Step::Dialogue(_, choices, _) => {
+                for choice in choices {
+                    if let Some(ref condition) = choice.condition {
+                        check(condition)?;
+                    }
+                    if let Some(OptionCase::Compare {
+                        ref selector,
+                        ref operand,
+                        ..
+                    }) = choice.case
+                    {
+                        check(selector)?;
+                        check(operand)?;
+                    }
+                    if let ChoiceKind::Inline(ref steps) = choice.kind {
+                        check_constants(steps, constants)?;
+                    }
+                }
+            }
+            Step::Assign(name, expr) => {
+                if constants.contains_key(&name.0) {
+                    return Err(YarnError::AssignToConstant(name.0.clone()));
+                }
+                check(expr)?;
+            }
+            Step::Conditional(condition, if_steps, else_ifs, else_steps) => {
+                check(condition)?;
+                check_constants(if_steps, constants)?;
+                for (condition, steps) in else_ifs {
+                    check(condition)?;
+                    check_constants(steps, constants)?;
+                }
+                check_constants(else_steps, constants)?;
+            }
+            Step::LineGroup(group) => {
+                for condition in group.lines.iter().filter_map(|l| l.condition.as_ref()) {
+                    check(condition)?;
+                }
+            }
+            Step::Assert(assertion) => check(&assertion.condition)?,
+            Step::Log(args) => args.iter().try_for_each(check)?,
+            Step::Return(value) => value.iter().try_for_each(check)?,
+            Step::Command(..)
+            | Step::Jump(..)
+            | Step::Declare(..)
+            | Step::Const(..)
+            | Step::Enum(..)
+            | Step::Unknown(..) => {}
+        }
+    }
+    Ok(())
+}
+
+fn check_expr(expr: &Expr, constants: &HashMap<String, Value>) -> Result<(), YarnError> {
+    match expr {
+        Expr::Term(Term::Constant(name)) if !constants.contains_key(name) => {
+            Err(YarnError::UndefinedConstant(name.clone()))
+        }
+        Expr::Term(Term::Function(_, args)) => {
+            args.iter().try_for_each(|arg| check_expr(arg, constants))
+        }
+        Expr::Term(_) => Ok(()),
+        Expr::Unary(_, expr) | Expr::Parentheses(expr) => check_expr(expr, constants),
+        Expr::Binary(_, left, right) => {
+            check_expr(left, constants)?;
+            check_expr(right, constants)
+        }
+        Expr::Ternary(condition, if_true, if_false) => {
+            check_expr(condition, constants)?;
+            check_expr(if_true, constants)?;
+            check_expr(if_false, constants)
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use crate::engine::{FunctionCallback, Value, YarnEngine, YarnType};
+use crate::error::TypeError;
+use std::convert::TryFrom;
+
+/// A type that can be extracted from a Yarn value. Implement this for wrapper types
+/// to read them directly with `YarnEngine::get_variable_as`.
+pub trait FromValue: Sized {
+    /// Convert the value without coercion, failing if it holds a different type.
+    fn from_value(value: Value) -> Result<Self, TypeError>;
+
+    /// Convert the value, coercing it if necessary. Defaults to `from_value`.
+    fn from_value_lenient(value: Value) -> Result<Self, TypeError> {
+        Self::from_value(value)
+    }
+
+    /// The only type accepted by `from_value`, if there is one.
+    fn type_name() -> Option<YarnType> {
+        None
+    }
+}
+
+/// A type that can be converted into a Yarn value, such as the return value of a
+/// function registered with `YarnEngine::register_typed_fn`.
+pub trait IntoValue {
+    /// Convert into a Yarn value.
+    fn into_value(self) -> Value;
+}
+
+impl<T: Into<Value>> IntoValue for T {
+    fn into_value(self) -> Value {
+        self.into()
+    }
+}
+
+/// A type whose fields are stored in Yarn variables, such as a game's save data.
+/// Derive it with `#[derive(YarnVariables)]` when the `derive` feature is enabled.
+pub trait YarnVariables: Sized {
+    /// Set the variable for each field to the field's value.
+    fn apply_to<Ctx: 'static>(&self, engine: &mut YarnEngine<Ctx>);
+
+    /// Read each field from its variable with `YarnEngine::get_variable_as`.
+    fn extract_from<Ctx: 'static>(engine: &YarnEngine<Ctx>) -> Result<Self, TypeError>;
+}
+
+/// A closure that can be registered with `YarnEngine::register_typed_fn`. This is
+/// implemented for closures of up to four arguments whose arguments implement
+/// `FromValue` and whose return value implements `IntoValue`.
+pub trait RegisterFn<Args> {
+    /// The number of arguments accepted by the closure.
+    fn num_args() -> usize;
+
+    /// The type of each argument accepted by the closure, if only one is accepted.
+    fn param_types() -> Vec<Option<YarnType>>;
+
+    /// Wrap the closure in a callback that converts its arguments and return value.
+    /// Arguments of the wrong type cause an evaluation error.
+    fn into_callback(self) -> Box<FunctionCallback>;
+}
+
+macro_rules! impl_register_fn {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> RegisterFn<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: IntoValue,
+            $($arg: FromValue,)*
+        {
+            fn num_args() -> usize {
+                <[&str]>::len(&[$(stringify!($arg)),*])
+            }
+
+            fn param_types() -> Vec<Option<YarnType>> {
+                vec![$($arg::type_name()),*]
+            }
+
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_callback(self) -> Box<FunctionCallback> {
+                Box::new(move |args, _| {
+                    let mut args = args.into_iter();
+                    $(let $arg = $arg::from_value(args.next().ok_or("missing argument")?)
+                        .map_err(|error| error.to_string())?;)*
+                    Ok(self($($arg),*).into_value())
+                })
+            }
+        }
+    };
+}
+
+impl_register_fn!();
+impl_register_fn!(A);
+impl_register_fn!(A, B);
+impl_register_fn!(A, B, C);
+impl_register_fn!(A, B, C, D);
+
+/// The name of the type held by the value, for error messages.
+pub(crate) fn type_name(value: &Value) -> &'static str {
+    YarnType::of(value).name()
+}
+
+fn mismatch(expected: &'static str, found: Value) -> TypeError {
+    TypeError::Mismatch { expected, found }
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        Ok(value)
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        match value {
+            Value::Number(f) => Ok(f),
+            value => Err(mismatch("number", value)),
+        }
+    }
+
+    /// Coerce using `Value::as_num`.
+    fn from_value_lenient(value: Value) -> Result<Self, TypeError> {
+        Ok(value.as_num())
+    }
+
+    fn type_name() -> Option<YarnType> {
+        Some(YarnType::Number)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+            value => Err(mismatch("boolean", value)),
+        }
+    }
+
+    /// Coerce using `Value::as_bool`.
+    fn from_value_lenient(value: Value) -> Result<Self, TypeError> {
+        Ok(value.as_bool())
+    }
+
+    fn type_name() -> Option<YarnType> {
+        Some(YarnType::Boolean)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        match value {
+            Value::String(s) => Ok(s),
+            value => Err(mismatch("string", value)),
+        }
+    }
+
+    /// Coerce using `Value::as_string`.
+    fn from_value_lenient(value: Value) -> Result<Self, TypeError> {
+        Ok(value.as_string())
+    }
+
+    fn type_name() -> Option<YarnType> {
+        Some(YarnType::String)
+    }
+}
+
+macro_rules! impl_from_value_integer {
+    ($($ty:ident),*) => {
+        $(
+            /// Accepts numbers with no fractional part that fit in the type.
+            impl FromValue for $ty {
+                fn from_value(value: Value) -> Result<Self, TypeError> {
+                    match value {
+                        Value::Number(f)
+                            if f.fract() == 0.0 && f >= $ty::MIN as f32 && f <= $ty::MAX as f32 =>
+                        {
+                            Ok(f as $ty)
+                        }
+                        value => Err(mismatch("whole number", value)),
+                    }
+                }
+
+                /// Coerce using `Value::as_num`, rounding towards zero and saturating.
+                fn from_value_lenient(value: Value) -> Result<Self, TypeError> {
+                    Ok(value.as_num() as $ty)
+                }
+
+                fn type_name() -> Option<YarnType> {
+                    Some(YarnType::Number)
+                }
+            }
+
+            impl From<$ty> for Value {
+                fn from(n: $ty) -> Value {
+                    Value::Number(n as f32)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_value_integer!(i32, u32);
+
+impl TryFrom<Value> for f32 {
+    type Error = TypeError;
+    fn try_from(value: Value) -> Result<Self, TypeError> {
+        f32::from_value(value)
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = TypeError;
+    fn try_from(value: Value) -> Result<Self, TypeError> {
+        bool::from_value(value)
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = TypeError;
+    fn try_from(value: Value) -> Result<Self, TypeError> {
+        String::from_value(value)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(f: f32) -> Value {
+        Value::Number(f)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Boolean(b)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
@@ -0,0 +1,281 @@
+use crate::engine::{NodeName, Nodes};
+use crate::localize::{self, LineKind, LocalizableLine};
+use crate::normalize::TextNormalization;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// The differences between two versions of a script, such as the nodes of two
+/// engines, for reviewing changes to content.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScriptDiff {
+    /// Each node that was added, removed or modified: those of the new version in
+    /// its order, followed by those removed in the order of the old version.
+    pub nodes: Vec<NodeDiff>,
+}
+
+/// How a node differs between two versions of a script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeDiff {
+    /// The title of the node.
+    pub node: NodeName,
+    pub status: NodeStatus,
+    /// The headers that were added, removed or changed, ordered by name.
+    pub headers: Vec<HeaderChange>,
+    /// The lines and options that were added, removed or changed. Lines are
+    /// matched by their `#line:` tag if they have one, and otherwise by their
+    /// generated ID, so an untagged line whose text changed is removed and added.
+    pub lines: Vec<LineChange>,
+}
+
+/// Whether a node was added, removed or modified.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NodeStatus {
+    Added,
+    Removed,
+    /// The node's headers, text or other steps changed.
+    Modified,
+}
+
+/// A header whose value differs between two versions of a node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderChange {
+    /// The name of the header, in lowercase.
+    pub name: String,
+    /// The old value, or `None` if the header was added.
+    pub old: Option<String>,
+    /// The new value, or `None` if the header was removed.
+    pub new: Option<String>,
+}
+
+/// A line of dialogue or option whose text or tags differ between two versions of
+/// a node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineChange {
+    /// The line's ID, as given by `YarnEngine::extract_lines`.
+    pub id: String,
+    /// The old line, or `None` if the line was added.
+    pub old: Option<LocalizableLine>,
+    /// The new line, or `None` if the line was removed.
+    pub new: Option<LocalizableLine>,
+}
+
+impl ScriptDiff {
+    /// Whether the two versions have the same nodes and content.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The diff as a JSON document, for review tools.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"nodes\":[");
+        for (idx, node) in self.nodes.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            let status = match node.status {
+                NodeStatus::Added => "added",
+                NodeStatus::Removed => "removed",
+                NodeStatus::Modified => "modified",
+            };
+            let _ = write!(
+                out,
+                "{{\"node\":{},\"status\":\"{}\",\"headers\":[",
+                json_string(node.node.as_str()),
+                status
+            );
+            for (idx, header) in node.headers.iter().enumerate() {
+                if idx > 0 {
+                    out.push(',');
+                }
+                let _ = write!(
+                    out,
+                    "{{\"name\":{},\"old\":{},\"new\":{}}}",
+                    json_string(&header.name),
+                    json_option(header.old.as_deref()),
+                    json_option(header.new.as_deref())
+                );
+            }
+            out.push_str("],\"lines\":[");
+            for (idx, line) in node.lines.iter().enumerate() {
+                if idx > 0 {
+                    out.push(',');
+                }
+                let _ = write!(
+                    out,
+                    "{{\"id\":{},\"old\":{},\"new\":{}}}",
+                    json_string(&line.id),
+                    json_line(line.old.as_ref()),
+                    json_line(line.new.as_ref())
+                );
+            }
+            out.push_str("]}");
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+/// Compare two versions of a script. Text is compared after parsing, so changes
+/// to whitespace and formatting that do not change the content are ignored.
+pub fn diff(old: &Nodes, new: &Nodes) -> ScriptDiff {
+    let normalization = TextNormalization::default();
+    let lines_by_node = |nodes: &Nodes| {
+        let mut lines: HashMap<NodeName, Vec<LocalizableLine>> = HashMap::new();
+        for line in localize::extract_lines(nodes, &normalization) {
+            lines.entry(line.node.clone()).or_default().push(line);
+        }
+        lines
+    };
+    let mut old_lines = lines_by_node(old);
+    let mut new_lines = lines_by_node(new);
+    let mut lines = |name: &NodeName| {
+        (
+            old_lines.remove(name).unwrap_or_default(),
+            new_lines.remove(name).unwrap_or_default(),
+        )
+    };
+
+    let mut nodes = vec![];
+    for node in new.iter() {
+        let (old_node_lines, new_node_lines) = lines(&node.title);
+        let previous = match old.get(&node.title) {
+            Some(previous) => previous,
+            None => {
+                nodes.push(NodeDiff {
+                    node: node.title.clone(),
+                    status: NodeStatus::Added,
+                    headers: diff_headers(&HashMap::new(), &node.extra),
+                    lines: diff_lines(vec![], new_node_lines),
+                });
+                continue;
+            }
+        };
+        let headers = diff_headers(&previous.extra, &node.extra);
+        let lines = diff_lines(old_node_lines, new_node_lines);
+        let steps_changed = previous.steps().ok() != node.steps().ok();
+        if !headers.is_empty() || !lines.is_empty() || steps_changed {
+            nodes.push(NodeDiff {
+                node: node.title.clone(),
+                status: NodeStatus::Modified,
+                headers,
+                lines,
+            });
+        }
+    }
+    for node in old.iter().filter(|node| !new.contains(&node.title)) {
+        let (old_node_lines, _) = lines(&node.title);
+        nodes.push(NodeDiff {
+            node: node.title.clone(),
+            status: NodeStatus::Removed,
+            headers: diff_headers(&node.extra, &HashMap::new()),
+            lines: diff_lines(old_node_lines, vec![]),
+        });
+    }
+    ScriptDiff { nodes }
+}
+
+fn diff_headers(old: &HashMap<String, String>, new: &HashMap<String, String>) -> Vec<HeaderChange> {
+    let mut names = old.keys().chain(new.keys()).collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| old.get(*name) != new.get(*name))
+        .map(|name| HeaderChange {
+            name: name.clone(),
+            old: old.get(name).cloned(),
+            new: new.get(name).cloned(),
+        })
+        .collect()
+}
+
+/// The changes between the lines of two versions of a node, in the order of the
+/// new version followed by the lines removed. Lines with a `#line:` tag are matched
+/// by their tag, and the others by their position in the node, so that editing an
+/// untagged line reports it as changed rather than as removed and added.
+fn diff_lines(old: Vec<LocalizableLine>, new: Vec<LocalizableLine>) -> Vec<LineChange> {
+    let key = |(position, line): (usize, &LocalizableLine)| {
+        let tagged = line.tags.iter().any(|tag| tag.starts_with("line:"));
+        if tagged {
+            LineKey::Tagged(line.id.clone())
+        } else {
+            LineKey::Position(position)
+        }
+    };
+    let order = old.iter().enumerate().map(key).collect::<Vec<_>>();
+    let mut old = order.iter().cloned().zip(old).collect::<HashMap<_, _>>();
+    let mut changes = vec![];
+    for (position, line) in new.into_iter().enumerate() {
+        let previous = old.remove(&key((position, &line)));
+        let unchanged = previous.as_ref().is_some_and(|previous| {
+            previous.text == line.text && previous.tags == line.tags && previous.kind == line.kind
+        });
+        if !unchanged {
+            changes.push(LineChange {
+                id: line.id.clone(),
+                old: previous,
+                new: Some(line),
+            });
+        }
+    }
+    let removed = order.iter().filter_map(|key| old.remove(key));
+    changes.extend(removed.map(|line| LineChange {
+        id: line.id.clone(),
+        old: Some(line),
+        new: None,
+    }));
+    changes
+}
+
+/// How a line is matched between two versions of a node.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum LineKey {
+    Tagged(String),
+    Position(usize),
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub(crate) fn json_option(s: Option<&str>) -> String {
+    s.map_or_else(|| "null".to_string(), json_string)
+}
+
+fn json_line(line: Option<&LocalizableLine>) -> String {
+    let line = match line {
+        Some(line) => line,
+        None => return "null".to_string(),
+    };
+    let kind = match line.kind {
+        LineKind::Say => "say",
+        LineKind::Option => "option",
+    };
+    let tags = line
+        .tags
+        .iter()
+        .map(|tag| json_string(tag))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"kind\":\"{}\",\"text\":{},\"tags\":[{}]}}",
+        kind,
+        json_string(&line.text),
+        tags
+    )
+}
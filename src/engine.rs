@@ -1,29 +1,184 @@
-use crate::parse;
+use crate::chosen::{self, ChoiceCounts};
+use crate::constants;
+use crate::convert::{self, FromValue, RegisterFn};
+use crate::enums::{self, Enums};
+use crate::error::{ErrorReport, RegisterError, TypeError, YarnError};
+use crate::filter::{ContentFilter, ContentRef, FilterDecision};
+use crate::flags;
+use crate::lint::{self, LintDiagnostic, LintRule};
+use crate::load::{LoadOptions, LoadReport, LoadSession};
+use crate::localize::{self, LocalizableLine};
+use crate::markup::{self, MarkupSpan};
+use crate::metrics::ParseMetrics;
+use crate::normalize::TextNormalization;
+use crate::paginate::{self, Pagination};
+use crate::parse::{self, LazyBody, ParseOptions, TokenIterator};
+use crate::progress::StoryProgress;
+use crate::pseudo;
+use crate::random::{Randomness, Rng, RngMode};
+use crate::stats::{Counter, Stats};
+use crate::storylet::{self, AvailableNode};
+use crate::suggest;
+use crate::validate::{self, ActivationProblem, FunctionInfo, LineLengthLimit, ValidationWarning};
+use crate::view::{EvalView, SyncFunction, SyncFunctionCallback};
 use send_wrapper::SendWrapper;
+use std::cell::{RefCell, RefMut};
 use std::cmp::PartialEq;
 use std::{
-    collections::HashMap,
-    ops::{Add, Div, Mul, Sub},
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, fs, io,
+    ops::{Add, Div, Mul, RangeInclusive, Sub},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+    time::SystemTime,
 };
 
 //TODO: dialogue options inside conditionals
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub struct NodeName(pub String);
+/// The title of a node. Names are reference counted, so cloning one is cheap, and
+/// loaded nodes share a single allocation for each distinct name.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeName(pub Arc<str>);
+
+impl NodeName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Serialized as the title.
+#[cfg(feature = "serde")]
+impl serde::Serialize for NodeName {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Deserialized from the title.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for NodeName {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(NodeName::from)
+    }
+}
+
+impl From<&str> for NodeName {
+    fn from(name: &str) -> NodeName {
+        NodeName(name.into())
+    }
+}
+
+impl From<String> for NodeName {
+    fn from(name: String) -> NodeName {
+        NodeName(name.into())
+    }
+}
+
+impl fmt::Display for NodeName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The distinct node names seen while loading, so that a node's title and every jump
+/// or option referring to it share storage. Conditions are shared in the same way,
+/// as large scripts repeat the same conditions many times. Shared between parsing
+/// threads.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct NodeNames {
+    names: Arc<Mutex<HashSet<Arc<str>>>>,
+    /// Conditions keyed by their source, as formatted by `Expr`'s `Display`.
+    conditions: Arc<Mutex<HashMap<String, Arc<Expr>>>>,
+}
+
+impl NodeNames {
+    /// The shared name equal to the given string, adding it if it is new.
+    pub(crate) fn intern(&self, name: &str) -> NodeName {
+        let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(name) = names.get(name) {
+            return NodeName(name.clone());
+        }
+        let name: Arc<str> = name.into();
+        names.insert(name.clone());
+        NodeName(name)
+    }
+
+    /// The shared condition equal to the given one, adding it if it is new.
+    pub(crate) fn intern_condition(&self, condition: Expr) -> Arc<Expr> {
+        let mut conditions = self
+            .conditions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let source = condition.to_string();
+        match conditions.get(&source) {
+            // Distinct conditions formatted alike are kept apart.
+            Some(shared) if **shared == condition => shared.clone(),
+            Some(_) => Arc::new(condition),
+            None => {
+                let condition = Arc::new(condition);
+                conditions.insert(source, condition.clone());
+                condition
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct VariableName(pub String);
 
-struct Variables(HashMap<VariableName, Value>);
+impl VariableName {
+    /// Whether this is a node-local variable, written `$_name`. Local variables are
+    /// discarded when execution leaves the node that set them and are never
+    /// included in the engine's exported variables.
+    pub fn is_local(&self) -> bool {
+        self.0.starts_with('_')
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Variables {
+    pub(crate) values: HashMap<VariableName, Value>,
+    /// The generation in which each variable was last written.
+    written: HashMap<VariableName, u64>,
+    /// Incremented on every write.
+    generation: u64,
+}
+
 impl Variables {
     fn set(&mut self, name: VariableName, value: Value) {
-        self.0.insert(name, value);
+        self.generation += 1;
+        self.written.insert(name.clone(), self.generation);
+        self.values.insert(name, value);
+    }
+
+    fn remove(&mut self, name: &VariableName) {
+        self.generation += 1;
+        self.written.insert(name.clone(), self.generation);
+        self.values.remove(name);
+    }
+
+    /// The latest generation in which any of the given variables was written, or 0
+    /// if none of them have been.
+    fn last_written(&self, names: &[VariableName]) -> u64 {
+        names
+            .iter()
+            .filter_map(|name| self.written.get(name))
+            .max()
+            .cloned()
+            .unwrap_or(0)
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) struct Choice {
-    text: String,
-    kind: ChoiceKind,
+    pub(crate) text: String,
+    pub(crate) kind: ChoiceKind,
+    /// The option is only presented if the condition is true.
+    pub(crate) condition: Option<Arc<Expr>>,
+    pub(crate) tags: Vec<String>,
+    /// The option's case in a `<<options match>>` block, if any.
+    pub(crate) case: Option<OptionCase>,
 }
 
 impl Choice {
@@ -31,50 +186,145 @@ impl Choice {
         Choice {
             text,
             kind: ChoiceKind::External(name),
+            condition: None,
+            tags: vec![],
+            case: None,
         }
     }
 
-    pub(crate) fn inline(text: String, steps: Vec<Step>, condition: Option<Expr>) -> Choice {
+    pub(crate) fn inline(text: String, steps: Vec<Step>) -> Choice {
         Choice {
             text,
-            kind: ChoiceKind::Inline(steps, condition),
+            kind: ChoiceKind::Inline(steps),
+            condition: None,
+            tags: vec![],
+            case: None,
+        }
+    }
+
+    pub(crate) fn with_condition(mut self, condition: Option<Arc<Expr>>) -> Choice {
+        self.condition = condition;
+        self
+    }
+
+    pub(crate) fn with_tags(mut self, tags: Vec<String>) -> Choice {
+        self.tags = tags;
+        self
+    }
+
+    pub(crate) fn with_case(mut self, case: Option<OptionCase>) -> Choice {
+        self.case = case;
+        self
+    }
+}
+
+/// The case of an option in a block of options preceded by
+/// `<<options match selector>>`. Of the options with a comparison, only the first
+/// whose comparison and condition are true is presented.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum OptionCase {
+    /// Compare the value of the selector with the operand, as in `<<case < 10>>`,
+    /// or `<<case 10>>` for equality.
+    Compare {
+        selector: Arc<Expr>,
+        op: BinaryOp,
+        operand: Expr,
+    },
+    /// Presented only if no comparison matched, written `<<case else>>`.
+    Else,
+}
+
+impl fmt::Display for OptionCase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionCase::Compare { op, operand, .. } => write!(f, "{} {}", op.symbol(), operand),
+            OptionCase::Else => f.write_str("else"),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum ChoiceKind {
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ChoiceKind {
     External(NodeName),
-    Inline(Vec<Step>, Option<Expr>),
+    Inline(Vec<Step>),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) enum Step {
-    Dialogue(String, Vec<Choice>),
+    Dialogue(String, Vec<Choice>, Vec<String>),
     Command(String),
     Assign(VariableName, Expr),
-    Conditional(Expr, Vec<Step>, Vec<(Expr, Vec<Step>)>, Vec<Step>),
+    Conditional(Arc<Expr>, Vec<Step>, Vec<(Arc<Expr>, Vec<Step>)>, Vec<Step>),
     Jump(NodeName),
+    Assert(Assertion),
+    Log(Vec<Expr>),
+    /// `<<declare $name = default>>`, which gives a variable a type and a default
+    /// value when the node is loaded and does nothing when executed.
+    Declare(VariableName, Value, YarnType),
+    /// `<<const NAME = value>>`, which defines a constant when the node is loaded
+    /// and does nothing when executed.
+    Const(String, Value),
+    /// `<<enum Name>>` followed by a `<<case Name>>` for each case and `<<endenum>>`,
+    /// which declares an enum when the node is loaded and does nothing when executed.
+    Enum(String, Vec<String>),
+    /// Consecutive `=> line` alternatives, of which one is presented.
+    LineGroup(LineGroup),
+    /// `<<return value>>` or `<<return>>`, which ends the conversation.
+    Return(Option<Expr>),
+    /// A `<<statement>>` that could not be parsed, kept verbatim when parsing with
+    /// `ParseOptions::forward_compatible`. Does nothing when executed.
+    Unknown(String),
+}
+
+/// A group of alternative lines. Each time the group is reached, one line whose
+/// condition holds is presented, preferring the lines shown least recently.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LineGroup {
+    pub(crate) lines: Vec<GroupLine>,
+    /// The line of the source where the group starts, which identifies it within
+    /// its node.
+    pub(crate) line: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct GroupLine {
+    pub(crate) text: String,
+    /// The line is only presented if the condition is true.
+    pub(crate) condition: Option<Arc<Expr>>,
+    pub(crate) tags: Vec<String>,
 }
 
-#[derive(Debug, PartialEq)]
+/// An `<<assert condition, "message">>` step, checked only when assertions are
+/// enabled.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Assertion {
+    pub(crate) condition: Expr,
+    /// The source of the condition.
+    pub(crate) text: String,
+    pub(crate) message: Option<String>,
+    pub(crate) line: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) enum Expr {
     Unary(UnaryOp, Box<Expr>),
     Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
     Term(Term),
     Parentheses(Box<Expr>),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) enum UnaryOp {
     Not,
     Negate,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) enum BinaryOp {
     And,
     Or,
+    Xor,
     Plus,
     Minus,
     Multiply,
@@ -85,43 +335,392 @@ pub(crate) enum BinaryOp {
     LessThan,
     GreaterThanEqual,
     LessThanEqual,
+    Coalesce,
+}
+
+impl BinaryOp {
+    /// The operator as written in Yarn source.
+    pub(crate) fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::And => "and",
+            BinaryOp::Or => "or",
+            BinaryOp::Xor => "xor",
+            BinaryOp::Plus => "+",
+            BinaryOp::Minus => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Equals => "==",
+            BinaryOp::NotEquals => "!=",
+            BinaryOp::GreaterThan => ">",
+            BinaryOp::LessThan => "<",
+            BinaryOp::GreaterThanEqual => ">=",
+            BinaryOp::LessThanEqual => "<=",
+            BinaryOp::Coalesce => "??",
+        }
+    }
+
+    /// How tightly the operator binds; higher values bind more tightly.
+    pub(crate) fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Coalesce => 0,
+            BinaryOp::Or => 1,
+            // As with the bitwise operators in C, xor binds tighter than or but
+            // looser than and.
+            BinaryOp::Xor => 2,
+            BinaryOp::And => 3,
+            BinaryOp::Equals | BinaryOp::NotEquals => 4,
+            BinaryOp::GreaterThan
+            | BinaryOp::LessThan
+            | BinaryOp::GreaterThanEqual
+            | BinaryOp::LessThanEqual => 5,
+            BinaryOp::Plus | BinaryOp::Minus => 6,
+            BinaryOp::Multiply | BinaryOp::Divide => 7,
+        }
+    }
+}
+
+/// Expressions are displayed as Yarn source, with parentheses where the source had
+/// them.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Unary(UnaryOp::Not, expr) => write!(f, "!{}", expr),
+            Expr::Unary(UnaryOp::Negate, expr) => write!(f, "-{}", expr),
+            Expr::Binary(op, left, right) => write!(f, "{} {} {}", left, op.symbol(), right),
+            Expr::Ternary(condition, if_true, if_false) => {
+                write!(f, "{} ? {} : {}", condition, if_true, if_false)
+            }
+            Expr::Term(Term::Number(n)) => write!(f, "{}", n),
+            Expr::Term(Term::Boolean(b)) => write!(f, "{}", b),
+            Expr::Term(Term::Null) => f.write_str("null"),
+            Expr::Term(Term::String(s)) => write!(f, "\"{}\"", s),
+            Expr::Term(Term::Variable(name)) => write!(f, "${}", name.0),
+            Expr::Term(Term::Constant(name)) => write!(f, "{}", name),
+            Expr::Term(Term::EnumCase(type_name, case)) => {
+                write!(f, "{}.{}", type_name.as_deref().unwrap_or(""), case)
+            }
+            Expr::Term(Term::Function(name, args)) => {
+                write!(f, "{}(", name)?;
+                for (idx, arg) in args.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                f.write_str(")")
+            }
+            Expr::Parentheses(expr) => write!(f, "({})", expr),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) enum Term {
     Number(f32),
     Boolean(bool),
     String(String),
     Variable(VariableName),
     Function(String, Vec<Expr>),
+    /// A reference to a `<<const>>`, written without a `$`.
+    Constant(String),
+    /// A case of an enum, such as `Mood.Happy`. The enum is omitted in the shorthand
+    /// `.Happy`, and inferred from the other operand or from the enums that have
+    /// such a case.
+    EnumCase(Option<String>, String),
+    Null,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Node {
     pub title: NodeName,
     pub extra: HashMap<String, String>,
+    /// The name of the file or string the node was loaded from, if one was given.
+    pub source: Option<String>,
     pub(crate) steps: Vec<Step>,
-    pub visited: bool,
+    /// The line of each step, as given by `step_lines`.
+    pub(crate) step_lines: Vec<usize>,
+    /// The unparsed body of a node loaded lazily, which takes the place of `steps`.
+    pub(crate) lazy_body: Option<Arc<LazyBody>>,
+    /// The lines of content outside any node skipped around this node under
+    /// `StrayContent::Warn`.
+    pub(crate) stray_lines: Vec<usize>,
+}
+
+impl Node {
+    /// The steps of this node, parsing its body first if it was loaded lazily.
+    pub(crate) fn steps(&self) -> Result<&[Step], YarnError> {
+        self.steps_with_lines().map(|(steps, _)| steps)
+    }
+
+    /// The source line of each step of this node, in the order the steps appear in
+    /// the source: each step before the steps nested in it, which is the order of
+    /// `validate::flatten`. Empty if the body fails to parse.
+    pub(crate) fn step_lines(&self) -> &[usize] {
+        self.steps_with_lines().map_or(&[], |(_, lines)| lines)
+    }
+
+    fn steps_with_lines(&self) -> Result<(&[Step], &[usize]), YarnError> {
+        match self.lazy_body {
+            Some(ref body) => body.steps().map_err(|err| match err {
+                YarnError::Parse { line, message } => YarnError::Parse {
+                    line,
+                    message: format!("{} in node `{}`", message, self.title),
+                },
+                err => err,
+            }),
+            None => Ok((&self.steps, &self.step_lines)),
+        }
+    }
+
+    /// The value of the header with the given name, if present. Header names are
+    /// case-insensitive. The title is not included in the node's headers.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.extra
+            .get(&name.to_lowercase())
+            .map(|value| value.as_str())
+    }
+
+    /// The value of the header with the given name parsed as `T`, if present.
+    pub fn header_as<T: FromStr>(&self, name: &str) -> Option<Result<T, T::Err>> {
+        self.header(name).map(|value| value.parse())
+    }
+
+    /// All headers of this node other than the title, as name/value pairs. Names are
+    /// normalized to lowercase.
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.extra
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Whether the node's whitespace-separated `tags` header includes the given tag.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.header("tags")
+            .is_some_and(|tags| tags.split_whitespace().any(|t| t == tag))
+    }
 }
 
 struct Conversation {
     node: NodeName,
     base_index: usize,
     indexes: Vec<StepIndex>,
+    /// The choices awaiting a selection, if any.
+    presented: Option<PresentedChoices>,
+    /// The nodes entered during this conversation, oldest first, including the
+    /// current node.
+    trail: Vec<NodeName>,
+    /// The node-local variables set since entering the current node.
+    locals: Variables,
+    /// Entries produced by the latest step that are waiting behind the errors
+    /// emitted before them.
+    pending: VecDeque<YarnEntry>,
+    /// The number of jumps made since the last entry was produced.
+    jumps: usize,
+    /// Whether the content filter has been consulted about entering the node.
+    entered: bool,
+    /// Whether this is the cleanup of a stopped conversation, which cannot present
+    /// options.
+    aborting: bool,
+    /// The position of the step being executed, as `base_index` and `indexes`, which
+    /// may have advanced since it began.
+    started: Option<(usize, Vec<StepIndex>)>,
+}
+
+/// The maximum number of nodes retained in a conversation's trail.
+const MAX_TRAIL_LENGTH: usize = 64;
+
+/// A set of choices as presented to the embedder.
+struct PresentedChoices {
+    prompt: String,
+    /// The indexes of the available options within the dialogue step, in
+    /// presentation order.
+    indexes: Vec<usize>,
+    /// The text of each available option.
+    texts: Vec<String>,
+    /// The position of the default option within `indexes`.
+    default_choice: usize,
+}
+
+/// How the options following `<<options match selector>>` were selected, for
+/// presenting the options left out as unavailable.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OptionMatch {
+    /// The value of the selector, or `None` if it failed to evaluate.
+    pub value: Option<Value>,
+    /// The position in `choices` of the option whose case matched, or of the
+    /// `<<case else>>` option if none did, if it is available.
+    pub choice: Option<usize>,
+    /// The case of that option as written, such as `< 10` or `else`.
+    pub case: Option<String>,
+    /// The text of the options with a case that are not available, in source
+    /// order.
+    pub unmatched: Vec<String>,
 }
 
+/// A selection made between a set of options.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChoiceRecord {
+    /// The node containing the options.
+    pub node: NodeName,
+    /// The line of dialogue presented with the options.
+    pub prompt: String,
+    /// The text of the selected option.
+    pub text: String,
+    /// The position of the selected option among the options presented.
+    pub index: usize,
+}
+
+/// The dialogue state captured when a node completes, for recovering from a crash.
+/// Taken automatically once enabled with `YarnEngine::set_checkpoint_limit`.
+/// Temporary variables are not captured.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    /// The position of the checkpoint among those taken by the engine, increasing by
+    /// one with each.
+    pub sequence: u64,
+    /// When the checkpoint was taken.
+    pub time: SystemTime,
+    /// The node that completed.
+    pub node: NodeName,
+    /// The node the conversation continued with, or `None` if it ended.
+    pub next: Option<NodeName>,
+    variables: Variables,
+    visits: HashMap<NodeName, u32>,
+    chosen: ChoiceCounts,
+    line_groups: HashMap<(NodeName, usize), Vec<u32>>,
+    clock: f64,
+    random: Randomness,
+}
+
+/// How expressions that mix types, such as adding a boolean to a number, and
+/// assignments that change the type of a declared variable are treated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TypeChecking {
+    /// Convert values to the types required, such as treating a string as 0 in
+    /// arithmetic.
+    Coerce,
+    /// Convert values as with `Coerce`, reporting each conversion to the closures
+    /// registered with `YarnEngine::on_coercion_warning`.
+    Warn,
+    /// Stop execution with `YarnError::TypeMismatch`.
+    Strict,
+}
+
+/// A structural limit on execution, used to catch authoring mistakes such as nodes
+/// that jump to one another forever.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RuntimeLimit {
+    /// `YarnEngine::set_jump_limit`.
+    Jumps,
+    /// `YarnEngine::set_nesting_limit`.
+    Nesting,
+}
+
+impl fmt::Display for RuntimeLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RuntimeLimit::Jumps => "maximum consecutive jumps",
+            RuntimeLimit::Nesting => "maximum nesting depth",
+        })
+    }
+}
+
+/// How runtime evaluation failures in a conversation, such as an undefined
+/// function in an assignment or a condition, are handled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OnError {
+    /// Stop the conversation and report the error to the caller.
+    EndConversation,
+    /// Skip the failing step and continue, treating a failed condition as false. The
+    /// error is reported to the closures registered with
+    /// `YarnEngine::on_recovered_error`.
+    SkipStep,
+    /// Skip the failing step as with `SkipStep`, producing a `YarnEntry::Error`
+    /// before the next entry.
+    Emit,
+}
+
+/// An implicit conversion between types found by type checking, such as the string
+/// in `$gold > "10"` being treated as the number 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoercionWarning {
+    /// The node being executed, if any.
+    pub node: Option<NodeName>,
+    /// The operator whose operands were converted, such as `">"`. Assignments to
+    /// declared variables are reported with `"="`.
+    pub operator: &'static str,
+    /// The types of the operands, such as `"number"` or `"string"`. For
+    /// assignments, the declared type followed by the assigned value's type.
+    pub operands: Vec<&'static str>,
+    /// The operation in which the conversion happened.
+    pub expression: String,
+}
+
+/// A closure that will be invoked for each implicit conversion found in pedantic mode.
+pub type CoercionWarningCallback = dyn FnMut(&CoercionWarning);
+
+/// A closure that will be invoked for each evaluation error recovered from by the
+/// error policy.
+pub type RecoveredErrorCallback = dyn FnMut(&ErrorReport);
+
+/// A closure that will be invoked with the ID of a line and the locale whose string
+/// table has no translation for it.
+pub type MissingLineCallback = dyn FnMut(&str, &str);
+
+/// A closure that will be invoked with the message of each `<<log>>` step.
+pub type LogCallback = dyn FnMut(&str);
+
+/// A log callback that also receives the context passed to `YarnEngine::next_with`.
+pub type ContextLogCallback<Ctx> = dyn FnMut(&str, &mut Ctx);
+
+/// A closure that will be invoked each time an option is selected.
+pub type ChoiceMadeCallback = dyn FnMut(&ChoiceRecord);
+
+/// A choice made callback that also receives the context passed to
+/// `YarnEngine::choose_with`.
+pub type ContextChoiceMadeCallback<Ctx> = dyn FnMut(&ChoiceRecord, &mut Ctx);
+
 impl Conversation {
     fn new(node: NodeName) -> Conversation {
         Conversation {
+            trail: vec![node.clone()],
             node,
             base_index: 0,
             indexes: vec![],
+            presented: None,
+            locals: Variables::default(),
+            pending: VecDeque::new(),
+            jumps: 0,
+            entered: false,
+            aborting: false,
+            started: None,
+        }
+    }
+
+    /// Record that the current step is beginning to execute.
+    fn start_step(&mut self) {
+        match self.started {
+            Some((ref mut base_index, ref mut indexes)) => {
+                *base_index = self.base_index;
+                indexes.clone_from(&self.indexes);
+            }
+            None => self.started = Some((self.base_index, self.indexes.clone())),
+        }
+    }
+
+    /// The path of the current step.
+    fn line_path(&self) -> LinePath {
+        LinePath {
+            node: self.node.clone(),
+            base_index: self.base_index,
+            indexes: self.indexes.clone(),
+            part: None,
         }
     }
 }
 
-#[derive(Copy, Clone)]
-enum StepIndex {
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum StepIndex {
     Dialogue(usize, usize),
     If(usize),
     ElseIf(usize, usize),
@@ -139,8 +738,29 @@ impl StepIndex {
         *idx += 1;
     }
 }
+
+/// Where a localizable string is in the loaded nodes: its node, the position of its
+/// step as the conversation tracks it, and which option or line of a group holds
+/// it, if any.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct LinePath {
+    pub(crate) node: NodeName,
+    pub(crate) base_index: usize,
+    pub(crate) indexes: Vec<StepIndex>,
+    pub(crate) part: Option<usize>,
+}
+
+impl LinePath {
+    /// The path of an option or line of a group of the step at this path.
+    pub(crate) fn part(&self, part: usize) -> LinePath {
+        LinePath {
+            part: Some(part),
+            ..self.clone()
+        }
+    }
+}
 /// A primitive value .
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum Value {
     /// A string value.
     String(String),
@@ -148,16 +768,33 @@ pub enum Value {
     Number(f32),
     /// A boolean value.
     Boolean(bool),
-    //TODO: null
+    /// A case of an enum declared with `<<enum>>`, such as `Mood.Happy`. Enum values
+    /// can only be compared for equality, and their string form is the case name.
+    Enum {
+        /// The name of the enum.
+        type_name: String,
+        /// The name of the case.
+        case: String,
+    },
+    /// No value, written `null`, such as the result of a function with nothing to
+    /// return. Null is only equal to null, and any operation on it other than
+    /// equality, `!`, `and`, `or` and `xor` fails to evaluate.
+    Null,
 }
 
 impl PartialEq for Value {
     fn eq(&self, other: &Value) -> bool {
         match (self, other) {
-            (&Value::String(ref s1), ref v) => s1 == &v.as_string(),
-            (ref v, &Value::String(ref s2)) => s2 == &v.as_string(),
-            (&Value::Number(f1), ref v) => f1 == v.as_num(),
-            (ref v, &Value::Number(f2)) => f2 == v.as_num(),
+            (Value::Enum { .. }, Value::Enum { .. }) => {
+                self.enum_type() == other.enum_type() && self.as_string() == other.as_string()
+            }
+            (Value::Enum { .. }, _) | (_, Value::Enum { .. }) => false,
+            (Value::Null, Value::Null) => true,
+            (Value::Null, _) | (_, Value::Null) => false,
+            (Value::String(s1), v) => s1 == &v.as_string(),
+            (v, Value::String(s2)) => s2 == &v.as_string(),
+            (&Value::Number(f1), v) => f1 == v.as_num(),
+            (v, &Value::Number(f2)) => f2 == v.as_num(),
             (&Value::Boolean(b1), &Value::Boolean(b2)) => b1 == b2,
         }
     }
@@ -198,303 +835,3306 @@ impl Div for Value {
 }
 
 impl Value {
+    /// The value as a literal term of an expression.
+    pub(crate) fn to_term(&self) -> Term {
+        match *self {
+            Value::Boolean(b) => Term::Boolean(b),
+            Value::String(ref s) => Term::String(s.clone()),
+            Value::Number(f) => Term::Number(f),
+            Value::Enum {
+                ref type_name,
+                ref case,
+            } => Term::EnumCase(Some(type_name.clone()), case.clone()),
+            Value::Null => Term::Null,
+        }
+    }
+
     /// The contained value represented as a string.
     pub fn as_string(&self) -> String {
         match *self {
             Value::Boolean(b) => b.to_string(),
             Value::String(ref s) => (*s).clone(),
             Value::Number(f) => f.to_string(),
+            Value::Enum { ref case, .. } => case.clone(),
+            Value::Null => "null".to_string(),
         }
     }
 
     /// The contained value represented as a boolean.
-    /// If not already a boolean, true if a non-empty string or non-zero number, false otherwise.
-    fn as_bool(&self) -> bool {
+    /// If not already a boolean, true if a non-empty string, non-zero number or enum
+    /// case, false otherwise.
+    pub fn as_bool(&self) -> bool {
         match *self {
             Value::Boolean(b) => b,
             Value::String(ref s) => !s.is_empty(),
             Value::Number(f) => f != 0.0,
+            Value::Enum { .. } => true,
+            Value::Null => false,
         }
     }
 
     /// The contained value represented as a floating point number.
-    /// If not already a number, 0 if a string, 0 or 1 if a boolean.
-    fn as_num(&self) -> f32 {
+    /// If not already a number, 0 if a string, enum case or null, 0 or 1 if a boolean.
+    pub fn as_num(&self) -> f32 {
         match *self {
             Value::Boolean(b) => b as isize as f32,
             Value::String(ref _s) => 0.,
             Value::Number(f) => f,
+            Value::Enum { .. } => 0.,
+            Value::Null => 0.,
         }
     }
-}
 
-struct Function {
-    num_args: usize,
-    callback: SendWrapper<Box<FunctionCallback>>,
-}
-
-/// A closure that will be invoked when a particular function is called in a Yarn expression.
-pub type FunctionCallback = dyn Fn(Vec<Value>, &Nodes) -> Result<Value, ()>;
+    /// Whether the value is `Value::Null`.
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
 
-/// The engine that stores all conversation-related state.
-pub struct YarnEngine {
-    state: NodeState,
-    engine_state: EngineState,
-    conversion_ended: bool,
+    /// The name of the enum, if the value is an enum case.
+    pub fn enum_type(&self) -> Option<&str> {
+        match *self {
+            Value::Enum { ref type_name, .. } => Some(type_name),
+            _ => None,
+        }
+    }
 }
 
-struct EngineState {
-    variables: Variables,
-    functions: HashMap<String, Function>,
+/// The type of a Yarn value, as given to a variable by `<<declare>>` or to a
+/// function's parameters and return value by `YarnEngine::set_function_signature`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum YarnType {
+    /// A string, written `string`.
+    String,
+    /// A number, written `number`.
+    Number,
+    /// A boolean, written `bool` or `boolean`.
+    Boolean,
+    /// A case of any enum declared with `<<enum>>`.
+    Enum,
+    /// The null value.
+    Null,
 }
 
-impl EngineState {
-    fn evaluate(&self, expr: &Expr, state: &Nodes) -> Result<Value, ()> {
-        match expr {
-            Expr::Parentheses(expr) => self.evaluate(expr, state),
-            Expr::Term(Term::Number(f)) => Ok(Value::Number(*f)),
-            Expr::Term(Term::Boolean(b)) => Ok(Value::Boolean(*b)),
-            Expr::Term(Term::String(ref s)) => Ok(Value::String((*s).clone())),
-            Expr::Term(Term::Variable(ref n)) => self.variables.0.get(n).cloned().ok_or(()),
-            Expr::Term(Term::Function(ref name, ref args)) => {
-                let mut eval_args = vec![];
-                for arg in args {
-                    let v = self.evaluate(arg, state)?;
-                    eval_args.push(v);
-                }
-                let f = self.functions.get(name).ok_or(())?;
-                if f.num_args != args.len() {
-                    return Err(());
-                }
-                (f.callback)(eval_args, state)
-            }
-
-            Expr::Unary(UnaryOp::Not, expr) => self
-                .evaluate(expr, state)
-                .map(|v| Value::Boolean(!v.as_bool())),
-            Expr::Unary(UnaryOp::Negate, expr) => self
-                .evaluate(expr, state)
-                .map(|v| Value::Number(-v.as_num())),
+impl YarnType {
+    /// The type of the given value.
+    pub fn of(value: &Value) -> YarnType {
+        match value {
+            Value::String(_) => YarnType::String,
+            Value::Number(_) => YarnType::Number,
+            Value::Boolean(_) => YarnType::Boolean,
+            Value::Enum { .. } => YarnType::Enum,
+            Value::Null => YarnType::Null,
+        }
+    }
 
-            Expr::Binary(BinaryOp::And, left, right) => {
-                let left = self.evaluate(left, state)?.as_bool();
-                let right = self.evaluate(right, state)?.as_bool();
-                Ok(Value::Boolean(left && right))
-            }
-            Expr::Binary(BinaryOp::Or, left, right) => {
-                let left = self.evaluate(left, state)?.as_bool();
-                let right = self.evaluate(right, state)?.as_bool();
-                Ok(Value::Boolean(left || right))
-            }
+    /// The name of the type, such as `"number"`, for messages.
+    pub fn name(self) -> &'static str {
+        match self {
+            YarnType::String => "string",
+            YarnType::Number => "number",
+            YarnType::Boolean => "boolean",
+            YarnType::Enum => "enum",
+            YarnType::Null => "null",
+        }
+    }
+}
 
-            Expr::Binary(BinaryOp::Plus, left, right) => {
-                let left = self.evaluate(left, state)?;
-                let right = self.evaluate(right, state)?;
-                Ok(left + right)
-            }
-            Expr::Binary(BinaryOp::Minus, left, right) => {
-                let left = self.evaluate(left, state)?;
-                let right = self.evaluate(right, state)?;
-                Ok(left - right)
-            }
-            Expr::Binary(BinaryOp::Multiply, left, right) => {
-                let left = self.evaluate(left, state)?;
-                let right = self.evaluate(right, state)?;
-                Ok(left * right)
-            }
-            Expr::Binary(BinaryOp::Divide, left, right) => {
-                let left = self.evaluate(left, state)?;
-                let right = self.evaluate(right, state)?;
-                Ok(left / right)
-            }
+impl fmt::Display for YarnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
-            Expr::Binary(BinaryOp::Equals, left, right) => {
-                let left = self.evaluate(left, state)?;
-                let right = self.evaluate(right, state)?;
-                Ok(Value::Boolean(left == right))
-            }
-            Expr::Binary(BinaryOp::NotEquals, left, right) => {
-                let left = self.evaluate(left, state)?;
-                let right = self.evaluate(right, state)?;
-                Ok(Value::Boolean(!(left == right)))
-            }
+/// Select one of the candidate lines of a group, preferring those shown least
+/// recently and breaking ties at random. `shown` holds when each line of the group
+/// was last shown.
+fn select_line(shown: Option<&Vec<u32>>, candidates: &[usize], rng: &mut Rng) -> Option<usize> {
+    let last_shown = |index: usize| {
+        shown
+            .and_then(|shown| shown.get(index))
+            .copied()
+            .unwrap_or(0)
+    };
+    let oldest = candidates.iter().map(|&index| last_shown(index)).min()?;
+    let ties = candidates
+        .iter()
+        .copied()
+        .filter(|&index| last_shown(index) == oldest)
+        .collect::<Vec<_>>();
+    Some(ties[rng.below(ties.len())])
+}
 
-            Expr::Binary(BinaryOp::GreaterThan, left, right) => {
-                let left = self.evaluate(left, state)?;
-                let right = self.evaluate(right, state)?;
-                Ok(Value::Boolean(left.as_num() > right.as_num()))
-            }
-            Expr::Binary(BinaryOp::GreaterThanEqual, left, right) => {
-                let left = self.evaluate(left, state)?;
-                let right = self.evaluate(right, state)?;
-                Ok(Value::Boolean(left.as_num() >= right.as_num()))
+/// Replace each `{n}` in the template with the string form of the nth argument.
+/// `{{` and `}}` produce literal braces. Any other brace, or an index without a
+/// corresponding argument, is an error.
+fn format_template(template: &str, args: &[Value]) -> Result<String, String> {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '{' | '}' if chars.as_str().starts_with(ch) => {
+                result.push(ch);
+                chars.next();
             }
-            Expr::Binary(BinaryOp::LessThan, left, right) => {
-                let left = self.evaluate(left, state)?;
-                let right = self.evaluate(right, state)?;
-                Ok(Value::Boolean(left.as_num() < right.as_num()))
-            }
-            Expr::Binary(BinaryOp::LessThanEqual, left, right) => {
-                let left = self.evaluate(left, state)?;
-                let right = self.evaluate(right, state)?;
-                Ok(Value::Boolean(left.as_num() <= right.as_num()))
+            '{' => {
+                let (index, rest) = chars
+                    .as_str()
+                    .split_once('}')
+                    .ok_or("unclosed `{` in template")?;
+                let index: usize = index
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid placeholder `{{{}}}`", index))?;
+                let arg = args
+                    .get(index)
+                    .ok_or_else(|| format!("no argument for placeholder `{{{}}}`", index))?;
+                result.push_str(&arg.as_string());
+                chars = rest.chars();
             }
+            '}' => return Err("unmatched `}` in template".to_string()),
+            ch => result.push(ch),
         }
     }
+    Ok(result)
 }
 
-/// A collection of Yarn nodes.
-pub struct Nodes(pub HashMap<NodeName, Node>);
-
-struct NodeState {
-    nodes: Nodes,
-    conversation: Option<Conversation>,
+struct Function<Ctx> {
+    num_args: RangeInclusive<usize>,
+    /// The type of each parameter and of the return value, where known.
+    params: Vec<Option<YarnType>>,
+    returns: Option<YarnType>,
+    callback: SendWrapper<Box<ContextFunctionCallback<Ctx>>>,
 }
 
-impl NodeState {
-    fn set_conversation(&mut self, conversation: Option<NodeName>) {
-        self.conversation = conversation.map(|x| Conversation::new(x));
-    }
+/// A closure that will be invoked when a particular function is called in a Yarn expression.
+/// An error message it returns fails the evaluation with `YarnError::FunctionFailed`.
+pub type FunctionCallback = dyn Fn(Vec<Value>, &EvalContext) -> Result<Value, String>;
 
-    fn push_step(&mut self, index: StepIndex) {
-        self.conversation.as_mut().unwrap().indexes.push(index);
-    }
-    fn advance(&mut self) {
-        let conversation = self.conversation.as_mut().unwrap();
-        match conversation.indexes.last_mut() {
-            Some(index) => index.advance(),
-            None => conversation.base_index += 1,
+/// A function callback that also receives the context passed to `YarnEngine::next_with`.
+pub type ContextFunctionCallback<Ctx> =
+    dyn Fn(Vec<Value>, &EvalContext, &mut Ctx) -> Result<Value, String>;
+
+/// The story state available to function callbacks.
+pub struct EvalContext<'a> {
+    pub(crate) node: Option<&'a NodeName>,
+    pub(crate) nodes: &'a Nodes,
+    pub(crate) visits: &'a HashMap<NodeName, u32>,
+    pub(crate) chosen: &'a ChoiceCounts,
+    pub(crate) variables: &'a Variables,
+    pub(crate) locals: Option<&'a Variables>,
+    pub(crate) time: f64,
+    pub(crate) random: &'a RefCell<Randomness>,
+}
+
+impl<'a> EvalContext<'a> {
+    /// The current value of the given variable, if it has been set. Local variables
+    /// are looked up in the node currently being executed.
+    pub fn get_variable(&self, name: &VariableName) -> Option<&'a Value> {
+        if name.is_local() {
+            self.locals.and_then(|locals| locals.values.get(name))
+        } else {
+            self.variables.values.get(name)
         }
     }
-    fn get_current_step(&self) -> Option<&Step> {
-        let conversation = self
-            .conversation
-            .as_ref()
-            .expect("No active conversation found");
-        let mut steps = {
-            let current = self.nodes.0.get(&conversation.node).expect("missing node");
-            &current.steps
+
+    /// The names of set variables similar to the given one, for error messages.
+    fn similar_variables(&self, name: &VariableName) -> Vec<String> {
+        let variables = if name.is_local() {
+            self.locals
+        } else {
+            Some(self.variables)
         };
-        let mut current_step_index = conversation.base_index;
+        let candidates = variables
+            .iter()
+            .flat_map(|variables| variables.values.keys())
+            .map(|name| name.0.as_str());
+        suggest::similar_names(&name.0, candidates)
+    }
 
-        for index in &conversation.indexes {
-            match (&steps[current_step_index], *index) {
-                (&Step::Dialogue(_, ref choices), StepIndex::Dialogue(choice, step_index)) => {
-                    let choice = &choices[choice];
-                    match choice.kind {
-                        ChoiceKind::Inline(ref choice_steps, _) => {
-                            steps = choice_steps;
-                            current_step_index = step_index;
-                        }
-                        ChoiceKind::External(..) => unreachable!(),
-                    }
-                }
-                (&Step::Conditional(_, ref if_steps, ..), StepIndex::If(step_index)) => {
-                    steps = if_steps;
-                    current_step_index = step_index;
-                }
-                (
-                    &Step::Conditional(_, _, ref else_ifs, ..),
-                    StepIndex::ElseIf(index, step_index),
-                ) => {
-                    steps = &else_ifs[index].1;
-                    current_step_index = step_index;
-                }
-                (&Step::Conditional(_, _, _, ref else_steps), StepIndex::Else(step_index)) => {
-                    steps = else_steps;
-                    current_step_index = step_index;
-                }
-                _ => unreachable!(),
-            }
-        }
+    /// All loaded nodes.
+    pub fn nodes(&self) -> &'a Nodes {
+        self.nodes
+    }
 
-        steps.get(current_step_index)
+    /// The loaded node with the given name, if any.
+    pub fn node(&self, name: &NodeName) -> Option<&'a Node> {
+        self.nodes.get(name)
     }
-}
 
-impl YarnEngine {
-    /// Create a new YarnEngine instance associated with the given handler.
-    pub fn new() -> Self {
-        let mut engine = YarnEngine {
-            state: NodeState {
-                nodes: Nodes(HashMap::new()),
-                conversation: None,
-            },
-            engine_state: EngineState {
-                variables: Variables(HashMap::new()),
-                functions: HashMap::new(),
-            },
-            conversion_ended: false,
-            // handler,
-        };
+    /// The number of times the given node has been visited.
+    pub fn visit_count(&self, name: &NodeName) -> u32 {
+        self.visits.get(name).cloned().unwrap_or(0)
+    }
 
-        // Define built-in functions.
-        engine.register_function(
-            "visited".to_string(),
-            1,
-            Box::new(|args, state| match args[0] {
-                Value::String(ref s) => state
-                    .0
-                    .get(&NodeName(s.to_string()))
-                    .map(|node| Value::Boolean(node.visited))
-                    .ok_or(()),
-                _ => return Err(()),
-            }),
-        );
+    /// The generator for random choices made in the current node.
+    fn rng(&self) -> RefMut<'a, Rng> {
+        let node = self.node.map(|node| (node, self.visit_count(node)));
+        RefMut::map(self.random.borrow_mut(), |random| random.rng(node))
+    }
 
-        engine
+    /// The number of times the option at the given position among the node's
+    /// options has been selected.
+    pub fn times_chosen(&self, node: &NodeName, option: usize) -> u32 {
+        let key = (node.clone(), option);
+        self.chosen.get(&key).cloned().unwrap_or(0)
     }
 
-    /// Parse the provided string as a series of Yarn nodes, appending the results to
-    /// the internal node storage. Returns Ok if parsing succeeded, Err otherwise.
-    pub fn load_from_string(&mut self, s: &str) -> Result<(), ()> {
-        let nodes = parse::parse_nodes_from_string(s)?;
-        for node in nodes {
-            self.state.nodes.0.insert(node.title.clone(), node);
-        }
-        Ok(())
+    /// The time in seconds last given to `YarnEngine::set_time`.
+    pub fn time(&self) -> f64 {
+        self.time
     }
+}
 
-    /// Register a native function for use in Yarn expressions.
-    pub fn register_function(
+/// A closure that will be invoked each time a node is marked as visited, along with
+/// the node's updated visit count.
+pub type NodeVisitedCallback = dyn FnMut(&NodeName, u32);
+
+/// A node visited callback that also receives the context passed to
+/// `YarnEngine::next_with`.
+pub type ContextNodeVisitedCallback<Ctx> = dyn FnMut(&NodeName, u32, &mut Ctx);
+
+/// A closure writing a value interpolated into a line or option, set with
+/// `YarnEngine::set_display_formatter`.
+pub type DisplayFormatter = dyn Fn(&Value) -> String;
+
+/// A closure deciding whether a node is an entry point, as listed by
+/// `YarnEngine::entry_points`.
+pub type EntryPointPredicate = dyn Fn(&Node) -> bool;
+
+/// The engine that stores all conversation-related state.
+///
+/// Callbacks may be registered with context-aware signatures that receive a
+/// `&mut Ctx`, such as the game world, supplied to `next_with` and `choose_with`.
+/// Engines without a context use the `Iterator` implementation and `choose`.
+///
+/// Callbacks cannot call back into the engine that is running them. The engine is
+/// mutably borrowed while it executes steps, so a callback can only reach it
+/// through shared ownership such as `Rc<RefCell<_>>`, whose `try_borrow_mut` then
+/// fails with `BorrowMutError`. Function callbacks read variables through their
+/// `EvalContext`; other changes, such as setting variables, should be made through
+/// the context or once the entry has been returned.
+pub struct YarnEngine<Ctx = ()> {
+    state: NodeState,
+    engine_state: EngineState<Ctx>,
+    status: ConversationStatus,
+    node_visited_callbacks: Vec<SendWrapper<Box<ContextNodeVisitedCallback<Ctx>>>>,
+    choice_made_callbacks: Vec<SendWrapper<Box<ContextChoiceMadeCallback<Ctx>>>>,
+    log_callbacks: Vec<SendWrapper<Box<ContextLogCallback<Ctx>>>>,
+    coercion_callbacks: Vec<SendWrapper<Box<CoercionWarningCallback>>>,
+    recovered_error_callbacks: Vec<SendWrapper<Box<RecoveredErrorCallback>>>,
+    missing_line_callbacks: Vec<SendWrapper<Box<MissingLineCallback>>>,
+    last_choice: Option<ChoiceRecord>,
+    /// The most recent checkpoints, oldest first.
+    checkpoints: Vec<Checkpoint>,
+    checkpoint_limit: usize,
+    checkpoint_sequence: u64,
+    /// The conversations paused by `interject_node`, innermost last, with their
+    /// status when paused.
+    interrupted: Vec<(Conversation, ConversationStatus)>,
+    /// The report of the latest error that stopped a conversation.
+    last_error: Option<ErrorReport>,
+    line_length_limit: Option<LineLengthLimit>,
+    lint_rules: Vec<SendWrapper<Box<dyn LintRule>>>,
+    pagination: Option<Pagination>,
+    start_node: NodeName,
+    entry_point_predicate: Option<SendWrapper<Box<EntryPointPredicate>>>,
+}
+
+/// The phase of the conversation currently held by a `YarnEngine`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConversationStatus {
+    /// No conversation is active.
+    Idle,
+    /// A conversation is active and will execute further steps on the next call to `next`.
+    Running,
+    /// A set of choices was presented. Execution will not resume until
+    /// `YarnEngine::choose` is invoked.
+    WaitingForChoice,
+    /// A line of dialogue or a command was presented. Execution resumes on the
+    /// next call to `next`.
+    WaitingForProceed,
+    /// A command was presented and commands require acknowledgement. Execution
+    /// will not resume until `YarnEngine::proceed` is invoked.
+    WaitingForCommand,
+    /// The active conversation has finished. Execution will not resume until a new
+    /// node is made active with `YarnEngine::activate`.
+    Ended,
+}
+
+/// A closure that will be invoked when a command with a particular name is executed.
+/// It receives the command's whitespace-separated arguments after interpolation.
+pub type CommandCallback = dyn FnMut(Vec<String>);
+
+/// A command callback that also receives the context passed to
+/// `YarnEngine::next_with`.
+pub type ContextCommandCallback<Ctx> = dyn FnMut(Vec<String>, &mut Ctx);
+
+struct Command<Ctx> {
+    blocking: bool,
+    callback: SendWrapper<Box<ContextCommandCallback<Ctx>>>,
+}
+
+pub(crate) struct EngineState<Ctx> {
+    variables: Variables,
+    functions: HashMap<String, Function<Ctx>>,
+    commands: HashMap<String, Command<Ctx>>,
+    dispatch_commands: bool,
+    commands_require_proceed: bool,
+    substitute_bare_variables: bool,
+    normalization: TextNormalization,
+    pseudo_localization: bool,
+    pseudo_localize_values: bool,
+    markup: bool,
+    coalesce_undefined_variables: bool,
+    lenient_conversions: bool,
+    step_budget: usize,
+    jump_limit: usize,
+    nesting_limit: usize,
+    assertions_enabled: bool,
+    stats_enabled: bool,
+    /// Statistics for the lifetime of the engine.
+    stats: Stats,
+    /// Statistics for the current conversation.
+    conversation_stats: Stats,
+    /// Interpolated dialogue and option text, keyed by where the text is.
+    rendered: RefCell<HashMap<LinePath, Rendered>>,
+    type_checking: TypeChecking,
+    /// Implicit conversions found in `TypeChecking::Warn` mode and not yet reported.
+    coercions: RefCell<Vec<CoercionWarning>>,
+    /// The cause of the latest evaluation failure, such as a conversion in
+    /// `TypeChecking::Strict` mode or an undefined variable.
+    failure: RefCell<Option<YarnError>>,
+    /// The outermost expression of the latest evaluation failure.
+    failed_expression: RefCell<Option<String>>,
+    on_error: OnError,
+    /// Evaluation errors skipped by the error policy and not yet reported. Their
+    /// node and trail are filled in before the conversation leaves the node.
+    recovered: RefCell<Vec<ErrorReport>>,
+    /// The type of each declared variable.
+    declarations: HashMap<VariableName, YarnType>,
+    /// The value of each `<<const>>` in the loaded nodes, shared with any `EvalView`.
+    constants: Arc<HashMap<String, Value>>,
+    /// The enums declared in the loaded nodes, shared with any `EvalView`.
+    enums: Arc<Enums>,
+    /// The registered functions that can be called through an `EvalView`. A view's
+    /// state calls these in place of `functions`.
+    sync_functions: Arc<HashMap<String, SyncFunction>>,
+    /// The translations of each locale, keyed by line ID.
+    string_tables: HashMap<String, HashMap<String, String>>,
+    default_locale: Option<String>,
+    active_locale: Option<String>,
+    /// The ID of each localizable string, keyed by where it is.
+    line_ids: HashMap<LinePath, String>,
+    /// The nodes whose lines are in `line_ids`, which are indexed when a line of
+    /// theirs is first presented.
+    indexed_nodes: HashSet<NodeName>,
+    content_filter: Option<SendWrapper<Box<ContentFilter>>>,
+    display_formatter: Option<SendWrapper<Box<DisplayFormatter>>>,
+    /// The IDs of the lines that have been presented.
+    seen_lines: HashSet<String>,
+    skim: bool,
+    skim_skips_seen: bool,
+    /// Lines missing from a string table, with the locale, not yet reported.
+    missing_lines: RefCell<Vec<(String, String)>>,
+    /// The name prefix of temporary variables, without the `$`.
+    temporary_prefix: String,
+}
+
+/// The result of interpolating a line of dialogue or option text.
+struct Rendered {
+    /// The global variables the text's interpolations read, or `None` if the text
+    /// may render differently without any of them changing, such as when it calls
+    /// a function or reads a local variable.
+    reads: Option<Vec<VariableName>>,
+    /// The latest generation in which any of `reads` had been written.
+    generation: u64,
+    text: String,
+}
+
+/// Whether applying the arithmetic or comparison operator to the operands converts
+/// either of them to another type.
+pub(crate) fn coerces(op: &BinaryOp, left: YarnType, right: YarnType) -> bool {
+    use YarnType::*;
+    match (op, left, right) {
+        // Any value can be compared with null.
+        (BinaryOp::Equals, Null, _) | (BinaryOp::Equals, _, Null) => false,
+        (BinaryOp::NotEquals, Null, _) | (BinaryOp::NotEquals, _, Null) => false,
+        (BinaryOp::Equals, ..) | (BinaryOp::NotEquals, ..) => left != right,
+        (BinaryOp::Plus, String, String) => false,
+        (_, Number, Number) => false,
+        _ => true,
+    }
+}
+
+/// Collect the variables read by the given expression, returning false if its value
+/// depends on anything else.
+fn collect_reads(expr: &Expr, reads: &mut Vec<VariableName>) -> bool {
+    match expr {
+        Expr::Term(Term::Variable(name)) if name.is_local() => false,
+        Expr::Term(Term::Variable(name)) => {
+            reads.push(name.clone());
+            true
+        }
+        Expr::Term(Term::Function(..)) => false,
+        Expr::Term(_) => true,
+        Expr::Unary(_, expr) | Expr::Parentheses(expr) => collect_reads(expr, reads),
+        Expr::Binary(_, left, right) => collect_reads(left, reads) && collect_reads(right, reads),
+        Expr::Ternary(condition, if_true, if_false) => {
+            collect_reads(condition, reads)
+                && collect_reads(if_true, reads)
+                && collect_reads(if_false, reads)
+        }
+    }
+}
+
+/// What text is interpolated for, which decides how values are written into it.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Interpolation {
+    /// Dialogue or option text, whose values are written by the display formatter.
+    Display,
+    /// A command, whose values are written as by `Value::as_string`. If
+    /// `bare_variables` is set, each `$variable` outside of braces is also replaced
+    /// with the variable's value.
+    Command { bare_variables: bool },
+}
+
+impl EngineState<()> {
+    /// A state for evaluating expressions through the given view, which calls the
+    /// view's functions with the engine's evaluation settings.
+    pub(crate) fn for_view(view: &EvalView) -> EngineState<()> {
+        let mut state = EngineState::new();
+        state.sync_functions = view.functions.clone();
+        state.constants = view.constants.clone();
+        state.enums = view.enums.clone();
+        state.type_checking = view.type_checking;
+        state.coalesce_undefined_variables = view.coalesce_undefined_variables;
+        state
+    }
+}
+
+impl<Ctx> EngineState<Ctx> {
+    /// The state of a new engine, with no functions registered.
+    fn new() -> EngineState<Ctx> {
+        EngineState {
+            variables: Variables::default(),
+            functions: HashMap::new(),
+            commands: HashMap::new(),
+            dispatch_commands: false,
+            commands_require_proceed: false,
+            substitute_bare_variables: false,
+            normalization: TextNormalization::default(),
+            pseudo_localization: false,
+            pseudo_localize_values: false,
+            markup: false,
+            coalesce_undefined_variables: true,
+            lenient_conversions: false,
+            step_budget: 10_000,
+            jump_limit: 1_000,
+            nesting_limit: 64,
+            assertions_enabled: false,
+            stats_enabled: false,
+            stats: Stats::default(),
+            conversation_stats: Stats::default(),
+            rendered: RefCell::new(HashMap::new()),
+            type_checking: TypeChecking::Coerce,
+            coercions: RefCell::new(vec![]),
+            failure: RefCell::new(None),
+            failed_expression: RefCell::new(None),
+            on_error: OnError::EndConversation,
+            recovered: RefCell::new(vec![]),
+            declarations: HashMap::new(),
+            constants: Arc::default(),
+            enums: Arc::default(),
+            sync_functions: Arc::default(),
+            string_tables: HashMap::new(),
+            default_locale: None,
+            active_locale: None,
+            line_ids: HashMap::new(),
+            indexed_nodes: HashSet::new(),
+            content_filter: None,
+            display_formatter: None,
+            seen_lines: HashSet::new(),
+            skim: false,
+            skim_skips_seen: false,
+            missing_lines: RefCell::new(vec![]),
+            temporary_prefix: "temp_".to_string(),
+        }
+    }
+
+    /// Replace each `{expression}` in the given text with the expression's value.
+    /// Braces can be escaped with a backslash.
+    fn interpolate(
+        &self,
+        text: &str,
+        interpolation: Interpolation,
+        state: &EvalContext,
+        ctx: &mut Ctx,
+    ) -> Result<String, ()> {
+        let mut result = String::with_capacity(text.len());
+        let mut chars = text.char_indices().peekable();
+        while let Some((idx, ch)) = chars.next() {
+            match ch {
+                '\\' if matches!(chars.peek(), Some((_, '{')) | Some((_, '}'))) => {
+                    result.push(chars.next().unwrap().1);
+                }
+                // Nothing within `[nomarkup]` is interpolated.
+                '[' if self.markup && text[idx..].starts_with(markup::NOMARKUP) => {
+                    let end = text[idx..]
+                        .find(markup::NOMARKUP_END)
+                        .map_or(text.len(), |end| idx + end);
+                    result.push_str(&text[idx..end]);
+                    while chars.peek().is_some_and(|&(next, _)| next < end) {
+                        chars.next();
+                    }
+                }
+                '{' => {
+                    let start = idx + 1;
+                    let end = loop {
+                        match chars.next() {
+                            Some((end, '}')) => break end,
+                            Some(_) => (),
+                            None => return Err(()),
+                        }
+                    };
+                    let expr = parse::parse_complete_expr(&text[start..end])?;
+                    let value = self.evaluate(&expr, state, ctx)?;
+                    result.push_str(&self.write_value(&value, interpolation));
+                }
+                '$' if matches!(
+                    interpolation,
+                    Interpolation::Command {
+                        bare_variables: true
+                    }
+                ) =>
+                {
+                    let start = idx + 1;
+                    let mut end = start;
+                    while let Some(&(next, ch)) = chars.peek() {
+                        let valid = if end == start {
+                            parse::is_identifier_start(ch)
+                        } else {
+                            parse::is_identifier_continue(ch)
+                        };
+                        if !valid {
+                            break;
+                        }
+                        end = next + ch.len_utf8();
+                        chars.next();
+                    }
+                    if end == start {
+                        result.push('$');
+                        continue;
+                    }
+                    let name = VariableName(text[start..end].to_string());
+                    self.count(|stats| &stats.variable_lookups);
+                    let value = state.get_variable(&name).ok_or(())?;
+                    result.push_str(&self.write_value(value, interpolation));
+                }
+                ch => result.push(ch),
+            }
+        }
+        Ok(result)
+    }
+
+    /// A value as written into interpolated text.
+    fn write_value(&self, value: &Value, interpolation: Interpolation) -> String {
+        match (interpolation, &self.display_formatter) {
+            (Interpolation::Display, Some(formatter)) => formatter(value),
+            (Interpolation::Display, None) | (Interpolation::Command { .. }, _) => {
+                value.as_string()
+            }
+        }
+    }
+
+    /// Interpolate a line of dialogue or option text, reusing the previous result for
+    /// the line at the same path if none of the variables it reads have been written
+    /// since.
+    fn interpolate_line(
+        &self,
+        text: &str,
+        path: &LinePath,
+        state: &EvalContext,
+        ctx: &mut Ctx,
+    ) -> Result<String, ()> {
+        if !text.contains('{') {
+            return self.interpolate(text, Interpolation::Display, state, ctx);
+        }
+        let reads = match self.rendered.borrow().get(path) {
+            Some(Rendered {
+                reads: Some(reads),
+                generation,
+                text,
+            }) if self.variables.last_written(reads) == *generation => {
+                return Ok(text.clone());
+            }
+            Some(rendered) => Some(rendered.reads.clone()),
+            None => None,
+        };
+        let rendered = self.interpolate(text, Interpolation::Display, state, ctx)?;
+        let reads = reads.unwrap_or_else(|| {
+            let mut exprs = vec![];
+            validate::interpolated_exprs(text, &mut exprs);
+            let mut reads = vec![];
+            let cacheable = exprs.iter().all(|expr| collect_reads(expr, &mut reads));
+            if cacheable {
+                Some(reads)
+            } else {
+                None
+            }
+        });
+        let generation = reads
+            .as_ref()
+            .map_or(0, |reads| self.variables.last_written(reads));
+        self.rendered.borrow_mut().insert(
+            path.clone(),
+            Rendered {
+                reads,
+                generation,
+                text: rendered.clone(),
+            },
+        );
+        Ok(rendered)
+    }
+
+    /// Evaluate both operands of a binary operator. A shorthand enum case such as
+    /// `.Happy` prefers the enum of the other operand.
+    fn evaluate_operands(
+        &self,
+        left: &Expr,
+        right: &Expr,
+        state: &EvalContext,
+        ctx: &mut Ctx,
+    ) -> Result<(Value, Value), ()> {
+        match (left, right) {
+            (Expr::Term(Term::EnumCase(None, case)), other) => {
+                let other = self.evaluate_expr(other, state, ctx)?;
+                Ok((self.enum_case(None, other.enum_type(), case)?, other))
+            }
+            (other, Expr::Term(Term::EnumCase(None, case))) => {
+                let other = self.evaluate_expr(other, state, ctx)?;
+                let case = self.enum_case(None, other.enum_type(), case)?;
+                Ok((other, case))
+            }
+            _ => Ok((
+                self.evaluate_expr(left, state, ctx)?,
+                self.evaluate_expr(right, state, ctx)?,
+            )),
+        }
+    }
+
+    /// Evaluate the value assigned to a variable. A shorthand enum case prefers the
+    /// enum of the variable's current value.
+    fn evaluate_assigned(
+        &self,
+        name: &VariableName,
+        expr: &Expr,
+        state: &EvalContext,
+        ctx: &mut Ctx,
+    ) -> Result<Value, ()> {
+        match expr {
+            Expr::Term(Term::EnumCase(None, case)) => {
+                let current = state.get_variable(name);
+                self.enum_case(None, current.and_then(Value::enum_type), case)
+            }
+            _ => self.evaluate(expr, state, ctx),
+        }
+    }
+
+    /// The value of an enum case, failing if the enum has no such case or the enum
+    /// of a shorthand case cannot be inferred.
+    fn enum_case(
+        &self,
+        type_name: Option<&str>,
+        hint: Option<&str>,
+        case: &str,
+    ) -> Result<Value, ()> {
+        enums::resolve_case(&self.enums, type_name, hint, case).ok_or_else(|| {
+            let written = format!("{}.{}", type_name.unwrap_or(""), case);
+            self.fail(YarnError::UnknownEnumCase(written))
+        })
+    }
+
+    /// Fail an operation other than equality on an enum value or null, whatever the
+    /// type checking mode.
+    fn invalid_operation(
+        &self,
+        operator: &'static str,
+        operands: &[&Value],
+        expr: &Expr,
+        state: &EvalContext,
+    ) -> Result<Value, ()> {
+        self.fail(YarnError::TypeMismatch(CoercionWarning {
+            node: state.node.cloned(),
+            operator,
+            operands: operands
+                .iter()
+                .map(|value| convert::type_name(value))
+                .collect(),
+            expression: expr.to_string(),
+        }));
+        Err(())
+    }
+
+    /// Handle an implicit conversion of the given operands while evaluating `expr`
+    /// according to the type checking mode, failing if conversions are not allowed.
+    fn check_coercion(
+        &self,
+        operator: &'static str,
+        operands: &[&Value],
+        expr: &Expr,
+        state: &EvalContext,
+    ) -> Result<(), ()> {
+        let operands = operands
+            .iter()
+            .map(|value| convert::type_name(value))
+            .collect();
+        self.check_types(operator, operands, expr.to_string(), state)
+    }
+
+    fn check_types(
+        &self,
+        operator: &'static str,
+        operands: Vec<&'static str>,
+        expression: String,
+        state: &EvalContext,
+    ) -> Result<(), ()> {
+        if self.type_checking == TypeChecking::Coerce {
+            return Ok(());
+        }
+        let warning = CoercionWarning {
+            node: state.node.cloned(),
+            operator,
+            operands,
+            expression,
+        };
+        if self.type_checking == TypeChecking::Strict {
+            self.fail(YarnError::TypeMismatch(warning));
+            return Err(());
+        }
+        self.coercions.borrow_mut().push(warning);
+        Ok(())
+    }
+
+    /// Interpolate the dialogue or option text at the given path for presentation,
+    /// applying any normalization and pseudo-localization.
+    fn present(
+        &self,
+        text: &str,
+        path: &LinePath,
+        state: &EvalContext,
+        ctx: &mut Ctx,
+    ) -> Result<String, ()> {
+        let text = self.translate(text, path);
+        let text = if !self.pseudo_localization {
+            self.interpolate_line(text, path, state, ctx)?
+        } else if self.pseudo_localize_values {
+            pseudo::accent(&self.interpolate_line(text, path, state, ctx)?, self.markup)
+        } else {
+            // The accented template is temporary, so it must bypass the cache.
+            let template = pseudo::accent_template(text, self.markup);
+            self.interpolate(&template, Interpolation::Display, state, ctx)?
+        };
+        let text = if self.normalization.is_enabled() {
+            self.normalization.apply(&text)
+        } else {
+            text
+        };
+        if self.pseudo_localization {
+            Ok(pseudo::expand(&text, self.markup))
+        } else {
+            Ok(text)
+        }
+    }
+
+    /// The translation of a line from the string table of the active locale, then
+    /// that of the default locale, or the source text if neither has one. Each
+    /// table that lacks the line is recorded as missing it.
+    fn translate<'a>(&'a self, text: &'a str, path: &LinePath) -> &'a str {
+        let id = match self.line_ids.get(path) {
+            Some(id) => id,
+            None => return text,
+        };
+        let default = self
+            .default_locale
+            .iter()
+            .filter(|&locale| self.active_locale.as_ref() != Some(locale));
+        for locale in self.active_locale.iter().chain(default) {
+            let table = match self.string_tables.get(locale) {
+                Some(table) => table,
+                None => continue,
+            };
+            match table.get(id) {
+                Some(translation) => return translation,
+                None => self
+                    .missing_lines
+                    .borrow_mut()
+                    .push((id.clone(), locale.clone())),
+            }
+        }
+        text
+    }
+
+    /// The content filter's decision about the given content, or `Allow` if no
+    /// filter is set.
+    fn filter(&self, content: ContentRef) -> FilterDecision {
+        match self.content_filter {
+            Some(ref filter) => filter(&content),
+            None => FilterDecision::Allow,
+        }
+    }
+
+    /// Match the cases of a block of options against its selector, which is
+    /// evaluated once. Returns the selector's value and, for each option with a
+    /// case, whether the option may be presented, having evaluated its condition.
+    fn match_cases(
+        &self,
+        choices: &[Choice],
+        state: &EvalContext,
+        ctx: &mut Ctx,
+    ) -> Result<(Option<Value>, Vec<Option<bool>>), YarnError> {
+        let selector = choices.iter().find_map(|choice| match choice.case {
+            Some(OptionCase::Compare { ref selector, .. }) => Some(selector),
+            _ => None,
+        });
+        let value = match selector.map(|selector| self.evaluate(selector, state, ctx)) {
+            Some(Ok(value)) => Some(value),
+            Some(Err(())) => self.recover().map(|()| None)?,
+            None => None,
+        };
+        let mut cases = vec![None; choices.len()];
+        let mut matched = false;
+        for (index, choice) in choices.iter().enumerate() {
+            let (op, operand) = match choice.case {
+                Some(OptionCase::Compare {
+                    ref op,
+                    ref operand,
+                    ..
+                }) => (op, operand),
+                Some(OptionCase::Else) | None => continue,
+            };
+            cases[index] = Some(false);
+            let value = match value {
+                Some(ref value) if !matched => value,
+                _ => continue,
+            };
+            let comparison = Expr::Binary(
+                op.clone(),
+                Box::new(Expr::Term(value.to_term())),
+                Box::new(operand.clone()),
+            );
+            let result = self.evaluate(&comparison, state, ctx);
+            if self.condition(result)? && self.option_condition(choice, state, ctx)? {
+                cases[index] = Some(true);
+                matched = true;
+            }
+        }
+        for (index, choice) in choices.iter().enumerate() {
+            if choice.case == Some(OptionCase::Else) {
+                cases[index] = Some(!matched && self.option_condition(choice, state, ctx)?);
+            }
+        }
+        Ok((value, cases))
+    }
+
+    /// Whether the condition of an option, if any, is true.
+    fn option_condition(
+        &self,
+        choice: &Choice,
+        state: &EvalContext,
+        ctx: &mut Ctx,
+    ) -> Result<bool, YarnError> {
+        match choice.condition {
+            Some(ref condition) => {
+                let value = self.evaluate(condition, state, ctx);
+                self.condition(value)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Index the IDs of the lines of the given node, if this has not been done since
+    /// it was loaded.
+    fn index_lines(&mut self, node: Option<&Node>) {
+        if let Some(node) = node {
+            if self.indexed_nodes.insert(node.title.clone()) {
+                self.line_ids.extend(localize::line_ids(node));
+            }
+        }
+    }
+
+    /// Record the line at the given path as seen, returning whether it had been seen
+    /// before. Lines without an ID, such as interjections, are never seen.
+    fn see(&mut self, path: &LinePath) -> bool {
+        match self.line_ids.get(path) {
+            Some(id) => !self.seen_lines.insert(id.clone()),
+            None => false,
+        }
+    }
+
+    /// The entry presenting a line rendered from the text at the given path, which is
+    /// recorded as seen. When skimming, a line seen before is flagged as such, or left
+    /// out if seen lines are skipped.
+    fn say(&mut self, path: &LinePath, text: String) -> Option<YarnEntry> {
+        let seen = self.see(path) && self.skim;
+        if seen && self.skim_skips_seen {
+            return None;
+        }
+        let (text, spans) = self.markup(text);
+        Some(YarnEntry::Say(Say {
+            text,
+            spans,
+            seen,
+            page: None,
+        }))
+    }
+
+    /// Separate presented text into plain text and markup spans, if markup is
+    /// enabled.
+    fn markup(&self, text: String) -> (String, Vec<MarkupSpan>) {
+        if self.markup {
+            markup::parse_markup(&text)
+        } else {
+            (text, vec![])
+        }
+    }
+
+    /// Whether the variable is temporary, according to the temporary prefix.
+    fn is_temporary(&self, name: &VariableName) -> bool {
+        !self.temporary_prefix.is_empty() && name.0.starts_with(&self.temporary_prefix)
+    }
+
+    /// Record the cause of an evaluation failure.
+    fn fail(&self, cause: YarnError) {
+        *self.failure.borrow_mut() = Some(cause);
+    }
+
+    /// The outcome of a condition, which is false if it failed to evaluate and the
+    /// error policy allows the conversation to continue.
+    fn condition(&self, value: Result<Value, ()>) -> Result<bool, YarnError> {
+        match value {
+            Ok(value) => Ok(value.as_bool()),
+            Err(()) => self.recover().map(|()| false),
+        }
+    }
+
+    /// Handle a failed evaluation according to the error policy, returning its
+    /// cause if the conversation must stop.
+    fn recover(&self) -> Result<(), YarnError> {
+        let cause = self.failure.take().unwrap_or(YarnError::Evaluation);
+        match self.on_error {
+            OnError::EndConversation => Err(cause),
+            OnError::SkipStep | OnError::Emit => {
+                let mut report = cause.to_report();
+                let expression = self.failed_expression.take();
+                report.expression = report.expression.or(expression);
+                self.recovered.borrow_mut().push(report);
+                Ok(())
+            }
+        }
+    }
+
+    /// Increment the given counter, if statistics are enabled.
+    fn count(&self, counter: Counter) {
+        if self.stats_enabled {
+            for stats in &[&self.stats, &self.conversation_stats] {
+                let cell = counter(stats);
+                cell.set(cell.get() + 1);
+            }
+        }
+    }
+
+    /// Parse and evaluate a single expression outside of any conversation.
+    pub(crate) fn evaluate_source(
+        &self,
+        expr: &str,
+        state: &EvalContext,
+        ctx: &mut Ctx,
+    ) -> Result<Value, YarnError> {
+        let mut tokenizer = TokenIterator::new(expr);
+        let parsed = parse::parse_expr(&mut tokenizer).map_err(|()| YarnError::Parse {
+            line: None,
+            message: format!("invalid expression `{}`", expr),
+        })?;
+        if tokenizer.next().is_some() {
+            return Err(YarnError::Parse {
+                line: None,
+                message: format!("unexpected input after expression `{}`", expr),
+            });
+        }
+        let value = self.evaluate(&parsed, state, ctx);
+        // Only conversations report implicit conversions.
+        self.coercions.borrow_mut().clear();
+        value.map_err(|()| self.failure.take().unwrap_or(YarnError::Evaluation))
+    }
+
+    fn evaluate(&self, expr: &Expr, state: &EvalContext, ctx: &mut Ctx) -> Result<Value, ()> {
+        self.count(|stats| &stats.expressions);
+        let value = self.evaluate_expr(expr, state, ctx);
+        if value.is_err() {
+            *self.failed_expression.borrow_mut() = Some(expr.to_string());
+        }
+        value
+    }
+
+    fn evaluate_expr(&self, expr: &Expr, state: &EvalContext, ctx: &mut Ctx) -> Result<Value, ()> {
+        match expr {
+            Expr::Parentheses(expr) => self.evaluate_expr(expr, state, ctx),
+            Expr::Ternary(condition, if_true, if_false) => {
+                if self.evaluate_expr(condition, state, ctx)?.as_bool() {
+                    self.evaluate_expr(if_true, state, ctx)
+                } else {
+                    self.evaluate_expr(if_false, state, ctx)
+                }
+            }
+            Expr::Term(Term::Number(f)) => Ok(Value::Number(*f)),
+            Expr::Term(Term::Boolean(b)) => Ok(Value::Boolean(*b)),
+            Expr::Term(Term::Null) => Ok(Value::Null),
+            Expr::Term(Term::String(ref s)) => Ok(Value::String((*s).clone())),
+            Expr::Term(Term::Constant(ref name)) => self
+                .constants
+                .get(name)
+                .cloned()
+                .ok_or_else(|| self.fail(YarnError::UndefinedConstant(name.clone()))),
+            Expr::Term(Term::EnumCase(ref type_name, ref case)) => {
+                self.enum_case(type_name.as_deref(), None, case)
+            }
+            Expr::Term(Term::Variable(ref n)) => {
+                self.count(|stats| &stats.variable_lookups);
+                state.get_variable(n).cloned().ok_or_else(|| {
+                    self.fail(YarnError::UndefinedVariable {
+                        name: n.clone(),
+                        suggestions: state.similar_variables(n),
+                    })
+                })
+            }
+            Expr::Term(Term::Function(ref name, ref args)) => {
+                let mut eval_args = vec![];
+                for arg in args {
+                    let v = self.evaluate_expr(arg, state, ctx)?;
+                    eval_args.push(v);
+                }
+                // A view's state has no `functions` and calls sync functions instead.
+                let function = (self.functions.get(name), self.sync_functions.get(name));
+                let num_args = match function {
+                    (Some(f), _) => &f.num_args,
+                    (None, Some(f)) => &f.num_args,
+                    (None, None) => {
+                        let names = self.functions.keys().chain(self.sync_functions.keys());
+                        self.fail(YarnError::UnknownFunction {
+                            name: name.clone(),
+                            suggestions: suggest::similar_names(
+                                name,
+                                names.map(|name| name.as_str()),
+                            ),
+                        });
+                        return Err(());
+                    }
+                };
+                if !num_args.contains(&args.len()) {
+                    self.fail(YarnError::WrongArgumentCount {
+                        function: name.clone(),
+                        args: args.len(),
+                        expected: num_args.clone(),
+                    });
+                    return Err(());
+                }
+                self.count(|stats| &stats.function_calls);
+                let result = match function {
+                    (Some(f), _) => (f.callback)(eval_args, state, ctx),
+                    (None, Some(f)) => (f.callback)(eval_args, state),
+                    (None, None) => unreachable!(),
+                };
+                result.map_err(|message| {
+                    self.fail(YarnError::FunctionFailed {
+                        function: name.clone(),
+                        message,
+                    })
+                })
+            }
+
+            Expr::Unary(UnaryOp::Not, expr) => self
+                .evaluate_expr(expr, state, ctx)
+                .map(|v| Value::Boolean(!v.as_bool())),
+            Expr::Unary(UnaryOp::Negate, operand) => {
+                let value = self.evaluate_expr(operand, state, ctx)?;
+                if value.enum_type().is_some() || value.is_null() {
+                    return self.invalid_operation("-", &[&value], expr, state);
+                }
+                if !matches!(value, Value::Number(_)) {
+                    self.check_coercion("-", &[&value], expr, state)?;
+                }
+                Ok(Value::Number(-value.as_num()))
+            }
+
+            Expr::Binary(BinaryOp::Coalesce, left, right) => {
+                // The right side is only evaluated when the left side is null or missing.
+                let value = match **left {
+                    Expr::Term(Term::Variable(ref n)) if self.coalesce_undefined_variables => {
+                        self.count(|stats| &stats.variable_lookups);
+                        state.get_variable(n).cloned().unwrap_or(Value::Null)
+                    }
+                    _ => self.evaluate_expr(left, state, ctx)?,
+                };
+                if value.is_null() {
+                    self.evaluate_expr(right, state, ctx)
+                } else {
+                    Ok(value)
+                }
+            }
+            Expr::Binary(BinaryOp::And, left, right) => {
+                let left = self.evaluate_expr(left, state, ctx)?.as_bool();
+                let right = self.evaluate_expr(right, state, ctx)?.as_bool();
+                Ok(Value::Boolean(left && right))
+            }
+            Expr::Binary(BinaryOp::Or, left, right) => {
+                let left = self.evaluate_expr(left, state, ctx)?.as_bool();
+                let right = self.evaluate_expr(right, state, ctx)?.as_bool();
+                Ok(Value::Boolean(left || right))
+            }
+
+            Expr::Binary(BinaryOp::Xor, left, right) => {
+                let left = self.evaluate_expr(left, state, ctx)?.as_bool();
+                let right = self.evaluate_expr(right, state, ctx)?.as_bool();
+                Ok(Value::Boolean(left != right))
+            }
+            Expr::Binary(op, left_expr, right_expr) => {
+                let (left, right) = self.evaluate_operands(left_expr, right_expr, state, ctx)?;
+                let equality = matches!(op, BinaryOp::Equals | BinaryOp::NotEquals);
+                let invalid = |value: &Value| value.enum_type().is_some() || value.is_null();
+                if !equality && (invalid(&left) || invalid(&right)) {
+                    return self.invalid_operation(op.symbol(), &[&left, &right], expr, state);
+                }
+                if coerces(op, YarnType::of(&left), YarnType::of(&right)) {
+                    self.check_coercion(op.symbol(), &[&left, &right], expr, state)?;
+                }
+                Ok(match op {
+                    BinaryOp::Plus => left + right,
+                    BinaryOp::Minus => left - right,
+                    BinaryOp::Multiply => left * right,
+                    BinaryOp::Divide => left / right,
+                    BinaryOp::Equals => Value::Boolean(left == right),
+                    BinaryOp::NotEquals => Value::Boolean(!(left == right)),
+                    BinaryOp::GreaterThan => Value::Boolean(left.as_num() > right.as_num()),
+                    BinaryOp::GreaterThanEqual => Value::Boolean(left.as_num() >= right.as_num()),
+                    BinaryOp::LessThan => Value::Boolean(left.as_num() < right.as_num()),
+                    BinaryOp::LessThanEqual => Value::Boolean(left.as_num() <= right.as_num()),
+                    BinaryOp::And | BinaryOp::Or | BinaryOp::Xor | BinaryOp::Coalesce => {
+                        unreachable!()
+                    }
+                })
+            }
+        }
+    }
+}
+
+/// A collection of Yarn nodes, kept in the order they were loaded.
+#[derive(Clone, Default)]
+pub struct Nodes {
+    nodes: Vec<Node>,
+    indexes: HashMap<NodeName, usize>,
+    names: NodeNames,
+}
+
+impl Nodes {
+    /// The node with the given name, if any.
+    pub fn get(&self, name: &NodeName) -> Option<&Node> {
+        self.indexes.get(name).map(|&idx| &self.nodes[idx])
+    }
+
+    /// Whether a node with the given name is present.
+    pub fn contains(&self, name: &NodeName) -> bool {
+        self.indexes.contains_key(name)
+    }
+
+    /// The number of nodes.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Whether there are no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The nodes in the order they were first loaded. A node that replaces an
+    /// earlier node with the same title takes that node's position.
+    pub fn iter(&self) -> std::slice::Iter<'_, Node> {
+        self.nodes.iter()
+    }
+
+    /// An error for a node that is not loaded, suggesting similarly named nodes.
+    pub(crate) fn unknown(&self, name: NodeName) -> YarnError {
+        let suggestions = self.similar_titles(&name);
+        YarnError::UnknownNode(name, suggestions)
+    }
+
+    /// The titles of loaded nodes similar to the given one, for error messages.
+    pub(crate) fn similar_titles(&self, name: &NodeName) -> Vec<String> {
+        suggest::similar_names(name.as_str(), self.iter().map(|node| node.title.as_str()))
+    }
+
+    /// Add the node, replacing any existing node with the same title.
+    pub(crate) fn insert(&mut self, node: Node) {
+        match self.indexes.get(&node.title) {
+            Some(&idx) => self.nodes[idx] = node,
+            None => {
+                self.indexes.insert(node.title.clone(), self.nodes.len());
+                self.nodes.push(node);
+            }
+        }
+    }
+}
+
+struct NodeState {
+    nodes: Arc<Nodes>,
+    visits: HashMap<NodeName, u32>,
+    chosen: ChoiceCounts,
+    /// For each line group, by node and source line, when each of its lines was
+    /// last shown: 0 if never, and otherwise a count that increases with each
+    /// showing of the group.
+    line_groups: HashMap<(NodeName, usize), Vec<u32>>,
+    /// The time in seconds given by the embedder.
+    time: f64,
+    random: RefCell<Randomness>,
+    conversation: Option<Conversation>,
+}
+
+impl NodeState {
+    fn eval_context<'a>(&'a self, variables: &'a Variables) -> EvalContext<'a> {
+        EvalContext {
+            node: self
+                .conversation
+                .as_ref()
+                .map(|conversation| &conversation.node),
+            nodes: &self.nodes,
+            visits: &self.visits,
+            chosen: &self.chosen,
+            variables,
+            locals: self
+                .conversation
+                .as_ref()
+                .map(|conversation| &conversation.locals),
+            time: self.time,
+            random: &self.random,
+        }
+    }
+
+    /// Record that the line at `index` of a group with `len` lines was shown.
+    fn show_line(&mut self, group: (NodeName, usize), index: usize, len: usize) {
+        let shown = self.line_groups.entry(group).or_default();
+        // The group may have changed since it was last shown.
+        shown.resize(len, 0);
+        let latest = shown.iter().max().copied().unwrap_or(0);
+        shown[index] = latest + 1;
+    }
+
+    fn visit(&mut self, name: &NodeName) -> u32 {
+        let count = self.visits.entry(name.clone()).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    /// Continue the active conversation from the start of the given node.
+    fn jump(&mut self, node: NodeName) {
+        let (mut trail, jumps) = self
+            .conversation
+            .take()
+            .map_or_else(|| (vec![], 0), |c| (c.trail, c.jumps));
+        if trail.len() == MAX_TRAIL_LENGTH {
+            trail.remove(0);
+        }
+        trail.push(node.clone());
+        let mut conversation = Conversation::new(node);
+        conversation.trail = trail;
+        conversation.jumps = jumps + 1;
+        self.conversation = Some(conversation);
+    }
+
+    fn push_step(&mut self, index: StepIndex) {
+        self.conversation.as_mut().unwrap().indexes.push(index);
+    }
+    /// Leave the innermost nested block, continuing with the step that follows the
+    /// conditional or dialogue containing it. Returns false if execution is not
+    /// inside a nested block.
+    fn pop_step(&mut self) -> bool {
+        let conversation = self.conversation.as_mut().unwrap();
+        if conversation.indexes.pop().is_none() {
+            return false;
+        }
+        self.advance();
+        true
+    }
+    fn advance(&mut self) {
+        let conversation = self.conversation.as_mut().unwrap();
+        match conversation.indexes.last_mut() {
+            Some(index) => index.advance(),
+            None => conversation.base_index += 1,
+        }
+    }
+    /// The source line of the step being executed, if known.
+    fn step_line(&self) -> Option<usize> {
+        let conversation = self.conversation.as_ref()?;
+        let (base_index, ref indexes) = *conversation.started.as_ref()?;
+        let node = self.nodes.get(&conversation.node)?;
+        let index = flat_index(node.steps().ok()?, base_index, indexes)?;
+        node.step_lines().get(index).copied()
+    }
+
+    fn get_current_step(&self) -> Result<Option<&Step>, YarnError> {
+        let conversation = self
+            .conversation
+            .as_ref()
+            .expect("No active conversation found");
+        let mut steps = {
+            let current = self
+                .nodes
+                .get(&conversation.node)
+                .ok_or_else(|| self.nodes.unknown(conversation.node.clone()))?;
+            current.steps()?
+        };
+        let mut current_step_index = conversation.base_index;
+
+        for index in &conversation.indexes {
+            match (&steps[current_step_index], *index) {
+                (Step::Dialogue(_, choices, _), StepIndex::Dialogue(choice, step_index)) => {
+                    let choice = &choices[choice];
+                    match choice.kind {
+                        ChoiceKind::Inline(ref choice_steps) => {
+                            steps = choice_steps;
+                            current_step_index = step_index;
+                        }
+                        ChoiceKind::External(..) => unreachable!(),
+                    }
+                }
+                (Step::Conditional(_, if_steps, ..), StepIndex::If(step_index)) => {
+                    steps = if_steps;
+                    current_step_index = step_index;
+                }
+                (Step::Conditional(_, _, else_ifs, ..), StepIndex::ElseIf(index, step_index)) => {
+                    steps = &else_ifs[index].1;
+                    current_step_index = step_index;
+                }
+                (Step::Conditional(_, _, _, else_steps), StepIndex::Else(step_index)) => {
+                    steps = else_steps;
+                    current_step_index = step_index;
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        Ok(steps.get(current_step_index))
+    }
+}
+
+/// The position of the step at the given position in `validate::flatten`, which is
+/// also its position in `Node::step_lines`.
+fn flat_index(mut steps: &[Step], mut index: usize, indexes: &[StepIndex]) -> Option<usize> {
+    let len = |steps: &[Step]| validate::flatten(steps).len();
+    let mut flat = 0;
+    for nested in indexes {
+        // Skip the steps before this one and this one itself.
+        flat += len(steps.get(..index)?) + 1;
+        match (steps.get(index)?, *nested) {
+            (Step::Dialogue(_, choices, _), StepIndex::Dialogue(choice, step_index)) => {
+                for other in choices.get(..choice)? {
+                    if let ChoiceKind::Inline(ref other) = other.kind {
+                        flat += len(other);
+                    }
+                }
+                match choices.get(choice)?.kind {
+                    ChoiceKind::Inline(ref choice_steps) => steps = choice_steps,
+                    ChoiceKind::External(..) => return None,
+                }
+                index = step_index;
+            }
+            (Step::Conditional(_, if_steps, ..), StepIndex::If(step_index)) => {
+                steps = if_steps;
+                index = step_index;
+            }
+            (Step::Conditional(_, if_steps, else_ifs, _), StepIndex::ElseIf(idx, step_index)) => {
+                flat += len(if_steps);
+                for (_, other) in else_ifs.get(..idx)? {
+                    flat += len(other);
+                }
+                steps = &else_ifs.get(idx)?.1;
+                index = step_index;
+            }
+            (Step::Conditional(_, if_steps, else_ifs, else_steps), StepIndex::Else(step_index)) => {
+                flat += len(if_steps);
+                for (_, other) in else_ifs {
+                    flat += len(other);
+                }
+                steps = else_steps;
+                index = step_index;
+            }
+            _ => return None,
+        }
+    }
+    Some(flat + len(steps.get(..index)?))
+}
+
+impl Default for YarnEngine {
+    fn default() -> Self {
+        YarnEngine::new()
+    }
+}
+
+impl YarnEngine {
+    /// Create a new YarnEngine instance.
+    pub fn new() -> Self {
+        YarnEngine::with_context()
+    }
+
+    /// Create a new YarnEngine instance that uses the provided nodes, which may be
+    /// shared with other engines. Conversation state and visit counts are not shared.
+    pub fn with_shared_nodes(nodes: Arc<Nodes>) -> Self {
+        let mut engine = YarnEngine::new();
+        engine.state.nodes = nodes;
+        engine
+    }
+
+    /// Make a choice between a series of options for the current Yarn node's active step.
+    /// Execution will resume immediately based on the choice provided. Fails with
+    /// `YarnError::NoConversation` once the conversation has ended,
+    /// `YarnError::NoChoices` if no options are presented, and
+    /// `YarnError::InvalidChoice` if there is no option at the given index.
+    pub fn choose(&mut self, choice: usize) -> Result<(), YarnError> {
+        self.choose_with(choice, &mut ())
+    }
+
+    /// Select the default option of the choices currently presented, such as when
+    /// the time allowed for a timed choice runs out. This is the option given by
+    /// `default_choice` in `YarnEntry::Choose`, or the first option if there is none.
+    pub fn choose_default(&mut self) -> Result<(), YarnError> {
+        self.choose_default_with(&mut ())
+    }
+
+    /// Parse and evaluate a single Yarn expression, such as `$gold + dice(6) > 10`,
+    /// against the current variables, functions and visit counts. Any active
+    /// conversation is unaffected.
+    pub fn evaluate_expression(&self, expr: &str) -> Result<Value, YarnError> {
+        self.evaluate_expression_with(expr, &mut ())
+    }
+
+    /// List the loaded nodes with their preconditions evaluated. See
+    /// `available_nodes_with`.
+    pub fn available_nodes(&self, filter_tag: Option<&str>) -> Vec<AvailableNode> {
+        self.available_nodes_with(filter_tag, &mut ())
+    }
+
+    /// Check whether a conversation starting at the given node can run without
+    /// failing. See `can_activate_with`.
+    pub fn can_activate(&self, name: &NodeName) -> Result<(), Vec<ActivationProblem>> {
+        self.can_activate_with(name, &mut ())
+    }
+
+    /// Execute a single step of the active conversation. See `step_with`.
+    pub fn step(&mut self) -> Result<StepResult, YarnError> {
+        self.step_with(&mut ())
+    }
+
+    /// Run the given node to completion without interaction, acknowledging every
+    /// line and selecting options according to the policy. Returns every entry
+    /// produced, ending with `YarnEntry::EndConversation`, which holds the value
+    /// returned by the node, if any.
+    ///
+    /// Refuses to run while another conversation is active, since that conversation
+    /// would be lost.
+    pub fn run_node(
+        &mut self,
+        node: &NodeName,
+        policy: ChoicePolicy,
+    ) -> Result<Vec<YarnEntry>, YarnError> {
+        self.run_node_with(node, policy, &mut ())
+    }
+
+    /// Activate the given node and run the conversation to completion, invoking the
+    /// handler for each entry produced. Stops the conversation if an error occurs.
+    pub fn run_with_handler(
+        &mut self,
+        node: NodeName,
+        handler: &mut impl YarnHandler,
+    ) -> Result<(), YarnError> {
+        if self.node(&node).is_none() {
+            return Err(self.state.nodes.unknown(node));
+        }
+        self.activate(node);
+        let result = loop {
+            let mut budget = self.engine_state.step_budget;
+            let entry = match self.run_steps(&mut budget, &mut ()) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            match entry {
+                YarnEntry::Say(line) => handler.say(line),
+                YarnEntry::Choose { text, choices, .. } => {
+                    let index = handler.choose(text, choices);
+                    if self.choose(index).is_err() {
+                        break Err(YarnError::InvalidChoice(index));
+                    }
+                }
+                YarnEntry::Command { action } => {
+                    handler.command(action);
+                    self.proceed();
+                }
+                YarnEntry::Error(error) => handler.error(error),
+                YarnEntry::EndConversation(_) => {
+                    handler.end_conversation();
+                    break Ok(());
+                }
+            }
+        };
+        if result.is_err() {
+            self.clear_conversation();
+        }
+        result
+    }
+}
+
+impl<Ctx: 'static> YarnEngine<Ctx> {
+    /// Create a new YarnEngine instance whose callbacks receive a context of type
+    /// `Ctx`.
+    pub fn with_context() -> Self {
+        let mut engine = YarnEngine {
+            state: NodeState {
+                nodes: Arc::new(Nodes::default()),
+                visits: HashMap::new(),
+                chosen: HashMap::new(),
+                line_groups: HashMap::new(),
+                time: 0.,
+                random: RefCell::new(Randomness::from_entropy()),
+                conversation: None,
+            },
+            engine_state: EngineState::new(),
+            status: ConversationStatus::Idle,
+            node_visited_callbacks: vec![],
+            choice_made_callbacks: vec![],
+            log_callbacks: vec![],
+            coercion_callbacks: vec![],
+            recovered_error_callbacks: vec![],
+            missing_line_callbacks: vec![],
+            last_choice: None,
+            checkpoints: vec![],
+            checkpoint_limit: 0,
+            checkpoint_sequence: 0,
+            interrupted: vec![],
+            last_error: None,
+            line_length_limit: None,
+            lint_rules: vec![],
+            pagination: None,
+            start_node: NodeName::from("Start"),
+            entry_point_predicate: None,
+        };
+
+        // Define built-in functions. Unknown nodes are treated as unvisited so that
+        // scripts can refer to optional content; replace the function with
+        // `register_function_overwriting` to make such references an error instead.
+        engine.insert_sync_function(
+            "visited".to_string(),
+            1..=1,
+            Box::new(|args, state| match args[0] {
+                Value::String(ref s) => Ok(Value::Boolean(
+                    state.visit_count(&NodeName::from(s.trim())) > 0,
+                )),
+                _ => Err("expected a node name".to_string()),
+            }),
+        );
+        engine.insert_sync_function(
+            "visited_count".to_string(),
+            1..=1,
+            Box::new(|args, state| match args[0] {
+                Value::String(ref s) => Ok(Value::Number(
+                    state.visit_count(&NodeName::from(s.trim())) as f32,
+                )),
+                _ => Err("expected a node name".to_string()),
+            }),
+        );
+        // `chosen("Hub", option)` takes the option's position among the node's
+        // options, or the ID from its `#line:` tag.
+        engine.insert_sync_function(
+            "chosen".to_string(),
+            2..=2,
+            Box::new(|args, state| {
+                let node = NodeName::from(args[0].as_string().trim());
+                let steps = match state.node(&node).map(|node| node.steps()) {
+                    Some(Ok(steps)) => steps,
+                    Some(Err(error)) => return Err(error.to_string()),
+                    None => return Ok(Value::Number(0.)),
+                };
+                let option = chosen::find(steps, &args[1])
+                    .ok_or_else(|| format!("`{}` has no option `{}`", node, args[1].as_string()))?;
+                Ok(Value::Number(state.times_chosen(&node, option) as f32))
+            }),
+        );
+        engine.insert_sync_function(
+            "flag".to_string(),
+            1..=1,
+            Box::new(|args, state| match args[0] {
+                Value::String(ref s) => Ok(Value::Boolean(
+                    state.get_variable(&flags::flag_variable(s.trim()))
+                        == Some(&Value::Boolean(true)),
+                )),
+                _ => Err("expected a flag name".to_string()),
+            }),
+        );
+        // The clock is set by the embedder, so scripts can measure cooldowns without
+        // the engine reading the wall clock. Numbers are single precision, so times
+        // are best kept small, such as seconds since the game started.
+        engine.insert_sync_function(
+            "time".to_string(),
+            0..=0,
+            Box::new(|_, state| Ok(Value::Number(state.time() as f32))),
+        );
+        engine.insert_sync_function(
+            "elapsed_since".to_string(),
+            1..=1,
+            Box::new(|args, state| {
+                Ok(Value::Number(
+                    (state.time() - f64::from(args[0].as_num())) as f32,
+                ))
+            }),
+        );
+        // Random numbers come from the engine's generator, so they repeat for the same
+        // seed. See `set_rng_mode`.
+        engine.insert_sync_function(
+            "dice".to_string(),
+            1..=1,
+            Box::new(|args, state| {
+                let sides = (args[0].as_num() as usize).max(1);
+                Ok(Value::Number((state.rng().below(sides) + 1) as f32))
+            }),
+        );
+        engine.insert_sync_function(
+            "random".to_string(),
+            0..=0,
+            Box::new(|_, state| Ok(Value::Number(state.rng().unit() as f32))),
+        );
+
+        // String functions operate on chars rather than bytes. Out-of-range indices
+        // are clamped to the bounds of the string.
+        engine.insert_sync_function(
+            "length".to_string(),
+            1..=1,
+            Box::new(|args, _| Ok(Value::Number(args[0].as_string().chars().count() as f32))),
+        );
+        engine.insert_sync_function(
+            "substring".to_string(),
+            2..=3,
+            Box::new(|args, _| {
+                let s = args[0].as_string();
+                // Float-to-integer casts saturate, so negative indices become 0.
+                let start = args[1].as_num() as usize;
+                let len = args.get(2).map_or(usize::MAX, |v| v.as_num() as usize);
+                Ok(Value::String(s.chars().skip(start).take(len).collect()))
+            }),
+        );
+        engine.insert_sync_function(
+            "upper".to_string(),
+            1..=1,
+            Box::new(|args, _| Ok(Value::String(args[0].as_string().to_uppercase()))),
+        );
+        engine.insert_sync_function(
+            "lower".to_string(),
+            1..=1,
+            Box::new(|args, _| Ok(Value::String(args[0].as_string().to_lowercase()))),
+        );
+        engine.insert_sync_function(
+            "contains".to_string(),
+            2..=2,
+            Box::new(|args, _| {
+                Ok(Value::Boolean(
+                    args[0].as_string().contains(&args[1].as_string()),
+                ))
+            }),
+        );
+        // `format("{0} of {1}", $count, $item)` fills each numbered placeholder with
+        // the corresponding argument after the template. Since interpolation in
+        // dialogue ends at the first `}`, templates are best used in `<<set>>`.
+        engine.insert_sync_function(
+            "format".to_string(),
+            1..=usize::MAX,
+            Box::new(|args, _| {
+                format_template(&args[0].as_string(), &args[1..]).map(Value::String)
+            }),
+        );
+
+        let (string, number, boolean) = (
+            Some(YarnType::String),
+            Some(YarnType::Number),
+            Some(YarnType::Boolean),
+        );
+        let signatures = [
+            ("visited", vec![string], boolean),
+            ("visited_count", vec![string], number),
+            ("flag", vec![string], boolean),
+            ("chosen", vec![string, None], number),
+            ("time", vec![], number),
+            ("elapsed_since", vec![number], number),
+            ("dice", vec![number], number),
+            ("random", vec![], number),
+            ("length", vec![None], number),
+            ("substring", vec![None, number, number], string),
+            ("upper", vec![None], string),
+            ("lower", vec![None], string),
+            ("contains", vec![None, None], boolean),
+            ("format", vec![string], string),
+        ];
+        for (name, params, returns) in &signatures {
+            let _ = engine.set_function_signature(name, params, *returns);
+        }
+
+        engine
+    }
+
+    /// A handle to this engine's nodes that can be shared with other engines via
+    /// `YarnEngine::with_shared_nodes`.
+    pub fn shared_nodes(&self) -> Arc<Nodes> {
+        self.state.nodes.clone()
+    }
+
+    /// Parse the provided string as a series of Yarn nodes, appending the results to
+    /// the internal node storage, failing with `YarnError::Parse` if the source is
+    /// malformed.
+    /// If the nodes are shared with other engines, this engine receives its own copy
+    /// of the nodes first, leaving the other engines unaffected.
+    pub fn load_from_string(&mut self, s: &str) -> Result<(), YarnError> {
+        self.load_from_string_with_options(s, &ParseOptions::default())
+    }
+
+    /// Parse the provided string as a series of Yarn nodes using the given options,
+    /// appending the results to the internal node storage. Source that exceeds one
+    /// of the options' limits produces `YarnError::LimitExceeded`.
+    pub fn load_from_string_with_options(
+        &mut self,
+        s: &str,
+        options: &ParseOptions,
+    ) -> Result<(), YarnError> {
+        self.load_nodes(s, options, None)
+    }
+
+    /// Like `load_from_string`, recording the given name as the source of each node.
+    pub fn load_from_string_named(&mut self, name: &str, s: &str) -> Result<(), YarnError> {
+        self.load_nodes(s, &ParseOptions::default(), Some(name))
+    }
+
+    /// Read and parse the given file as a series of Yarn nodes, recording its path as
+    /// the source of each node.
+    pub fn load_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), YarnError> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        let s = fs::read_to_string(path)
+            .map_err(|err| YarnError::Io(format!("failed to read `{}`: {}", name, err)))?;
+        self.load_nodes(&s, &ParseOptions::default(), Some(&name))
+    }
+
+    /// Load every `.yarn` file in the given directory with `load_from_file`, in order
+    /// of file name. Subdirectories are not searched.
+    pub fn load_from_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<(), YarnError> {
+        let path = path.as_ref();
+        let io_error =
+            |err: io::Error| YarnError::Io(format!("failed to read `{}`: {}", path.display(), err));
+        let mut files = vec![];
+        for entry in fs::read_dir(path).map_err(io_error)? {
+            let file = entry.map_err(io_error)?.path();
+            if file.is_file() && file.extension().is_some_and(|ext| ext == "yarn") {
+                files.push(file);
+            }
+        }
+        files.sort();
+        for file in files {
+            self.load_from_file(file)?;
+        }
+        Ok(())
+    }
+
+    /// Begin parsing the given source a few nodes at a time with
+    /// `LoadSession::work`, so that a large script can be loaded without pausing.
+    /// The nodes are loaded by `finish_load`, all at once.
+    pub fn begin_load(&self, source: impl Into<String>, options: &ParseOptions) -> LoadSession {
+        LoadSession::new(source.into(), options, self.state.nodes.names.clone())
+    }
+
+    /// Parse whatever remains of the session's source and load its nodes. Nothing is
+    /// loaded if any part of the source fails to parse or resolve.
+    pub fn finish_load(&mut self, session: LoadSession) -> Result<(), YarnError> {
+        let nodes = session.into_nodes()?;
+        self.add_nodes(nodes, None)
+    }
+
+    /// Parse and load a batch of named sources, such as the scripts of an asset
+    /// bundle, reporting each source's errors under its name. A node defined in two
+    /// sources of the batch is an error in the later one. With
+    /// `LoadOptions::atomic`, nothing is loaded if any source has an error, so that a
+    /// partly loaded batch cannot leave jumps to nodes that are missing.
+    pub fn load_sources(
+        &mut self,
+        sources: impl IntoIterator<Item = (String, String)>,
+        options: &LoadOptions,
+    ) -> LoadReport {
+        let mut report = LoadReport::default();
+        let mut parsed = vec![];
+        let mut defined_in = HashMap::new();
+        let mut metrics = options.parse.collect_metrics.then(ParseMetrics::default);
+        for (source, contents) in sources {
+            let names = &self.state.nodes.names;
+            let nodes = match metrics {
+                Some(ref mut metrics) => LoadSession::new(contents, &options.parse, names.clone())
+                    .into_nodes_measured(&source, metrics),
+                None => parse::parse_nodes_from_string(&contents, &options.parse, names),
+            };
+            let nodes = match nodes {
+                Ok(nodes) => nodes,
+                Err(err) => {
+                    report.errors.push((source, err));
+                    continue;
+                }
+            };
+            let clash = nodes.iter().find_map(|node| {
+                let first: &String = defined_in.get(&node.title)?;
+                Some((node.title.clone(), first.clone()))
+            });
+            if let Some((name, first)) = clash {
+                let err = YarnError::DuplicateNode {
+                    name,
+                    first,
+                    second: source.clone(),
+                };
+                report.errors.push((source, err));
+                continue;
+            }
+            for node in &nodes {
+                defined_in.insert(node.title.clone(), source.clone());
+            }
+            parsed.push((source, nodes));
+        }
+        report.metrics = metrics;
+        if options.atomic && !report.is_ok() {
+            return report;
+        }
+        let snapshot = options.atomic.then(|| {
+            let state = &self.engine_state;
+            (
+                self.state.nodes.clone(),
+                state.variables.clone(),
+                state.declarations.clone(),
+                state.constants.clone(),
+                state.enums.clone(),
+            )
+        });
+        for (source, nodes) in parsed {
+            match self.add_nodes(nodes, Some(&source)) {
+                Ok(()) => report.loaded.push(source),
+                Err(err) => report.errors.push((source, err)),
+            }
+        }
+        if let Some(snapshot) = snapshot.filter(|_| !report.is_ok()) {
+            let (nodes, variables, declarations, constants, enums) = snapshot;
+            self.state.nodes = nodes;
+            self.engine_state.variables = variables;
+            self.engine_state.declarations = declarations;
+            self.engine_state.constants = constants;
+            self.engine_state.enums = enums;
+            self.update_line_ids();
+            report.loaded.clear();
+        }
+        report
+    }
+
+    fn load_nodes(
+        &mut self,
+        s: &str,
+        options: &ParseOptions,
+        source: Option<&str>,
+    ) -> Result<(), YarnError> {
+        let nodes = parse::parse_nodes_from_string(s, options, &self.state.nodes.names)?;
+        self.add_nodes(nodes, source)
+    }
+
+    /// Add parsed nodes, applying their enums, constants and declarations. Nothing
+    /// is added if any of those fails to resolve.
+    fn add_nodes(&mut self, nodes: Vec<Node>, source: Option<&str>) -> Result<(), YarnError> {
+        self.engine_state.rendered.borrow_mut().clear();
+        // Enums, constants and declarations take effect on load. Lazily parsed bodies
+        // are only parsed here if they might contain one.
+        let parsed = |keyword: &'static str| {
+            nodes
+                .iter()
+                .filter(move |node| {
+                    let body = node.lazy_body.as_ref();
+                    body.is_none_or(|body| body.contains(keyword))
+                })
+                .filter_map(|node| node.steps().ok())
+        };
+        // Enums and constants are resolved on load, so each is defined once and
+        // references must name one loaded so far.
+        let mut enums = (*self.engine_state.enums).clone();
+        let mut declared_enums = vec![];
+        for steps in parsed("enum") {
+            enums::collect_enums(steps, &mut declared_enums);
+        }
+        for (name, cases) in declared_enums {
+            if enums.insert(name.clone(), cases).is_some() {
+                return Err(YarnError::DuplicateEnum(name));
+            }
+        }
+        let mut constants = (*self.engine_state.constants).clone();
+        let mut defined = vec![];
+        for steps in parsed("const") {
+            constants::collect_constants(steps, &mut defined);
+        }
+        for (name, value) in defined {
+            let value = enums::resolve_literal(&enums, value)?;
+            if constants.insert(name.clone(), value).is_some() {
+                return Err(YarnError::DuplicateConstant(name));
+            }
+        }
+        for node in &nodes {
+            if let (None, Ok(steps)) = (&node.lazy_body, node.steps()) {
+                constants::check_constants(steps, &constants)?;
+            }
+        }
+        let mut declarations = vec![];
+        for steps in parsed("declare") {
+            validate::collect_declarations(steps, &mut declarations);
+        }
+        let declarations = declarations
+            .into_iter()
+            .map(|(name, value, ty)| Ok((name, enums::resolve_literal(&enums, value)?, ty)))
+            .collect::<Result<Vec<_>, YarnError>>()?;
+        self.engine_state.enums = Arc::new(enums);
+        self.engine_state.constants = Arc::new(constants);
+        for (name, value, ty) in declarations {
+            self.engine_state.declarations.insert(name.clone(), ty);
+            if !self.engine_state.variables.values.contains_key(&name) {
+                self.engine_state.variables.set(name, value);
+            }
+        }
+        let storage = Arc::make_mut(&mut self.state.nodes);
+        for mut node in nodes {
+            node.source = source.map(|source| source.to_string());
+            storage.insert(node);
+        }
+        self.update_line_ids();
+        Ok(())
+    }
+
+    /// Parse the body of every node loaded with `ParseOptions::lazy_bodies` that has
+    /// not been parsed yet, returning the first error encountered.
+    pub fn parse_all(&self) -> Result<(), YarnError> {
+        for node in self.state.nodes.iter() {
+            node.steps()?;
+        }
+        Ok(())
+    }
+
+    /// The value of each `<<const>>` in the loaded nodes, by name.
+    pub fn constants(&self) -> &HashMap<String, Value> {
+        &self.engine_state.constants
+    }
+
+    /// The cases of the named enum declared in the loaded nodes, in the order
+    /// declared.
+    pub fn enum_cases(&self, name: &str) -> Option<&[String]> {
+        self.engine_state
+            .enums
+            .get(name)
+            .map(|cases| cases.as_slice())
+    }
+
+    /// The loaded node with the given name, if any.
+    pub fn node(&self, name: &NodeName) -> Option<&Node> {
+        self.state.nodes.get(name)
+    }
+
+    /// All loaded nodes, in the order they were loaded.
+    pub fn nodes(&self) -> &Nodes {
+        &self.state.nodes
+    }
+
+    /// The names of the loaded nodes whose source is the given name, in the order
+    /// they were loaded.
+    pub fn nodes_from_source(&self, source: &str) -> Vec<NodeName> {
+        self.state
+            .nodes
+            .iter()
+            .filter(|node| node.source.as_deref() == Some(source))
+            .map(|node| node.title.clone())
+            .collect()
+    }
+
+    /// The number of times the given node has been visited by this engine.
+    pub fn visit_count(&self, name: &NodeName) -> u32 {
+        self.state
+            .eval_context(&self.engine_state.variables)
+            .visit_count(name)
+    }
+
+    /// The number of times the option at the given position among the node's
+    /// options, counting from 0 in source order, has been selected by this engine.
+    pub fn times_chosen(&self, node: &NodeName, option: usize) -> u32 {
+        self.state
+            .eval_context(&self.engine_state.variables)
+            .times_chosen(node, option)
+    }
+
+    /// Forget which options have been selected, so that every option is presented
+    /// as never chosen.
+    pub fn reset_times_chosen(&mut self) {
+        self.state.chosen.clear();
+    }
+
+    /// The player's progress through the story apart from variables, for saving.
+    pub fn export_progress(&self) -> StoryProgress {
+        StoryProgress {
+            visits: self.state.visits.clone(),
+            times_chosen: self.state.chosen.clone(),
+            line_groups: self.state.line_groups.clone(),
+            seen_lines: self.engine_state.seen_lines.clone(),
+            time: self.state.time,
+        }
+    }
+
+    /// Add saved progress to the engine's own, combining them as described by
+    /// `StoryProgress::merge`. A save is restored exactly by importing it into an
+    /// engine with no progress of its own.
+    pub fn import_progress(&mut self, progress: &StoryProgress) {
+        let mut merged = self.export_progress();
+        merged.merge(progress);
+        self.state.visits = merged.visits;
+        self.state.chosen = merged.times_chosen;
+        self.state.line_groups = merged.line_groups;
+        self.engine_state.seen_lines = merged.seen_lines;
+        self.state.time = merged.time;
+        self.engine_state.rendered.borrow_mut().clear();
+    }
+
+    /// Collect every string in the loaded nodes that requires translation, along with
+    /// context for translators. Lines are ordered by the order the nodes were loaded,
+    /// then by position within each node.
+    pub fn extract_lines(&self) -> Vec<LocalizableLine> {
+        localize::extract_lines(&self.state.nodes, &self.engine_state.normalization)
+    }
+
+    /// Check the loaded nodes for problems that can be detected without running them,
+    /// such as nodes that jump to one another forever without yielding, calls to
+    /// functions that are not registered or are passed the wrong number of
+    /// arguments, and values whose types conflict with `<<declare>>` statements or
+    /// function signatures. Functions should be registered before calling this.
+    pub fn validate(&self) -> Vec<ValidationWarning> {
+        let line_length = self.line_length_limit.as_ref();
+        validate::validate(
+            &self.state.nodes,
+            self.function_info(),
+            &self.engine_state.enums,
+            line_length.map(|limit| (limit, &self.engine_state.normalization)),
+        )
+    }
+
+    /// What validation needs to know about each registered function.
+    fn function_info(&self) -> HashMap<String, FunctionInfo> {
+        let functions = &self.engine_state.functions;
+        functions
+            .iter()
+            .map(|(name, f)| {
+                let info = FunctionInfo {
+                    arity: f.num_args.clone(),
+                    params: f.params.clone(),
+                    returns: f.returns,
+                };
+                (name.clone(), info)
+            })
+            .collect()
+    }
+
+    /// Split lines of dialogue longer than the given limit into pages, presented as
+    /// consecutive `Say` entries that each wait to be proceeded from, or `None` to
+    /// present lines whole, which is the default. Each page records its position
+    /// with `Say::page` and keeps the line's seen flag.
+    pub fn set_pagination(&mut self, pagination: Option<Pagination>) {
+        self.pagination = pagination;
+    }
+
+    /// Set the longest lines and options that `validate` accepts, or `None` to
+    /// accept lines of any length, which is the default.
+    pub fn set_line_length_limit(&mut self, limit: Option<LineLengthLimit>) {
+        self.line_length_limit = limit;
+    }
+
+    /// Register a house rule to be checked by `lint`.
+    pub fn add_lint_rule(&mut self, rule: Box<dyn LintRule>) {
+        self.lint_rules.push(SendWrapper::new(rule));
+    }
+
+    /// Check the loaded nodes with `validate` and with each rule registered with
+    /// `add_lint_rule`, ordered by node. A node can suppress rules by listing their
+    /// IDs in a header, such as `lint-disable: unknown-node, require-speaker`.
+    pub fn lint(&self) -> Vec<LintDiagnostic> {
+        let rules = self
+            .lint_rules
+            .iter()
+            .map(|rule| &***rule)
+            .collect::<Vec<_>>();
+        lint::lint(
+            &self.state.nodes,
+            self.validate(),
+            self.extract_lines(),
+            &rules,
+        )
+    }
+
+    /// Register a native function for use in Yarn expressions, failing if a function
+    /// with the same name, including a built-in function, is already registered.
+    pub fn register_function(
+        &mut self,
+        name: String,
+        num_args: usize,
+        callback: Box<FunctionCallback>,
+    ) -> Result<(), RegisterError> {
+        self.register_function_with_arity(name, num_args..=num_args, callback)
+    }
+
+    /// Register a native function for use in Yarn expressions, replacing any existing
+    /// function with the same name, including built-in functions.
+    pub fn register_function_overwriting(
         &mut self,
         name: String,
         num_args: usize,
         callback: Box<FunctionCallback>,
     ) {
+        self.insert_function(
+            name,
+            num_args..=num_args,
+            Box::new(move |args, state, _| callback(args, state)),
+        );
+    }
+
+    /// Whether a function with the given name is registered.
+    pub fn has_function(&self, name: &str) -> bool {
+        self.engine_state.functions.contains_key(name)
+    }
+
+    /// Register a native closure for use in Yarn expressions, converting its
+    /// arguments and return value automatically. The number of arguments is taken
+    /// from the closure, and calls with arguments of the wrong type fail to evaluate.
+    /// Fails if a function with the same name is already registered.
+    pub fn register_typed_fn<Args, F: RegisterFn<Args>>(
+        &mut self,
+        name: impl Into<String>,
+        f: F,
+    ) -> Result<(), RegisterError> {
+        let name = name.into();
+        self.register_function(name.clone(), F::num_args(), f.into_callback())?;
+        let _ = self.set_function_signature(&name, &F::param_types(), None);
+        Ok(())
+    }
+
+    /// Describe the parameter and return types of a registered function for
+    /// `validate`. `None` marks a parameter or return value that may have any
+    /// type. Fails with
+    /// `YarnError::UnknownFunction` if no function with the given name is registered.
+    pub fn set_function_signature(
+        &mut self,
+        name: &str,
+        params: &[Option<YarnType>],
+        returns: Option<YarnType>,
+    ) -> Result<(), YarnError> {
+        let functions = &mut self.engine_state.functions;
+        let function = match functions.get_mut(name) {
+            Some(function) => function,
+            None => {
+                let names = functions.keys().map(|name| name.as_str());
+                return Err(YarnError::UnknownFunction {
+                    name: name.to_string(),
+                    suggestions: suggest::similar_names(name, names),
+                });
+            }
+        };
+        function.params = params.to_vec();
+        function.returns = returns;
+        Ok(())
+    }
+
+    /// Register a native function for use in Yarn expressions that accepts a range
+    /// of argument counts, such as a function with optional trailing arguments.
+    /// Fails if a function with the same name is already registered.
+    pub fn register_function_with_arity(
+        &mut self,
+        name: String,
+        num_args: RangeInclusive<usize>,
+        callback: Box<FunctionCallback>,
+    ) -> Result<(), RegisterError> {
+        self.check_function_name(&name)?;
+        self.insert_function(
+            name,
+            num_args,
+            Box::new(move |args, state, _| callback(args, state)),
+        );
+        Ok(())
+    }
+
+    /// Register a native function for use in Yarn expressions that receives the
+    /// context passed to `next_with`. Fails if a function with the same name is
+    /// already registered.
+    pub fn register_function_with_context(
+        &mut self,
+        name: String,
+        num_args: usize,
+        callback: Box<ContextFunctionCallback<Ctx>>,
+    ) -> Result<(), RegisterError> {
+        self.check_function_name(&name)?;
+        self.insert_function(name, num_args..=num_args, callback);
+        Ok(())
+    }
+
+    /// Register a native function for use in Yarn expressions that can also be
+    /// called from several threads at once through an `EvalView`. Fails if a
+    /// function with the same name is already registered.
+    pub fn register_sync_function(
+        &mut self,
+        name: String,
+        num_args: usize,
+        callback: Box<SyncFunctionCallback>,
+    ) -> Result<(), RegisterError> {
+        self.register_sync_function_with_arity(name, num_args..=num_args, callback)
+    }
+
+    /// Like `register_sync_function`, accepting a range of argument counts.
+    pub fn register_sync_function_with_arity(
+        &mut self,
+        name: String,
+        num_args: RangeInclusive<usize>,
+        callback: Box<SyncFunctionCallback>,
+    ) -> Result<(), RegisterError> {
+        self.check_function_name(&name)?;
+        self.insert_sync_function(name, num_args, callback);
+        Ok(())
+    }
+
+    fn check_function_name(&self, name: &str) -> Result<(), RegisterError> {
+        if self.has_function(name) {
+            return Err(RegisterError::AlreadyExists(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn insert_sync_function(
+        &mut self,
+        name: String,
+        num_args: RangeInclusive<usize>,
+        callback: Box<SyncFunctionCallback>,
+    ) {
+        let callback: Arc<SyncFunctionCallback> = Arc::from(callback);
+        let shared = callback.clone();
+        self.insert_function(
+            name.clone(),
+            num_args.clone(),
+            Box::new(move |args, state, _| shared(args, state)),
+        );
+        Arc::make_mut(&mut self.engine_state.sync_functions)
+            .insert(name, SyncFunction { num_args, callback });
+    }
+
+    fn insert_function(
+        &mut self,
+        name: String,
+        num_args: RangeInclusive<usize>,
+        callback: Box<ContextFunctionCallback<Ctx>>,
+    ) {
+        if self.engine_state.sync_functions.contains_key(&name) {
+            Arc::make_mut(&mut self.engine_state.sync_functions).remove(&name);
+        }
         self.engine_state.functions.insert(
             name,
             Function {
                 num_args,
+                params: vec![],
+                returns: None,
+                callback: SendWrapper::new(callback),
+            },
+        );
+    }
+
+    /// Register a native handler for commands with the given name. The handler is
+    /// invoked each time a matching command is executed, before the corresponding
+    /// `YarnEntry::Command` is produced.
+    pub fn register_command(&mut self, name: String, mut callback: Box<CommandCallback>) {
+        self.insert_command(name, false, Box::new(move |args, _| callback(args)));
+    }
+
+    /// Register a native handler for commands with the given name that always pauses
+    /// execution, even when registered commands are dispatched inline.
+    pub fn register_blocking_command(&mut self, name: String, mut callback: Box<CommandCallback>) {
+        self.insert_command(name, true, Box::new(move |args, _| callback(args)));
+    }
+
+    /// Register a native handler for commands with the given name that receives the
+    /// context passed to `next_with`.
+    pub fn register_command_with_context(
+        &mut self,
+        name: String,
+        callback: Box<ContextCommandCallback<Ctx>>,
+    ) {
+        self.insert_command(name, false, callback);
+    }
+
+    /// Register a native handler for commands with the given name that always pauses
+    /// execution and receives the context passed to `next_with`.
+    pub fn register_blocking_command_with_context(
+        &mut self,
+        name: String,
+        callback: Box<ContextCommandCallback<Ctx>>,
+    ) {
+        self.insert_command(name, true, callback);
+    }
+
+    fn insert_command(
+        &mut self,
+        name: String,
+        blocking: bool,
+        callback: Box<ContextCommandCallback<Ctx>>,
+    ) {
+        self.engine_state.commands.insert(
+            name,
+            Command {
+                blocking,
                 callback: SendWrapper::new(callback),
             },
         );
     }
-    /// Set a given variable to the provided value. Any Yarn expressions evaluated
-    /// after this call will observe the new value when using the variable.
-    pub fn set_variable(&mut self, name: VariableName, value: Value) {
-        self.engine_state.variables.set(name, value);
+
+    /// Set whether registered commands are dispatched inline. When enabled, executing
+    /// a command with a registered, non-blocking handler invokes the handler and
+    /// continues without producing a `YarnEntry::Command`. Unregistered and blocking
+    /// commands are produced as usual. Disabled by default.
+    pub fn set_dispatch_commands(&mut self, dispatch: bool) {
+        self.engine_state.dispatch_commands = dispatch;
+    }
+
+    /// Set whether execution pauses after each `YarnEntry::Command` until `proceed`
+    /// is invoked, so that steps following a command do not run until the embedder
+    /// has finished carrying it out. While paused, `next` returns `None`. Disabled
+    /// by default.
+    pub fn set_commands_require_proceed(&mut self, require: bool) {
+        self.engine_state.commands_require_proceed = require;
+    }
+
+    /// Acknowledge the most recent entry, allowing execution to resume after a
+    /// command when commands require acknowledgement.
+    pub fn proceed(&mut self) {
+        if self.status == ConversationStatus::WaitingForCommand {
+            self.status = ConversationStatus::Running;
+        }
+    }
+
+    /// Set whether bare `$variable` references in command text are replaced with the
+    /// variable's value, in addition to `{expression}` interpolation. Disabled by
+    /// default.
+    pub fn set_substitute_bare_variables(&mut self, substitute: bool) {
+        self.engine_state.substitute_bare_variables = substitute;
+    }
+
+    /// Set whether markup such as `[b]bold[/b]` is parsed in dialogue and option
+    /// text. The markup is removed from the presented text, and the spans it marks
+    /// are available from `Say::spans`. Disabled by default.
+    pub fn set_markup(&mut self, enabled: bool) {
+        self.engine_state.markup = enabled;
+    }
+
+    /// Set whether dialogue and option text is pseudo-localized as it is presented:
+    /// letters are accented, and the text is padded by about 30% and wrapped in
+    /// brackets, so that text which bypassed the engine or cannot fit a longer
+    /// translation stands out. Disabled by default.
+    pub fn set_pseudo_localization(&mut self, enabled: bool) {
+        self.engine_state.pseudo_localization = enabled;
+    }
+
+    /// Set whether pseudo-localization also accents the values interpolated into
+    /// text. By default they are left as is. Padding and brackets always surround
+    /// the whole text.
+    pub fn set_pseudo_localize_values(&mut self, enabled: bool) {
+        self.engine_state.pseudo_localize_values = enabled;
+    }
+
+    /// Set the adjustments applied to dialogue and option text as it is presented,
+    /// and to the text returned by `extract_lines`.
+    pub fn set_text_normalization(&mut self, normalization: TextNormalization) {
+        self.engine_state.normalization = normalization;
+    }
+
+    /// Set a closure writing the values interpolated into lines and options, such as
+    /// to format numbers for the player's locale, including in translated lines. By
+    /// default values are written as by `Value::as_string`. Commands and
+    /// expressions, such as string concatenation, are unaffected.
+    pub fn set_display_formatter(&mut self, formatter: impl Fn(&Value) -> String + 'static) {
+        self.engine_state.display_formatter = Some(SendWrapper::new(Box::new(formatter)));
+        self.engine_state.rendered.borrow_mut().clear();
+    }
+
+    /// Set whether an undefined variable on the left of `??` yields the right side
+    /// rather than an error. Enabled by default.
+    pub fn set_coalesce_undefined_variables(&mut self, coalesce: bool) {
+        self.engine_state.coalesce_undefined_variables = coalesce;
+        self.engine_state.rendered.borrow_mut().clear();
+    }
+
+    /// Set whether `<<assert>>` steps are checked. A failed assertion stops execution
+    /// with `YarnError::AssertionFailed`. When disabled, assertions are skipped
+    /// without being evaluated. Disabled by default.
+    pub fn set_assertions_enabled(&mut self, enabled: bool) {
+        self.engine_state.assertions_enabled = enabled;
+    }
+
+    /// Set whether expressions are evaluated pedantically: each implicit conversion
+    /// between types in arithmetic or comparison, such as comparing a number with a
+    /// string, is reported to the closures registered with `on_coercion_warning`.
+    /// Results are unaffected. Disabled by default. Equivalent to setting the type
+    /// checking mode to `TypeChecking::Warn` or `TypeChecking::Coerce`.
+    pub fn set_pedantic(&mut self, pedantic: bool) {
+        self.set_type_checking(if pedantic {
+            TypeChecking::Warn
+        } else {
+            TypeChecking::Coerce
+        });
+    }
+
+    /// Set how arithmetic and comparisons between values of different types, and
+    /// assignments of a different type to a `<<declare>>`d variable, are treated.
+    /// Defaults to `TypeChecking::Coerce`.
+    pub fn set_type_checking(&mut self, mode: TypeChecking) {
+        self.engine_state.type_checking = mode;
+    }
+
+    /// Register a closure to be invoked for each implicit conversion found while
+    /// running a conversation with `TypeChecking::Warn`.
+    pub fn on_coercion_warning(&mut self, callback: impl FnMut(&CoercionWarning) + 'static) {
+        self.coercion_callbacks
+            .push(SendWrapper::new(Box::new(callback)));
+    }
+
+    /// Set how evaluation errors in assignments, conditions, option conditions,
+    /// interpolated text and commands are handled while running a conversation.
+    /// Defaults to `OnError::EndConversation`.
+    pub fn set_error_policy(&mut self, policy: OnError) {
+        self.engine_state.on_error = policy;
+    }
+
+    /// The report of the latest error that stopped a conversation, as returned by
+    /// `try_next_with`, including the node, trail and expression it occurred in.
+    pub fn last_error(&self) -> Option<&ErrorReport> {
+        self.last_error.as_ref()
+    }
+
+    /// Register a closure to be invoked for each evaluation error skipped with
+    /// `OnError::SkipStep` or `OnError::Emit`, with the node, trail and expression
+    /// it occurred in.
+    pub fn on_recovered_error(&mut self, callback: impl FnMut(&ErrorReport) + 'static) {
+        self.recovered_error_callbacks
+            .push(SendWrapper::new(Box::new(callback)));
+    }
+
+    /// Set whether `get_variable_as` coerces values of a different type using the
+    /// rules of `Value::as_num`, `Value::as_bool` and `Value::as_string`, rather
+    /// than reporting a mismatch. Disabled by default.
+    pub fn set_lenient_conversions(&mut self, lenient: bool) {
+        self.engine_state.lenient_conversions = lenient;
+    }
+
+    /// Set whether statistics about the work performed by the engine are collected.
+    /// Disabled by default.
+    pub fn enable_stats(&mut self, enabled: bool) {
+        self.engine_state.stats_enabled = enabled;
+    }
+
+    /// Statistics collected since the engine was created or `reset_stats` was called.
+    pub fn stats(&self) -> &Stats {
+        &self.engine_state.stats
+    }
+
+    /// Statistics collected since the current or most recent conversation began.
+    pub fn conversation_stats(&self) -> &Stats {
+        &self.engine_state.conversation_stats
+    }
+
+    /// Reset all collected statistics to zero.
+    pub fn reset_stats(&mut self) {
+        self.engine_state.stats = Stats::default();
+        self.engine_state.conversation_stats = Stats::default();
+    }
+
+    /// Set the maximum number of steps executed by a single call to `next` or
+    /// `run_node`, guarding against scripts that loop forever. Exceeding the budget
+    /// is an error. Defaults to 10,000.
+    pub fn set_step_budget(&mut self, budget: usize) {
+        self.engine_state.step_budget = budget;
+    }
+
+    /// Set the maximum number of jumps made in a row without producing an entry,
+    /// such as by nodes that jump to one another forever. Exceeding the limit is
+    /// `YarnError::RuntimeLimitExceeded`. Defaults to 1,000.
+    pub fn set_jump_limit(&mut self, limit: usize) {
+        self.engine_state.jump_limit = limit;
+    }
+
+    /// Set the maximum depth of options and conditionals nested within one another
+    /// that execution may enter. Exceeding the limit is
+    /// `YarnError::RuntimeLimitExceeded`. Defaults to 64.
+    pub fn set_nesting_limit(&mut self, limit: usize) {
+        self.engine_state.nesting_limit = limit;
+    }
+
+    /// Seed the random choices made by the engine, such as between equally fresh
+    /// lines of a line group, so that they repeat from run to run. Engines are
+    /// seeded differently by default.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.state.random.borrow_mut().set_seed(seed);
+    }
+
+    /// Set whether random choices come from a single stream or from a stream for
+    /// each visit to a node. Defaults to `RngMode::GlobalStream`.
+    pub fn set_rng_mode(&mut self, mode: RngMode) {
+        self.state.random.borrow_mut().set_mode(mode);
+    }
+
+    /// Set the time in seconds returned by the `time()` function and used by
+    /// `elapsed_since(t)`, typically each frame or before activating a node. The
+    /// engine never reads the wall clock, so the time only changes when set and
+    /// never advances during a single call to `next`. It starts at 0, and is saved
+    /// with checkpoints and story progress.
+    pub fn set_time(&mut self, seconds: f64) {
+        self.state.time = seconds;
+    }
+
+    /// The time last given to `set_time`.
+    pub fn time(&self) -> f64 {
+        self.state.time
+    }
+
+    /// Like `run_node`, passing the given context to callbacks.
+    pub fn run_node_with(
+        &mut self,
+        node: &NodeName,
+        policy: ChoicePolicy,
+        ctx: &mut Ctx,
+    ) -> Result<Vec<YarnEntry>, YarnError> {
+        if self.is_active() {
+            return Err(YarnError::ConversationActive);
+        }
+        if self.node(node).is_none() {
+            return Err(self.state.nodes.unknown(node.clone()));
+        }
+        self.activate(node.clone());
+
+        let mut budget = self.engine_state.step_budget;
+        let mut entries = vec![];
+        let result = loop {
+            let entry = match self.run_steps(&mut budget, ctx) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            if let YarnEntry::Choose { ref choices, .. } = entry {
+                let index = match policy {
+                    ChoicePolicy::FirstAvailable => 0,
+                    ChoicePolicy::Index(select) => select(choices),
+                    ChoicePolicy::Fail => break Err(YarnError::UnexpectedChoice),
+                };
+                if self.choose_with(index, ctx).is_err() {
+                    break Err(YarnError::InvalidChoice(index));
+                }
+            }
+            self.proceed();
+            entries.push(entry);
+        };
+
+        match result {
+            Ok(()) => Ok(entries),
+            Err(e) => {
+                self.clear_conversation();
+                Err(e)
+            }
+        }
+    }
+
+    /// Register a closure to be invoked whenever a node is marked as visited. A node
+    /// is visited when execution leaves it, whether by reaching its end, jumping to
+    /// another node, or choosing an option that leads to another node.
+    pub fn on_node_visited(&mut self, mut callback: impl FnMut(&NodeName, u32) + 'static) {
+        self.on_node_visited_with_context(move |name, count, _| callback(name, count));
+    }
+
+    /// Register a closure to be invoked whenever a node is marked as visited, which
+    /// receives the context passed to `next_with`.
+    pub fn on_node_visited_with_context(
+        &mut self,
+        callback: impl FnMut(&NodeName, u32, &mut Ctx) + 'static,
+    ) {
+        self.node_visited_callbacks
+            .push(SendWrapper::new(Box::new(callback)));
+    }
+
+    /// Register a closure to be invoked whenever an option is selected, before
+    /// execution resumes.
+    pub fn on_choice_made(&mut self, mut callback: impl FnMut(&ChoiceRecord) + 'static) {
+        self.on_choice_made_with_context(move |record, _| callback(record));
+    }
+
+    /// Register a closure to be invoked whenever an option is selected, which
+    /// receives the context passed to `choose_with`.
+    pub fn on_choice_made_with_context(
+        &mut self,
+        callback: impl FnMut(&ChoiceRecord, &mut Ctx) + 'static,
+    ) {
+        self.choice_made_callbacks
+            .push(SendWrapper::new(Box::new(callback)));
+    }
+
+    /// Register a closure to receive the message of each `<<log>>` step: the string
+    /// form of each argument, separated by spaces. Log steps are not evaluated
+    /// while no closure is registered, and never produce a `YarnEntry`.
+    pub fn on_log(&mut self, mut callback: impl FnMut(&str) + 'static) {
+        self.on_log_with_context(move |message, _| callback(message));
+    }
+
+    /// Register a closure to receive the message of each `<<log>>` step, which
+    /// receives the context passed to `next_with`.
+    pub fn on_log_with_context(&mut self, callback: impl FnMut(&str, &mut Ctx) + 'static) {
+        self.log_callbacks
+            .push(SendWrapper::new(Box::new(callback)));
+    }
+
+    /// The nodes entered during the current conversation, oldest first: the node
+    /// that was activated, followed by the target of each jump or option that led
+    /// to another node. Only the most recent 64 nodes are retained.
+    pub fn node_trail(&self) -> &[NodeName] {
+        self.state
+            .conversation
+            .as_ref()
+            .map_or(&[], |conversation| &conversation.trail)
+    }
+
+    /// The most recent selection made in the current conversation, if any.
+    pub fn last_choice(&self) -> Option<&ChoiceRecord> {
+        self.last_choice.as_ref()
+    }
+
+    /// Set how many checkpoints are kept. When a node completes, a checkpoint of the
+    /// variables, visit counts and line group history is taken, and the oldest is
+    /// discarded once there are more than the limit. 0, the default, disables
+    /// checkpoints and discards any already taken.
+    pub fn set_checkpoint_limit(&mut self, limit: usize) {
+        self.checkpoint_limit = limit;
+        let excess = self.checkpoints.len().saturating_sub(limit);
+        self.checkpoints.drain(..excess);
+    }
+
+    /// The checkpoints taken so far, oldest first.
+    pub fn checkpoints(&self) -> &[Checkpoint] {
+        &self.checkpoints
+    }
+
+    /// Return to the state captured by the checkpoint, which may have been taken by
+    /// another engine with the same nodes loaded. The conversation resumes exactly at
+    /// the boundary: at the start of the checkpoint's `next` node, as if it had just
+    /// been jumped to, or with no conversation active if the conversation had ended.
+    /// Checkpoints taken after this one are kept. Temporary variables are cleared,
+    /// since checkpoints do not capture them.
+    pub fn restore_checkpoint(&mut self, checkpoint: &Checkpoint) -> Result<(), YarnError> {
+        if let Some(ref next) = checkpoint.next {
+            if self.state.nodes.get(next).is_none() {
+                let suggestions = self.state.nodes.similar_titles(next);
+                return Err(YarnError::UnknownNode(next.clone(), suggestions));
+            }
+        }
+        self.engine_state.variables = checkpoint.variables.clone();
+        self.engine_state.rendered.borrow_mut().clear();
+        self.state.visits = checkpoint.visits.clone();
+        self.state.chosen = checkpoint.chosen.clone();
+        self.state.line_groups = checkpoint.line_groups.clone();
+        self.state.time = checkpoint.clock;
+        *self.state.random.borrow_mut() = checkpoint.random.clone();
+        match checkpoint.next {
+            Some(ref next) => self.activate(next.clone()),
+            None => self.clear_conversation(),
+        }
+        Ok(())
+    }
+
+    /// Set a given variable to the provided value. Any Yarn expressions evaluated
+    /// after this call will observe the new value when using the variable.
+    /// Local variables are set in the current node and are ignored if no
+    /// conversation is active.
+    pub fn set_variable(&mut self, name: VariableName, value: impl Into<Value>) {
+        let value = value.into();
+        if name.is_local() {
+            if let Some(conversation) = self.state.conversation.as_mut() {
+                conversation.locals.set(name, value);
+            }
+        } else {
+            self.engine_state.variables.set(name, value);
+        }
+    }
+
+    /// The current value of the given variable, if it has been set.
+    pub fn get_variable(&self, name: &VariableName) -> Option<&Value> {
+        self.state
+            .eval_context(&self.engine_state.variables)
+            .get_variable(name)
+    }
+
+    /// The current value of the given variable converted to a Rust type. Values of a
+    /// different type are an error unless lenient conversions are enabled.
+    pub fn get_variable_as<T: FromValue>(&self, name: &VariableName) -> Result<T, TypeError> {
+        let value = self
+            .get_variable(name)
+            .cloned()
+            .ok_or_else(|| TypeError::Undefined(name.clone()))?;
+        if self.engine_state.lenient_conversions {
+            T::from_value_lenient(value)
+        } else {
+            T::from_value(value)
+        }
+    }
+
+    /// The current values of all global variables. Local variables are never
+    /// included. Equivalent to `variables_all`.
+    pub fn variables(&self) -> &HashMap<VariableName, Value> {
+        &self.engine_state.variables.values
+    }
+
+    /// The current values of all global variables, including temporary ones.
+    pub fn variables_all(&self) -> &HashMap<VariableName, Value> {
+        &self.engine_state.variables.values
+    }
+
+    /// The global variables that should be saved: every variable except temporary
+    /// ones, whose names begin with the temporary prefix.
+    pub fn variables_persistent(&self) -> impl Iterator<Item = (&VariableName, &Value)> {
+        let state = &self.engine_state;
+        state
+            .variables
+            .values
+            .iter()
+            .filter(move |(name, _)| !state.is_temporary(name))
+    }
+
+    /// The flags set with `<<flag name>>`, in no particular order. Flags are stored as
+    /// variables named `flag:name`, so they are saved along with other variables.
+    pub fn flags(&self) -> impl Iterator<Item = &str> {
+        self.engine_state
+            .variables
+            .values
+            .iter()
+            .filter(|(_, value)| **value == Value::Boolean(true))
+            .filter_map(|(name, _)| flags::flag_name(name))
+    }
+
+    /// Set a filter consulted before entering each node and before presenting each
+    /// line and option, such as to withhold content in a streamer mode. Options
+    /// the filter drops are not selected automatically either.
+    pub fn set_content_filter(&mut self, filter: impl Fn(&ContentRef) -> FilterDecision + 'static) {
+        self.engine_state.content_filter = Some(SendWrapper::new(Box::new(filter)));
+    }
+
+    /// Enable or disable skim mode, for replaying content. While skimming, lines that
+    /// have been presented before are flagged with `Say::seen` so that they can be
+    /// shown at once; options and unseen lines are presented as usual.
+    pub fn set_skim(&mut self, enabled: bool) {
+        self.engine_state.skim = enabled;
+    }
+
+    /// Whether skim mode leaves out lines that have been presented before rather
+    /// than flagging them. Disabled by default.
+    pub fn set_skim_skips_seen(&mut self, skip: bool) {
+        self.engine_state.skim_skips_seen = skip;
+    }
+
+    /// The IDs of the lines that have been presented, in no particular order, for
+    /// saving along with the game.
+    pub fn seen_lines(&self) -> impl Iterator<Item = &str> {
+        self.engine_state.seen_lines.iter().map(|id| id.as_str())
+    }
+
+    /// Record the lines with the given IDs as seen, such as those saved from
+    /// `seen_lines`.
+    pub fn mark_lines_seen<I: IntoIterator<Item = S>, S: Into<String>>(&mut self, ids: I) {
+        self.engine_state
+            .seen_lines
+            .extend(ids.into_iter().map(Into::into));
+    }
+
+    /// Forget which lines have been seen.
+    pub fn reset_seen_lines(&mut self) {
+        self.engine_state.seen_lines.clear();
+    }
+
+    /// Set the prefix that marks a variable as temporary scratch for the current
+    /// session, such as `temp_` for `$temp_count`. Temporary variables are left out
+    /// of `variables_persistent`. Defaults to `temp_`; an empty prefix disables
+    /// temporary variables.
+    pub fn set_temporary_prefix(&mut self, prefix: impl Into<String>) {
+        let prefix = prefix.into();
+        self.engine_state.temporary_prefix = prefix.trim_start_matches('$').to_string();
+    }
+
+    /// Remove every temporary variable.
+    pub fn clear_temporary_variables(&mut self) {
+        let temporary = self
+            .engine_state
+            .variables
+            .values
+            .keys()
+            .filter(|name| self.engine_state.is_temporary(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in &temporary {
+            self.engine_state.variables.remove(name);
+        }
+    }
+
+    /// Like `evaluate_expression`, passing the given context to functions.
+    pub fn evaluate_expression_with(&self, expr: &str, ctx: &mut Ctx) -> Result<Value, YarnError> {
+        let state = self.state.eval_context(&self.engine_state.variables);
+        self.engine_state.evaluate_source(expr, &state, ctx)
+    }
+
+    /// A read-only view of the story state that can be shared between threads to
+    /// evaluate expressions and preconditions in parallel, calling only functions
+    /// registered with `register_sync_function` and the built-in functions.
+    pub fn read_view(&self) -> EvalView<'_> {
+        let conversation = self.state.conversation.as_ref();
+        EvalView {
+            node: conversation.map(|conversation| &conversation.node),
+            nodes: &self.state.nodes,
+            visits: &self.state.visits,
+            chosen: &self.state.chosen,
+            variables: &self.engine_state.variables,
+            locals: conversation.map(|conversation| &conversation.locals),
+            time: self.state.time,
+            random: self.state.random.borrow().clone(),
+            functions: &self.engine_state.sync_functions,
+            constants: &self.engine_state.constants,
+            enums: &self.engine_state.enums,
+            type_checking: self.engine_state.type_checking,
+            coalesce_undefined_variables: self.engine_state.coalesce_undefined_variables,
+        }
+    }
+
+    /// List the loaded nodes, or only those whose `tags` header includes the given
+    /// tag, in the order they were loaded, with the `precondition` header of each
+    /// evaluated as by `evaluate_expression_with`. Variables, visit counts and any
+    /// active conversation are unaffected. A precondition that fails to evaluate is
+    /// reported on its node, whatever the error policy, and the other nodes are
+    /// still listed.
+    pub fn available_nodes_with(
+        &self,
+        filter_tag: Option<&str>,
+        ctx: &mut Ctx,
+    ) -> Vec<AvailableNode> {
+        storylet::available_nodes(&self.state.nodes, &self.state.visits, filter_tag, |expr| {
+            self.evaluate_expression_with(expr, ctx)
+        })
+    }
+
+    /// The value of the node's `precondition` header evaluated as a condition, or
+    /// `true` if it has none.
+    fn precondition(&self, node: &Node, ctx: &mut Ctx) -> Result<bool, YarnError> {
+        match node.header("precondition") {
+            Some(precondition) => self
+                .evaluate_expression_with(precondition, ctx)
+                .map(|value| value.as_bool()),
+            None => Ok(true),
+        }
+    }
+
+    /// Check whether a conversation starting at the given node can run without
+    /// failing, reporting every problem found: the node must be loaded, the nodes
+    /// reachable from it by jumps and options must only call registered functions
+    /// with the right number of arguments and only lead to loaded nodes, and its
+    /// `precondition` header, if any, must be true. Conditions and dynamic behaviour
+    /// are not taken into account, and no state is changed.
+    pub fn can_activate_with(
+        &self,
+        name: &NodeName,
+        ctx: &mut Ctx,
+    ) -> Result<(), Vec<ActivationProblem>> {
+        let node = match self.state.nodes.get(name) {
+            Some(node) => node,
+            None => {
+                let suggestions = self.state.nodes.similar_titles(name);
+                return Err(vec![ActivationProblem::UnknownNode(
+                    name.clone(),
+                    suggestions,
+                )]);
+            }
+        };
+        let mut problems = validate::check_reachable(
+            &self.state.nodes,
+            self.function_info(),
+            &self.engine_state.enums,
+            name,
+        )
+        .into_iter()
+        .map(ActivationProblem::InReachableNode)
+        .collect::<Vec<_>>();
+        match self.precondition(node, ctx) {
+            Ok(true) => {}
+            Ok(false) => problems.push(ActivationProblem::PreconditionFailed(
+                node.header("precondition").unwrap_or_default().to_string(),
+            )),
+            Err(err) => problems.push(ActivationProblem::PreconditionError(err)),
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Register the string table of a locale, mapping line IDs as given by
+    /// `extract_lines` to translations, and replacing any earlier table for the
+    /// locale. Translations may contain interpolations and markup like the lines
+    /// they replace.
+    ///
+    /// Each line is presented from the table of the active locale if it has the
+    /// line, then from the table of the default locale, and otherwise as written in
+    /// the script.
+    pub fn add_string_table(&mut self, locale: &str, table: HashMap<String, String>) {
+        self.engine_state
+            .string_tables
+            .insert(locale.to_string(), table);
+        self.engine_state.rendered.borrow_mut().clear();
+    }
+
+    /// Set the locale whose string table is used when the active locale's table
+    /// lacks a line.
+    pub fn set_default_locale(&mut self, locale: &str) {
+        self.engine_state.default_locale = Some(locale.to_string());
+        self.engine_state.rendered.borrow_mut().clear();
+    }
+
+    /// Set the locale in which lines are presented, from the next entry produced.
+    /// The locale should have a string table registered with `add_string_table`.
+    pub fn set_active_locale(&mut self, locale: &str) {
+        self.engine_state.active_locale = Some(locale.to_string());
+        self.engine_state.rendered.borrow_mut().clear();
+    }
+
+    /// Register a closure to be invoked with the line ID and locale each time a
+    /// line is presented that the string table of the active or default locale
+    /// lacks.
+    pub fn on_missing_line(&mut self, callback: impl FnMut(&str, &str) + 'static) {
+        self.missing_line_callbacks
+            .push(SendWrapper::new(Box::new(callback)));
+    }
+
+    /// Begin evaluating the provided Yarn node, presenting its lines in the given
+    /// locale. The locale remains active afterwards, as with `set_active_locale`.
+    pub fn activate_with_locale(&mut self, node: NodeName, locale: &str) {
+        self.set_active_locale(locale);
+        self.activate(node);
+    }
+
+    /// Forget the line IDs indexed from the previously loaded nodes, so that each
+    /// node is indexed afresh when its lines are next presented. Interpolated text is
+    /// keyed by where it is too, so it is rendered afresh.
+    fn update_line_ids(&mut self) {
+        self.engine_state.rendered.borrow_mut().clear();
+        self.engine_state.line_ids.clear();
+        self.engine_state.indexed_nodes.clear();
     }
 
     /// Begin evaluating the provided Yarn node.
     pub fn activate(&mut self, node: NodeName) {
         self.state.conversation = Some(Conversation::new(node));
-        self.conversion_ended = false;
+        self.interrupted.clear();
+        self.last_choice = None;
+        self.engine_state.conversation_stats = Stats::default();
+        self.status = ConversationStatus::Running;
     }
 
-    /// Make a choice between a series of options for the current Yarn node's active step.
-    /// Execution will resume immediately based on the choice provided.
-    pub fn choose(&mut self, choice: usize) -> Result<(), ()> {
-        let step = self.state.get_current_step();
+    /// Begin evaluating the start node, named by `set_start_node_name`. Fails if no
+    /// node with that name is loaded.
+    pub fn activate_start(&mut self) -> Result<(), YarnError> {
+        if self.state.nodes.get(&self.start_node).is_none() {
+            return Err(self.state.nodes.unknown(self.start_node.clone()));
+        }
+        self.activate(self.start_node.clone());
+        Ok(())
+    }
+
+    /// Set the name of the node activated by `activate_start`. Defaults to `Start`.
+    pub fn set_start_node_name(&mut self, name: NodeName) {
+        self.start_node = name;
+    }
+
+    /// The name of the node activated by `activate_start`.
+    pub fn start_node_name(&self) -> &NodeName {
+        &self.start_node
+    }
+
+    /// The loaded nodes where conversations can begin, in the order they were
+    /// loaded: by default those tagged `entry`, or those accepted by the predicate
+    /// set with `set_entry_point_predicate`.
+    pub fn entry_points(&self) -> Vec<&NodeName> {
+        let is_entry = |node: &Node| match self.entry_point_predicate {
+            Some(ref predicate) => predicate(node),
+            None => node.has_tag("entry"),
+        };
+        self.state
+            .nodes
+            .iter()
+            .filter(|node| is_entry(node))
+            .map(|node| &node.title)
+            .collect()
+    }
+
+    /// Set a closure deciding which nodes `entry_points` lists, in place of the
+    /// `entry` tag.
+    pub fn set_entry_point_predicate(&mut self, predicate: impl Fn(&Node) -> bool + 'static) {
+        self.entry_point_predicate = Some(SendWrapper::new(Box::new(predicate)));
+    }
+
+    /// Abandon the active conversation, if any. The engine becomes idle.
+    ///
+    /// If the current node has an `on_abort` header, the node it names is activated
+    /// instead to clean up, such as by resetting the camera. It runs as a conversation
+    /// of its own, ending with `EndConversation`, and fails with `UnexpectedChoice`
+    /// if it presents options. Stopping the cleanup abandons it. Conversations that
+    /// end by themselves are not cleaned up.
+    pub fn stop_conversation(&mut self) {
+        let cleanup = match self.state.conversation {
+            Some(ref conversation) if self.is_active() && !conversation.aborting => self
+                .state
+                .nodes
+                .get(&conversation.node)
+                .and_then(|node| node.header("on_abort"))
+                .map(|name| NodeName::from(name.trim())),
+            Some(_) | None => None,
+        };
+        self.clear_conversation();
+        if let Some(cleanup) = cleanup {
+            self.activate(cleanup);
+            self.state.conversation.as_mut().unwrap().aborting = true;
+        }
+    }
+
+    /// Abandon the active conversation, if any, without cleaning up.
+    fn clear_conversation(&mut self) {
+        self.state.conversation = None;
+        self.interrupted.clear();
+        self.status = ConversationStatus::Idle;
+    }
+
+    /// Interrupt the active conversation with a line of dialogue, such as a message
+    /// from the game, which is produced before the conversation's next entry. If
+    /// options are being presented, they are presented again after the line.
+    pub fn interject_line(&mut self, text: impl Into<String>) -> Result<(), YarnError> {
+        if !self.is_active() {
+            return Err(YarnError::NoConversation);
+        }
+        let (text, spans) = self.engine_state.markup(text.into());
+        let conversation = self.state.conversation.as_mut().unwrap();
+        conversation.pending.push_back(YarnEntry::Say(Say {
+            text,
+            spans,
+            seen: false,
+            page: None,
+        }));
+        Ok(())
+    }
+
+    /// Interrupt the active conversation with the given node. The node runs until it
+    /// ends, including any nodes it jumps to, and the conversation then resumes
+    /// where it was paused; options that were being presented are presented again.
+    /// A value given by `<<return>>` in the interjection is discarded.
+    pub fn interject_node(&mut self, node: NodeName) -> Result<(), YarnError> {
+        if !self.is_active() {
+            return Err(YarnError::NoConversation);
+        }
+        if self.state.nodes.get(&node).is_none() {
+            let suggestions = self.state.nodes.similar_titles(&node);
+            return Err(YarnError::UnknownNode(node, suggestions));
+        }
+        let paused = self.state.conversation.replace(Conversation::new(node));
+        self.interrupted.push((paused.unwrap(), self.status));
+        self.status = ConversationStatus::Running;
+        Ok(())
+    }
+
+    /// Resume the conversation paused by the innermost interjection, if any. Options
+    /// that were being presented are presented again by executing their step anew.
+    fn resume_interrupted(&mut self) -> bool {
+        let (mut conversation, status) = match self.interrupted.pop() {
+            Some(interrupted) => interrupted,
+            None => return false,
+        };
+        conversation.presented = None;
+        self.state.conversation = Some(conversation);
+        self.status = match status {
+            ConversationStatus::WaitingForCommand => ConversationStatus::WaitingForCommand,
+            ConversationStatus::Idle
+            | ConversationStatus::Running
+            | ConversationStatus::WaitingForChoice
+            | ConversationStatus::WaitingForProceed
+            | ConversationStatus::Ended => ConversationStatus::Running,
+        };
+        true
+    }
+
+    /// End the conversation at the content filter's request, without marking the
+    /// current node as visited. An interrupted conversation resumes instead.
+    fn end_filtered(
+        &mut self,
+        budget: &mut usize,
+        ctx: &mut Ctx,
+    ) -> Result<Option<YarnEntry>, YarnError> {
+        if self.resume_interrupted() {
+            return self.execute(budget, ctx);
+        }
+        self.status = ConversationStatus::Ended;
+        Ok(Some(YarnEntry::EndConversation(None)))
+    }
+
+    /// Mark the node of the active conversation as visited and notify any observers,
+    /// then continue with the given node, if any, and take a checkpoint.
+    fn complete_node(&mut self, next: Option<NodeName>, ctx: &mut Ctx) {
+        let name = match self.state.conversation {
+            Some(ref conversation) => conversation.node.clone(),
+            None => return,
+        };
+        let count = self.state.visit(&name);
+        for callback in &mut self.node_visited_callbacks {
+            callback(&name, count, ctx);
+        }
+        self.locate_recovered();
+        if let Some(ref next) = next {
+            self.state.jump(next.clone());
+        }
+        if self.checkpoint_limit == 0 {
+            return;
+        }
+        if self.checkpoints.len() == self.checkpoint_limit {
+            self.checkpoints.remove(0);
+        }
+        self.checkpoint_sequence += 1;
+        let mut variables = self.engine_state.variables.clone();
+        variables
+            .values
+            .retain(|name, _| !self.engine_state.is_temporary(name));
+        self.checkpoints.push(Checkpoint {
+            sequence: self.checkpoint_sequence,
+            time: SystemTime::now(),
+            node: name,
+            next,
+            variables,
+            visits: self.state.visits.clone(),
+            chosen: self.state.chosen.clone(),
+            line_groups: self.state.line_groups.clone(),
+            clock: self.state.time,
+            random: self.state.random.borrow().clone(),
+        });
+    }
+
+    /// The current phase of the conversation.
+    pub fn status(&self) -> ConversationStatus {
+        self.status
+    }
+
+    /// Whether a conversation is in progress, ie. it has been activated and has not
+    /// yet ended or been stopped.
+    pub fn is_active(&self) -> bool {
+        match self.status {
+            ConversationStatus::Running
+            | ConversationStatus::WaitingForChoice
+            | ConversationStatus::WaitingForProceed
+            | ConversationStatus::WaitingForCommand => true,
+            ConversationStatus::Idle | ConversationStatus::Ended => false,
+        }
+    }
+
+    /// Whether the most recent conversation ran to completion.
+    pub fn has_ended(&self) -> bool {
+        self.status == ConversationStatus::Ended
+    }
+
+    /// Like `choose_default`, passing the given context to callbacks.
+    pub fn choose_default_with(&mut self, ctx: &mut Ctx) -> Result<(), YarnError> {
+        if self.has_ended() {
+            return Err(YarnError::NoConversation);
+        }
+        let conversation = self
+            .state
+            .conversation
+            .as_ref()
+            .ok_or(YarnError::NoConversation)?;
+        let choice = conversation
+            .presented
+            .as_ref()
+            .ok_or(YarnError::NoChoices)?
+            .default_choice;
+        self.choose_with(choice, ctx)
+    }
+
+    /// Like `choose`, passing the given context to callbacks.
+    pub fn choose_with(&mut self, choice: usize, ctx: &mut Ctx) -> Result<(), YarnError> {
+        if self.has_ended() {
+            return Err(YarnError::NoConversation);
+        }
+        let conversation = self
+            .state
+            .conversation
+            .as_mut()
+            .ok_or(YarnError::NoConversation)?;
+        let presented = conversation
+            .presented
+            .as_ref()
+            .ok_or(YarnError::NoChoices)?;
+        if choice >= presented.indexes.len() {
+            return Err(YarnError::InvalidChoice(choice));
+        }
+        let mut presented = conversation.presented.take().unwrap();
+        let record = ChoiceRecord {
+            node: conversation.node.clone(),
+            prompt: presented.prompt,
+            text: presented.texts.swap_remove(choice),
+            index: choice,
+        };
+        let choice = presented.indexes[choice];
+        for callback in &mut self.choice_made_callbacks {
+            callback(&record, ctx);
+        }
+        self.last_choice = Some(record);
+
+        // Selections are counted by the option's position among the node's options.
+        if let Some(Step::Dialogue(_, choices, _)) = self.state.get_current_step()? {
+            let node = &self.state.conversation.as_ref().unwrap().node;
+            let steps = self
+                .state
+                .nodes
+                .get(node)
+                .ok_or_else(|| self.state.nodes.unknown(node.clone()))?
+                .steps()?;
+            let position = chosen::positions(steps, &choices[choice..=choice])[0];
+            *self
+                .state
+                .chosen
+                .entry((node.clone(), position))
+                .or_insert(0) += 1;
+        }
+
+        let step = self.state.get_current_step()?;
         match step {
-            Some(Step::Dialogue(_, ref choices)) => match choices[choice].kind {
+            Some(Step::Dialogue(_, ref choices, _)) => match choices[choice].kind {
                 ChoiceKind::External(ref node) => {
                     let node = node.clone();
-                    self.state.set_conversation(Some(node));
+                    self.complete_node(Some(node), ctx);
+                    self.status = ConversationStatus::Running;
                     Ok(())
                 }
                 ChoiceKind::Inline(..) => {
                     self.state.push_step(StepIndex::Dialogue(choice, 0));
+                    self.status = ConversationStatus::Running;
                     Ok(())
                 }
             },
@@ -502,99 +4142,917 @@ impl YarnEngine {
             Some(Step::Command(..))
             | Some(Step::Assign(..))
             | Some(Step::Conditional(..))
-            | Some(Step::Jump(..)) => unreachable!(),
+            | Some(Step::Jump(..))
+            | Some(Step::Assert(..))
+            | Some(Step::Log(..))
+            | Some(Step::Declare(..))
+            | Some(Step::Const(..))
+            | Some(Step::Enum(..))
+            | Some(Step::LineGroup(..))
+            | Some(Step::Return(..))
+            | Some(Step::Unknown(..)) => unreachable!(),
         }
     }
 }
 
+/// How `YarnEngine::run_node` selects an option when presented with choices.
+#[derive(Copy, Clone, Debug)]
+pub enum ChoicePolicy {
+    /// Select the first option.
+    FirstAvailable,
+    /// Select the option at the index returned by the function, which receives the
+    /// text of each option.
+    Index(fn(&[String]) -> usize),
+    /// Stop with `YarnError::UnexpectedChoice`.
+    Fail,
+}
+
 /// A handler for Yarn actions that require integration with the embedder.
-/// Invoked synchronously during Yarn execution when matching steps are
-/// evaluated.
-// pub trait YarnHandler {
-//     type Data;
+/// Invoked synchronously by `YarnEngine::run_with_handler` as each entry is
+/// produced.
+pub trait YarnHandler {
+    /// Present a line of dialogue without any choices.
+    fn say(&mut self, line: Say);
+
+    /// Present a line of dialogue with subsequent choices, returning the index of
+    /// the selected option.
+    fn choose(&mut self, text: String, choices: Vec<String>) -> usize;
+
+    /// Perform some kind of action.
+    fn command(&mut self, action: String);
+
+    /// The conversation has ended.
+    fn end_conversation(&mut self);
+
+    /// An evaluation error was skipped with `OnError::Emit`. Ignored by default.
+    fn error(&mut self, _error: ErrorReport) {}
+}
+
+/// A line of dialogue presented without choices.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Say {
+    text: String,
+    spans: Vec<MarkupSpan>,
+    seen: bool,
+    page: Option<(usize, usize)>,
+}
+
+impl Say {
+    /// The text of the line with any markup removed, suitable for screen readers.
+    pub fn plain_text(&self) -> &str {
+        &self.text
+    }
+
+    /// The markup in the line, with offsets in chars into `plain_text`. Empty unless
+    /// markup is enabled with `YarnEngine::set_markup`.
+    pub fn spans(&self) -> &[MarkupSpan] {
+        &self.spans
+    }
+
+    /// Whether the line had been presented before, so that it can be shown at once.
+    /// Always false unless skim mode is enabled with `YarnEngine::set_skim`.
+    pub fn seen(&self) -> bool {
+        self.seen
+    }
+
+    /// The index of this page among the pages of a line split by pagination, and the
+    /// number of pages, or `None` if the line was not split. See
+    /// `YarnEngine::set_pagination`.
+    pub fn page(&self) -> Option<(usize, usize)> {
+        self.page
+    }
+
+    /// The text of the line with any markup removed.
+    pub fn into_plain_text(self) -> String {
+        self.text
+    }
+}
+
+impl From<String> for Say {
+    fn from(text: String) -> Say {
+        Say {
+            text,
+            spans: vec![],
+            seen: false,
+            page: None,
+        }
+    }
+}
+
+impl From<&str> for Say {
+    fn from(text: &str) -> Say {
+        Say::from(text.to_string())
+    }
+}
 
-//     fn say(&mut self, text: String, data: Option<&mut Self::Data>);
+impl fmt::Display for Say {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
 
-//     fn choose(&mut self, text: String, choices: Vec<String>, data: Option<&mut Self::Data>);
+/// The outcome of `YarnEngine::step`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum StepResult {
+    /// A step was executed without producing an entry.
+    Executed {
+        /// The node containing the step.
+        node: NodeName,
+        /// What the step was.
+        kind: StepKind,
+    },
+    /// The step produced an entry, as `next` would have.
+    Entry(YarnEntry),
+    /// No step was executed, as no conversation is running: none is active, or it
+    /// is waiting for a choice or a command to finish, or it has ended.
+    Paused,
+}
 
-//     fn command(&mut self, action: String, data: Option<&mut Self::Data>) -> Result<(), ()>;
+impl From<Option<YarnEntry>> for StepResult {
+    fn from(entry: Option<YarnEntry>) -> StepResult {
+        entry.map_or(StepResult::Paused, StepResult::Entry)
+    }
+}
 
-//     fn end_conversation(&mut self, data: Option<&mut Self::Data>);
-// }
+/// A step executed by `YarnEngine::step` that produced no entry.
+#[derive(Clone, PartialEq, Debug)]
+pub enum StepKind {
+    /// A line of dialogue that failed to evaluate and was skipped.
+    Dialogue,
+    /// A group of alternative lines of which none was available, or whose line
+    /// failed to evaluate.
+    LineGroup,
+    /// A command dispatched to its handler, or that failed to evaluate.
+    Command,
+    /// A `<<set>>`.
+    Assign {
+        /// The variable assigned.
+        variable: VariableName,
+        /// The variable's value afterwards, or `None` if it has none, such as when
+        /// the assignment failed and the error was recovered from.
+        value: Option<Value>,
+    },
+    /// An `<<if>>`, which entered the block whose condition holds.
+    Conditional,
+    /// A `<<jump>>` to the given node.
+    Jump(NodeName),
+    /// An `<<assert>>` that held or was not checked.
+    Assert,
+    /// A `<<log>>`.
+    Log,
+    /// A `<<declare>>`, `<<const>>` or `<<enum>>`, which take effect on load.
+    Declaration,
+    /// A statement kept by `ParseOptions::forward_compatible`.
+    Unknown,
+}
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub enum YarnEntry {
     /// Present a line of dialogue without any choices. Execution will not
     /// resume until `YarnEngine::proceed` is invoked.
-    Say(String),
-    /// Present a line of dialogue with subsequent choices. Execution will not
-    /// resume until `YarnEngine::choose` is invoked.
-    Choose { text: String, choices: Vec<String> },
+    Say(Say),
+    /// Present a line of dialogue with subsequent choices. Only options whose
+    /// conditions pass are included. Execution will not resume until
+    /// `YarnEngine::choose` is invoked.
+    ///
+    /// In a node with an `autochoice: weighted` header, the engine instead selects
+    /// an available option at random, weighted by its `#weight:n` tag (1 if absent),
+    /// and presents the line as `Say`. The selection is recorded as `last_choice`.
+    Choose {
+        text: String,
+        choices: Vec<String>,
+        /// The number of seconds the player has to choose, given by a
+        /// `#timeout:seconds` tag on the line. The engine does not enforce it.
+        timeout: Option<f32>,
+        /// The option marked with a `#default` tag, which `YarnEngine::choose_default`
+        /// selects. If that option is unavailable, the first option is the default.
+        default_choice: Option<usize>,
+        /// The number of times each option has been selected before, in the same
+        /// order as `choices`.
+        times_chosen: Vec<u32>,
+        /// How the options were selected, if they follow `<<options match>>`.
+        matched: Option<OptionMatch>,
+    },
     /// Instruct the embedder to perform some kind of action. The given action
-    /// string is passed unmodified from the node source.
+    /// string is passed from the node source after interpolation.
     Command { action: String },
+    /// An evaluation error skipped with `OnError::Emit`. Execution continues on the
+    /// next call to `next`.
+    Error(ErrorReport),
     /// End the current conversation. Execution will not resume until a new
-    /// node is made active with `YarnEngine::activate`.
-    EndConversation,
+    /// node is made active with `YarnEngine::activate`. Contains the value of the
+    /// `<<return value>>` that ended the conversation, if any.
+    EndConversation(Option<Value>),
 }
 
-impl<'a> Iterator for YarnEngine {
+impl Iterator for YarnEngine {
     type Item = YarnEntry;
     fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(&mut ())
+    }
+}
+
+impl<Ctx: 'static> YarnEngine<Ctx> {
+    /// Produce the next entry of the active conversation, passing the given context
+    /// to any callbacks invoked along the way. Equivalent to `Iterator::next` for
+    /// engines without a context.
+    ///
+    /// Each conversation produces exactly one `YarnEntry::EndConversation`, after
+    /// which this returns `None` until another conversation is activated; choosing or
+    /// proceeding cannot revive it. This also returns `None` while waiting for
+    /// `proceed` after a command, so the engine is not a fused iterator.
+    pub fn next_with(&mut self, ctx: &mut Ctx) -> Option<YarnEntry> {
+        self.try_next_with(ctx).unwrap()
+    }
+
+    /// Like `next_with`, returning an error instead of panicking if the
+    /// conversation cannot continue. The conversation remains active, so
+    /// `node_trail` reports the node where the error occurred.
+    pub fn try_next_with(&mut self, ctx: &mut Ctx) -> Result<Option<YarnEntry>, YarnError> {
+        let mut budget = self.engine_state.step_budget;
+        self.run_steps(&mut budget, ctx)
+    }
+
+    /// Execute a single step of the active conversation, for stepping through it in
+    /// a debugger. Steps that produce no entry, such as assignments, conditionals
+    /// and jumps, are reported individually; `next_with` runs them all until an
+    /// entry is produced.
+    pub fn step_with(&mut self, ctx: &mut Ctx) -> Result<StepResult, YarnError> {
+        let waiting = self
+            .state
+            .conversation
+            .as_ref()
+            .is_some_and(|c| !c.pending.is_empty());
+        if self.state.conversation.is_none() || waiting {
+            return self.try_next_with(ctx).map(StepResult::from);
+        }
+        // The end of a nested block is not a step of its own.
+        while let Ok(None) = self.state.get_current_step() {
+            if !self.state.pop_step() {
+                break;
+            }
+        }
+        let node = self.state.conversation.as_ref().unwrap().node.clone();
+        let kind = match self.state.get_current_step() {
+            Ok(Some(Step::Dialogue(..))) => StepKind::Dialogue,
+            Ok(Some(Step::LineGroup(..))) => StepKind::LineGroup,
+            Ok(Some(Step::Command(..))) => StepKind::Command,
+            Ok(Some(Step::Assign(name, _))) => StepKind::Assign {
+                variable: name.clone(),
+                value: None,
+            },
+            Ok(Some(Step::Conditional(..))) => StepKind::Conditional,
+            Ok(Some(Step::Jump(target))) => StepKind::Jump(target.clone()),
+            Ok(Some(Step::Assert(..))) => StepKind::Assert,
+            Ok(Some(Step::Log(..))) => StepKind::Log,
+            Ok(Some(Step::Declare(..))) | Ok(Some(Step::Const(..))) | Ok(Some(Step::Enum(..))) => {
+                StepKind::Declaration
+            }
+            Ok(Some(Step::Unknown(..))) => StepKind::Unknown,
+            // Returning always produces an entry.
+            Ok(Some(Step::Return(..))) | Ok(None) | Err(_) => {
+                return self.try_next_with(ctx).map(StepResult::from)
+            }
+        };
+        let mut budget = 1;
+        match self.run_steps(&mut budget, ctx) {
+            Err(YarnError::StepBudgetExceeded) => {
+                let kind = match kind {
+                    StepKind::Assign { variable, .. } => StepKind::Assign {
+                        value: self.get_variable(&variable).cloned(),
+                        variable,
+                    },
+                    kind => kind,
+                };
+                Ok(StepResult::Executed { node, kind })
+            }
+            result => result.map(StepResult::from),
+        }
+    }
+
+    /// Execute steps until the next entry is produced, decrementing the budget for
+    /// each step executed, then report any implicit conversions found.
+    fn run_steps(
+        &mut self,
+        budget: &mut usize,
+        ctx: &mut Ctx,
+    ) -> Result<Option<YarnEntry>, YarnError> {
+        let conversation = self.state.conversation.as_mut();
+        if let Some(entry) = conversation.and_then(|c| c.pending.pop_front()) {
+            return Ok(Some(entry));
+        }
+        let result = self
+            .execute(budget, ctx)
+            .map_err(|err| self.describe_error(err))
+            .map(|entry| entry.map(|entry| self.paginate(entry)));
+        if let (Ok(Some(_)), Some(conversation)) = (&result, self.state.conversation.as_mut()) {
+            conversation.jumps = 0;
+        }
+        self.locate_recovered();
+        if let Err(ref err) = result {
+            let mut report = err.to_report();
+            let expression = self.engine_state.failed_expression.take();
+            report.expression = report.expression.or(expression);
+            self.locate(&mut report);
+            self.last_error = Some(report);
+        }
+        self.engine_state.failure.take();
+        self.engine_state.failed_expression.take();
+        let coercions = self.engine_state.coercions.take();
+        for warning in &coercions {
+            for callback in &mut self.coercion_callbacks {
+                callback(warning);
+            }
+        }
+        for (id, locale) in self.engine_state.missing_lines.take() {
+            for callback in &mut self.missing_line_callbacks {
+                callback(&id, &locale);
+            }
+        }
+        let recovered = self.engine_state.recovered.take();
+        for error in &recovered {
+            for callback in &mut self.recovered_error_callbacks {
+                callback(error);
+            }
+        }
+        if self.engine_state.on_error != OnError::Emit || recovered.is_empty() {
+            return result;
+        }
+        match (result, self.state.conversation.as_mut()) {
+            (Ok(entry), Some(conversation)) => {
+                let mut entries = recovered.into_iter().map(YarnEntry::Error);
+                let first = entries.next();
+                let pages = std::mem::take(&mut conversation.pending);
+                conversation
+                    .pending
+                    .extend(entries.chain(entry).chain(pages));
+                Ok(first)
+            }
+            // The errors are still emitted if execution resumes.
+            (Err(YarnError::StepBudgetExceeded), Some(conversation)) => {
+                let entries = recovered.into_iter().map(YarnEntry::Error);
+                conversation.pending.extend(entries);
+                Err(YarnError::StepBudgetExceeded)
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Split a line of dialogue into pages if pagination is enabled, returning the
+    /// first page and queueing the others to be presented next.
+    fn paginate(&mut self, entry: YarnEntry) -> YarnEntry {
+        let (say, pagination) = match (entry, &self.pagination) {
+            (YarnEntry::Say(say), Some(pagination)) => (say, pagination),
+            (entry, _) => return entry,
+        };
+        let pages = paginate::paginate(&say.text, &say.spans, pagination);
+        let total = pages.len();
+        if total == 1 {
+            return YarnEntry::Say(say);
+        }
+        let mut pages = pages.into_iter().enumerate().map(|(index, (text, spans))| {
+            YarnEntry::Say(Say {
+                text,
+                spans,
+                seen: say.seen,
+                page: Some((index, total)),
+            })
+        });
+        let first = pages.next().unwrap();
+        let conversation = self.state.conversation.as_mut().unwrap();
+        for page in pages.rev() {
+            conversation.pending.push_front(page);
+        }
+        first
+    }
+
+    /// Give the errors recovered from in the current node their node, line and trail.
+    fn locate_recovered(&self) {
+        for report in self.engine_state.recovered.borrow_mut().iter_mut() {
+            self.locate(report);
+        }
+    }
+
+    /// Give the errors recovered from in the step being executed their line, before
+    /// the next step begins.
+    fn locate_recovered_lines(&self) {
+        let mut recovered = self.engine_state.recovered.borrow_mut();
+        for report in recovered
+            .iter_mut()
+            .filter(|report| report.trail.is_empty())
+        {
+            report.line = report.line.or_else(|| self.state.step_line());
+        }
+    }
+
+    /// Give a report of an error in the current node its node, line and trail,
+    /// unless the error carries its own.
+    fn locate(&self, report: &mut ErrorReport) {
+        if let (true, Some(conversation)) = (report.trail.is_empty(), &self.state.conversation) {
+            report.node.get_or_insert_with(|| conversation.node.clone());
+            report.line = report.line.or_else(|| self.state.step_line());
+            report.trail = conversation.trail.clone();
+        }
+    }
+
+    /// Describe the given runtime limit being exceeded in the current node.
+    fn limit_exceeded(&self, limit: RuntimeLimit) -> YarnError {
+        let conversation = self.state.conversation.as_ref().unwrap();
+        let max = match limit {
+            RuntimeLimit::Jumps => self.engine_state.jump_limit,
+            RuntimeLimit::Nesting => self.engine_state.nesting_limit,
+        };
+        YarnError::RuntimeLimitExceeded {
+            limit,
+            max,
+            node: conversation.node.clone(),
+            trail: conversation.trail.clone(),
+        }
+    }
+
+    /// Replace an evaluation error with a description of its cause, if known.
+    fn describe_error(&self, err: YarnError) -> YarnError {
+        match (err, self.engine_state.failure.take()) {
+            (YarnError::Evaluation, Some(cause)) => cause,
+            (err, _) => err,
+        }
+    }
+
+    fn execute(
+        &mut self,
+        budget: &mut usize,
+        ctx: &mut Ctx,
+    ) -> Result<Option<YarnEntry>, YarnError> {
+        let paused = matches!(
+            self.status,
+            ConversationStatus::Ended | ConversationStatus::WaitingForCommand
+        );
+        if self.state.conversation.is_none() || paused {
+            return Ok(None);
+        }
         loop {
-            if self.state.conversation.is_none() {
-                return None;
+            if !self.engine_state.recovered.borrow().is_empty() {
+                self.locate_recovered_lines();
+            }
+            self.state.conversation.as_mut().unwrap().start_step();
+            if *budget == 0 {
+                return Err(YarnError::StepBudgetExceeded);
+            }
+            *budget -= 1;
+
+            let conversation = self.state.conversation.as_ref().unwrap();
+            if conversation.indexes.len() > self.engine_state.nesting_limit {
+                return Err(self.limit_exceeded(RuntimeLimit::Nesting));
             }
-            if self.conversion_ended {
-                return None;
+            if !conversation.entered {
+                let node = self.state.nodes.get(&conversation.node);
+                let decision = node.map_or(FilterDecision::Allow, |node| {
+                    self.engine_state.filter(ContentRef::Node(node))
+                });
+                let fallback = node.and_then(|node| node.header("fallback"));
+                let fallback = fallback.map(|name| NodeName::from(name.trim()));
+                self.state.conversation.as_mut().unwrap().entered = true;
+                match (decision, fallback) {
+                    (FilterDecision::Allow, _) => {}
+                    (FilterDecision::EndConversation, _) | (_, None) => {
+                        return self.end_filtered(budget, ctx);
+                    }
+                    (_, Some(fallback)) => {
+                        let conversation = self.state.conversation.as_ref().unwrap();
+                        if conversation.jumps >= self.engine_state.jump_limit {
+                            return Err(self.limit_exceeded(RuntimeLimit::Jumps));
+                        }
+                        self.state.jump(fallback);
+                        continue;
+                    }
+                }
             }
-            let step = self.state.get_current_step();
+
+            let step = self.state.get_current_step()?;
             if step.is_none() {
-                self.conversion_ended = true;
-                return Some(YarnEntry::EndConversation);
+                if self.state.pop_step() {
+                    continue;
+                }
+                self.complete_node(None, ctx);
+                if self.resume_interrupted() {
+                    return self.execute(budget, ctx);
+                }
+                self.status = ConversationStatus::Ended;
+                return Ok(Some(YarnEntry::EndConversation(None)));
             }
+            self.engine_state.count(|stats| &stats.steps);
 
             match step.unwrap() {
-                Step::Dialogue(text, choices) => {
-                    if choices.is_empty() {
-                        let text = text.clone();
-                        self.state.advance();
-                        return Some(YarnEntry::Say(text));
+                Step::Dialogue(text, choices, tags) => {
+                    let path = self.state.conversation.as_ref().unwrap().line_path();
+                    let node = &self.state.conversation.as_ref().unwrap().node;
+                    self.engine_state.index_lines(self.state.nodes.get(node));
+                    let state = self.state.eval_context(&self.engine_state.variables);
+                    let decision = self
+                        .engine_state
+                        .filter(ContentRef::Line { node, text, tags });
+                    let text = match decision {
+                        FilterDecision::Allow => {
+                            match self.engine_state.present(text, &path, &state, ctx) {
+                                Ok(text) => text,
+                                Err(()) => {
+                                    self.engine_state.recover()?;
+                                    self.state.advance();
+                                    continue;
+                                }
+                            }
+                        }
+                        FilterDecision::Redact(redaction) => redaction,
+                        FilterDecision::Drop | FilterDecision::Fallback if choices.is_empty() => {
+                            self.state.advance();
+                            continue;
+                        }
+                        FilterDecision::Drop | FilterDecision::Fallback => String::new(),
+                        FilterDecision::EndConversation => return self.end_filtered(budget, ctx),
+                    };
+
+                    // Options whose condition or text fails to evaluate are unavailable,
+                    // as are options whose case didn't match.
+                    let (selector, cases) = if choices.iter().any(|c| c.case.is_some()) {
+                        let (value, cases) = self.engine_state.match_cases(choices, &state, ctx)?;
+                        (Some(value), cases)
                     } else {
-                        return Some(YarnEntry::Choose {
-                            text: text.clone(),
-                            choices: choices.iter().map(|c| c.text.clone()).collect(),
-                        });
+                        (None, vec![])
+                    };
+                    let mut available = vec![];
+                    let mut options = vec![];
+                    let mut unmatched = vec![];
+                    for (index, choice) in choices.iter().enumerate() {
+                        let passes = match cases.get(index) {
+                            Some(&Some(matched)) => matched,
+                            _ => self.engine_state.option_condition(choice, &state, ctx)?,
+                        };
+                        if !passes && cases.get(index) != Some(&Some(false)) {
+                            continue;
+                        }
+                        let option = ContentRef::Option {
+                            node,
+                            text: &choice.text,
+                            tags: &choice.tags,
+                        };
+                        let option = match self.engine_state.filter(option) {
+                            FilterDecision::Allow => {
+                                let path = path.part(index);
+                                self.engine_state.present(&choice.text, &path, &state, ctx)
+                            }
+                            FilterDecision::Redact(redaction) => Ok(redaction),
+                            FilterDecision::Drop | FilterDecision::Fallback => continue,
+                            FilterDecision::EndConversation => {
+                                return self.end_filtered(budget, ctx)
+                            }
+                        };
+                        match option {
+                            Ok(option) if passes => {
+                                available.push(index);
+                                options.push(self.engine_state.markup(option).0);
+                            }
+                            Ok(option) => unmatched.push(self.engine_state.markup(option).0),
+                            Err(()) => self.engine_state.recover()?,
+                        }
+                    }
+
+                    // A line whose options are all unavailable is presented alone.
+                    if available.is_empty() {
+                        self.state.advance();
+                        match self.engine_state.say(&path, text) {
+                            Some(entry) => {
+                                self.status = ConversationStatus::WaitingForProceed;
+                                return Ok(Some(entry));
+                            }
+                            None => continue,
+                        }
+                    }
+
+                    // Nodes with an `autochoice: weighted` header select one of their
+                    // own options, weighted by each option's `#weight:` tag, and the
+                    // line is presented alone.
+                    let node = &self.state.conversation.as_ref().unwrap().node;
+                    let node = self.state.nodes.get(node);
+                    if node.and_then(|node| node.header("autochoice")) == Some("weighted") {
+                        let weights = available
+                            .iter()
+                            .map(|&index| {
+                                let tags = &choices[index].tags;
+                                let weight = tags.iter().find_map(|t| t.strip_prefix("weight:"));
+                                weight.and_then(|w| w.parse().ok()).unwrap_or(1.)
+                            })
+                            .collect::<Vec<f32>>();
+                        let state = self.state.eval_context(&self.engine_state.variables);
+                        let selection = state.rng().weighted(&weights);
+                        let entry = self.engine_state.say(&path, text.clone());
+                        self.state.conversation.as_mut().unwrap().presented =
+                            Some(PresentedChoices {
+                                prompt: self.engine_state.markup(text).0,
+                                indexes: available,
+                                texts: options,
+                                default_choice: 0,
+                            });
+                        self.choose_with(selection, ctx)
+                            .expect("the selection was just presented");
+                        match entry {
+                            Some(entry) => {
+                                self.status = ConversationStatus::WaitingForProceed;
+                                return Ok(Some(entry));
+                            }
+                            None => continue,
+                        }
+                    }
+
+                    // Choices have no way to carry spans, so only the plain text of
+                    // the prompt and options is presented.
+                    let text = self.engine_state.markup(text).0;
+                    // An unavailable default falls back to the first available option.
+                    let default_choice = choices
+                        .iter()
+                        .position(|c| c.tags.iter().any(|tag| tag == "default"))
+                        .map(|tagged| available.iter().position(|&i| i == tagged).unwrap_or(0));
+                    let timeout = tags
+                        .iter()
+                        .find_map(|tag| tag.strip_prefix("timeout:"))
+                        .and_then(|t| t.parse().ok());
+
+                    let node = &self.state.conversation.as_ref().unwrap().node;
+                    let positions = match self.state.nodes.get(node).map(|n| n.steps()) {
+                        Some(Ok(steps)) => chosen::positions(steps, choices),
+                        _ => vec![],
+                    };
+                    let times_chosen = available
+                        .iter()
+                        .map(|&index| {
+                            let position = positions.get(index).copied();
+                            position.map_or(0, |p| state.times_chosen(node, p))
+                        })
+                        .collect();
+                    let matched = selector.map(|value| {
+                        let choice = available.iter().position(|&index| cases[index].is_some());
+                        OptionMatch {
+                            value,
+                            choice,
+                            case: choice.and_then(|choice| {
+                                let case = choices[available[choice]].case.as_ref();
+                                case.map(|case| case.to_string())
+                            }),
+                            unmatched,
+                        }
+                    });
+
+                    if self.state.conversation.as_ref().unwrap().aborting {
+                        return Err(YarnError::UnexpectedChoice);
+                    }
+                    self.state.conversation.as_mut().unwrap().presented = Some(PresentedChoices {
+                        prompt: text.clone(),
+                        indexes: available,
+                        texts: options.clone(),
+                        default_choice: default_choice.unwrap_or(0),
+                    });
+                    // Options are presented even when skimming, and their prompt is
+                    // only recorded as seen.
+                    self.engine_state.see(&path);
+                    self.status = ConversationStatus::WaitingForChoice;
+                    return Ok(Some(YarnEntry::Choose {
+                        text,
+                        choices: options,
+                        timeout,
+                        default_choice,
+                        times_chosen,
+                        matched,
+                    }));
+                }
+                Step::LineGroup(group) => {
+                    let path = self.state.conversation.as_ref().unwrap().line_path();
+                    let node = &self.state.conversation.as_ref().unwrap().node;
+                    self.engine_state.index_lines(self.state.nodes.get(node));
+                    let state = self.state.eval_context(&self.engine_state.variables);
+                    let mut candidates = vec![];
+                    let mut redactions = HashMap::new();
+                    for (index, line) in group.lines.iter().enumerate() {
+                        if let Some(ref condition) = line.condition {
+                            let value = self.engine_state.evaluate(condition, &state, ctx);
+                            if !self.engine_state.condition(value)? {
+                                continue;
+                            }
+                        }
+                        let content = ContentRef::Line {
+                            node,
+                            text: &line.text,
+                            tags: &line.tags,
+                        };
+                        match self.engine_state.filter(content) {
+                            FilterDecision::Allow => {}
+                            FilterDecision::Redact(redaction) => {
+                                redactions.insert(index, redaction);
+                            }
+                            FilterDecision::Drop | FilterDecision::Fallback => continue,
+                            FilterDecision::EndConversation => {
+                                return self.end_filtered(budget, ctx)
+                            }
+                        }
+                        candidates.push(index);
+                    }
+                    let node = node.clone();
+                    let key = (node, group.line);
+                    let shown = self.state.line_groups.get(&key);
+                    let mut rng = self.state.eval_context(&self.engine_state.variables).rng();
+                    let index = select_line(shown, &candidates, &mut rng);
+                    drop(rng);
+                    // A group whose conditions are all false is skipped.
+                    let index = match index {
+                        Some(index) => index,
+                        None => {
+                            self.state.advance();
+                            continue;
+                        }
+                    };
+                    let path = path.part(index);
+                    let text = match redactions.remove(&index) {
+                        Some(redaction) => redaction,
+                        None => {
+                            match self.engine_state.present(
+                                &group.lines[index].text,
+                                &path,
+                                &state,
+                                ctx,
+                            ) {
+                                Ok(text) => text,
+                                Err(()) => {
+                                    self.engine_state.recover()?;
+                                    self.state.advance();
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    let len = group.lines.len();
+                    self.state.show_line(key, index, len);
+                    self.state.advance();
+                    match self.engine_state.say(&path, text) {
+                        Some(entry) => {
+                            self.status = ConversationStatus::WaitingForProceed;
+                            return Ok(Some(entry));
+                        }
+                        None => continue,
                     }
                 }
                 Step::Command(command) => {
-                    let command = command.clone();
+                    let interpolation = Interpolation::Command {
+                        bare_variables: self.engine_state.substitute_bare_variables,
+                    };
+                    let command = self.engine_state.interpolate(
+                        command,
+                        interpolation,
+                        &self.state.eval_context(&self.engine_state.variables),
+                        ctx,
+                    );
                     self.state.advance();
-                    return Some(YarnEntry::Command {
-                        action: command,
-                    });
+                    let command = match command {
+                        Ok(command) => command,
+                        Err(()) => {
+                            self.engine_state.recover()?;
+                            continue;
+                        }
+                    };
+                    let mut args = command.split_whitespace().map(|arg| arg.to_string());
+                    if let Some(name) = args.next() {
+                        if let Some(handler) = self.engine_state.commands.get_mut(&name) {
+                            (handler.callback)(args.collect(), ctx);
+                            if self.engine_state.dispatch_commands && !handler.blocking {
+                                continue;
+                            }
+                        }
+                    }
+                    self.status = if self.engine_state.commands_require_proceed {
+                        ConversationStatus::WaitingForCommand
+                    } else {
+                        ConversationStatus::WaitingForProceed
+                    };
+                    return Ok(Some(YarnEntry::Command { action: command }));
                 }
                 Step::Assign(name, expr) => {
-                    let value = self.engine_state.evaluate(expr, &self.state.nodes).unwrap();
-                    self.engine_state.variables.set((*name).clone(), value);
+                    let state = self.state.eval_context(&self.engine_state.variables);
+                    let value = self
+                        .engine_state
+                        .evaluate_assigned(name, expr, &state, ctx)
+                        .and_then(|value| match self.engine_state.declarations.get(name) {
+                            Some(&declared) if YarnType::of(&value) != declared => {
+                                let found = YarnType::of(&value);
+                                self.engine_state
+                                    .check_types(
+                                        "=",
+                                        vec![declared.name(), found.name()],
+                                        format!("${} = {}", name.0, expr),
+                                        &state,
+                                    )
+                                    .map(|()| value)
+                            }
+                            _ => Ok(value),
+                        });
+                    let name = (*name).clone();
+                    self.state.advance();
+                    match value {
+                        Ok(value) => self.set_variable(name, value),
+                        Err(()) => self.engine_state.recover()?,
+                    }
+                }
+                Step::Assert(assertion) => {
+                    if self.engine_state.assertions_enabled {
+                        let value = self.engine_state.evaluate(
+                            &assertion.condition,
+                            &self.state.eval_context(&self.engine_state.variables),
+                            ctx,
+                        );
+                        let passed = match value {
+                            Ok(value) => value.as_bool(),
+                            Err(()) => {
+                                self.engine_state.recover()?;
+                                true
+                            }
+                        };
+                        if !passed {
+                            let node = self.state.conversation.as_ref().unwrap().node.clone();
+                            return Err(YarnError::AssertionFailed {
+                                node,
+                                line: assertion.line,
+                                condition: assertion.text.clone(),
+                                message: assertion.message.clone(),
+                            });
+                        }
+                    }
+                    self.state.advance();
+                }
+                Step::Declare(..) | Step::Const(..) | Step::Enum(..) | Step::Unknown(..) => {
+                    self.state.advance()
+                }
+                Step::Log(args) => {
+                    if !self.log_callbacks.is_empty() {
+                        let state = self.state.eval_context(&self.engine_state.variables);
+                        let message = args
+                            .iter()
+                            .map(|arg| {
+                                let value = self.engine_state.evaluate(arg, &state, ctx)?;
+                                Ok(value.as_string())
+                            })
+                            .collect::<Result<Vec<_>, ()>>()
+                            .map(|args| args.join(" "));
+                        match message {
+                            Ok(message) => {
+                                for callback in &mut self.log_callbacks {
+                                    callback(&message, ctx);
+                                }
+                            }
+                            Err(()) => self.engine_state.recover()?,
+                        }
+                    }
                     self.state.advance();
                 }
                 Step::Jump(name) => {
                     let name = name.clone();
-                    self.state.set_conversation(Some(name));
+                    let conversation = self.state.conversation.as_ref().unwrap();
+                    if conversation.jumps >= self.engine_state.jump_limit {
+                        return Err(self.limit_exceeded(RuntimeLimit::Jumps));
+                    }
+                    self.complete_node(Some(name), ctx);
+                }
+                Step::Return(value) => {
+                    let state = self.state.eval_context(&self.engine_state.variables);
+                    let value = match value {
+                        Some(expr) => match self.engine_state.evaluate(expr, &state, ctx) {
+                            Ok(value) => Some(value),
+                            Err(()) => {
+                                self.engine_state.recover()?;
+                                None
+                            }
+                        },
+                        None => None,
+                    };
+                    self.complete_node(None, ctx);
+                    if self.resume_interrupted() {
+                        return self.execute(budget, ctx);
+                    }
+                    self.status = ConversationStatus::Ended;
+                    return Ok(Some(YarnEntry::EndConversation(value)));
                 }
                 Step::Conditional(expr, _if_steps, else_ifs, _else_steps) => {
-                    let value = self.engine_state.evaluate(expr, &self.state.nodes).unwrap();
-                    if value.as_bool() {
+                    let value = self.engine_state.evaluate(
+                        expr,
+                        &self.state.eval_context(&self.engine_state.variables),
+                        ctx,
+                    );
+                    if self.engine_state.condition(value)? {
                         self.state.push_step(StepIndex::If(0));
                     } else {
                         let mut matched = false;
                         for (else_if_index, else_ifs) in else_ifs.iter().enumerate() {
-                            let value = self
-                                .engine_state
-                                .evaluate(&else_ifs.0, &self.state.nodes)
-                                .unwrap();
-                            if value.as_bool() {
+                            let value = self.engine_state.evaluate(
+                                &else_ifs.0,
+                                &self.state.eval_context(&self.engine_state.variables),
+                                ctx,
+                            );
+                            if self.engine_state.condition(value)? {
                                 self.state.push_step(StepIndex::ElseIf(else_if_index, 0));
                                 matched = true;
                                 break;
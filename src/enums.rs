@@ -0,0 +1,82 @@
+use crate::engine::{ChoiceKind, Step, Value};
+use crate::error::YarnError;
+use std::collections::HashMap;
+
+/// The cases of each enum declared with `<<enum>>`, by name.
+pub(crate) type Enums = HashMap<String, Vec<String>>;
+
+/// Collect the `<<enum>>` steps in the given steps: each enum and its cases.
+pub(crate) fn collect_enums(steps: &[Step], enums: &mut Vec<(String, Vec<String>)>) {
+    for step in steps {
+        match step {
+            Step::Enum(name, cases) => enums.push((name.clone(), cases.clone())),
+            Step::Dialogue(_, choices, _) => {
+                for choice in choices {
+                    if let ChoiceKind::Inline(ref steps) = choice.kind {
+                        collect_enums(steps, enums);
+                    }
+                }
+            }
+            Step::Conditional(_, if_steps, else_ifs, else_steps) => {
+                collect_enums(if_steps, enums);
+                for (_, steps) in else_ifs {
+                    collect_enums(steps, enums);
+                }
+                collect_enums(else_steps, enums);
+            }
+            Step::Command(..)
+            | Step::Assign(..)
+            | Step::Jump(..)
+            | Step::Assert(..)
+            | Step::Log(..)
+            | Step::Declare(..)
+            | Step::Const(..)
+            | Step::LineGroup(..)
+            | Step::Return(..)
+            | Step::Unknown(..) => {}
+        }
+    }
+}
+
+/// The value of an enum case. Without the name of the enum, the case belongs to
+/// `hint` if that enum has such a case, and otherwise to the only enum that does.
+pub(crate) fn resolve_case(
+    enums: &Enums,
+    type_name: Option<&str>,
+    hint: Option<&str>,
+    case: &str,
+) -> Option<Value> {
+    let has_case = |name: &str| {
+        enums
+            .get(name)
+            .is_some_and(|cases| cases.iter().any(|c| c == case))
+    };
+    let type_name = match (type_name, hint) {
+        (Some(type_name), _) => Some(type_name).filter(|name| has_case(name))?,
+        (None, Some(hint)) if has_case(hint) => hint,
+        (None, _) => {
+            let mut matching = enums.keys().filter(|name| has_case(name));
+            match (matching.next(), matching.next()) {
+                (Some(name), None) => name,
+                _ => return None,
+            }
+        }
+    };
+    Some(Value::Enum {
+        type_name: type_name.to_string(),
+        case: case.to_string(),
+    })
+}
+
+/// Resolve an enum case given as a literal, whose enum is empty if it was written
+/// in shorthand. Other values are returned as they are.
+pub(crate) fn resolve_literal(enums: &Enums, value: Value) -> Result<Value, YarnError> {
+    match value {
+        Value::Enum { type_name, case } => {
+            let explicit = Some(type_name.as_str()).filter(|name| !name.is_empty());
+            resolve_case(enums, explicit, None, &case)
+                .ok_or_else(|| YarnError::UnknownEnumCase(format!("{}.{}", type_name, case)))
+        }
+        value => Ok(value),
+    }
+}
@@ -0,0 +1,429 @@
+use crate::convert;
+use crate::engine::{CoercionWarning, NodeName, RuntimeLimit, Value, VariableName};
+use crate::parse::ParseLimit;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// An error encountered while parsing or running Yarn content.
+#[derive(Clone, Debug, PartialEq)]
+pub enum YarnError {
+    /// The source could not be parsed.
+    Parse {
+        /// The line where parsing failed, if the problem is with a particular line.
+        line: Option<usize>,
+        /// A description of the problem.
+        message: String,
+    },
+    /// A file could not be read. Contains a description of the problem.
+    Io(String),
+    /// The source exceeded one of the limits in `ParseOptions`.
+    LimitExceeded {
+        /// The limit that was exceeded.
+        limit: ParseLimit,
+        /// The line of the source where the limit was exceeded.
+        line: usize,
+    },
+    /// An expression could not be evaluated, such as when a function reported an
+    /// error or was passed the wrong number of arguments.
+    Evaluation,
+    /// The named node is not loaded. Also contains the titles of similarly named
+    /// nodes, if any.
+    UnknownNode(NodeName, Vec<String>),
+    /// An expression called a function that is not registered.
+    UnknownFunction {
+        /// The name of the function.
+        name: String,
+        /// Similarly named registered functions.
+        suggestions: Vec<String>,
+    },
+    /// An expression called a function with a number of arguments it does not
+    /// accept.
+    WrongArgumentCount {
+        /// The name of the function.
+        function: String,
+        /// The number of arguments passed.
+        args: usize,
+        /// The numbers of arguments the function accepts.
+        expected: RangeInclusive<usize>,
+    },
+    /// A function returned an error.
+    FunctionFailed {
+        /// The name of the function.
+        function: String,
+        /// The message the function returned.
+        message: String,
+    },
+    /// An expression read a variable that has not been set.
+    UndefinedVariable {
+        /// The name of the variable.
+        name: VariableName,
+        /// The names of similarly named variables that have been set.
+        suggestions: Vec<String>,
+    },
+    /// The operation requires that no conversation is active.
+    ConversationActive,
+    /// The operation requires an active conversation.
+    NoConversation,
+    /// Choices were presented when none were expected.
+    UnexpectedChoice,
+    /// An option was selected while no options were presented.
+    NoChoices,
+    /// The selected option does not exist.
+    InvalidChoice(usize),
+    /// More steps were executed than the engine's step budget allows.
+    StepBudgetExceeded,
+    /// Values of different types were combined, or a declared variable was assigned
+    /// a value of a different type, with `TypeChecking::Strict`.
+    TypeMismatch(CoercionWarning),
+    /// An expression referred to a constant that has not been defined.
+    UndefinedConstant(String),
+    /// A constant was defined more than once.
+    DuplicateConstant(String),
+    /// A `<<set>>` assigned to the name of a constant.
+    AssignToConstant(String),
+    /// An enum was declared more than once.
+    DuplicateEnum(String),
+    /// An enum case, as written, is not a case of the enum, or its enum could not be
+    /// inferred.
+    UnknownEnumCase(String),
+    /// A node was defined in more than one source of a batch loaded with
+    /// `YarnEngine::load_sources`.
+    DuplicateNode {
+        /// The title of the node.
+        name: NodeName,
+        /// The source that defined the node first.
+        first: String,
+        /// The source that defined the node again.
+        second: String,
+    },
+    /// An `<<assert>>` condition was false while assertions were enabled.
+    AssertionFailed {
+        /// The node containing the assertion.
+        node: NodeName,
+        /// The line of the source containing the assertion.
+        line: usize,
+        /// The source of the condition.
+        condition: String,
+        /// The assertion's message, if any.
+        message: Option<String>,
+    },
+    /// Execution exceeded one of the engine's runtime limits.
+    RuntimeLimitExceeded {
+        /// The limit that was exceeded.
+        limit: RuntimeLimit,
+        /// The value of the limit.
+        max: usize,
+        /// The node being executed.
+        node: NodeName,
+        /// The nodes entered during the conversation, oldest first.
+        trail: Vec<NodeName>,
+    },
+}
+
+impl fmt::Display for YarnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            YarnError::Parse {
+                line: Some(line),
+                message,
+            } => write!(f, "parse error at line {}: {}", line, message),
+            YarnError::Parse {
+                line: None,
+                message,
+            } => write!(f, "parse error: {}", message),
+            YarnError::Io(msg) => write!(f, "i/o error: {}", msg),
+            YarnError::LimitExceeded { limit, line } => {
+                write!(f, "{} exceeded at line {}", limit, line)
+            }
+            YarnError::Evaluation => write!(f, "expression could not be evaluated"),
+            YarnError::UnknownNode(name, suggestions) => {
+                write!(f, "unknown node `{}`", name)?;
+                did_you_mean(f, "", suggestions)
+            }
+            YarnError::UnknownFunction { name, suggestions } => {
+                write!(f, "unknown function `{}`", name)?;
+                did_you_mean(f, "", suggestions)
+            }
+            YarnError::WrongArgumentCount {
+                function,
+                args,
+                expected,
+            } => write!(
+                f,
+                "`{}` called with {} arguments but accepts {} to {}",
+                function,
+                args,
+                expected.start(),
+                expected.end()
+            ),
+            YarnError::FunctionFailed { function, message } => {
+                write!(f, "`{}` failed: {}", function, message)
+            }
+            YarnError::UndefinedVariable { name, suggestions } => {
+                write!(f, "undefined variable `${}`", name.0)?;
+                did_you_mean(f, "$", suggestions)
+            }
+            YarnError::ConversationActive => write!(f, "a conversation is already active"),
+            YarnError::NoConversation => write!(f, "no conversation is active"),
+            YarnError::UnexpectedChoice => write!(f, "unexpected choice"),
+            YarnError::NoChoices => write!(f, "no options are presented"),
+            YarnError::InvalidChoice(index) => write!(f, "no option at index {}", index),
+            YarnError::StepBudgetExceeded => write!(f, "step budget exceeded"),
+            YarnError::TypeMismatch(mismatch) => {
+                write!(
+                    f,
+                    "type mismatch: `{}` applied to {} in `{}`",
+                    mismatch.operator,
+                    mismatch.operands.join(" and "),
+                    mismatch.expression
+                )
+            }
+            YarnError::UndefinedConstant(name) => write!(f, "undefined constant `{}`", name),
+            YarnError::DuplicateConstant(name) => {
+                write!(f, "constant `{}` is already defined", name)
+            }
+            YarnError::AssignToConstant(name) => write!(f, "cannot assign to constant `{}`", name),
+            YarnError::DuplicateEnum(name) => write!(f, "enum `{}` is already declared", name),
+            YarnError::UnknownEnumCase(case) => write!(f, "unknown enum case `{}`", case),
+            YarnError::DuplicateNode {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "node `{}` in `{}` is already defined in `{}`",
+                name, second, first
+            ),
+            YarnError::AssertionFailed {
+                node,
+                line,
+                condition,
+                message,
+            } => {
+                write!(
+                    f,
+                    "assertion `{}` failed in node `{}` at line {}",
+                    condition, node, line
+                )?;
+                match message {
+                    Some(message) => write!(f, ": {}", message),
+                    None => Ok(()),
+                }
+            }
+            YarnError::RuntimeLimitExceeded {
+                limit,
+                max,
+                node,
+                trail,
+            } => {
+                write!(f, "{} of {} exceeded in node `{}`", limit, max, node)?;
+                let trail = trail.iter().map(|n| n.as_str()).collect::<Vec<_>>();
+                write!(f, " (trail: {})", trail.join(" -> "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for YarnError {}
+
+impl YarnError {
+    /// A stable identifier for the kind of error, such as `undefined-variable`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            YarnError::Parse { .. } => "parse",
+            YarnError::Io(_) => "io",
+            YarnError::LimitExceeded { .. } => "limit-exceeded",
+            YarnError::Evaluation => "evaluation",
+            YarnError::UnknownNode(..) => "unknown-node",
+            YarnError::UnknownFunction { .. } => "unknown-function",
+            YarnError::WrongArgumentCount { .. } => "wrong-argument-count",
+            YarnError::FunctionFailed { .. } => "function-failed",
+            YarnError::UndefinedVariable { .. } => "undefined-variable",
+            YarnError::ConversationActive => "conversation-active",
+            YarnError::NoConversation => "no-conversation",
+            YarnError::UnexpectedChoice => "unexpected-choice",
+            YarnError::NoChoices => "no-choices",
+            YarnError::InvalidChoice(_) => "invalid-choice",
+            YarnError::StepBudgetExceeded => "step-budget-exceeded",
+            YarnError::TypeMismatch(_) => "type-mismatch",
+            YarnError::UndefinedConstant(_) => "undefined-constant",
+            YarnError::DuplicateConstant(_) => "duplicate-constant",
+            YarnError::AssignToConstant(_) => "assign-to-constant",
+            YarnError::DuplicateEnum(_) => "duplicate-enum",
+            YarnError::UnknownEnumCase(_) => "unknown-enum-case",
+            YarnError::DuplicateNode { .. } => "duplicate-node",
+            YarnError::AssertionFailed { .. } => "assertion-failed",
+            YarnError::RuntimeLimitExceeded { .. } => "runtime-limit-exceeded",
+        }
+    }
+
+    /// The error as a report, with the context the error itself carries. Reports
+    /// handed over by the engine also give the context it occurred in.
+    pub fn to_report(&self) -> ErrorReport {
+        let mut report = ErrorReport {
+            error: self.clone(),
+            kind: self.kind(),
+            message: self.to_string(),
+            node: None,
+            line: None,
+            trail: vec![],
+            expression: None,
+            name: None,
+            engine_version: env!("CARGO_PKG_VERSION"),
+        };
+        match self {
+            YarnError::LimitExceeded { line, .. } => report.line = Some(*line),
+            YarnError::Parse { line, .. } => report.line = *line,
+            YarnError::UnknownNode(name, _) | YarnError::DuplicateNode { name, .. } => {
+                report.name = Some(name.to_string());
+            }
+            YarnError::UnknownFunction { name, .. }
+            | YarnError::WrongArgumentCount { function: name, .. }
+            | YarnError::FunctionFailed { function: name, .. }
+            | YarnError::UndefinedConstant(name)
+            | YarnError::DuplicateConstant(name)
+            | YarnError::AssignToConstant(name)
+            | YarnError::DuplicateEnum(name)
+            | YarnError::UnknownEnumCase(name) => report.name = Some(name.clone()),
+            YarnError::UndefinedVariable { name, .. } => report.name = Some(name.0.clone()),
+            YarnError::TypeMismatch(mismatch) => {
+                report.node = mismatch.node.clone();
+                report.expression = Some(mismatch.expression.clone());
+            }
+            YarnError::AssertionFailed {
+                node,
+                line,
+                condition,
+                ..
+            } => {
+                report.node = Some(node.clone());
+                report.line = Some(*line);
+                report.expression = Some(condition.clone());
+            }
+            YarnError::RuntimeLimitExceeded { node, trail, .. } => {
+                report.node = Some(node.clone());
+                report.trail = trail.clone();
+            }
+            YarnError::Io(_)
+            | YarnError::Evaluation
+            | YarnError::ConversationActive
+            | YarnError::NoConversation
+            | YarnError::UnexpectedChoice
+            | YarnError::NoChoices
+            | YarnError::InvalidChoice(_)
+            | YarnError::StepBudgetExceeded => {}
+        }
+        report
+    }
+}
+
+/// An error together with the context it occurred in, as fields for logging and
+/// telemetry. Given by `YarnError::to_report`, and by the engine for the errors it
+/// recovers from or that stop a conversation. With the `serde` feature, reports
+/// serialize for telemetry without the `error` itself, which `kind` and `message`
+/// describe.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ErrorReport {
+    /// The error itself.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub error: YarnError,
+    /// The kind of error, as given by `YarnError::kind`.
+    pub kind: &'static str,
+    /// The error's description, as given by its `Display` implementation.
+    pub message: String,
+    /// The node being executed, if known.
+    pub node: Option<NodeName>,
+    /// The line of the source, if known: where a parse error was found, or the line
+    /// of the step being executed when an error occurred in a conversation.
+    pub line: Option<usize>,
+    /// The nodes entered during the conversation, oldest first, ending with `node`.
+    pub trail: Vec<NodeName>,
+    /// The expression that failed to evaluate, as Yarn source, if any.
+    pub expression: Option<String>,
+    /// The name of the variable, function, constant, enum case or node involved,
+    /// if any. Variable names are given without their `$`.
+    pub name: Option<String>,
+    /// The version of this crate.
+    pub engine_version: &'static str,
+}
+
+impl fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)?;
+        match self.node {
+            Some(ref node) => write!(f, " in node `{}`", node),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Append a hint listing the suggested names, each with the given prefix.
+pub(crate) fn did_you_mean(
+    f: &mut fmt::Formatter<'_>,
+    prefix: &str,
+    suggestions: &[String],
+) -> fmt::Result {
+    for (idx, suggestion) in suggestions.iter().enumerate() {
+        let separator = match idx {
+            0 => "; did you mean",
+            _ if idx + 1 == suggestions.len() => " or",
+            _ => ",",
+        };
+        write!(f, "{} `{}{}`", separator, prefix, suggestion)?;
+    }
+    if suggestions.is_empty() {
+        Ok(())
+    } else {
+        write!(f, "?")
+    }
+}
+
+/// An error encountered while reading a variable as a particular Rust type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypeError {
+    /// The variable has not been set.
+    Undefined(VariableName),
+    /// The value holds a different type than the one requested.
+    Mismatch {
+        /// The name of the requested type.
+        expected: &'static str,
+        /// The value that could not be converted.
+        found: Value,
+    },
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeError::Undefined(name) => write!(f, "undefined variable `${}`", name.0),
+            TypeError::Mismatch { expected, found } => write!(
+                f,
+                "expected a {}, found a {}",
+                expected,
+                convert::type_name(found)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+/// An error encountered while registering a native function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    /// A function with the given name is already registered.
+    AlreadyExists(String),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::AlreadyExists(name) => {
+                write!(f, "function `{}` is already registered", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegisterError {}
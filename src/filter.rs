@@ -0,0 +1,50 @@
+use crate::engine::{Node, NodeName};
+
+/// Content about to be presented, as passed to the filter set with
+/// `YarnEngine::set_content_filter`.
+#[derive(Copy, Clone, Debug)]
+pub enum ContentRef<'a> {
+    /// A node about to be entered, whether by activation, a jump or an option.
+    Node(&'a Node),
+    /// A line of dialogue, including the prompt presented with options and each
+    /// line of a line group.
+    Line {
+        /// The node containing the line.
+        node: &'a NodeName,
+        /// The text of the line as written, before interpolation.
+        text: &'a str,
+        /// The line's hashtags, without their leading `#`.
+        tags: &'a [String],
+    },
+    /// An option, including those selected automatically.
+    Option {
+        /// The node containing the option.
+        node: &'a NodeName,
+        /// The text of the option as written, before interpolation.
+        text: &'a str,
+        /// The option's hashtags, without their leading `#`.
+        tags: &'a [String],
+    },
+}
+
+/// What happens to content passed to the content filter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilterDecision {
+    /// Present the content as usual.
+    Allow,
+    /// Enter the node named by the blocked node's `fallback` header instead, or end
+    /// the conversation if it has none. Lines and options are dropped.
+    Fallback,
+    /// End the conversation.
+    EndConversation,
+    /// Leave out the line or option. Nodes fall back as with `Fallback`. A dropped
+    /// prompt leaves its options presented with empty text, and a dropped line of a
+    /// line group leaves the group to choose among its other lines.
+    Drop,
+    /// Present the given text in place of the line or option. Nodes fall back as
+    /// with `Fallback`.
+    Redact(String),
+}
+
+/// A closure deciding whether content may be presented.
+pub type ContentFilter = dyn Fn(&ContentRef) -> FilterDecision;
@@ -0,0 +1,75 @@
+use crate::engine::{ChoiceKind, Expr, Step, Term, VariableName};
+
+/// The prefix of the variables holding flags, so that `<<flag met_king>>` sets
+/// `flag:met_king`. Scripts cannot name such a variable, so flags never clash with
+/// the variables they set themselves.
+pub(crate) const FLAG_PREFIX: &str = "flag:";
+
+/// The variable holding the given flag.
+pub(crate) fn flag_variable(flag: &str) -> VariableName {
+    VariableName(format!("{}{}", FLAG_PREFIX, flag))
+}
+
+/// The flag held by the given variable, if it holds one.
+pub(crate) fn flag_name(variable: &VariableName) -> Option<&str> {
+    variable.0.strip_prefix(FLAG_PREFIX)
+}
+
+/// Collect the flags set by `<<flag>>` in the given steps.
+pub(crate) fn collect_set_flags(steps: &[Step], flags: &mut Vec<String>) {
+    for step in steps {
+        match step {
+            Step::Assign(name, _) => flags.extend(flag_name(name).map(str::to_string)),
+            Step::Dialogue(_, choices, _) => {
+                for choice in choices {
+                    if let ChoiceKind::Inline(ref steps) = choice.kind {
+                        collect_set_flags(steps, flags);
+                    }
+                }
+            }
+            Step::Conditional(_, if_steps, else_ifs, else_steps) => {
+                collect_set_flags(if_steps, flags);
+                for (_, steps) in else_ifs {
+                    collect_set_flags(steps, flags);
+                }
+                collect_set_flags(else_steps, flags);
+            }
+            Step::Command(..)
+            | Step::Jump(..)
+            | Step::Assert(..)
+            | Step::Log(..)
+            | Step::Declare(..)
+            | Step::Const(..)
+            | Step::Enum(..)
+            | Step::LineGroup(..)
+            | Step::Return(..)
+            | Step::Unknown(..) => {}
+        }
+    }
+}
+
+/// Collect the flags checked by `flag("name")` in the given expression. Flags whose
+/// names are not string literals cannot be known without running the script.
+pub(crate) fn collect_checked_flags(expr: &Expr, flags: &mut Vec<String>) {
+    match expr {
+        Expr::Term(Term::Function(name, args)) => {
+            if let (true, [Expr::Term(Term::String(flag))]) = (name == "flag", args.as_slice()) {
+                flags.push(flag.trim().to_string());
+            }
+            for arg in args {
+                collect_checked_flags(arg, flags);
+            }
+        }
+        Expr::Term(_) => {}
+        Expr::Unary(_, expr) | Expr::Parentheses(expr) => collect_checked_flags(expr, flags),
+        Expr::Binary(_, left, right) => {
+            collect_checked_flags(left, flags);
+            collect_checked_flags(right, flags);
+        }
+        Expr::Ternary(condition, if_true, if_false) => {
+            collect_checked_flags(condition, flags);
+            collect_checked_flags(if_true, flags);
+            collect_checked_flags(if_false, flags);
+        }
+    }
+}
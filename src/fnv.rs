@@ -0,0 +1,24 @@
+/// The 64-bit FNV-1a hash, which unlike the standard library's hashers is
+/// guaranteed to give the same result on every platform and version.
+pub(crate) struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv {
+    /// Hash the string followed by a separator, so that `("ab", "c")` and
+    /// `("a", "bc")` hash differently.
+    pub(crate) fn write(&mut self, s: &str) {
+        for &byte in s.as_bytes().iter().chain(&[0xff]) {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
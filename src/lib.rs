@@ -1,8 +1,72 @@
-pub use self::engine::{YarnEngine, FunctionCallback, Value, NodeName, YarnEntry};
+#[cfg(feature = "bevy")]
+pub use self::bevy_plugin::{
+    ChooseEvent, ChooseSelection, CommandEvent, EndEvent, SayEvent, YarnDialogue,
+    YarnDialoguePlugin, YarnFileAsset, YarnFileLoader,
+};
+pub use self::convert::{FromValue, IntoValue, RegisterFn, YarnVariables};
+pub use self::diff::{diff, HeaderChange, LineChange, NodeDiff, NodeStatus, ScriptDiff};
+pub use self::engine::{
+    Checkpoint, ChoiceMadeCallback, ChoicePolicy, ChoiceRecord, CoercionWarning,
+    CoercionWarningCallback, CommandCallback, ContextChoiceMadeCallback, ContextCommandCallback,
+    ContextFunctionCallback, ContextLogCallback, ContextNodeVisitedCallback, ConversationStatus,
+    DisplayFormatter, EntryPointPredicate, EvalContext, FunctionCallback, LogCallback,
+    MissingLineCallback, Node, NodeName, NodeVisitedCallback, Nodes, OnError, OptionMatch,
+    RecoveredErrorCallback, RuntimeLimit, Say, StepKind, StepResult, TypeChecking, Value,
+    VariableName, YarnEngine, YarnEntry, YarnHandler, YarnType,
+};
+pub use self::error::{ErrorReport, RegisterError, TypeError, YarnError};
+pub use self::filter::{ContentFilter, ContentRef, FilterDecision};
+pub use self::lint::{LintDiagnostic, LintRule, LintSink};
+pub use self::load::{LoadOptions, LoadProgress, LoadReport, LoadSession};
+pub use self::localize::{assign_line_ids, LineKind, LocalizableLine};
+pub use self::markup::MarkupSpan;
+pub use self::metrics::{NodeMetrics, ParseMetrics, SourceMetrics};
+pub use self::normalize::TextNormalization;
+pub use self::paginate::{Pagination, SplitOn};
+pub use self::parse::{MixedIndentation, ParseLimit, ParseOptions, StrayContent};
+pub use self::progress::StoryProgress;
+pub use self::random::RngMode;
+pub use self::stats::Stats;
+pub use self::storylet::AvailableNode;
+pub use self::validate::{ActivationProblem, LineLengthLimit, ValidationWarning};
+pub use self::view::{EvalView, SyncFunctionCallback};
+#[cfg(feature = "derive")]
+pub use yarn_spool_derive::YarnVariables;
 
+// Lets derived code refer to `::yarn_spool` within this crate's tests.
+#[cfg(all(test, feature = "derive"))]
+extern crate self as yarn_spool;
+
+#[cfg(feature = "bevy")]
+mod bevy_plugin;
+mod chosen;
+mod constants;
+mod convert;
+mod diff;
 mod engine;
+mod enums;
+mod error;
+mod filter;
+mod flags;
+mod fnv;
+mod lint;
+mod load;
+mod localize;
+mod markup;
+mod metrics;
+mod normalize;
+mod paginate;
+#[cfg(feature = "parallel")]
+mod parallel;
 pub(crate) mod parse;
+mod progress;
+mod pseudo;
+mod random;
+mod stats;
+mod storylet;
+mod suggest;
+mod validate;
+mod view;
 
 #[cfg(test)]
 mod test;
-
@@ -0,0 +1,176 @@
+use crate::engine::{ChoiceKind, Node, NodeName, Nodes, Step};
+use crate::localize::LocalizableLine;
+use crate::validate::ValidationWarning;
+use std::collections::HashMap;
+
+/// A house rule checked by `YarnEngine::lint` alongside the built-in validations,
+/// such as requiring every line to have a speaker. Each method is called as the
+/// loaded nodes are walked, and does nothing by default.
+pub trait LintRule {
+    /// The rule's ID, such as `require-speaker`, which a node can suppress by
+    /// listing it in its `lint-disable` header.
+    fn id(&self) -> &str;
+
+    /// Check a node and its headers.
+    fn check_node(&self, _node: &Node, _sink: &mut LintSink<'_>) {}
+
+    /// Check a line of dialogue or option text in the given node.
+    fn check_line(&self, _node: &Node, _line: &LocalizableLine, _sink: &mut LintSink<'_>) {}
+
+    /// Check a command in the given node, as written between `<<` and `>>`.
+    fn check_command(&self, _node: &Node, _command: &str, _sink: &mut LintSink<'_>) {}
+}
+
+/// A problem found by `YarnEngine::lint`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LintDiagnostic {
+    /// The ID of the rule that reported the problem.
+    pub rule: String,
+    /// The node the problem was found in.
+    pub node: NodeName,
+    /// The ID of the line concerned, if any, as given by `YarnEngine::extract_lines`.
+    pub line: Option<String>,
+    /// A description of the problem.
+    pub message: String,
+}
+
+/// Collects the problems a `LintRule` reports in a node.
+pub struct LintSink<'a> {
+    rule: &'a str,
+    node: &'a NodeName,
+    diagnostics: &'a mut Vec<LintDiagnostic>,
+}
+
+impl LintSink<'_> {
+    /// Report a problem with the node being checked.
+    pub fn report(&mut self, message: impl Into<String>) {
+        self.push(None, message.into());
+    }
+
+    /// Report a problem with the line with the given ID.
+    pub fn report_line(&mut self, line: &str, message: impl Into<String>) {
+        self.push(Some(line.to_string()), message.into());
+    }
+
+    fn push(&mut self, line: Option<String>, message: String) {
+        self.diagnostics.push(LintDiagnostic {
+            rule: self.rule.to_string(),
+            node: self.node.clone(),
+            line,
+            message,
+        });
+    }
+}
+
+/// Combine the built-in warnings with the problems found by each rule, ordered by
+/// node, leaving out those of rules the node disables. `lines` are the lines of
+/// every node, as given by `extract_lines`.
+pub(crate) fn lint(
+    nodes: &Nodes,
+    warnings: Vec<ValidationWarning>,
+    lines: Vec<LocalizableLine>,
+    rules: &[&dyn LintRule],
+) -> Vec<LintDiagnostic> {
+    let mut builtin: HashMap<NodeName, Vec<ValidationWarning>> = HashMap::new();
+    for warning in warnings {
+        builtin
+            .entry(warning.node().clone())
+            .or_default()
+            .push(warning);
+    }
+    let mut lines_by_node: HashMap<NodeName, Vec<LocalizableLine>> = HashMap::new();
+    for line in lines {
+        lines_by_node
+            .entry(line.node.clone())
+            .or_default()
+            .push(line);
+    }
+
+    let mut diagnostics = vec![];
+    for node in nodes.iter() {
+        let disabled = disabled_rules(node);
+        for warning in builtin.remove(&node.title).unwrap_or_default() {
+            if disabled.contains(&warning.rule()) {
+                continue;
+            }
+            let line = match warning {
+                ValidationWarning::LineTooLong { ref line, .. } => Some(line.clone()),
+                _ => None,
+            };
+            diagnostics.push(LintDiagnostic {
+                rule: warning.rule().to_string(),
+                node: node.title.clone(),
+                line,
+                message: warning.to_string(),
+            });
+        }
+
+        let mut commands = vec![];
+        if let Ok(steps) = node.steps() {
+            collect_commands(steps, &mut commands);
+        }
+        let lines = lines_by_node.remove(&node.title).unwrap_or_default();
+        for rule in rules.iter().filter(|rule| !disabled.contains(&rule.id())) {
+            let mut sink = LintSink {
+                rule: rule.id(),
+                node: &node.title,
+                diagnostics: &mut diagnostics,
+            };
+            rule.check_node(node, &mut sink);
+            for line in &lines {
+                rule.check_line(node, line, &mut sink);
+            }
+            for command in &commands {
+                rule.check_command(node, command, &mut sink);
+            }
+        }
+    }
+    diagnostics
+}
+
+/// The IDs of the rules listed in the node's `lint-disable` header, separated by
+/// commas or whitespace.
+fn disabled_rules(node: &Node) -> Vec<&str> {
+    node.header("lint-disable")
+        .map(|rules| {
+            rules
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|rule| !rule.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Collect the commands in the given steps in source order, including those nested
+/// in inline options and conditionals.
+fn collect_commands<'a>(steps: &'a [Step], commands: &mut Vec<&'a str>) {
+    for step in steps {
+        match step {
+            Step::Command(command) => commands.push(command),
+            Step::Dialogue(_, choices, _) => {
+                for choice in choices {
+                    if let ChoiceKind::Inline(ref steps) = choice.kind {
+                        collect_commands(steps, commands);
+                    }
+                }
+            }
+            Step::Conditional(_, if_steps, else_ifs, else_steps) => {
+                collect_commands(if_steps, commands);
+                for (_, steps) in else_ifs {
+                    collect_commands(steps, commands);
+                }
+                collect_commands(else_steps, commands);
+            }
+            Step::Assign(..)
+            | Step::Jump(..)
+            | Step::Assert(..)
+            | Step::Log(..)
+            | Step::Declare(..)
+            | Step::Const(..)
+            | Step::Enum(..)
+            | Step::LineGroup(..)
+            | Step::Return(..)
+            | Step::Unknown(..) => {}
+        }
+    }
+}
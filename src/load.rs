@@ -0,0 +1,194 @@
+use crate::engine::{Node, NodeNames};
+use crate::error::YarnError;
+use crate::metrics::{NodeMetrics, ParseMetrics, SourceMetrics};
+use crate::parse::{self, ParseLimit, ParseOptions};
+use std::time::{Duration, Instant};
+
+/// Options controlling how `YarnEngine::load_sources` loads a batch of sources.
+#[derive(Clone, Debug)]
+pub struct LoadOptions {
+    /// The options used to parse each source.
+    pub parse: ParseOptions,
+    /// Whether the batch is loaded all or nothing. When atomic, any error leaves the
+    /// engine as it was; otherwise every source without an error is loaded.
+    pub atomic: bool,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            parse: ParseOptions::default(),
+            atomic: true,
+        }
+    }
+}
+
+/// The outcome of `YarnEngine::load_sources`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadReport {
+    /// The names of the sources whose nodes were loaded, in the order given.
+    pub loaded: Vec<String>,
+    /// Each error, with the name of the source it was found in.
+    pub errors: Vec<(String, YarnError)>,
+    /// How long each source and node took to parse, if
+    /// `ParseOptions::collect_metrics` was set.
+    pub metrics: Option<ParseMetrics>,
+}
+
+impl LoadReport {
+    /// Whether every source was loaded without error.
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// A source being parsed a few nodes at a time, begun with `YarnEngine::begin_load`
+/// so that parsing a large script can be spread across frames. None of its nodes
+/// are loaded until the session is passed to `YarnEngine::finish_load`.
+pub struct LoadSession {
+    source: String,
+    options: ParseOptions,
+    names: NodeNames,
+    /// The byte offset of the first unparsed node, and its line.
+    position: usize,
+    line: usize,
+    nodes: Vec<Node>,
+    done: bool,
+    error: Option<YarnError>,
+}
+
+/// How much of a `LoadSession`'s source has been parsed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LoadProgress {
+    /// The number of nodes parsed.
+    pub nodes: usize,
+    /// The number of bytes of the source parsed.
+    pub bytes: usize,
+    /// The length of the source in bytes.
+    pub total_bytes: usize,
+    /// Whether the whole source has been parsed.
+    pub done: bool,
+}
+
+impl LoadSession {
+    pub(crate) fn new(source: String, options: &ParseOptions, names: NodeNames) -> LoadSession {
+        let long_line = source
+            .split('\n')
+            .position(|line| line.len() > options.max_line_length);
+        let error = long_line.map(|idx| YarnError::LimitExceeded {
+            limit: ParseLimit::LineLength,
+            line: idx + 1,
+        });
+        LoadSession {
+            source,
+            options: options.clone(),
+            names,
+            position: 0,
+            line: 1,
+            nodes: vec![],
+            done: false,
+            error,
+        }
+    }
+
+    /// Parse at most the given number of nodes, returning the progress so far or the
+    /// first error encountered.
+    pub fn work(&mut self, nodes: usize) -> Result<LoadProgress, YarnError> {
+        if let Some(ref error) = self.error {
+            return Err(error.clone());
+        }
+        if !self.done && nodes > 0 {
+            let max = nodes.min(self.options.max_nodes - self.nodes.len());
+            let parsed = parse::parse_nodes_from(
+                &self.source,
+                self.position,
+                self.line,
+                max,
+                &self.options,
+                &self.names,
+            );
+            let (parsed, end, done) = match parsed {
+                Ok(parsed) => parsed,
+                Err(error) => {
+                    self.error = Some(error.clone());
+                    return Err(error);
+                }
+            };
+            self.line += self.source[self.position..end].matches('\n').count();
+            self.position = end;
+            self.nodes.extend(parsed);
+            self.done = done;
+            if !done && self.nodes.len() == self.options.max_nodes {
+                let error = YarnError::LimitExceeded {
+                    limit: ParseLimit::Nodes,
+                    line: self.line,
+                };
+                self.error = Some(error.clone());
+                return Err(error);
+            }
+        }
+        Ok(self.progress())
+    }
+
+    /// Parse nodes one at a time until the given time has elapsed, returning the
+    /// progress so far or the first error encountered. At least one node is parsed.
+    pub fn work_for(&mut self, budget: Duration) -> Result<LoadProgress, YarnError> {
+        let start = Instant::now();
+        loop {
+            let progress = self.work(1)?;
+            if progress.done || start.elapsed() >= budget {
+                return Ok(progress);
+            }
+        }
+    }
+
+    /// How much of the source has been parsed.
+    pub fn progress(&self) -> LoadProgress {
+        LoadProgress {
+            nodes: self.nodes.len(),
+            bytes: if self.done {
+                self.source.len()
+            } else {
+                self.position
+            },
+            total_bytes: self.source.len(),
+            done: self.done,
+        }
+    }
+
+    /// Parse the rest of the source, returning the nodes.
+    pub(crate) fn into_nodes(mut self) -> Result<Vec<Node>, YarnError> {
+        while !self.work(usize::MAX)?.done {}
+        Ok(self.nodes)
+    }
+
+    /// Parse the whole source a node at a time, adding the time taken by each node
+    /// and by the source to the metrics.
+    pub(crate) fn into_nodes_measured(
+        mut self,
+        name: &str,
+        metrics: &mut ParseMetrics,
+    ) -> Result<Vec<Node>, YarnError> {
+        let start = Instant::now();
+        let mut nodes = vec![];
+        let result = loop {
+            let node_start = Instant::now();
+            let progress = self.work(1);
+            let duration = node_start.elapsed();
+            if let Some(node) = self.nodes.get(nodes.len()) {
+                nodes.push(NodeMetrics::new(node, duration));
+            }
+            match progress {
+                Ok(progress) if progress.done => break Ok(self.nodes),
+                Ok(_) => {}
+                Err(err) => break Err(err),
+            }
+        };
+        metrics.sources.push(SourceMetrics {
+            name: name.to_string(),
+            duration: start.elapsed(),
+            nodes,
+        });
+        result
+    }
+}
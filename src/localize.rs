@@ -0,0 +1,334 @@
+use crate::engine::{ChoiceKind, LinePath, Node, NodeName, NodeNames, Nodes, Step, StepIndex};
+use crate::error::YarnError;
+use crate::fnv::Fnv;
+use crate::normalize::TextNormalization;
+use crate::parse::{self, ParseOptions};
+use std::collections::HashMap;
+
+/// Whether a localizable string is a line of dialogue or the text of an option.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LineKind {
+    /// A line of dialogue, including the prompt that precedes a set of options.
+    Say,
+    /// The text of an option presented to the player.
+    Option,
+}
+
+/// A string that requires translation, along with context for translators.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalizableLine {
+    /// The value of the line's `#line:` tag if present, otherwise an identifier
+    /// generated from a hash of the node title and text.
+    pub id: String,
+    /// The node containing the line.
+    pub node: NodeName,
+    /// The speaking character, if the text is of the form `Character: text`.
+    pub character: Option<String>,
+    /// Whether this is a line of dialogue or an option.
+    pub kind: LineKind,
+    /// The text to be translated, without any hashtags.
+    pub text: String,
+    /// The line's hashtags, without their leading `#`.
+    pub tags: Vec<String>,
+    /// The previous line of dialogue in the same block. For options, this is the
+    /// line presented alongside them.
+    pub previous: Option<String>,
+    /// The next line of dialogue in the same block.
+    pub next: Option<String>,
+}
+
+/// The speaking character of a line of the form `Character: text`.
+pub(crate) fn character(text: &str) -> Option<&str> {
+    let idx = text.find(':')?;
+    let name = text[..idx].trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Collect every localizable string in the given nodes, ordered by node and then by
+/// position within each node. Nodes whose bodies fail to parse are skipped. The
+/// text of each line and its neighbours is normalized if any normalization is
+/// enabled.
+pub(crate) fn extract_lines(
+    nodes: &Nodes,
+    normalization: &TextNormalization,
+) -> Vec<LocalizableLine> {
+    let mut lines = vec![];
+    for node in nodes.iter() {
+        let mut counter = HashMap::new();
+        if let Ok(steps) = node.steps() {
+            extract_block(node, steps, &top_level(node), &mut counter, &mut lines);
+        }
+    }
+    let mut lines = lines.into_iter().map(|(_, line)| line).collect::<Vec<_>>();
+    if normalization.is_enabled() {
+        for line in &mut lines {
+            line.text = normalization.apply(&line.text);
+            for text in line.previous.iter_mut().chain(line.next.iter_mut()) {
+                *text = normalization.apply(text);
+            }
+        }
+    }
+    lines
+}
+
+/// The ID of each localizable string in the given node, as given by
+/// `extract_lines`, keyed by where it is in the node.
+pub(crate) fn line_ids(node: &Node) -> HashMap<LinePath, String> {
+    let mut lines = vec![];
+    if let Ok(steps) = node.steps() {
+        extract_block(
+            node,
+            steps,
+            &top_level(node),
+            &mut HashMap::new(),
+            &mut lines,
+        );
+    }
+    lines
+        .into_iter()
+        .map(|(key, line)| (key, line.id))
+        .collect()
+}
+
+/// Add a `#line:` tag to each line of dialogue and option in the given source that
+/// lacks one, holding the ID that would otherwise be generated for it, so that
+/// writers can commit the tagged source and keep its IDs however it is edited.
+/// Everything else in the source is left as it is.
+pub fn assign_line_ids(source: &str) -> Result<String, YarnError> {
+    let ids = node_line_ids(source)?;
+    let mut nodes = ids.clone().into_iter();
+    // The IDs of the lines of the node whose body is being read, if any.
+    let mut body: Option<std::vec::IntoIter<String>> = None;
+    let mut out = String::with_capacity(source.len());
+    for line in source.split_inclusive('\n') {
+        let content = line.trim_end_matches(&['\r', '\n'][..]);
+        let trimmed = content.trim();
+        match body {
+            None if trimmed.starts_with("---") => {
+                body = Some(nodes.next().unwrap_or_default().into_iter())
+            }
+            None => {}
+            Some(_) if trimmed.starts_with("===") => body = None,
+            Some(ref mut ids) if is_localizable(trimmed) => {
+                let id = ids.next().unwrap_or_default();
+                let (_, tags) = parse::split_hashtags(trimmed);
+                if !id.is_empty() && !tags.iter().any(|tag| tag.starts_with("line:")) {
+                    out.push_str(content.trim_end());
+                    out.push_str(" #");
+                    out.push_str(&id);
+                    out.push_str(&line[content.len()..]);
+                    continue;
+                }
+            }
+            Some(_) => {}
+        }
+        out.push_str(line);
+    }
+    // Lines are matched to the source by position, so check that the tags landed
+    // where they belong.
+    if node_line_ids(&out)? != ids {
+        return Err(YarnError::Parse {
+            line: None,
+            message: "could not match every line to its source".to_string(),
+        });
+    }
+    Ok(out)
+}
+
+/// The IDs of the lines of each node in the given source, in order.
+fn node_line_ids(source: &str) -> Result<Vec<Vec<String>>, YarnError> {
+    let names = NodeNames::default();
+    let nodes = parse::parse_nodes_from_string(source, &ParseOptions::default(), &names)?;
+    nodes
+        .iter()
+        .map(|node| {
+            let mut lines = vec![];
+            let steps = node.steps()?;
+            extract_block(
+                node,
+                steps,
+                &top_level(node),
+                &mut HashMap::new(),
+                &mut lines,
+            );
+            Ok(lines.into_iter().map(|(_, line)| line.id).collect())
+        })
+        .collect()
+}
+
+/// Whether a line of a node body holds a line of dialogue or an option, rather than
+/// a statement or a jump.
+fn is_localizable(line: &str) -> bool {
+    if line.is_empty() || line.starts_with("<<") {
+        return false;
+    }
+    match line.strip_prefix("[[") {
+        Some(link) => link.split("]]").next().unwrap_or("").contains('|'),
+        None => true,
+    }
+}
+
+/// The value of the line's `#line:` tag, or otherwise an ID generated from a hash of
+/// the node title and the text, so that it does not change when other lines are
+/// added or removed. The ID begins with the title, with any character that cannot
+/// appear in a tag replaced by `_`. Repeats of the same line within a node are
+/// numbered in order, counted in `counter`.
+fn line_id(
+    node: &Node,
+    text: &str,
+    tags: &[String],
+    counter: &mut HashMap<String, usize>,
+) -> String {
+    if let Some(tag) = tags.iter().find(|tag| tag.starts_with("line:")) {
+        return tag.clone();
+    }
+    let mut hash = Fnv::default();
+    hash.write(node.title.as_str());
+    hash.write(text);
+    let title = node
+        .title
+        .as_str()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let id = format!("line:{}-{:016x}", title, hash.finish());
+    let count = counter.entry(id.clone()).or_default();
+    *count += 1;
+    match *count {
+        1 => id,
+        count => format!("{}-{}", id, count),
+    }
+}
+
+/// The path of each top-level step of a node, by its index.
+fn top_level(node: &Node) -> impl Fn(usize) -> LinePath + '_ {
+    move |index| LinePath {
+        node: node.title.clone(),
+        base_index: index,
+        indexes: vec![],
+        part: None,
+    }
+}
+
+/// The path of a step of a block nested in the step at the given path.
+fn nested(path: &LinePath, index: StepIndex) -> LinePath {
+    let mut path = path.clone();
+    path.indexes.push(index);
+    path
+}
+
+fn extract_block(
+    node: &Node,
+    steps: &[Step],
+    path: &dyn Fn(usize) -> LinePath,
+    counter: &mut HashMap<String, usize>,
+    lines: &mut Vec<(LinePath, LocalizableLine)>,
+) {
+    let dialogue = steps
+        .iter()
+        .filter_map(|step| match step {
+            Step::Dialogue(text, ..) => Some(text),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let mut dialogue_index = 0usize;
+
+    for (index, step) in steps.iter().enumerate() {
+        let path = path(index);
+        match step {
+            Step::Dialogue(text, choices, tags) => {
+                let previous = dialogue_index
+                    .checked_sub(1)
+                    .map(|idx| dialogue[idx].clone());
+                let next = dialogue.get(dialogue_index + 1).map(|t| (*t).clone());
+                dialogue_index += 1;
+                // Like Yarn Spinner, mark the line presented alongside options, which
+                // stays on screen while the player chooses.
+                let mut tags = tags.clone();
+                if !choices.is_empty() && !tags.iter().any(|tag| tag == "lastline") {
+                    tags.push("lastline".to_string());
+                }
+                lines.push((
+                    path.clone(),
+                    LocalizableLine {
+                        id: line_id(node, text, &tags, counter),
+                        node: node.title.clone(),
+                        character: character(text).map(|c| c.to_string()),
+                        kind: LineKind::Say,
+                        text: text.clone(),
+                        tags,
+                        previous,
+                        next,
+                    },
+                ));
+
+                for (choice_index, choice) in choices.iter().enumerate() {
+                    lines.push((
+                        path.part(choice_index),
+                        LocalizableLine {
+                            id: line_id(node, &choice.text, &choice.tags, counter),
+                            node: node.title.clone(),
+                            character: character(&choice.text).map(|c| c.to_string()),
+                            kind: LineKind::Option,
+                            text: choice.text.clone(),
+                            tags: choice.tags.clone(),
+                            previous: Some(text.clone()),
+                            next: None,
+                        },
+                    ));
+                    if let ChoiceKind::Inline(ref steps) = choice.kind {
+                        let block = |step| nested(&path, StepIndex::Dialogue(choice_index, step));
+                        extract_block(node, steps, &block, counter, lines);
+                    }
+                }
+            }
+            Step::Conditional(_, if_steps, else_ifs, else_steps) => {
+                let block = |step| nested(&path, StepIndex::If(step));
+                extract_block(node, if_steps, &block, counter, lines);
+                for (else_if, (_, steps)) in else_ifs.iter().enumerate() {
+                    let block = |step| nested(&path, StepIndex::ElseIf(else_if, step));
+                    extract_block(node, steps, &block, counter, lines);
+                }
+                let block = |step| nested(&path, StepIndex::Else(step));
+                extract_block(node, else_steps, &block, counter, lines);
+            }
+            Step::LineGroup(group) => {
+                for (line_index, line) in group.lines.iter().enumerate() {
+                    lines.push((
+                        path.part(line_index),
+                        LocalizableLine {
+                            id: line_id(node, &line.text, &line.tags, counter),
+                            node: node.title.clone(),
+                            character: character(&line.text).map(|c| c.to_string()),
+                            kind: LineKind::Say,
+                            text: line.text.clone(),
+                            tags: line.tags.clone(),
+                            previous: None,
+                            next: None,
+                        },
+                    ));
+                }
+            }
+            Step::Command(..)
+            | Step::Assign(..)
+            | Step::Jump(..)
+            | Step::Assert(..)
+            | Step::Log(..)
+            | Step::Declare(..)
+            | Step::Const(..)
+            | Step::Enum(..)
+            | Step::Return(..)
+            | Step::Unknown(..) => {}
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::localize;
+use crate::parse;
+
+/// The attribute whose contents are never interpreted as markup or interpolation.
+pub(crate) const NOMARKUP: &str = "[nomarkup]";
+pub(crate) const NOMARKUP_END: &str = "[/nomarkup]";
+
+/// A range of a line's plain text marked up with an attribute, such as `[b]` in
+/// `[b]Hello[/b]` or `[wave size=2]` in `[wave size=2]Hi[/wave]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MarkupSpan {
+    /// The name of the attribute.
+    pub name: String,
+    /// The attribute's properties in the order written. A value given to the
+    /// attribute itself, as in `[wave=2]`, is a property named after the attribute.
+    pub properties: Vec<(String, String)>,
+    /// The position of the first marked character in the plain text, in chars.
+    pub start: usize,
+    /// The number of chars marked. Self-closing attributes such as `[pause/]` mark
+    /// no characters.
+    pub length: usize,
+}
+
+impl MarkupSpan {
+    /// The value of the named property, if present.
+    pub fn property(&self, name: &str) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Remove the markup from a line, returning its plain text and the spans marked,
+/// ordered by where they open. `\[` and `\]` produce literal brackets, and
+/// brackets that do not form a valid attribute are kept as text. `[/]` closes
+/// every open attribute, and attributes left open extend to the end of the line.
+///
+/// The contents of `[nomarkup]...[/nomarkup]` are copied to the plain text as
+/// they are. A line of the form `Character: text` is marked with a `character`
+/// attribute covering the name, the colon and the spaces after it, whose `name`
+/// property is the character's name.
+pub(crate) fn parse_markup(text: &str) -> (String, Vec<MarkupSpan>) {
+    let mut plain = String::with_capacity(text.len());
+    let mut len = 0;
+    let mut spans: Vec<MarkupSpan> = vec![];
+    // Indexes into `spans` of the attributes still open.
+    let mut open: Vec<usize> = vec![];
+    let mut rest = text;
+    while let Some(ch) = rest.chars().next() {
+        if ch == '\\' && (rest[1..].starts_with('[') || rest[1..].starts_with(']')) {
+            plain.push_str(&rest[1..2]);
+            len += 1;
+            rest = &rest[2..];
+            continue;
+        }
+        if let Some(contents) = rest.strip_prefix(NOMARKUP) {
+            let (contents, after) = contents.split_once(NOMARKUP_END).unwrap_or((contents, ""));
+            plain.push_str(contents);
+            len += contents.chars().count();
+            rest = after;
+            continue;
+        }
+        if ch == '[' {
+            if let Some((tag, after)) = rest[1..].split_once(']').and_then(|(tag, after)| {
+                let tag = parse_tag(tag)?;
+                Some((tag, after))
+            }) {
+                match tag {
+                    Tag::Open(name, properties, self_closing) => {
+                        if !self_closing {
+                            open.push(spans.len());
+                        }
+                        spans.push(MarkupSpan {
+                            name,
+                            properties,
+                            start: len,
+                            length: 0,
+                        });
+                    }
+                    Tag::Close(None) => {
+                        for idx in open.drain(..) {
+                            spans[idx].length = len - spans[idx].start;
+                        }
+                    }
+                    Tag::Close(Some(name)) => {
+                        if let Some(pos) = open.iter().rposition(|&idx| spans[idx].name == name) {
+                            let idx = open.remove(pos);
+                            spans[idx].length = len - spans[idx].start;
+                        }
+                    }
+                }
+                rest = after;
+                continue;
+            }
+        }
+        plain.push(ch);
+        len += 1;
+        rest = &rest[ch.len_utf8()..];
+    }
+    for idx in open {
+        spans[idx].length = len - spans[idx].start;
+    }
+    if let Some(name) = localize::character(&plain) {
+        let colon = plain.find(':').unwrap();
+        let spaces = plain[colon + 1..].len() - plain[colon + 1..].trim_start().len();
+        spans.insert(
+            0,
+            MarkupSpan {
+                name: "character".to_string(),
+                properties: vec![("name".to_string(), name.to_string())],
+                start: 0,
+                length: plain[..colon + 1 + spaces].chars().count(),
+            },
+        );
+    }
+    (plain, spans)
+}
+
+enum Tag {
+    /// An opening or self-closing attribute with its properties.
+    Open(String, Vec<(String, String)>, bool),
+    /// A closing marker, naming the attribute unless it closes all of them.
+    Close(Option<String>),
+}
+
+/// Parse the contents of a `[...]` marker, returning None if it is not valid
+/// markup.
+fn parse_tag(tag: &str) -> Option<Tag> {
+    let tag = tag.trim();
+    if let Some(name) = tag.strip_prefix('/') {
+        let name = name.trim();
+        return match name {
+            "" => Some(Tag::Close(None)),
+            name if parse::is_identifier(name) => Some(Tag::Close(Some(name.to_string()))),
+            _ => None,
+        };
+    }
+    let (tag, self_closing) = match tag.strip_suffix('/') {
+        Some(tag) => (tag.trim_end(), true),
+        None => (tag, false),
+    };
+    let name_end = tag
+        .find(|ch: char| ch.is_whitespace() || ch == '=')
+        .unwrap_or(tag.len());
+    let name = &tag[..name_end];
+    if !parse::is_identifier(name) {
+        return None;
+    }
+    let mut properties = vec![];
+    let mut rest = &tag[name_end..];
+    if let Some(value) = rest.strip_prefix('=') {
+        let (value, after) = parse_value(value)?;
+        properties.push((name.to_string(), value));
+        rest = after;
+    }
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let (key, value) = rest.split_once('=')?;
+        if !parse::is_identifier(key) {
+            return None;
+        }
+        let (value, after) = parse_value(value)?;
+        properties.push((key.to_string(), value));
+        rest = after;
+    }
+    Some(Tag::Open(name.to_string(), properties, self_closing))
+}
+
+/// Parse a property value, which is either quoted or ends at whitespace, returning
+/// it and the text after it.
+fn parse_value(s: &str) -> Option<(String, &str)> {
+    if let Some(quoted) = s.strip_prefix('"') {
+        let (value, after) = quoted.split_once('"')?;
+        return Some((value.to_string(), after));
+    }
+    let end = s.find(char::is_whitespace).unwrap_or(s.len());
+    if end == 0 {
+        return None;
+    }
+    Some((s[..end].to_string(), &s[end..]))
+}
@@ -0,0 +1,199 @@
+use crate::engine::{ChoiceKind, Node, NodeName, Step};
+use crate::validate;
+use std::cmp::Reverse;
+use std::time::Duration;
+
+/// How long parsing took and how much was parsed, collected by
+/// `YarnEngine::load_sources` when `ParseOptions::collect_metrics` is set. With the
+/// `serde` feature, metrics serialize for dashboards, with durations in seconds.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ParseMetrics {
+    /// Each source, in the order given, including those that failed to parse.
+    pub sources: Vec<SourceMetrics>,
+}
+
+/// The parsing of a single source.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SourceMetrics {
+    /// The name of the source.
+    pub name: String,
+    /// How long the whole source took to parse.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "seconds"))]
+    pub duration: Duration,
+    /// Each node parsed, in source order. Nodes parsed before an error are included.
+    pub nodes: Vec<NodeMetrics>,
+}
+
+/// The parsing of a single node.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct NodeMetrics {
+    /// The node's title.
+    pub name: NodeName,
+    /// How long the node took to parse.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "seconds"))]
+    pub duration: Duration,
+    /// The number of steps in the node's body, including those nested in
+    /// conditionals and options. Zero if its body is parsed lazily.
+    pub steps: usize,
+    /// The number of expressions in the node's body, including conditions and
+    /// interpolations. Zero if its body is parsed lazily.
+    pub expressions: usize,
+}
+
+impl NodeMetrics {
+    /// Measure the given node, which took the given time to parse.
+    pub(crate) fn new(node: &Node, duration: Duration) -> NodeMetrics {
+        let mut exprs = vec![];
+        validate::collect_exprs(&node.steps, &mut exprs);
+        NodeMetrics {
+            name: node.title.clone(),
+            duration,
+            steps: count_steps(&node.steps),
+            expressions: exprs.len(),
+        }
+    }
+}
+
+impl ParseMetrics {
+    /// The total time spent parsing.
+    pub fn duration(&self) -> Duration {
+        self.sources.iter().map(|source| source.duration).sum()
+    }
+
+    /// The number of nodes parsed.
+    pub fn node_count(&self) -> usize {
+        self.nodes().count()
+    }
+
+    /// The number of steps parsed.
+    pub fn step_count(&self) -> usize {
+        self.nodes().map(|(_, node)| node.steps).sum()
+    }
+
+    /// The number of expressions parsed.
+    pub fn expression_count(&self) -> usize {
+        self.nodes().map(|(_, node)| node.expressions).sum()
+    }
+
+    /// The given number of nodes that took longest to parse, slowest first, with
+    /// the names of their sources.
+    pub fn slowest_nodes(&self, count: usize) -> Vec<(&str, &NodeMetrics)> {
+        let mut nodes = self.nodes().collect::<Vec<_>>();
+        nodes.sort_by_key(|(_, node)| Reverse(node.duration));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// A table for reading in a terminal: a row for each source, the totals, then
+    /// the ten slowest nodes.
+    pub fn to_table_string(&self) -> String {
+        let width = self
+            .sources
+            .iter()
+            .map(|source| source.name.chars().count())
+            .chain(
+                self.nodes()
+                    .map(|(_, node)| node.name.as_str().chars().count()),
+            )
+            .chain(Some("source".len()))
+            .max()
+            .unwrap_or(0);
+        let mut table = format!(
+            "{:<width$}  {:>10}  {:>6}  {:>6}  {:>11}\n",
+            "source",
+            "time (ms)",
+            "nodes",
+            "steps",
+            "expressions",
+            width = width
+        );
+        let mut row = |name: &str, duration: Duration, nodes: &[&NodeMetrics]| {
+            table.push_str(&format!(
+                "{:<width$}  {:>10.3}  {:>6}  {:>6}  {:>11}\n",
+                name,
+                duration.as_secs_f64() * 1000.,
+                nodes.len(),
+                nodes.iter().map(|node| node.steps).sum::<usize>(),
+                nodes.iter().map(|node| node.expressions).sum::<usize>(),
+                width = width
+            ));
+        };
+        for source in &self.sources {
+            row(
+                &source.name,
+                source.duration,
+                &source.nodes.iter().collect::<Vec<_>>(),
+            );
+        }
+        let all = self.nodes().map(|(_, node)| node).collect::<Vec<_>>();
+        row("total", self.duration(), &all);
+
+        table.push_str(&format!(
+            "\n{:<width$}  {:>10}\n",
+            "slowest node",
+            "time (ms)",
+            width = width
+        ));
+        for (_, node) in self.slowest_nodes(10) {
+            table.push_str(&format!(
+                "{:<width$}  {:>10.3}\n",
+                node.name,
+                node.duration.as_secs_f64() * 1000.,
+                width = width
+            ));
+        }
+        table
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = (&str, &NodeMetrics)> {
+        self.sources.iter().flat_map(|source| {
+            let name = source.name.as_str();
+            source.nodes.iter().map(move |node| (name, node))
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+fn seconds<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// The number of steps, including those nested in conditionals and inline options.
+fn count_steps(steps: &[Step]) -> usize {
+    steps
+        .iter()
+        .map(|step| {
+            1 + match step {
+                Step::Dialogue(_, choices, _) => choices
+                    .iter()
+                    .map(|choice| match choice.kind {
+                        ChoiceKind::Inline(ref steps) => count_steps(steps),
+                        ChoiceKind::External(..) => 0,
+                    })
+                    .sum(),
+                Step::Conditional(_, if_steps, else_ifs, else_steps) => {
+                    count_steps(if_steps)
+                        + else_ifs
+                            .iter()
+                            .map(|(_, steps)| count_steps(steps))
+                            .sum::<usize>()
+                        + count_steps(else_steps)
+                }
+                Step::Assign(..)
+                | Step::Command(..)
+                | Step::Jump(..)
+                | Step::Assert(..)
+                | Step::Log(..)
+                | Step::Declare(..)
+                | Step::Const(..)
+                | Step::Enum(..)
+                | Step::LineGroup(..)
+                | Step::Return(..)
+                | Step::Unknown(..) => 0,
+            }
+        })
+        .sum()
+}
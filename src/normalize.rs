@@ -0,0 +1,62 @@
+/// Adjustments applied to dialogue and option text as it is presented, leaving the
+/// loaded nodes unchanged. Every adjustment is disabled by default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TextNormalization {
+    /// Remove whitespace from the end of the text.
+    pub trim_trailing_whitespace: bool,
+    /// Replace each run of whitespace within the text with a single space.
+    pub collapse_whitespace: bool,
+    /// Replace non-breaking spaces with ordinary spaces.
+    pub replace_non_breaking_spaces: bool,
+    /// Replace curly quotes, dashes and ellipses with their closest ASCII
+    /// equivalents.
+    pub ascii_punctuation: bool,
+}
+
+impl TextNormalization {
+    /// Whether any adjustment is enabled.
+    pub fn is_enabled(&self) -> bool {
+        *self != TextNormalization::default()
+    }
+
+    /// Apply the enabled adjustments to the text. Non-breaking spaces are only
+    /// trimmed or collapsed if they are also replaced.
+    pub fn apply(&self, text: &str) -> String {
+        let mut result = String::with_capacity(text.len());
+        let mut in_whitespace = false;
+        for ch in text.chars() {
+            let ch = match ch {
+                '\u{a0}' | '\u{2007}' | '\u{202f}' if self.replace_non_breaking_spaces => ' ',
+                ch => ch,
+            };
+            if self.collapse_whitespace && is_breaking_whitespace(ch) {
+                if !in_whitespace {
+                    result.push(' ');
+                }
+                in_whitespace = true;
+                continue;
+            }
+            in_whitespace = false;
+            match ch {
+                '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}' if self.ascii_punctuation => {
+                    result.push('\'')
+                }
+                '\u{201c}' | '\u{201d}' | '\u{201e}' | '\u{201f}' if self.ascii_punctuation => {
+                    result.push('"')
+                }
+                '\u{2010}'..='\u{2015}' if self.ascii_punctuation => result.push('-'),
+                '\u{2026}' if self.ascii_punctuation => result.push_str("..."),
+                ch => result.push(ch),
+            }
+        }
+        if self.trim_trailing_whitespace {
+            let len = result.trim_end_matches(is_breaking_whitespace).len();
+            result.truncate(len);
+        }
+        result
+    }
+}
+
+fn is_breaking_whitespace(ch: char) -> bool {
+    ch.is_whitespace() && !matches!(ch, '\u{a0}' | '\u{2007}' | '\u{202f}')
+}
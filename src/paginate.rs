@@ -0,0 +1,118 @@
+use crate::markup::MarkupSpan;
+
+/// How lines of dialogue too long for a dialogue box are split into pages, set with
+/// `YarnEngine::set_pagination`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pagination {
+    /// The maximum length of a page, in chars of plain text.
+    pub max_chars: usize,
+    /// Where pages may end.
+    pub split_on: SplitOn,
+}
+
+impl Pagination {
+    /// Pages of at most the given number of chars, ending at sentence boundaries
+    /// where possible.
+    pub fn new(max_chars: usize) -> Pagination {
+        Pagination {
+            max_chars,
+            split_on: SplitOn::SentenceBoundary,
+        }
+    }
+}
+
+/// Where a page of a long line may end.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SplitOn {
+    /// After the last sentence that fits, ending with `.`, `!`, `?` or `…` and any
+    /// closing quotes or brackets. A sentence too long for a page is split between
+    /// words.
+    SentenceBoundary,
+    /// After the last word that fits.
+    Word,
+}
+
+/// Split a line's plain text and markup spans into pages. Whitespace between pages
+/// is dropped, and a word too long for a page is split within it. A span
+/// straddling a page break is cut into a span on each page it covers, and a
+/// self-closing span stays on the page where it occurs.
+pub(crate) fn paginate(
+    text: &str,
+    spans: &[MarkupSpan],
+    pagination: &Pagination,
+) -> Vec<(String, Vec<MarkupSpan>)> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let ranges = page_ranges(&chars, pagination);
+    ranges
+        .iter()
+        .enumerate()
+        .map(|(idx, &(start, end))| {
+            // The chars up to the next page, including dropped whitespace.
+            let owned = match idx {
+                0 => 0,
+                _ => start,
+            }..ranges.get(idx + 1).map_or(usize::MAX, |next| next.0);
+            let text = chars[start..end].iter().collect();
+            let spans = spans
+                .iter()
+                .filter_map(|span| {
+                    let span_end = span.start + span.length;
+                    let covered = match span.length {
+                        0 => owned.contains(&span.start),
+                        _ => span.start < end && span_end > start,
+                    };
+                    let span_start = span.start.clamp(start, end);
+                    covered.then(|| MarkupSpan {
+                        start: span_start - start,
+                        length: span_end.min(end).saturating_sub(span_start),
+                        ..span.clone()
+                    })
+                })
+                .collect();
+            (text, spans)
+        })
+        .collect()
+}
+
+/// The ranges of chars on each page, without surrounding whitespace.
+fn page_ranges(chars: &[char], pagination: &Pagination) -> Vec<(usize, usize)> {
+    let max = pagination.max_chars.max(1);
+    let skip_space = |mut idx: usize| {
+        while chars.get(idx).is_some_and(|c| c.is_whitespace()) {
+            idx += 1;
+        }
+        idx
+    };
+    let trim_end = |start: usize, mut end: usize| {
+        while end > start && chars[end - 1].is_whitespace() {
+            end -= 1;
+        }
+        end
+    };
+    let len = trim_end(0, chars.len());
+    let mut pages = vec![];
+    let mut start = skip_space(0).min(len);
+    while len - start > max {
+        let limit = start + max;
+        // A page can end at whitespace, which is left out.
+        let breaks = (start + 1..=limit)
+            .rev()
+            .filter(|&idx| chars[idx].is_whitespace());
+        let sentence_end = |idx: &usize| {
+            let mut end = *idx;
+            while end > start && "\"'”’)]".contains(chars[end - 1]) {
+                end -= 1;
+            }
+            end > start && ".!?…".contains(chars[end - 1])
+        };
+        let end = match pagination.split_on {
+            SplitOn::SentenceBoundary => breaks.clone().find(sentence_end),
+            SplitOn::Word => None,
+        };
+        let end = end.or_else(|| breaks.clone().next()).unwrap_or(limit);
+        pages.push((start, trim_end(start, end)));
+        start = skip_space(end);
+    }
+    pages.push((start, len));
+    pages
+}
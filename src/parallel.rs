@@ -0,0 +1,39 @@
+use crate::engine::{Node, NodeNames};
+use crate::error::YarnError;
+use crate::parse::{self, ParseOptions, TokenIterator};
+use rayon::prelude::*;
+use std::sync::Arc;
+
+/// Parse the given string as a series of nodes, producing the same nodes and errors
+/// as `parse::parse_nodes_serially`. Headers and node boundaries are scanned on the
+/// current thread, then the bodies are parsed on rayon's thread pool.
+pub(crate) fn parse_nodes(
+    s: &str,
+    options: &ParseOptions,
+    names: &NodeNames,
+) -> Result<Vec<Node>, YarnError> {
+    let scan_options = ParseOptions {
+        lazy_bodies: true,
+        ..options.clone()
+    };
+    let mut tokenizer = TokenIterator::with_options(s, scan_options);
+    tokenizer.set_names(names);
+    let mut nodes = vec![];
+    let scanned = parse::parse_nodes_into(&mut tokenizer, &mut nodes);
+    let scanned = tokenizer.checked(scanned);
+
+    nodes.par_iter().for_each(|node| {
+        let _ = node.steps();
+    });
+
+    // Report the first error in source order, as the serial parser would: a body
+    // error in an earlier node takes precedence over a failure to scan a later one.
+    for node in &mut nodes {
+        if let Some(body) = node.lazy_body.take() {
+            let body = Arc::try_unwrap(body).expect("body is not shared");
+            (node.steps, node.step_lines) = body.into_steps()?;
+        }
+    }
+    scanned?;
+    Ok(nodes)
+}
@@ -1,22 +1,115 @@
-use crate::engine::{BinaryOp, Choice, Expr, Node, NodeName, Step, Term, UnaryOp, VariableName};
+use crate::engine::{
+    Assertion, BinaryOp, Choice, Expr, GroupLine, LineGroup, Node, NodeName, NodeNames, OptionCase,
+    Step, Term, UnaryOp, Value, VariableName, YarnType,
+};
+use crate::error::YarnError;
+use crate::flags;
+use crate::markup;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::{Arc, OnceLock};
 
+/// Parse an expression. The conditional operator `condition ? a : b` has the lowest
+/// precedence, below `??` and `or`, and is right associative.
 pub(crate) fn parse_expr(tokenizer: &mut TokenIterator) -> Result<Expr, ()> {
+    let depth = tokenizer.expression_depth;
+    let expr = parse_expr_nested(tokenizer);
+    tokenizer.expression_depth = depth;
+    expr
+}
+
+/// Parse an expression, counting each operator towards the expression depth limit
+/// since each one may deepen the resulting tree.
+fn parse_expr_nested(tokenizer: &mut TokenIterator) -> Result<Expr, ()> {
+    let mut operands = vec![parse_operand(tokenizer)?];
+    let mut operators: Vec<BinaryOp> = vec![];
+    loop {
+        match tokenizer.peek_past_spaces() {
+            Some(')') | Some(',') | Some(':') | None => break,
+            _ => (),
+        }
+        let op = match parse_binary_op(tokenizer)? {
+            Some(op) => op,
+            None => {
+                tokenizer.nest(ParseLimit::ExpressionDepth)?;
+                while !operators.is_empty() {
+                    reduce(&mut operands, &mut operators);
+                }
+                let condition = operands.pop().unwrap();
+                let if_true = parse_expr(tokenizer)?;
+                if tokenizer.next().ok_or(())? != Token::Colon {
+                    return Err(());
+                }
+                let if_false = parse_expr(tokenizer)?;
+                return Ok(Expr::Ternary(
+                    Box::new(condition),
+                    Box::new(if_true),
+                    Box::new(if_false),
+                ));
+            }
+        };
+        // All binary operators are left associative, so reduce any pending operator
+        // that binds at least as tightly as this one.
+        while operators
+            .last()
+            .is_some_and(|top| top.precedence() >= op.precedence())
+        {
+            reduce(&mut operands, &mut operators);
+        }
+        tokenizer.nest(ParseLimit::ExpressionDepth)?;
+        operators.push(op);
+        operands.push(parse_operand(tokenizer)?);
+    }
+    while !operators.is_empty() {
+        reduce(&mut operands, &mut operators);
+    }
+    Ok(operands.pop().unwrap())
+}
+
+fn reduce(operands: &mut Vec<Expr>, operators: &mut Vec<BinaryOp>) {
+    let op = operators.pop().unwrap();
+    let right = operands.pop().unwrap();
+    let left = operands.pop().unwrap();
+    operands.push(Expr::Binary(op, Box::new(left), Box::new(right)));
+}
+
+/// Parse a single term, possibly preceded by unary operators.
+fn parse_operand(tokenizer: &mut TokenIterator) -> Result<Expr, ()> {
+    let depth = tokenizer.expression_depth;
+    let operand = parse_operand_nested(tokenizer);
+    tokenizer.expression_depth = depth;
+    operand
+}
+
+fn parse_operand_nested(tokenizer: &mut TokenIterator) -> Result<Expr, ()> {
+    tokenizer.nest(ParseLimit::ExpressionDepth)?;
     let t = tokenizer.next().ok_or(())?;
-    let left = match t {
+    let operand = match t {
         Token::Number(num) => Expr::Term(Term::Number(num)),
         Token::ExclamationMark => {
-            let expr = parse_expr(tokenizer)?;
+            let expr = parse_operand(tokenizer)?;
             Expr::Unary(UnaryOp::Not, Box::new(expr))
         }
         Token::Word(ref w) if w == "not" => {
-            let expr = parse_expr(tokenizer)?;
+            let expr = parse_operand(tokenizer)?;
             Expr::Unary(UnaryOp::Not, Box::new(expr))
         }
-        Token::Word(ref w) if w == "true" => Expr::Term(Term::Boolean(true)),
-        Token::Word(ref w) if w == "false" => Expr::Term(Term::Boolean(false)),
+        Token::Word(ref w) if w.eq_ignore_ascii_case("true") || w.eq_ignore_ascii_case("false") => {
+            if tokenizer.options().strict_boolean_literals && w != "true" && w != "false" {
+                return Err(());
+            }
+            Expr::Term(Term::Boolean(w.eq_ignore_ascii_case("true")))
+        }
+        Token::Word(ref w) if w == "null" => Expr::Term(Term::Null),
+        Token::Word(ref w) if !is_call(tokenizer, w) => {
+            // A constant or enum case, after which the rest of the word is pushed back.
+            let (term, len) = parse_name(w)?;
+            tokenizer.position -= w.len() - len;
+            Expr::Term(term)
+        }
         Token::Word(ref w) => {
-            println!("function? {}", w);
             match tokenizer.next().ok_or(())? {
                 Token::LeftParenthesis => (),
                 _ => return Err(()),
@@ -35,32 +128,81 @@ pub(crate) fn parse_expr(tokenizer: &mut TokenIterator) -> Result<Expr, ()> {
                     break;
                 }
                 args.push(parse_expr(tokenizer)?);
-                println!("arg: {:?}", args.last().unwrap());
             }
-            println!("function with {} args", args.len());
             Expr::Term(Term::Function(w.to_string(), args))
         }
-        Token::Quote => Expr::Term(Term::String(parse_string_until(tokenizer, '"')?)),
+        Token::Quote => Expr::Term(Term::String(
+            parse_string_until(tokenizer, '"')?.to_string(),
+        )),
         Token::Minus => {
-            let expr = parse_expr(tokenizer)?;
+            let expr = parse_operand(tokenizer)?;
             Expr::Unary(UnaryOp::Negate, Box::new(expr))
         }
         Token::DollarSign => {
-            let name = match tokenizer.next().ok_or(())? {
-                Token::Word(name) => name,
-                _ => return Err(()),
-            };
-            Expr::Term(Term::Variable(VariableName(name)))
+            let name = tokenizer.take_while(is_identifier_continue);
+            if !is_identifier(name) {
+                return Err(());
+            }
+            Expr::Term(Term::Variable(VariableName(name.to_string())))
+        }
+        Token::LeftParenthesis => {
+            let expr = parse_expr(tokenizer)?;
+            if tokenizer.next().ok_or(())? != Token::RightParenthesis {
+                return Err(());
+            }
+            Expr::Parentheses(Box::new(expr))
         }
-        Token::LeftParenthesis => Expr::Parentheses(Box::new(parse_expr(tokenizer)?)),
         _ => return Err(()),
     };
-    match tokenizer.peek() {
-        Some(')') | Some(',') | None => return Ok(left),
-        _ => (),
+    Ok(operand)
+}
+
+/// Whether the word just consumed names a function being called rather than a
+/// constant. Spaces are only skipped after a complete identifier, so that the rest
+/// of a word such as `MAX>3` can still be pushed back.
+fn is_call(tokenizer: &mut TokenIterator, word: &str) -> bool {
+    if word.chars().all(is_identifier_continue) {
+        tokenizer.peek_past_spaces() == Some('(')
+    } else {
+        tokenizer.peek() == Some('(')
+    }
+}
+
+/// Parse the constant or enum case at the start of a word, returning it and its
+/// length in bytes.
+fn parse_name(word: &str) -> Result<(Term, usize), ()> {
+    fn identifier(s: &str) -> Result<&str, ()> {
+        let end = s.find(|ch| !is_identifier_continue(ch)).unwrap_or(s.len());
+        Some(&s[..end]).filter(|name| is_identifier(name)).ok_or(())
     }
+    if let Some(rest) = word.strip_prefix('.') {
+        let case = identifier(rest)?;
+        return Ok((Term::EnumCase(None, case.to_string()), case.len() + 1));
+    }
+    let name = identifier(word)?;
+    if let Some(rest) = word[name.len()..].strip_prefix('.') {
+        let case = identifier(rest)?;
+        let len = name.len() + case.len() + 1;
+        return Ok((
+            Term::EnumCase(Some(name.to_string()), case.to_string()),
+            len,
+        ));
+    }
+    if RESERVED_WORDS.contains(&name) {
+        return Err(());
+    }
+    Ok((Term::Constant(name.to_string()), name.len()))
+}
+
+/// Words that cannot name a constant, since they are literals or operators.
+const RESERVED_WORDS: &[&str] = &[
+    "true", "false", "null", "not", "and", "or", "xor", "eq", "is", "neq", "le", "leq", "gt", "geq",
+];
 
-    let op = match tokenizer.next().unwrap() {
+/// Parse a binary operator, or return `None` if the next token begins the
+/// conditional operator instead.
+fn parse_binary_op(tokenizer: &mut TokenIterator) -> Result<Option<BinaryOp>, ()> {
+    let op = match tokenizer.next().ok_or(())? {
         Token::Plus => BinaryOp::Plus,
         Token::Minus => BinaryOp::Minus,
         Token::Star => BinaryOp::Multiply,
@@ -93,9 +235,18 @@ pub(crate) fn parse_expr(tokenizer: &mut TokenIterator) -> Result<Expr, ()> {
                 BinaryOp::GreaterThan
             }
         }
+        Token::QuestionMark => {
+            if tokenizer.peek() != Some('?') {
+                return Ok(None);
+            }
+            let _ = tokenizer.next();
+            BinaryOp::Coalesce
+        }
+        Token::Caret => BinaryOp::Xor,
         Token::Word(word) => match &*word {
             "and" => BinaryOp::And,
             "or" => BinaryOp::Or,
+            "xor" => BinaryOp::Xor,
             "eq" | "is" => BinaryOp::Equals,
             "neq" => BinaryOp::NotEquals,
             "le" => BinaryOp::LessThan,
@@ -106,8 +257,21 @@ pub(crate) fn parse_expr(tokenizer: &mut TokenIterator) -> Result<Expr, ()> {
         },
         _ => return Err(()),
     };
-    let right = parse_expr(tokenizer)?;
-    Ok(Expr::Binary(op, Box::new(left), Box::new(right)))
+    Ok(Some(op))
+}
+
+/// Parse the entirety of the given string as a single expression.
+pub(crate) fn parse_complete_expr(s: &str) -> Result<Expr, ()> {
+    parse_complete_expr_from(&mut TokenIterator::new(s))
+}
+
+/// Parse all remaining input of the tokenizer as a single expression.
+fn parse_complete_expr_from(tokenizer: &mut TokenIterator) -> Result<Expr, ()> {
+    let expr = parse_expr(tokenizer)?;
+    if tokenizer.next().is_some() {
+        return Err(());
+    }
+    Ok(expr)
 }
 
 #[derive(Debug, PartialEq)]
@@ -118,36 +282,58 @@ pub(crate) enum Line {
     Else,
     EndIf,
     Action(String),
-    Option(Option<String>, NodeName),
-    InlineOption(String, Option<String>),
+    /// A link option or jump: its text, target, condition, case and hashtags.
+    Option(
+        Option<String>,
+        NodeName,
+        Option<String>,
+        Option<String>,
+        Vec<String>,
+    ),
+    /// An inline option: its text, condition, case and hashtags.
+    InlineOption(String, Option<String>, Option<String>, Vec<String>),
+    Alternative(String, Option<String>, Vec<String>),
 }
 
 pub(crate) fn parse_line(tokenizer: &mut TokenIterator) -> Result<(u32, Line), ()> {
+    if tokenizer.peek().is_none() {
+        return tokenizer.fail(|| "unexpected end of input".to_string());
+    }
+    let text = tokenizer.peek_line().trim();
     let t = tokenizer.next().ok_or(())?;
     let indent = tokenizer.last_indent();
-    do_parse_line(t, tokenizer).map(|l| (indent, l))
+    match do_parse_line(t, tokenizer) {
+        Ok(line) => Ok((indent, line)),
+        Err(()) => tokenizer.fail(|| format!("invalid line `{}`", text)),
+    }
 }
 
 fn do_parse_line(token: Token, tokenizer: &mut TokenIterator) -> Result<Line, ()> {
     match token {
         Token::Word(mut text) => {
             let rest = tokenizer.remainder_of_line().ok_or(())?;
-            text += &rest;
-            return Ok(Line::Dialogue(text));
+            text += rest;
+            dialogue(text)
+        }
+        Token::QuestionMark | Token::Colon | Token::Caret => {
+            let first = match token {
+                Token::QuestionMark => '?',
+                Token::Colon => ':',
+                _ => '^',
+            };
+            let rest = tokenizer.remainder_of_line().unwrap_or_default();
+            dialogue(format!("{}{}", first, rest))
         }
         Token::LeftAngle => {
             if tokenizer.next().ok_or(())? != Token::LeftAngle {
                 return Err(());
             }
-            let rest = parse_string_until(tokenizer, '>')?;
-            if tokenizer.next().ok_or(())? != Token::RightAngle {
-                return Err(());
-            }
-            if rest.starts_with("if ") {
-                return Ok(Line::If(rest[3..].trim().to_owned()));
+            let rest = parse_string_until_str(tokenizer, ">>")?;
+            if let Some(expr) = rest.strip_prefix("if ") {
+                return Ok(Line::If(expr.trim().to_owned()));
             }
-            if rest.starts_with("elseif ") {
-                return Ok(Line::ElseIf(rest[7..].trim().to_owned()));
+            if let Some(expr) = rest.strip_prefix("elseif ") {
+                return Ok(Line::ElseIf(expr.trim().to_owned()));
             }
             if rest.trim() == "else" {
                 return Ok(Line::Else);
@@ -155,7 +341,7 @@ fn do_parse_line(token: Token, tokenizer: &mut TokenIterator) -> Result<Line, ()
             if rest.trim() == "endif" {
                 return Ok(Line::EndIf);
             }
-            return Ok(Line::Action(rest.trim().to_owned()));
+            Ok(Line::Action(rest.trim().to_owned()))
         }
         Token::LeftBracket => {
             if tokenizer.next().ok_or(())? != Token::LeftBracket {
@@ -165,63 +351,173 @@ fn do_parse_line(token: Token, tokenizer: &mut TokenIterator) -> Result<Line, ()
             if tokenizer.next().ok_or(())? != Token::RightBracket {
                 return Err(());
             }
+            let rest = tokenizer.remainder_of_line().unwrap_or_default();
+            let (before, case, cond, after) = split_condition(rest)?;
+            let (text, mut tags) = split_hashtags(before);
+            // Only a condition and hashtags may follow a link.
+            if !text.trim().is_empty() {
+                return Err(());
+            }
+            tags.extend(split_hashtags(after).1);
             let mut parts = contents.split('|');
             let first = parts.next().unwrap();
             let second = parts.next();
             if let Some(second) = second {
                 return Ok(Line::Option(
                     Some(first.to_string()),
-                    NodeName(second.to_string()),
+                    tokenizer.names.intern(second),
+                    cond,
+                    case,
+                    tags,
                 ));
             }
-            return Ok(Line::Option(None, NodeName(first.to_string())));
+            Ok(Line::Option(
+                None,
+                tokenizer.names.intern(first),
+                cond,
+                case,
+                tags,
+            ))
         }
-        Token::Minus => {
+        Token::Equals => {
             if tokenizer.next().ok_or(())? != Token::RightAngle {
                 return Err(());
             }
             let rest = tokenizer.remainder_of_line().ok_or(())?;
-            let (text, cond) = match rest.find("<<") {
-                Some(idx) => {
-                    let remainder = &rest[idx + 2..].trim();
-                    if !remainder.starts_with("if ") {
-                        return Err(());
-                    }
-                    let end = remainder.find(">>").ok_or(())?;
-                    (
-                        rest[..idx].trim().to_string(),
-                        Some(remainder[3..end].trim().to_string()),
-                    )
-                }
-                None => (rest.trim().to_string(), None),
-            };
-            return Ok(Line::InlineOption(text, cond));
+            let (text, case, cond, after) = split_condition(rest)?;
+            let (text, mut tags) = split_hashtags(text);
+            if contains_link(&text) || case.is_some() {
+                return Err(());
+            }
+            tags.extend(split_hashtags(after).1);
+            Ok(Line::Alternative(text.trim().to_string(), cond, tags))
         }
-        _ => return Err(()),
+        Token::Minus | Token::Star => {
+            if token == Token::Star && !tokenizer.options().star_options {
+                return Err(());
+            }
+            if token == Token::Minus && tokenizer.next().ok_or(())? != Token::RightAngle {
+                return Err(());
+            }
+            let rest = tokenizer.remainder_of_line().ok_or(())?;
+            let (text, case, cond, after) = split_condition(rest)?;
+            let (text, mut tags) = split_hashtags(text);
+            if contains_link(&text) {
+                return Err(());
+            }
+            tags.extend(split_hashtags(after).1);
+            Ok(Line::InlineOption(
+                text.trim().to_string(),
+                cond,
+                case,
+                tags,
+            ))
+        }
+        _ => Err(()),
     }
 }
 
-fn parse_string_until(tokenizer: &mut TokenIterator, until: char) -> Result<String, ()> {
-    let mut buffer = String::new();
-    loop {
-        let ch = tokenizer.next_char().ok_or(())?;
-        if ch == until {
-            return Ok(buffer);
+/// A line of dialogue, which must not contain a link: options and jumps occupy
+/// lines of their own.
+fn dialogue(text: String) -> Result<Line, ()> {
+    if contains_link(&text) {
+        return Err(());
+    }
+    Ok(Line::Dialogue(text))
+}
+
+/// Whether the text contains the brackets of an option or jump.
+fn contains_link(text: &str) -> bool {
+    text.contains("[[") || text.contains("]]")
+}
+
+/// The text of an option line, its case, its condition and the text after them.
+type SplitOption<'a> = (&'a str, Option<String>, Option<String>, &'a str);
+
+/// Split an option line at a trailing `<<case value>>` and `<<if condition>>`,
+/// each optional and in either order.
+fn split_condition(line: &str) -> Result<SplitOption<'_>, ()> {
+    let idx = match line.find("<<") {
+        Some(idx) => idx,
+        None => return Ok((line, None, None, "")),
+    };
+    let (mut case, mut condition) = (None, None);
+    let mut rest = &line[idx..];
+    while let Some(statement) = rest.strip_prefix("<<") {
+        let end = statement.find(">>").ok_or(())?;
+        let statement = statement[..end].trim();
+        let slot = match statement.split_once(char::is_whitespace) {
+            Some(("case", value)) => (&mut case, value),
+            Some(("if", value)) => (&mut condition, value),
+            _ => return Err(()),
+        };
+        if slot.0.replace(slot.1.trim().to_string()).is_some() {
+            return Err(());
         }
-        buffer.push(ch);
+        rest = rest[end + 4..].trim_start();
     }
+    Ok((&line[..idx], case, condition, rest))
+}
+
+/// Split any `#hashtags` off the end of a line, returning the remaining text and
+/// the tags without their leading `#`. A hashtag must be preceded by whitespace
+/// or begin the line, and must not be within `[nomarkup]`.
+pub(crate) fn split_hashtags(line: &str) -> (String, Vec<String>) {
+    let in_nomarkup = |i: usize| {
+        line[..i]
+            .rfind(markup::NOMARKUP)
+            .is_some_and(|open| !line[open..i].contains(markup::NOMARKUP_END))
+    };
+    let start = line.char_indices().find(|&(i, ch)| {
+        ch == '#' && (i == 0 || line[..i].ends_with(char::is_whitespace)) && !in_nomarkup(i)
+    });
+    match start {
+        Some((i, _)) => {
+            let tags = line[i..]
+                .split_whitespace()
+                .filter_map(|tag| tag.strip_prefix('#'))
+                .filter(|tag| !tag.is_empty())
+                .map(|tag| tag.to_string())
+                .collect();
+            (line[..i].trim_end().to_string(), tags)
+        }
+        None => (line.to_string(), vec![]),
+    }
+}
+
+/// Consume characters up to and including the given delimiter, returning the
+/// characters before it.
+fn parse_string_until<'a>(tokenizer: &mut TokenIterator<'a>, until: char) -> Result<&'a str, ()> {
+    tokenizer
+        .take_until(until.encode_utf8(&mut [0; 4]))
+        .ok_or(())
+}
+
+/// Consume characters up to and including the given delimiter, returning the
+/// characters before it.
+fn parse_string_until_str<'a>(
+    tokenizer: &mut TokenIterator<'a>,
+    until: &str,
+) -> Result<&'a str, ()> {
+    tokenizer.take_until(until).ok_or(())
 }
 
 #[derive(Debug)]
 enum DialogueOption {
-    Inline(String, Option<String>),
-    External(String, NodeName),
+    Inline(String, Option<String>, Option<String>, Vec<String>),
+    External(
+        String,
+        NodeName,
+        Option<String>,
+        Option<String>,
+        Vec<String>,
+    ),
 }
 
 fn try_parse_option(
     tokenizer: &mut TokenIterator,
     indent: u32,
-) -> Result<Option<DialogueOption>, ()> {
+) -> Result<Option<(u32, DialogueOption)>, ()> {
     let t = match tokenizer.peek() {
         Some(t) => t,
         None => return Ok(None),
@@ -229,11 +525,26 @@ fn try_parse_option(
     if tokenizer.last_indent() < indent {
         return Ok(None);
     }
-    if t == '[' || t == '-' {
-        let (_indent, line) = parse_line(tokenizer)?;
+    if t == '[' {
+        // A bracketed line without a pipe is a jump, which ends the options.
+        let line = tokenizer.peek_line();
+        let contents = line.split("]]").next().unwrap_or("");
+        if !contents.contains('|') {
+            return Ok(None);
+        }
+    }
+    let star = t == '*' && tokenizer.options().star_options;
+    if t == '[' || t == '-' || star {
+        let (option_indent, line) = parse_line(tokenizer)?;
         match line {
-            Line::Option(Some(text), name) => Ok(Some(DialogueOption::External(text, name))),
-            Line::InlineOption(s, condition) => Ok(Some(DialogueOption::Inline(s, condition))),
+            Line::Option(Some(text), name, condition, case, tags) => Ok(Some((
+                option_indent,
+                DialogueOption::External(text, name, condition, case, tags),
+            ))),
+            Line::InlineOption(s, condition, case, tags) => Ok(Some((
+                option_indent,
+                DialogueOption::Inline(s, condition, case, tags),
+            ))),
             _ => unreachable!(),
         }
     } else {
@@ -243,7 +554,7 @@ fn try_parse_option(
 
 struct ConditionalParts {
     if_steps: Vec<Step>,
-    else_ifs: Vec<(Expr, Vec<Step>)>,
+    else_ifs: Vec<(Arc<Expr>, Vec<Step>)>,
     else_steps: Vec<Step>,
 }
 
@@ -255,6 +566,18 @@ enum ConditionalParsePhase {
 }
 
 fn parse_conditional(tokenizer: &mut TokenIterator, indent: u32) -> Result<ConditionalParts, ()> {
+    let depth = tokenizer.block_depth;
+    let parts = parse_conditional_nested(tokenizer, indent);
+    tokenizer.block_depth = depth;
+    parts
+}
+
+fn parse_conditional_nested(
+    tokenizer: &mut TokenIterator,
+    indent: u32,
+) -> Result<ConditionalParts, ()> {
+    tokenizer.nest(ParseLimit::BlockDepth)?;
+    let start = tokenizer.line();
     let mut parts = ConditionalParts {
         if_steps: vec![],
         else_ifs: vec![],
@@ -262,20 +585,22 @@ fn parse_conditional(tokenizer: &mut TokenIterator, indent: u32) -> Result<Condi
     };
     let mut phase = ConditionalParsePhase::If;
     loop {
-        let (_index, line) = parse_line(tokenizer)?;
+        if tokenizer.peek().is_none() || tokenizer.peek_line().starts_with("===") {
+            return tokenizer.fail_at(start, || "`<<if>>` without `<<endif>>`".to_string());
+        }
+        let (line_indent, line) = parse_line(tokenizer)?;
         match line {
             Line::ElseIf(s) => {
                 if phase == ConditionalParsePhase::Else {
-                    return Err(());
+                    return tokenizer.fail(|| "`<<elseif>>` after `<<else>>`".to_string());
                 }
                 phase = ConditionalParsePhase::ElseIf;
-                let mut expr_tokenizer = TokenIterator::new(&s);
-                let expr = parse_expr(&mut expr_tokenizer)?;
+                let expr = parse_condition(tokenizer, &s)?;
                 parts.else_ifs.push((expr, vec![]));
             }
             Line::Else => {
                 if phase == ConditionalParsePhase::Else {
-                    return Err(());
+                    return tokenizer.fail(|| "`<<else>>` after `<<else>>`".to_string());
                 }
                 phase = ConditionalParsePhase::Else;
             }
@@ -283,6 +608,9 @@ fn parse_conditional(tokenizer: &mut TokenIterator, indent: u32) -> Result<Condi
                 return Ok(parts);
             }
             l => {
+                if tokenizer.options().indented_conditionals && line_indent <= indent {
+                    return tokenizer.fail(|| "line in `<<if>>` is not indented".to_string());
+                }
                 let step = parse_toplevel_line(tokenizer, l, indent)?;
                 let steps = match phase {
                     ConditionalParsePhase::If => &mut parts.if_steps,
@@ -297,72 +625,433 @@ fn parse_conditional(tokenizer: &mut TokenIterator, indent: u32) -> Result<Condi
     }
 }
 
-fn parse_toplevel_line(tokenizer: &mut TokenIterator, line: Line, indent: u32) -> Result<Step, ()> {
-    match line {
-        Line::Dialogue(s) => {
-            println!("found dialogue '{}'", s);
-            let mut choices = vec![];
-            loop {
-                let opt = try_parse_option(tokenizer, indent)?;
-                println!("found opt {:?} with indent {}", opt, indent);
-                match opt {
-                    Some(DialogueOption::Inline(text, condition)) => {
-                        println!("peeking after inline opt: {:?}", tokenizer.peek());
-                        let this_indent = tokenizer.last_indent();
-                        println!("this indent: {}", this_indent);
-                        let mut steps = vec![];
-                        loop {
-                            if tokenizer.peek().is_none() || tokenizer.last_indent() < this_indent {
-                                break;
-                            }
-                            steps.push(parse_step(tokenizer)?);
-                        }
-                        let condition = match condition {
-                            Some(c) => {
-                                let mut expr_tokenizer = TokenIterator::new(&c);
-                                Some(parse_expr(&mut expr_tokenizer)?)
-                            }
-                            None => None,
-                        };
-                        choices.push(Choice::inline(text, steps, condition));
-                    }
-                    Some(DialogueOption::External(text, node)) => {
-                        choices.push(Choice::external(text, node));
-                    }
-                    None => break,
-                }
+/// Parse the steps of an inline option, which are indented deeper than the option.
+fn parse_option_body(tokenizer: &mut TokenIterator, option_indent: u32) -> Result<Vec<Step>, ()> {
+    let depth = tokenizer.block_depth;
+    tokenizer.nest(ParseLimit::BlockDepth)?;
+    // Peeking skips the indentation of the first line of the body.
+    tokenizer.peek();
+    let this_indent = tokenizer.last_indent();
+    let mut steps = vec![];
+    loop {
+        if tokenizer.peek().is_none()
+            || this_indent <= option_indent
+            || tokenizer.last_indent() < this_indent
+        {
+            break;
+        }
+        steps.push(parse_step(tokenizer)?);
+    }
+    tokenizer.block_depth = depth;
+    Ok(steps)
+}
+
+/// Parse the condition of an option, if it has one.
+fn parse_option_condition(
+    tokenizer: &TokenIterator,
+    condition: Option<String>,
+) -> Result<Option<Arc<Expr>>, ()> {
+    match condition {
+        Some(c) => parse_condition(tokenizer, &c).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Parse a condition, sharing it with any identical condition already loaded.
+fn parse_condition(tokenizer: &TokenIterator, s: &str) -> Result<Arc<Expr>, ()> {
+    let expr = match parse_expr(&mut tokenizer.nested(s)) {
+        Ok(expr) => expr,
+        Err(()) => return tokenizer.fail(|| format!("invalid condition `{}`", s)),
+    };
+    Ok(tokenizer.names.intern_condition(expr))
+}
+
+/// Parse a line of dialogue and the options that follow it. The options may have
+/// cases only if a selector is given, and at least one must have a case then.
+fn parse_dialogue(
+    tokenizer: &mut TokenIterator,
+    line: &str,
+    indent: u32,
+    selector: Option<&Arc<Expr>>,
+) -> Result<Step, ()> {
+    let (s, tags) = split_hashtags(line);
+    let mut choices = vec![];
+    loop {
+        let opt = try_parse_option(tokenizer, indent)?;
+        let choice = match opt {
+            Some((option_indent, DialogueOption::Inline(text, condition, case, option_tags))) => {
+                let condition = parse_option_condition(tokenizer, condition)?;
+                let case = parse_option_case(tokenizer, selector, case)?;
+                let steps = parse_option_body(tokenizer, option_indent)?;
+                Choice::inline(text, steps)
+                    .with_condition(condition)
+                    .with_case(case)
+                    .with_tags(option_tags)
+            }
+            Some((_, DialogueOption::External(text, node, condition, case, option_tags))) => {
+                let condition = parse_option_condition(tokenizer, condition)?;
+                let case = parse_option_case(tokenizer, selector, case)?;
+                Choice::external(text, node)
+                    .with_condition(condition)
+                    .with_case(case)
+                    .with_tags(option_tags)
             }
-            return Ok(Step::Dialogue(s, choices));
+            None => break,
+        };
+        choices.push(choice);
+    }
+    if selector.is_some() && choices.iter().all(|choice| choice.case.is_none()) {
+        return tokenizer.fail(|| "`<<options match>>` without any `<<case>>`".to_string());
+    }
+    Ok(Step::Dialogue(s, choices, tags))
+}
+
+/// Parse the case of an option, which requires a selector. A case without a
+/// comparison operator compares for equality.
+fn parse_option_case(
+    tokenizer: &TokenIterator,
+    selector: Option<&Arc<Expr>>,
+    case: Option<String>,
+) -> Result<Option<OptionCase>, ()> {
+    let (selector, case) = match (selector, case) {
+        (_, None) => return Ok(None),
+        (None, Some(_)) => {
+            return tokenizer.fail(|| "`<<case>>` without `<<options match>>`".to_string())
         }
+        (Some(selector), Some(case)) => (selector, case),
+    };
+    if case == "else" {
+        return Ok(Some(OptionCase::Else));
+    }
+    let mut operation = tokenizer.nested(&case);
+    let op = match parse_binary_op(&mut operation) {
+        Ok(Some(
+            op @ (BinaryOp::Equals
+            | BinaryOp::NotEquals
+            | BinaryOp::LessThan
+            | BinaryOp::LessThanEqual
+            | BinaryOp::GreaterThan
+            | BinaryOp::GreaterThanEqual),
+        )) => op,
+        _ => {
+            operation = tokenizer.nested(&case);
+            BinaryOp::Equals
+        }
+    };
+    Ok(Some(OptionCase::Compare {
+        selector: selector.clone(),
+        op,
+        operand: parse_complete_expr_from(&mut operation)?,
+    }))
+}
+
+fn parse_toplevel_line(tokenizer: &mut TokenIterator, line: Line, indent: u32) -> Result<Step, ()> {
+    // Steps begin in source order, each before the steps nested in it.
+    tokenizer.step_lines.push(tokenizer.line());
+    match line {
+        Line::Dialogue(s) => parse_dialogue(tokenizer, &s, indent, None),
         Line::If(s) => {
-            let mut expr_tokenizer = TokenIterator::new(&s);
-            let expr = parse_expr(&mut expr_tokenizer)?;
+            let expr = parse_condition(tokenizer, &s)?;
             let parts = parse_conditional(tokenizer, indent)?;
-            return Ok(Step::Conditional(
+            Ok(Step::Conditional(
                 expr,
                 parts.if_steps,
                 parts.else_ifs,
                 parts.else_steps,
-            ));
+            ))
+        }
+        Line::Action(s) if s.starts_with("options match ") => {
+            // The options of the following line are matched against the selector.
+            let selector = parse_condition(tokenizer, &s["options match ".len()..])?;
+            match parse_line(tokenizer)? {
+                (indent, Line::Dialogue(s)) => {
+                    parse_dialogue(tokenizer, &s, indent, Some(&selector))
+                }
+                _ => tokenizer.fail(|| {
+                    "`<<options match>>` must be followed by a line with options".to_string()
+                }),
+            }
         }
-        Line::Action(s) => {
-            if s.starts_with("set ") {
-                let rest = &s[4..].trim();
-                let var_end = rest.find(' ').ok_or(())?;
-                let var = &rest[0..var_end];
-                let mut tokenizer = TokenIterator::new(&rest[var_end..]);
-                let expr = parse_expr(&mut tokenizer)?;
-                return Ok(Step::Assign(VariableName(var.to_string()), expr));
+        Line::Action(s) => match parse_action(tokenizer, &s) {
+            // Exceeding a limit still fails, as the statement may be hostile.
+            Err(())
+                if tokenizer.options().forward_compatible
+                    && tokenizer.limit_exceeded.get().is_none() =>
+            {
+                tokenizer.failure.borrow_mut().take();
+                Ok(Step::Unknown(s))
             }
-            return Ok(Step::Command(s));
+            Err(()) => tokenizer.fail(|| format!("invalid statement `<<{}>>`", s)),
+            step => step,
+        },
+        Line::Alternative(text, condition, tags) => {
+            let line = tokenizer.line();
+            let mut lines = vec![];
+            let mut next = Some((text, condition, tags));
+            // The group continues while the following lines at the same indentation
+            // also begin with `=>`.
+            while let Some((text, condition, tags)) = next.take() {
+                let condition = parse_option_condition(tokenizer, condition)?;
+                lines.push(GroupLine {
+                    text,
+                    condition,
+                    tags,
+                });
+                if tokenizer.peek() == Some('=')
+                    && tokenizer.last_indent() == indent
+                    && tokenizer.peek_line().starts_with("=>")
+                {
+                    if let Line::Alternative(text, condition, tags) = parse_line(tokenizer)?.1 {
+                        next = Some((text, condition, tags));
+                    }
+                }
+            }
+            Ok(Step::LineGroup(LineGroup { lines, line }))
+        }
+        Line::Option(None, name, None, None, _) => Ok(Step::Jump(name)),
+        Line::EndIf => tokenizer.fail(|| "`<<endif>>` without `<<if>>`".to_string()),
+        Line::ElseIf(_) => tokenizer.fail(|| "`<<elseif>>` without `<<if>>`".to_string()),
+        Line::Else => tokenizer.fail(|| "`<<else>>` without `<<if>>`".to_string()),
+        Line::Option(..) | Line::InlineOption(..) => {
+            tokenizer.fail(|| "option without a line of dialogue before it".to_string())
+        }
+    }
+}
+
+/// Parse the contents of a `<<statement>>` other than a conditional.
+fn parse_action(tokenizer: &mut TokenIterator, s: &str) -> Result<Step, ()> {
+    if let Some(rest) = s.strip_prefix("set ") {
+        let rest = rest.trim().strip_prefix('$').ok_or(())?;
+        let var_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .ok_or(())?;
+        let var = &rest[0..var_end];
+        if !is_identifier(var) {
+            return Err(());
+        }
+        // The variable may be followed by `to` or `=`, or directly by the value.
+        let value = rest[var_end..].trim_start();
+        let value = match value.strip_prefix("to ") {
+            Some(value) => value,
+            None => value.strip_prefix('=').unwrap_or(value),
+        };
+        let expr = parse_complete_expr_from(&mut tokenizer.nested(value))?;
+        return Ok(Step::Assign(VariableName(var.to_string()), expr));
+    }
+    let jump = s
+        .strip_prefix("jump")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+    if let Some(rest) = jump {
+        let name = rest.trim();
+        if name.is_empty() {
+            return Err(());
         }
-        Line::Option(None, name) => {
-            return Ok(Step::Jump(name));
+        return Ok(Step::Jump(tokenizer.names.intern(name)));
+    }
+    if let Some(rest) = s.strip_prefix("declare ") {
+        return parse_declaration(tokenizer, rest);
+    }
+    if let Some(rest) = s.strip_prefix("const ") {
+        return parse_constant(tokenizer, rest);
+    }
+    if let Some(name) = s.strip_prefix("enum ") {
+        return parse_enum(tokenizer, name);
+    }
+    if let Some(rest) = s.strip_prefix("assert ") {
+        return parse_assertion(tokenizer, rest).map(Step::Assert);
+    }
+    let ret = s
+        .strip_prefix("return")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+    if let Some(rest) = ret {
+        if rest.trim().is_empty() {
+            return Ok(Step::Return(None));
+        }
+        let expr = parse_complete_expr_from(&mut tokenizer.nested(rest))?;
+        return Ok(Step::Return(Some(expr)));
+    }
+    if let Some(name) = s.strip_prefix("flag ") {
+        let name = name.trim();
+        if !is_identifier(name) {
+            return Err(());
+        }
+        let set = Expr::Term(Term::Boolean(true));
+        return Ok(Step::Assign(flags::flag_variable(name), set));
+    }
+    let log = s
+        .strip_prefix("log")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+    if let Some(rest) = log {
+        return parse_log_args(tokenizer, rest).map(Step::Log);
+    }
+    Ok(Step::Command(s.to_string()))
+}
+
+/// Parse the arguments of `<<assert condition>>` or `<<assert condition, "message">>`.
+fn parse_assertion(tokenizer: &TokenIterator, s: &str) -> Result<Assertion, ()> {
+    let mut nested = tokenizer.nested(s);
+    let condition = parse_expr(&mut nested)?;
+    let text = s[..nested.position].trim().to_string();
+    let message = match nested.next() {
+        None => None,
+        Some(Token::Comma) => {
+            if nested.next() != Some(Token::Quote) {
+                return Err(());
+            }
+            let message = parse_string_until(&mut nested, '"')?.to_string();
+            if nested.next().is_some() {
+                return Err(());
+            }
+            Some(message)
+        }
+        Some(_) => return Err(()),
+    };
+    Ok(Assertion {
+        condition,
+        text,
+        message,
+        line: tokenizer.line(),
+    })
+}
+
+/// Parse `<<declare $name = default>>` or `<<declare $name = default as Type>>`. The
+/// default must be a literal, whose type must match the type given.
+fn parse_declaration(tokenizer: &TokenIterator, s: &str) -> Result<Step, ()> {
+    let rest = s.trim().strip_prefix('$').ok_or(())?;
+    let name_end = rest
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .ok_or(())?;
+    let value = rest[name_end..].trim_start();
+    let value = match value.strip_prefix("to ") {
+        Some(value) => value,
+        None => value.strip_prefix('=').ok_or(())?,
+    };
+    let (value, declared) = match value.rsplit_once(" as ") {
+        Some((value, ty)) => match ty.trim().to_lowercase().as_str() {
+            "number" => (value, Some(YarnType::Number)),
+            "string" => (value, Some(YarnType::String)),
+            "bool" | "boolean" => (value, Some(YarnType::Boolean)),
+            _ => (value, None),
+        },
+        None => (value, None),
+    };
+    let value = parse_literal(tokenizer, value)?;
+    let ty = YarnType::of(&value);
+    if declared.is_some_and(|declared| declared != ty) {
+        return Err(());
+    }
+    let name = &rest[..name_end];
+    if !is_identifier(name) {
+        return Err(());
+    }
+    Ok(Step::Declare(VariableName(name.to_string()), value, ty))
+}
+
+/// Parse `<<const NAME = value>>`. The value must be a literal.
+fn parse_constant(tokenizer: &TokenIterator, s: &str) -> Result<Step, ()> {
+    let (name, value) = s.split_once('=').ok_or(())?;
+    let name = name.trim();
+    if !is_identifier(name) || RESERVED_WORDS.contains(&name) {
+        return Err(());
+    }
+    let value = parse_literal(tokenizer, value)?;
+    Ok(Step::Const(name.to_string(), value))
+}
+
+/// Parse the cases of `<<enum Name>>` up to `<<endenum>>`, each declared with
+/// `<<case Name>>` on a line of its own.
+fn parse_enum(tokenizer: &mut TokenIterator, name: &str) -> Result<Step, ()> {
+    let name = name.trim();
+    if !is_identifier(name) || RESERVED_WORDS.contains(&name) {
+        return Err(());
+    }
+    let start = tokenizer.line();
+    let mut cases: Vec<String> = vec![];
+    loop {
+        if tokenizer.peek().is_none() || tokenizer.peek_line().starts_with("===") {
+            return tokenizer.fail_at(start, || "`<<enum>>` without `<<endenum>>`".to_string());
         }
-        Line::EndIf | Line::ElseIf(_) | Line::Else | Line::Option(..) | Line::InlineOption(..) => {
-            return Err(())
+        match parse_line(tokenizer)?.1 {
+            Line::Action(s) if s == "endenum" => break,
+            Line::Action(s) => {
+                let case = s.strip_prefix("case ").ok_or(())?.trim();
+                if !is_identifier(case) || cases.iter().any(|other| other == case) {
+                    return Err(());
+                }
+                cases.push(case.to_string());
+            }
+            _ => return Err(()),
         }
     }
+    if cases.is_empty() {
+        return Err(());
+    }
+    Ok(Step::Enum(name.to_string(), cases))
+}
+
+/// Parse a number, string, boolean, enum case or null literal, which may be negated.
+/// The enum of a shorthand case such as `.Happy` is left empty, to be resolved on
+/// load.
+fn parse_literal(tokenizer: &TokenIterator, s: &str) -> Result<Value, ()> {
+    match parse_complete_expr_from(&mut tokenizer.nested(s))? {
+        Expr::Term(Term::EnumCase(type_name, case)) => Ok(Value::Enum {
+            type_name: type_name.unwrap_or_default(),
+            case,
+        }),
+        Expr::Term(Term::Number(n)) => Ok(Value::Number(n)),
+        Expr::Term(Term::String(s)) => Ok(Value::String(s)),
+        Expr::Term(Term::Boolean(b)) => Ok(Value::Boolean(b)),
+        Expr::Term(Term::Null) => Ok(Value::Null),
+        Expr::Unary(UnaryOp::Negate, expr) => match *expr {
+            Expr::Term(Term::Number(n)) => Ok(Value::Number(-n)),
+            _ => Err(()),
+        },
+        _ => Err(()),
+    }
+}
+
+/// Whether the text is a valid variable name, without its leading `$`: a letter or
+/// `_` followed by letters, digits, `_` and combining marks, so `$café_visité` and
+/// `$名前` are valid in any normalization form. Node titles are not restricted,
+/// except that they cannot contain `|`, `]]` or line breaks where they appear in
+/// links.
+pub(crate) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(is_identifier_start) && chars.all(is_identifier_continue)
+}
+
+/// Whether the character may begin a variable name. This approximates Unicode's
+/// XID_Start property with `char::is_alphabetic`, plus `_`.
+pub(crate) fn is_identifier_start(ch: char) -> bool {
+    ch.is_alphabetic() || ch == '_'
+}
+
+/// Whether the character may continue a variable name. This approximates Unicode's
+/// XID_Continue property with `char::is_alphanumeric` and the common blocks of
+/// combining marks, plus `_`.
+pub(crate) fn is_identifier_continue(ch: char) -> bool {
+    ch.is_alphanumeric()
+        || ch == '_'
+        || matches!(
+            ch,
+            '\u{0300}'..='\u{036F}'
+                | '\u{1AB0}'..='\u{1AFF}'
+                | '\u{1DC0}'..='\u{1DFF}'
+                | '\u{20D0}'..='\u{20FF}'
+                | '\u{3099}'..='\u{309A}'
+                | '\u{FE20}'..='\u{FE2F}'
+        )
+}
+
+/// Parse the arguments of `<<log arg arg ...>>`. Each argument is a single operand,
+/// so operators must be parenthesized: `<<log "total" ($a + $b)>>`.
+fn parse_log_args(tokenizer: &TokenIterator, s: &str) -> Result<Vec<Expr>, ()> {
+    let mut nested = tokenizer.nested(s);
+    let mut args = vec![];
+    while nested.peek_past_spaces().is_some() {
+        args.push(parse_operand(&mut nested)?);
+    }
+    Ok(args)
 }
 
 pub(crate) fn parse_step(tokenizer: &mut TokenIterator) -> Result<Step, ()> {
@@ -373,14 +1062,18 @@ pub(crate) fn parse_step(tokenizer: &mut TokenIterator) -> Result<Step, ()> {
 pub(crate) fn parse_node_contents(tokenizer: &mut TokenIterator) -> Result<Vec<Step>, ()> {
     let mut steps = vec![];
     loop {
-        match tokenizer.peek().ok_or(())? {
-            '=' => {
+        let ch = match tokenizer.peek() {
+            Some(ch) => ch,
+            None => return tokenizer.fail(|| "node without `===`".to_string()),
+        };
+        match ch {
+            '=' if !tokenizer.peek_line().starts_with("=>") => {
+                let text = tokenizer.peek_line().trim();
                 let _ = tokenizer.next();
-                if tokenizer.next() != Some(Token::Equals) {
-                    return Err(());
-                }
-                if tokenizer.next() != Some(Token::Equals) {
-                    return Err(());
+                if tokenizer.next() != Some(Token::Equals)
+                    || tokenizer.next() != Some(Token::Equals)
+                {
+                    return tokenizer.fail(|| format!("invalid line `{}`", text));
                 }
                 return Ok(steps);
             }
@@ -390,53 +1083,321 @@ pub(crate) fn parse_node_contents(tokenizer: &mut TokenIterator) -> Result<Vec<S
 }
 
 pub(crate) fn parse_node(tokenizer: &mut TokenIterator) -> Result<Node, ()> {
-    let mut node = Node {
-        title: NodeName(String::new()),
-        extra: HashMap::new(),
-        steps: vec![],
-        visited: false,
-    };
+    let mut title = None;
+    let mut extra = HashMap::new();
     loop {
+        if tokenizer.peek().is_none() {
+            return tokenizer.fail(|| "node without `---`".to_string());
+        }
+        let text = tokenizer.peek_line().trim();
         let t = tokenizer.next().ok_or(())?;
         match t {
             Token::Word(name) => {
-                let value = tokenizer.remainder_of_line().ok_or(())?;
-                if name == "title:" {
-                    if !node.title.0.is_empty() {
-                        return Err(());
+                // Header names are case-insensitive and may be surrounded by whitespace.
+                let line = name + tokenizer.remainder_of_line().unwrap_or_default();
+                let (key, value) = match line.split_once(':') {
+                    Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+                    None => return tokenizer.fail(|| format!("invalid header `{}`", text)),
+                };
+                if key == "title" {
+                    if title.is_some() {
+                        return tokenizer.fail(|| "duplicate `title` header".to_string());
                     }
-                    node.title.0 = value.trim().to_string();
-                } else {
-                    node.extra
-                        .insert(name[..name.len() - 1].to_string(), value.trim().to_string());
+                    title = Some(tokenizer.names.intern(value));
+                } else if extra.insert(key.clone(), value.to_string()).is_some() {
+                    return tokenizer.fail(|| format!("duplicate `{}` header", key));
                 }
             }
             Token::Minus => {
-                if tokenizer.next().ok_or(())? != Token::Minus {
-                    return Err(());
+                if tokenizer.next() != Some(Token::Minus) || tokenizer.next() != Some(Token::Minus)
+                {
+                    return tokenizer.fail(|| format!("invalid header `{}`", text));
                 }
-                if tokenizer.next().ok_or(())? != Token::Minus {
-                    return Err(());
+                let mut node = Node {
+                    title: title.unwrap_or_else(|| tokenizer.names.intern("")),
+                    extra,
+                    source: None,
+                    steps: vec![],
+                    step_lines: vec![],
+                    lazy_body: None,
+                    stray_lines: vec![],
+                };
+                if tokenizer.options().lazy_bodies {
+                    node.lazy_body = Some(Arc::new(LazyBody::scan(tokenizer)?));
+                } else {
+                    (node.steps, node.step_lines) = parse_body(tokenizer)?;
                 }
-                node.steps = parse_node_contents(tokenizer)?;
                 return Ok(node);
             }
-            _ => return Err(()),
+            _ => return tokenizer.fail(|| format!("invalid header `{}`", text)),
+        }
+    }
+}
+
+/// The steps of a node body and the line of each.
+type Body = (Vec<Step>, Vec<usize>);
+
+/// Parse the steps of a node up to and including the closing `===`, along with the
+/// line of each step as given by `Node::step_lines`.
+fn parse_body(tokenizer: &mut TokenIterator) -> Result<Body, ()> {
+    tokenizer.reset_indentation_style();
+    tokenizer.step_lines.clear();
+    let steps = parse_node_contents(tokenizer)?;
+    if tokenizer.options().mixed_indentation == MixedIndentation::Error
+        && tokenizer.mixed_indentation()
+    {
+        return tokenizer.fail(|| "indentation mixes tabs and spaces".to_string());
+    }
+    Ok((steps, std::mem::take(&mut tokenizer.step_lines)))
+}
+
+/// The unparsed body of a node loaded with `ParseOptions::lazy_bodies`, which is
+/// parsed the first time its steps are needed.
+#[derive(Debug)]
+pub(crate) struct LazyBody {
+    /// The body, from the line after `---` through the closing `===`.
+    text: String,
+    options: ParseOptions,
+    names: NodeNames,
+    first_line: usize,
+    /// The parsed steps and the line of each.
+    steps: OnceLock<Result<Body, YarnError>>,
+}
+
+impl LazyBody {
+    /// Consume a node body without parsing it. The tokenizer must be positioned just
+    /// after the `---` that begins the body.
+    fn scan(tokenizer: &mut TokenIterator) -> Result<LazyBody, ()> {
+        tokenizer.remainder_of_line();
+        let start = tokenizer.position;
+        let first_line = tokenizer.line() + 1;
+        loop {
+            let line_start = tokenizer.position;
+            let line = match tokenizer.remainder_of_line() {
+                Some(line) => line,
+                None => return tokenizer.fail(|| "node without `===`".to_string()),
+            };
+            if line.trim_start().starts_with("===") {
+                let end = line_start + line.len();
+                return Ok(LazyBody {
+                    text: tokenizer.input[start..end].to_string(),
+                    options: tokenizer.options().clone(),
+                    names: tokenizer.names.clone(),
+                    first_line,
+                    steps: OnceLock::new(),
+                });
+            }
         }
     }
+
+    /// The parsed steps of the body and the line of each, parsing them if this is the
+    /// first use.
+    pub(crate) fn steps(&self) -> Result<(&[Step], &[usize]), YarnError> {
+        self.steps
+            .get_or_init(|| {
+                let mut tokenizer = TokenIterator::with_options(&self.text, self.options.clone());
+                tokenizer.names = self.names.clone();
+                tokenizer.first_line = self.first_line;
+                let steps = parse_body(&mut tokenizer);
+                tokenizer.checked(steps)
+            })
+            .as_ref()
+            .map(|(steps, lines)| (&steps[..], &lines[..]))
+            .map_err(Clone::clone)
+    }
+
+    /// Parse the body if necessary, returning the result.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn into_steps(self) -> Result<Body, YarnError> {
+        let _ = self.steps();
+        self.steps.into_inner().expect("body was just parsed")
+    }
+
+    /// Whether the unparsed body contains the given text.
+    pub(crate) fn contains(&self, text: &str) -> bool {
+        self.text.contains(text)
+    }
+}
+
+impl PartialEq for LazyBody {
+    fn eq(&self, other: &LazyBody) -> bool {
+        self.text == other.text
+    }
 }
 
 pub(crate) fn parse_nodes(tokenizer: &mut TokenIterator) -> Result<Vec<Node>, ()> {
     let mut nodes = vec![];
+    parse_nodes_into(tokenizer, &mut nodes)?;
+    Ok(nodes)
+}
+
+/// Parse nodes until the end of the input, appending them to the given vector. On
+/// error, the nodes parsed before the error are kept.
+pub(crate) fn parse_nodes_into(
+    tokenizer: &mut TokenIterator,
+    nodes: &mut Vec<Node>,
+) -> Result<(), ()> {
     while tokenizer.peek().is_some() {
-        nodes.push(parse_node(tokenizer)?);
+        if nodes.len() == tokenizer.options().max_nodes {
+            return tokenizer.exceeded(ParseLimit::Nodes);
+        }
+        match parse_framed_node(tokenizer)? {
+            Some(node) => nodes.push(node),
+            None => break,
+        }
     }
-    Ok(nodes)
+    Ok(())
+}
+
+/// Parse the next node, skipping any content outside a node before it according to
+/// `ParseOptions::stray_content`. Returns `None` if no node remains.
+fn parse_framed_node(tokenizer: &mut TokenIterator) -> Result<Option<Node>, ()> {
+    let mut stray_lines = skip_stray_content(tokenizer)?;
+    if tokenizer.peek().is_none() {
+        return Ok(None);
+    }
+    let mut node = parse_node(tokenizer)?;
+    // Skipped content after a node belongs to it, but is only rejected when the next
+    // node is parsed, so that earlier nodes of an incremental load are still read.
+    if tokenizer.options().stray_content == StrayContent::Warn {
+        stray_lines.extend(skip_stray_content(tokenizer)?);
+    }
+    node.stray_lines = stray_lines;
+    Ok(Some(node))
+}
+
+/// Move to the start of the next header block, returning the numbers of the lines
+/// skipped on the way, or failing at the first of them under `StrayContent::Error`.
+/// Blank lines and `//` comments are not stray. A run of headers without a `title`
+/// is not a header block, so a `Name: text` line set apart from the block by a
+/// blank line is stray.
+fn skip_stray_content(tokenizer: &mut TokenIterator) -> Result<Vec<usize>, ()> {
+    let rest = tokenizer.rest();
+    let ignored = |line: &str| line.is_empty() || line.starts_with("//");
+    let mut block = None;
+    let mut run = None;
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with("---") {
+            block = Some(match run {
+                Some((start, true)) => start,
+                _ => offset,
+            });
+            break;
+        } else if let Some(name) = header_name(trimmed) {
+            let (_, titled) = run.get_or_insert((offset, false));
+            *titled |= name.eq_ignore_ascii_case("title");
+        } else if !trimmed.starts_with("//") {
+            run = None;
+        }
+        offset += line.len();
+    }
+    let end = block.unwrap_or(rest.len());
+
+    let start = tokenizer.position;
+    let mut stray_lines = vec![];
+    let mut offset = 0;
+    for line in rest[..end].split_inclusive('\n') {
+        if !ignored(line.trim()) {
+            stray_lines.push(tokenizer.line_before(start + offset + 1));
+        }
+        offset += line.len();
+    }
+    if let (Some(&line), StrayContent::Error) =
+        (stray_lines.first(), tokenizer.options().stray_content)
+    {
+        return tokenizer.fail_at(line, || "content outside a node".to_string());
+    }
+    if end > 0 {
+        tokenizer.position = start + end;
+        tokenizer.start_of_line = true;
+        tokenizer.last_indent = 0;
+    }
+    Ok(stray_lines)
 }
 
-pub(crate) fn parse_nodes_from_string(s: &str) -> Result<Vec<Node>, ()> {
-    let mut tokenizer = TokenIterator::new(s);
-    parse_nodes(&mut tokenizer)
+/// The name of the header on the trimmed line, if it is a `name: value` header whose
+/// name is a single word.
+fn header_name(line: &str) -> Option<&str> {
+    let name = line.split_once(':')?.0.trim_end();
+    let starts_word = name
+        .chars()
+        .next()
+        .is_some_and(|ch| !ch.is_ascii_digit() && !"()|$<>=-+*/!?^\",[]".contains(ch));
+    if starts_word && !name.contains(char::is_whitespace) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Parse the given string as a series of nodes, reporting which limit was exceeded
+/// if the source is too large or deeply nested. With the `parallel` feature, node
+/// bodies are parsed across threads unless they are to be parsed lazily. Node names
+/// are interned in `names`.
+pub(crate) fn parse_nodes_from_string(
+    s: &str,
+    options: &ParseOptions,
+    names: &NodeNames,
+) -> Result<Vec<Node>, YarnError> {
+    let long_line = s
+        .split('\n')
+        .position(|line| line.len() > options.max_line_length);
+    if let Some(idx) = long_line {
+        return Err(YarnError::LimitExceeded {
+            limit: ParseLimit::LineLength,
+            line: idx + 1,
+        });
+    }
+    #[cfg(feature = "parallel")]
+    {
+        if !options.lazy_bodies {
+            return crate::parallel::parse_nodes(s, options, names);
+        }
+    }
+    parse_nodes_serially(s, options, names)
+}
+
+/// Parse at most `max` nodes from the given string, beginning at byte `start` on
+/// line `first_line`. Returns the nodes, the offset after them, and whether the end
+/// of the input was reached.
+pub(crate) fn parse_nodes_from(
+    s: &str,
+    start: usize,
+    first_line: usize,
+    max: usize,
+    options: &ParseOptions,
+    names: &NodeNames,
+) -> Result<(Vec<Node>, usize, bool), YarnError> {
+    let mut tokenizer = TokenIterator::with_options(&s[start..], options.clone());
+    tokenizer.set_names(names);
+    tokenizer.first_line = first_line;
+    let mut nodes = vec![];
+    let mut parse = || {
+        while nodes.len() < max && tokenizer.peek().is_some() {
+            match parse_framed_node(&mut tokenizer)? {
+                Some(node) => nodes.push(node),
+                None => break,
+            }
+        }
+        Ok(tokenizer.peek().is_none())
+    };
+    let done = parse();
+    let done = tokenizer.checked(done)?;
+    Ok((nodes, start + tokenizer.position, done))
+}
+
+/// Parse the given string as a series of nodes on the current thread.
+pub(crate) fn parse_nodes_serially(
+    s: &str,
+    options: &ParseOptions,
+    names: &NodeNames,
+) -> Result<Vec<Node>, YarnError> {
+    let mut tokenizer = TokenIterator::with_options(s, options.clone());
+    tokenizer.set_names(names);
+    let nodes = parse_nodes(&mut tokenizer);
+    tokenizer.checked(nodes)
 }
 
 #[derive(Debug, PartialEq)]
@@ -453,6 +1414,9 @@ pub(crate) enum Token {
     Quote,
     Comma,
     ExclamationMark,
+    QuestionMark,
+    Colon,
+    Caret,
     LeftBracket,
     RightBracket,
     LeftParenthesis,
@@ -461,20 +1425,311 @@ pub(crate) enum Token {
     Word(String),
 }
 
+/// How to treat a node whose lines are indented with both tabs and spaces.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MixedIndentation {
+    /// Expand tabs to the next multiple of the tab width and accept the node.
+    Normalize,
+    /// Reject the node with a parse error.
+    Error,
+}
+
+/// How to treat content outside a node, such as text above the first header block
+/// or after a `===` that no header block follows.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StrayContent {
+    /// Reject the source with a parse error at the first such line.
+    Error,
+    /// Skip the content, reporting each line with `YarnEngine::validate`. Content in
+    /// a source without any nodes is skipped silently.
+    Warn,
+}
+
+/// A limit on the size or nesting of parsed source, used to reject hostile input
+/// before it exhausts the stack or memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParseLimit {
+    /// `ParseOptions::max_expression_depth`.
+    ExpressionDepth,
+    /// `ParseOptions::max_block_depth`.
+    BlockDepth,
+    /// `ParseOptions::max_line_length`.
+    LineLength,
+    /// `ParseOptions::max_nodes`.
+    Nodes,
+}
+
+impl fmt::Display for ParseLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParseLimit::ExpressionDepth => "maximum expression depth",
+            ParseLimit::BlockDepth => "maximum block depth",
+            ParseLimit::LineLength => "maximum line length",
+            ParseLimit::Nodes => "maximum number of nodes",
+        })
+    }
+}
+
+/// Options controlling how Yarn source is parsed.
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    /// The indentation width of a tab. A tab advances the indentation to the next
+    /// multiple of this width.
+    pub tab_width: u32,
+    /// How to treat a node whose lines are indented with both tabs and spaces.
+    pub mixed_indentation: MixedIndentation,
+    /// Whether the contents of a conditional must be indented deeper than the
+    /// `<<if>>` line that opens it. Conditionals are always delimited by
+    /// `<<endif>>`; option bodies are always delimited by indentation.
+    pub indented_conditionals: bool,
+    /// Whether boolean literals must be spelled in lowercase. By default `True` and
+    /// `FALSE` are accepted; when strict, they are parse errors. Expressions
+    /// interpolated into text are not parsed until run time and are always lenient.
+    pub strict_boolean_literals: bool,
+    /// The maximum nesting depth of an expression. Parentheses, function arguments,
+    /// unary operators and each binary operator add a level.
+    pub max_expression_depth: usize,
+    /// The maximum nesting depth of conditionals and option bodies.
+    pub max_block_depth: usize,
+    /// The maximum length of a line in bytes.
+    pub max_line_length: usize,
+    /// The maximum number of nodes in a single source.
+    pub max_nodes: usize,
+    /// Whether to defer parsing each node's body until it is first needed, such as
+    /// when the node is activated or jumped to. Headers are always parsed
+    /// immediately. Errors in a body are reported when it is parsed; use
+    /// `YarnEngine::parse_all` to parse every body up front.
+    pub lazy_bodies: bool,
+    /// Whether `*` begins a shortcut option like `->`, as in some Yarn dialects.
+    pub star_options: bool,
+    /// Whether a `<<statement>>` that cannot be parsed, such as one using syntax
+    /// from a newer version of Yarn, is kept verbatim and skipped when run instead
+    /// of failing. Each is reported by `YarnEngine::validate`. Conditionals must
+    /// still parse; text, including its `[markup]` and `{interpolations}`, is
+    /// always kept verbatim.
+    pub forward_compatible: bool,
+    /// Whether `YarnEngine::load_sources` times the parsing of each source and node,
+    /// reporting it in `LoadReport::metrics`. Nodes are then parsed one at a time,
+    /// on the current thread.
+    pub collect_metrics: bool,
+    /// How to treat non-blank lines outside a node other than `//` comments. A
+    /// header block is the run of `name: value` lines directly above a `---`, one of
+    /// which must be the `title`.
+    pub stray_content: StrayContent,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            tab_width: 4,
+            mixed_indentation: MixedIndentation::Normalize,
+            indented_conditionals: false,
+            strict_boolean_literals: false,
+            max_expression_depth: 256,
+            max_block_depth: 64,
+            max_line_length: 64 * 1024,
+            max_nodes: 100_000,
+            lazy_bodies: false,
+            star_options: false,
+            forward_compatible: false,
+            collect_metrics: false,
+            stray_content: StrayContent::Error,
+        }
+    }
+}
+
 pub(crate) struct TokenIterator<'a> {
-    input: Box<dyn Iterator<Item = char> + 'a>,
-    last_char: Option<char>,
+    input: &'a str,
+    /// The byte offset of the next unconsumed character.
+    position: usize,
     last_indent: u32,
     start_of_line: bool,
+    options: ParseOptions,
+    indented_with_spaces: bool,
+    indented_with_tabs: bool,
+    expression_depth: usize,
+    block_depth: usize,
+    /// The first parse limit exceeded and the line where it happened. Shared with
+    /// nested tokenizers so that the limit can be reported for the whole source.
+    limit_exceeded: Rc<Cell<Option<(ParseLimit, usize)>>>,
+    /// Why parsing failed and the line where it did, recorded where the failure was
+    /// found. Shared with nested tokenizers so that failures within expressions are
+    /// described too.
+    failure: Rc<RefCell<Option<(usize, String)>>>,
+    /// Interns node names, shared with nested tokenizers.
+    names: NodeNames,
+    /// The line of each step parsed, in the order the steps began.
+    step_lines: Vec<usize>,
+    /// The line number of the start of the input.
+    first_line: usize,
+    /// A byte offset into the input and the number of line breaks before it, so that
+    /// line numbers can be found without rescanning the whole input.
+    line_cache: Cell<(usize, usize)>,
 }
 
 impl<'a> TokenIterator<'a> {
     pub(crate) fn new(input: &'a str) -> TokenIterator<'a> {
+        TokenIterator::with_options(input, ParseOptions::default())
+    }
+
+    pub(crate) fn with_options(input: &'a str, options: ParseOptions) -> TokenIterator<'a> {
         TokenIterator {
-            input: Box::new(input.chars()),
-            last_char: None,
+            input,
+            position: 0,
             last_indent: 0,
             start_of_line: true,
+            options,
+            indented_with_spaces: false,
+            indented_with_tabs: false,
+            expression_depth: 0,
+            block_depth: 0,
+            limit_exceeded: Rc::new(Cell::new(None)),
+            failure: Rc::new(RefCell::new(None)),
+            names: NodeNames::default(),
+            step_lines: vec![],
+            first_line: 1,
+            line_cache: Cell::new((0, 0)),
+        }
+    }
+
+    /// Intern node names in the given set rather than a set of this tokenizer's own.
+    pub(crate) fn set_names(&mut self, names: &NodeNames) {
+        self.names = names.clone();
+    }
+
+    /// A tokenizer for text taken from the current line, such as an expression,
+    /// which shares this tokenizer's options and limits.
+    pub(crate) fn nested<'b>(&self, input: &'b str) -> TokenIterator<'b> {
+        let mut tokenizer = TokenIterator::with_options(input, self.options.clone());
+        tokenizer.limit_exceeded = self.limit_exceeded.clone();
+        tokenizer.failure = self.failure.clone();
+        tokenizer.names = self.names.clone();
+        tokenizer.first_line = self.line();
+        tokenizer
+    }
+
+    /// The line number of the most recently consumed character.
+    fn line(&self) -> usize {
+        self.line_before(self.position)
+    }
+
+    /// The line number of the character before the given byte offset.
+    fn line_before(&self, position: usize) -> usize {
+        let end = position.saturating_sub(1);
+        let (offset, line_breaks) = match self.line_cache.get() {
+            (offset, line_breaks) if offset <= end => (offset, line_breaks),
+            _ => (0, 0),
+        };
+        let bytes = &self.input.as_bytes()[offset..end];
+        let line_breaks = line_breaks + bytes.iter().filter(|&&b| b == b'\n').count();
+        self.line_cache.set((end, line_breaks));
+        self.first_line + line_breaks
+    }
+
+    /// Describe a parse failure, including any limit that was exceeded.
+    pub(crate) fn checked<T>(&self, result: Result<T, ()>) -> Result<T, YarnError> {
+        result.map_err(|()| {
+            if let Some((limit, line)) = self.limit_exceeded.get() {
+                return YarnError::LimitExceeded { limit, line };
+            }
+            let (line, message) = self
+                .failure
+                .borrow_mut()
+                .take()
+                .unwrap_or_else(|| (self.line(), "invalid Yarn source".to_string()));
+            YarnError::Parse {
+                line: Some(line),
+                message,
+            }
+        })
+    }
+
+    /// Record why parsing failed at the current line, unless the reason was already
+    /// recorded where the failure was found, and fail.
+    fn fail<T>(&self, message: impl FnOnce() -> String) -> Result<T, ()> {
+        self.fail_at(self.line(), message)
+    }
+
+    /// Record why parsing failed at the given line, unless the reason was already
+    /// recorded, and fail.
+    fn fail_at<T>(&self, line: usize, message: impl FnOnce() -> String) -> Result<T, ()> {
+        self.failure
+            .borrow_mut()
+            .get_or_insert_with(|| (line, message()));
+        Err(())
+    }
+
+    /// Record that the given limit was exceeded at the current line.
+    fn exceeded<T>(&self, limit: ParseLimit) -> Result<T, ()> {
+        if self.limit_exceeded.get().is_none() {
+            self.limit_exceeded.set(Some((limit, self.line())));
+        }
+        Err(())
+    }
+
+    /// Enter a nested expression or block, failing if the corresponding depth limit
+    /// is exceeded. Callers restore the previous depth when leaving.
+    fn nest(&mut self, limit: ParseLimit) -> Result<(), ()> {
+        let (depth, max) = match limit {
+            ParseLimit::ExpressionDepth => (
+                &mut self.expression_depth,
+                self.options.max_expression_depth,
+            ),
+            _ => (&mut self.block_depth, self.options.max_block_depth),
+        };
+        *depth += 1;
+        if *depth > max {
+            return self.exceeded(limit);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn options(&self) -> &ParseOptions {
+        &self.options
+    }
+
+    /// Account for a whitespace character at the start of a line. Returns false if
+    /// the character is not indentation.
+    fn indent(&mut self, ch: char) -> bool {
+        if !self.start_of_line {
+            return false;
+        }
+        match ch {
+            ' ' => {
+                self.last_indent += 1;
+                self.indented_with_spaces = true;
+            }
+            '\t' => {
+                let width = self.options.tab_width.max(1);
+                self.last_indent = (self.last_indent / width + 1) * width;
+                self.indented_with_tabs = true;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Forget which indentation characters have been seen so far.
+    pub(crate) fn reset_indentation_style(&mut self) {
+        self.indented_with_spaces = false;
+        self.indented_with_tabs = false;
+    }
+
+    /// Whether both tabs and spaces have been used for indentation since the last
+    /// call to `reset_indentation_style`.
+    pub(crate) fn mixed_indentation(&self) -> bool {
+        self.indented_with_spaces && self.indented_with_tabs
+    }
+
+    /// Skip any spaces or tabs, then return the next character without consuming it.
+    fn peek_past_spaces(&mut self) -> Option<char> {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') => {
+                    let _ = self.next_char();
+                }
+                ch => return ch,
+            }
         }
     }
 
@@ -482,9 +1737,10 @@ impl<'a> TokenIterator<'a> {
         let mut ch;
         loop {
             ch = self.next_char();
-            if self.start_of_line && ch == Some(' ') {
-                self.last_indent += 1;
-                continue;
+            if let Some(c) = ch {
+                if self.indent(c) {
+                    continue;
+                }
             }
             self.start_of_line = false;
             if ch != Some('\n') {
@@ -493,33 +1749,72 @@ impl<'a> TokenIterator<'a> {
             self.start_of_line = true;
             self.last_indent = 0;
         }
-        self.last_char = ch;
+        if let Some(ch) = ch {
+            self.push_back(ch);
+        }
         ch
     }
 
-    pub(crate) fn remainder_of_line(&mut self) -> Option<String> {
-        let mut buffer = String::new();
-        while let Some(ch) = self.next_char() {
-            if ch == '\n' {
+    /// The unconsumed input.
+    fn rest(&self) -> &'a str {
+        &self.input[self.position..]
+    }
+
+    /// Consume the remainder of the current line, including the line break, returning
+    /// the line without it. Returns None at the end of the input.
+    pub(crate) fn remainder_of_line(&mut self) -> Option<&'a str> {
+        let rest = self.rest();
+        match rest.find('\n') {
+            Some(end) => {
+                self.position += end + 1;
                 self.start_of_line = true;
                 self.last_indent = 0;
-                return Some(buffer);
+                Some(&rest[..end])
+            }
+            None if rest.is_empty() => None,
+            None => {
+                self.position = self.input.len();
+                Some(rest)
             }
-            buffer.push(ch);
-        }
-        if buffer.is_empty() {
-            return None;
         }
-        return Some(buffer);
+    }
+
+    /// Consume characters up to and including the given delimiter, returning the
+    /// characters before it.
+    fn take_until(&mut self, until: &str) -> Option<&'a str> {
+        let rest = self.rest();
+        let end = rest.find(until)?;
+        self.position += end + until.len();
+        Some(&rest[..end])
+    }
+
+    /// Consume characters while the predicate holds, returning them.
+    fn take_while(&mut self, mut predicate: impl FnMut(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let end = rest.find(|ch| !predicate(ch)).unwrap_or(rest.len());
+        self.position += end;
+        &rest[..end]
     }
 
     fn next_char(&mut self) -> Option<char> {
-        self.last_char.take().or_else(|| self.input.next())
+        let ch = self.rest().chars().next()?;
+        self.position += ch.len_utf8();
+        Some(ch)
     }
 
+    /// Return the most recently consumed character to the input.
     fn push_back(&mut self, ch: char) {
-        assert!(self.last_char.is_none());
-        self.last_char = Some(ch);
+        self.position -= ch.len_utf8();
+        debug_assert!(self.rest().starts_with(ch));
+    }
+
+    /// Return the remainder of the current line without consuming it.
+    fn peek_line(&self) -> &'a str {
+        let rest = self.rest();
+        match rest.find('\n') {
+            Some(end) => &rest[..=end],
+            None => rest,
+        }
     }
 
     pub(crate) fn last_indent(&self) -> u32 {
@@ -530,7 +1825,6 @@ impl<'a> TokenIterator<'a> {
 impl<'a> Iterator for TokenIterator<'a> {
     type Item = Token;
     fn next(&mut self) -> Option<Token> {
-        let mut buffer = String::new();
         loop {
             let ch = self.next_char()?;
             if ch != ' ' {
@@ -549,49 +1843,40 @@ impl<'a> Iterator for TokenIterator<'a> {
                 '*' => return Some(Token::Star),
                 '/' => return Some(Token::Slash),
                 '!' => return Some(Token::ExclamationMark),
+                '?' => return Some(Token::QuestionMark),
+                ':' => return Some(Token::Colon),
+                '^' => return Some(Token::Caret),
                 '"' => return Some(Token::Quote),
                 ',' => return Some(Token::Comma),
                 '[' => return Some(Token::LeftBracket),
                 ']' => return Some(Token::RightBracket),
                 '0'..='9' => {
-                    buffer.push(ch);
+                    let start = self.position - ch.len_utf8();
                     let mut before_decimal = true;
-                    while let Some(ch) = self.next_char() {
-                        match ch {
-                            '0'..='9' => buffer.push(ch),
-                            '.' if before_decimal => {
-                                before_decimal = false;
-                                buffer.push(ch);
-                            }
-                            ch => {
-                                self.push_back(ch);
-                                break;
-                            }
+                    self.take_while(|ch| match ch {
+                        '0'..='9' => true,
+                        '.' if before_decimal => {
+                            before_decimal = false;
+                            true
                         }
-                    }
-                    return Some(Token::Number(buffer.parse().ok()?));
+                        _ => false,
+                    });
+                    return Some(Token::Number(
+                        self.input[start..self.position].parse().ok()?,
+                    ));
                 }
-                ' ' if self.start_of_line => self.last_indent += 1,
-                ' ' => (),
+                ' ' | '\t' if self.indent(ch) => (),
+                ' ' | '\t' => (),
                 '\n' => {
                     self.start_of_line = true;
                     self.last_indent = 0;
                 }
                 ch => {
-                    buffer.push(ch);
-                    loop {
-                        let ch = match self.next_char() {
-                            Some(ch) => ch,
-                            None if buffer.is_empty() => return None,
-                            None => return Some(Token::Word(buffer)),
-                        };
-                        if ![' ', '\n', '('].contains(&ch) {
-                            buffer.push(ch);
-                        } else {
-                            self.push_back(ch);
-                            return Some(Token::Word(buffer));
-                        }
-                    }
+                    let start = self.position - ch.len_utf8();
+                    self.take_while(|ch| {
+                        ![' ', '\t', '\n', '(', ')', ',', '?', ':', '^'].contains(&ch)
+                    });
+                    return Some(Token::Word(self.input[start..self.position].to_string()));
                 }
             }
         }
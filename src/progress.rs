@@ -0,0 +1,188 @@
+use crate::engine::NodeName;
+use crate::error::YarnError;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// The first line of a `StoryProgress` in its text form, followed by the version.
+const HEADER: &str = "yarn-spool progress";
+
+/// Everything an engine remembers about the player's progress through the story
+/// apart from variables: the nodes visited, the options chosen, the lines of each
+/// line group shown, the lines seen and the time. Exported with `YarnEngine::export_progress`
+/// and saved alongside `YarnEngine::variables_persistent`, this is the recommended
+/// way to save a game.
+///
+/// The text form given by `to_string` and read by `parse` is stable across
+/// versions of this crate; progress saved by a later version with a newer format is
+/// rejected. With the `serde` feature, progress can also be saved with serde, in a
+/// form that is not versioned. Maps keyed by node and position are written as lists
+/// of `[[node, position], value]` entries, as JSON objects only have string keys.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StoryProgress {
+    /// The number of times each node has been visited.
+    pub visits: HashMap<NodeName, u32>,
+    /// The number of times each option has been chosen, by node and by the option's
+    /// position among the node's options in source order.
+    #[cfg_attr(feature = "serde", serde(with = "entries"))]
+    pub times_chosen: HashMap<(NodeName, usize), u32>,
+    /// For each line group, by node and source line, when each of its lines was
+    /// last shown: 0 if never, and otherwise a count that increases with each
+    /// showing of the group.
+    #[cfg_attr(feature = "serde", serde(with = "entries"))]
+    pub line_groups: HashMap<(NodeName, usize), Vec<u32>>,
+    /// The IDs of the lines that have been presented.
+    pub seen_lines: HashSet<String>,
+    /// The time given to `YarnEngine::set_time`.
+    pub time: f64,
+}
+
+impl StoryProgress {
+    /// The version of the text form written by this version of the crate.
+    pub const VERSION: u32 = 2;
+
+    /// Add the given progress to this progress: visit and choice counts are
+    /// summed, seen lines are combined, the history of each line group keeps the
+    /// later showing of each line, and the later time is kept.
+    pub fn merge(&mut self, other: &StoryProgress) {
+        for (node, count) in &other.visits {
+            *self.visits.entry(node.clone()).or_default() += count;
+        }
+        for (option, count) in &other.times_chosen {
+            *self.times_chosen.entry(option.clone()).or_default() += count;
+        }
+        for (group, shown) in &other.line_groups {
+            let existing = self.line_groups.entry(group.clone()).or_default();
+            if existing.len() < shown.len() {
+                existing.resize(shown.len(), 0);
+            }
+            for (existing, &shown) in existing.iter_mut().zip(shown) {
+                *existing = (*existing).max(shown);
+            }
+        }
+        self.seen_lines.extend(other.seen_lines.iter().cloned());
+        self.time = self.time.max(other.time);
+    }
+}
+
+/// One record per line, sorted so that equal progress is written identically.
+impl fmt::Display for StoryProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", HEADER, StoryProgress::VERSION)?;
+        writeln!(f, "time\t{}", self.time)?;
+        let mut visits = self.visits.iter().collect::<Vec<_>>();
+        visits.sort();
+        for (node, count) in visits {
+            writeln!(f, "visit\t{}\t{}", node, count)?;
+        }
+        let mut chosen = self.times_chosen.iter().collect::<Vec<_>>();
+        chosen.sort();
+        for ((node, option), count) in chosen {
+            writeln!(f, "chosen\t{}\t{}\t{}", node, option, count)?;
+        }
+        let mut groups = self.line_groups.iter().collect::<Vec<_>>();
+        groups.sort();
+        for ((node, line), shown) in groups {
+            let shown = shown.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+            writeln!(f, "group\t{}\t{}\t{}", node, line, shown.join(","))?;
+        }
+        let mut seen = self.seen_lines.iter().collect::<Vec<_>>();
+        seen.sort();
+        for id in seen {
+            writeln!(f, "seen\t{}", id)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for StoryProgress {
+    type Err = YarnError;
+
+    fn from_str(s: &str) -> Result<StoryProgress, YarnError> {
+        let mut lines = s.lines().enumerate();
+        let version = lines
+            .next()
+            .and_then(|(_, line)| line.strip_prefix(HEADER))
+            .and_then(|version| version.trim().parse::<u32>().ok())
+            .ok_or_else(|| YarnError::Parse {
+                line: Some(1),
+                message: "not a saved story progress".to_string(),
+            })?;
+        if version > StoryProgress::VERSION {
+            return Err(YarnError::Parse {
+                line: Some(1),
+                message: format!(
+                    "story progress version {} is newer than supported version {}",
+                    version,
+                    StoryProgress::VERSION
+                ),
+            });
+        }
+
+        let mut progress = StoryProgress::default();
+        for (idx, line) in lines.filter(|(_, line)| !line.trim().is_empty()) {
+            let invalid = || YarnError::Parse {
+                line: Some(idx + 1),
+                message: "invalid story progress".to_string(),
+            };
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let number = |field: &str| field.parse::<u32>().map_err(|_| invalid());
+            match fields.as_slice() {
+                ["visit", node, count] => {
+                    progress
+                        .visits
+                        .insert(NodeName::from(*node), number(count)?);
+                }
+                ["chosen", node, option, count] => {
+                    let option = number(option)? as usize;
+                    let key = (NodeName::from(*node), option);
+                    progress.times_chosen.insert(key, number(count)?);
+                }
+                ["group", node, line, shown] => {
+                    let shown = shown
+                        .split(',')
+                        .filter(|n| !n.is_empty())
+                        .map(number)
+                        .collect::<Result<_, _>>()?;
+                    let key = (NodeName::from(*node), number(line)? as usize);
+                    progress.line_groups.insert(key, shown);
+                }
+                ["seen", id] => {
+                    progress.seen_lines.insert(id.to_string());
+                }
+                ["time", time] if version >= 2 => {
+                    progress.time = time.parse().map_err(|_| invalid())?;
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(progress)
+    }
+}
+
+/// Serde support for maps whose keys are not strings, as lists of entries.
+#[cfg(feature = "serde")]
+mod entries {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+    use std::hash::Hash;
+
+    pub(super) fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map)
+    }
+
+    pub(super) fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Vec::<(K, V)>::deserialize(deserializer).map(|entries| entries.into_iter().collect())
+    }
+}
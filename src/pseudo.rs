@@ -0,0 +1,76 @@
+/// Replace ASCII letters with accented equivalents, so text that bypassed
+/// pseudo-localization stands out. Markup attributes are left untouched if
+/// `markup` is set.
+pub(crate) fn accent(text: &str, markup: bool) -> String {
+    accent_except(text, if markup { "[]" } else { "" })
+}
+
+/// Accent the literal parts of a line, leaving `{expression}` interpolations and
+/// escaped braces untouched so that interpolated values keep their original text.
+pub(crate) fn accent_template(text: &str, markup: bool) -> String {
+    accent_except(text, if markup { "{}[]" } else { "{}" })
+}
+
+/// Accent the text outside the regions delimited by each pair of characters in
+/// `delimiters`. Escaped delimiters are copied as they are.
+fn accent_except(text: &str, delimiters: &str) -> String {
+    let delimiters = delimiters.chars().collect::<Vec<_>>();
+    let mut result = String::with_capacity(text.len() * 2);
+    let mut chars = text.chars().peekable();
+    // The closing delimiter of the region being copied, if any.
+    let mut close = None;
+    while let Some(ch) = chars.next() {
+        if ch == '\\' && chars.peek().is_some_and(|next| delimiters.contains(next)) {
+            result.push(ch);
+            result.push(chars.next().unwrap());
+            continue;
+        }
+        match close {
+            Some(end) => {
+                if ch == end {
+                    close = None;
+                }
+                result.push(ch);
+            }
+            None => match delimiters.chunks(2).find(|pair| pair[0] == ch) {
+                Some(pair) => {
+                    close = Some(pair[1]);
+                    result.push(ch);
+                }
+                None => result.push(accent_char(ch)),
+            },
+        }
+    }
+    result
+}
+
+/// Lengthen the text by about 30% and wrap it in brackets, to reveal layouts that
+/// cannot fit longer translations and strings assembled from several lines. The
+/// brackets are escaped if `markup` is set.
+pub(crate) fn expand(text: &str, markup: bool) -> String {
+    let padding = (text.chars().count() * 3).div_ceil(10);
+    let (open, close) = if markup { ("\\[", "\\]") } else { ("[", "]") };
+    format!("{}{} {}{}", open, text, "~".repeat(padding.max(1)), close)
+}
+
+fn accent_char(ch: char) -> char {
+    match ch {
+        'a' => 'á',
+        'c' => 'ç',
+        'e' => 'é',
+        'i' => 'í',
+        'n' => 'ñ',
+        'o' => 'ó',
+        'u' => 'ú',
+        'y' => 'ý',
+        'A' => 'Å',
+        'C' => 'Ç',
+        'E' => 'É',
+        'I' => 'Î',
+        'N' => 'Ñ',
+        'O' => 'Ö',
+        'U' => 'Ü',
+        'Y' => 'Ý',
+        ch => ch,
+    }
+}
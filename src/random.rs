@@ -0,0 +1,134 @@
+use crate::engine::NodeName;
+use crate::fnv::Fnv;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+
+/// Where the random choices made while a node is current come from, set with
+/// `YarnEngine::set_rng_mode`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RngMode {
+    /// A single stream for the whole engine, so each choice depends on every choice
+    /// made before it.
+    GlobalStream,
+    /// A stream for each visit to a node, derived from the seed, the node's title
+    /// and its visit count, so that the same seed gives the same choices in a node
+    /// whatever happened earlier.
+    PerNodeSalted,
+}
+
+/// The generators behind an engine's random choices.
+#[derive(Clone, Debug)]
+pub(crate) struct Randomness {
+    mode: RngMode,
+    seed: u64,
+    global: Rng,
+    /// For each node, the visit count its stream was derived for, and the stream.
+    streams: HashMap<NodeName, (u32, Rng)>,
+}
+
+impl Randomness {
+    /// Generators seeded differently for each engine.
+    pub(crate) fn from_entropy() -> Randomness {
+        let mut randomness = Randomness {
+            mode: RngMode::GlobalStream,
+            seed: 0,
+            global: Rng::new(0),
+            streams: HashMap::new(),
+        };
+        randomness.set_seed(Rng::from_entropy().next_u64());
+        randomness
+    }
+
+    pub(crate) fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.global = Rng::new(seed);
+        self.streams.clear();
+    }
+
+    pub(crate) fn set_mode(&mut self, mode: RngMode) {
+        self.mode = mode;
+    }
+
+    /// The generator for a choice made in the given node, which has been visited
+    /// the given number of times, or outside of any node.
+    pub(crate) fn rng(&mut self, node: Option<(&NodeName, u32)>) -> &mut Rng {
+        let (node, visits) = match (self.mode, node) {
+            (RngMode::PerNodeSalted, Some(node)) => node,
+            (RngMode::GlobalStream, _) | (RngMode::PerNodeSalted, None) => return &mut self.global,
+        };
+        let seed = self.seed;
+        let stream = self
+            .streams
+            .entry(node.clone())
+            .or_insert_with(|| (visits, Rng::salted(seed, node.as_str(), visits)));
+        if stream.0 != visits {
+            *stream = (visits, Rng::salted(seed, node.as_str(), visits));
+        }
+        &mut stream.1
+    }
+}
+
+/// A small SplitMix64 generator, so that the same seed makes the same selections
+/// on every platform.
+#[derive(Clone, Debug)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    /// A generator seeded differently for each engine.
+    pub(crate) fn from_entropy() -> Rng {
+        Rng(RandomState::new().build_hasher().finish())
+    }
+
+    /// A generator for the given visit to a node, depending only on the seed, the
+    /// node's title and the visit count.
+    fn salted(seed: u64, node: &str, visits: u32) -> Rng {
+        let mut hash = Fnv::default();
+        hash.write(node);
+        let hash = hash.finish();
+        // Each input is mixed in by a separate generator, as seeds that differ by a
+        // multiple of the increment would give the same stream shifted.
+        let node_seed = Rng(seed ^ hash).next_u64();
+        Rng(Rng(node_seed ^ u64::from(visits)).next_u64())
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..1`.
+    pub(crate) fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A number in `0..n`, which must not be empty.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// An index into `weights`, chosen with probability proportional to its weight.
+    /// Indexes are equally likely if no weight is positive. `weights` must not be
+    /// empty.
+    pub(crate) fn weighted(&mut self, weights: &[f32]) -> usize {
+        let total: f64 = weights.iter().map(|&w| f64::from(w.max(0.))).sum();
+        if total <= 0. {
+            return self.below(weights.len());
+        }
+        let mut target = self.unit() * total;
+        for (index, &weight) in weights.iter().enumerate() {
+            target -= f64::from(weight.max(0.));
+            if target < 0. {
+                return index;
+            }
+        }
+        weights.iter().rposition(|&w| w > 0.).unwrap()
+    }
+}
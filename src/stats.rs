@@ -0,0 +1,38 @@
+use std::cell::Cell;
+
+/// Counts of the work performed by a `YarnEngine`, collected while statistics are
+/// enabled with `YarnEngine::enable_stats`.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub(crate) steps: Cell<u64>,
+    pub(crate) expressions: Cell<u64>,
+    pub(crate) function_calls: Cell<u64>,
+    pub(crate) variable_lookups: Cell<u64>,
+}
+
+impl Stats {
+    /// The number of steps executed, such as lines of dialogue, commands,
+    /// assignments, conditionals and jumps.
+    pub fn steps(&self) -> u64 {
+        self.steps.get()
+    }
+
+    /// The number of expressions evaluated, including conditions, assignments and
+    /// interpolated text. Subexpressions are not counted separately.
+    pub fn expressions(&self) -> u64 {
+        self.expressions.get()
+    }
+
+    /// The number of calls to registered functions.
+    pub fn function_calls(&self) -> u64 {
+        self.function_calls.get()
+    }
+
+    /// The number of times a script read the value of a variable.
+    pub fn variable_lookups(&self) -> u64 {
+        self.variable_lookups.get()
+    }
+}
+
+/// Selects one of the counters in `Stats`.
+pub(crate) type Counter = fn(&Stats) -> &Cell<u64>;
@@ -0,0 +1,60 @@
+use crate::engine::{Node, NodeName, Nodes, Value};
+use crate::error::YarnError;
+use std::collections::HashMap;
+
+/// A node that could be activated, as listed by `YarnEngine::available_nodes`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AvailableNode {
+    /// The node's title.
+    pub name: NodeName,
+    /// The value of the node's `precondition` header evaluated as a condition, or
+    /// `true` if it has none. An error if the precondition could not be parsed or
+    /// evaluated, or the `priority` header is not a number.
+    pub available: Result<bool, YarnError>,
+    /// The node's `priority` header, if present.
+    pub priority: Option<f32>,
+    /// The number of times the node has been visited.
+    pub visits: u32,
+}
+
+impl AvailableNode {
+    /// Whether the precondition evaluated to true.
+    pub fn is_available(&self) -> bool {
+        self.available == Ok(true)
+    }
+}
+
+/// List the loaded nodes as described by `YarnEngine::available_nodes_with`,
+/// evaluating each `precondition` header with the given closure.
+pub(crate) fn available_nodes(
+    nodes: &Nodes,
+    visits: &HashMap<NodeName, u32>,
+    filter_tag: Option<&str>,
+    mut evaluate: impl FnMut(&str) -> Result<Value, YarnError>,
+) -> Vec<AvailableNode> {
+    let tagged = |node: &Node| match filter_tag {
+        Some(tag) => node.has_tag(tag),
+        None => true,
+    };
+    nodes
+        .iter()
+        .filter(|node| tagged(node))
+        .map(|node| {
+            let priority = node.header_as::<f32>("priority").transpose();
+            let available = match (node.header("precondition"), &priority) {
+                (_, Err(_)) => Err(YarnError::Parse {
+                    line: None,
+                    message: format!("invalid priority in node `{}`", node.title),
+                }),
+                (Some(precondition), Ok(_)) => evaluate(precondition).map(|value| value.as_bool()),
+                (None, Ok(_)) => Ok(true),
+            };
+            AvailableNode {
+                name: node.title.clone(),
+                available,
+                priority: priority.ok().flatten(),
+                visits: visits.get(&node.title).cloned().unwrap_or(0),
+            }
+        })
+        .collect()
+}
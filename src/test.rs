@@ -1,10 +1,37 @@
-use crate::engine::{BinaryOp, Choice, Expr, Node, NodeName, Step, Term, UnaryOp, VariableName};
-use crate::engine::{Value, YarnEngine, YarnEntry};
+use crate::diff::{HeaderChange, NodeStatus};
+use crate::engine::{
+    BinaryOp, Choice, ChoiceKind, Expr, Node, NodeName, NodeNames, Step, Term, UnaryOp,
+    VariableName,
+};
+use crate::engine::{
+    ChoicePolicy, ChoiceRecord, CoercionWarning, ConversationStatus, FunctionCallback, OnError,
+    OptionMatch, RuntimeLimit, Say, StepKind, StepResult, TypeChecking, Value, YarnEngine,
+    YarnEntry, YarnHandler, YarnType,
+};
+use crate::error::{ErrorReport, RegisterError, TypeError, YarnError};
+use crate::filter::{ContentRef, FilterDecision};
+use crate::lint::{LintDiagnostic, LintRule, LintSink};
+use crate::load::LoadOptions;
+use crate::localize::{LineKind, LocalizableLine};
+use crate::markup::MarkupSpan;
+use crate::metrics::ParseMetrics;
+use crate::normalize::TextNormalization;
+use crate::paginate::{Pagination, SplitOn};
 use crate::parse::{
     parse_expr, parse_line, parse_node, parse_node_contents, parse_nodes, parse_step,
 };
+use crate::parse::{
+    parse_nodes_from_string, MixedIndentation, ParseLimit, ParseOptions, StrayContent,
+};
 use crate::parse::{Line, Token, TokenIterator};
+use crate::progress::StoryProgress;
+use crate::random::RngMode;
+use crate::storylet::AvailableNode;
+use crate::validate::{ActivationProblem, LineLengthLimit, ValidationWarning};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
 
 #[test]
 fn tokenize_number() {
@@ -76,9 +103,9 @@ fn remainder_of_line() {
     let input = "     hi there\n    bye  bye";
     let mut t = TokenIterator::new(input);
     assert_eq!(t.next().unwrap(), Token::Word("hi".to_string()));
-    assert_eq!(t.remainder_of_line(), Some(" there".to_string()));
+    assert_eq!(t.remainder_of_line(), Some(" there"));
     assert_eq!(t.next().unwrap(), Token::Word("bye".to_string()));
-    assert_eq!(t.remainder_of_line(), Some("  bye".to_string()));
+    assert_eq!(t.remainder_of_line(), Some("  bye"));
     assert!(t.remainder_of_line().is_none());
     assert!(t.next().is_none());
 }
@@ -94,8 +121,9 @@ fn parse_dialogue_with_option() {
             "this is dialogue".to_string(),
             vec![Choice::external(
                 "this is a choice".to_string(),
-                NodeName("targetnode".to_string())
-            ),]
+                NodeName::from("targetnode")
+            ),],
+            vec![]
         )
     );
 }
@@ -111,15 +139,13 @@ fn parse_dialogue_with_two_options() {
         Step::Dialogue(
             "this is dialogue".to_string(),
             vec![
-                Choice::external(
-                    "this is a choice".to_string(),
-                    NodeName("targetnode".to_string()),
-                ),
+                Choice::external("this is a choice".to_string(), NodeName::from("targetnode"),),
                 Choice::external(
                     "this is another choice".to_string(),
-                    NodeName("targetnode2".to_string()),
+                    NodeName::from("targetnode2"),
                 )
-            ]
+            ],
+            vec![]
         )
     );
 }
@@ -130,13 +156,14 @@ fn parse_conditional_dialogue() {
     let mut t = TokenIterator::new(input);
     let step = parse_step(&mut t).unwrap();
     let expected = Step::Conditional(
-        Expr::Term(Term::Boolean(true)),
+        Arc::new(Expr::Term(Term::Boolean(true))),
         vec![Step::Dialogue(
             "this is dialogue".to_string(),
             vec![Choice::external(
                 "this is a choice".to_string(),
-                NodeName("targetnode".to_string()),
+                NodeName::from("targetnode"),
             )],
+            vec![],
         )],
         vec![],
         vec![],
@@ -156,21 +183,23 @@ this is other dialogue
     let mut t = TokenIterator::new(input);
     let step = parse_step(&mut t).unwrap();
     let expected = Step::Conditional(
-        Expr::Term(Term::Boolean(true)),
+        Arc::new(Expr::Term(Term::Boolean(true))),
         vec![Step::Dialogue(
             "this is dialogue".to_string(),
             vec![Choice::external(
                 "this is a choice".to_string(),
-                NodeName("targetnode".to_string()),
+                NodeName::from("targetnode"),
             )],
+            vec![],
         )],
         vec![],
         vec![Step::Dialogue(
             "this is other dialogue".to_string(),
             vec![Choice::external(
                 "this is another choice".to_string(),
-                NodeName("targetnode2".to_string()),
+                NodeName::from("targetnode2"),
             )],
+            vec![],
         )],
     );
     assert_eq!(step, expected);
@@ -193,36 +222,43 @@ whatever
     let mut t = TokenIterator::new(input);
     let step = parse_step(&mut t).unwrap();
     let expected = Step::Conditional(
-        Expr::Term(Term::Boolean(true)),
+        Arc::new(Expr::Term(Term::Boolean(true))),
         vec![Step::Dialogue(
             "this is dialogue".to_string(),
             vec![Choice::external(
                 "this is a choice".to_string(),
-                NodeName("targetnode".to_string()),
+                NodeName::from("targetnode"),
             )],
+            vec![],
         )],
         vec![
             (
-                Expr::Term(Term::Boolean(false)),
+                Arc::new(Expr::Term(Term::Boolean(false))),
                 vec![Step::Dialogue(
                     "this is other dialogue".to_string(),
                     vec![Choice::external(
                         "this is another choice".to_string(),
-                        NodeName("targetnode2".to_string()),
+                        NodeName::from("targetnode2"),
                     )],
+                    vec![],
                 )],
             ),
             (
-                Expr::Term(Term::Boolean(true)),
-                vec![Step::Dialogue("third dialogue!".to_string(), vec![])],
+                Arc::new(Expr::Term(Term::Boolean(true))),
+                vec![Step::Dialogue(
+                    "third dialogue!".to_string(),
+                    vec![],
+                    vec![],
+                )],
             ),
         ],
         vec![Step::Dialogue(
             "whatever".to_string(),
             vec![Choice::external(
                 "look a choice".to_string(),
-                NodeName("targetnode3".to_string()),
+                NodeName::from("targetnode3"),
             )],
+            vec![],
         )],
     );
     assert_eq!(step, expected);
@@ -257,9 +293,9 @@ more
     let mut t = TokenIterator::new(input);
     let steps = parse_node_contents(&mut t).unwrap();
     let expected = vec![
-        Step::Dialogue("dialogue".to_string(), vec![]),
-        Step::Dialogue("dialogue2".to_string(), vec![]),
-        Step::Dialogue("dialogue3".to_string(), vec![]),
+        Step::Dialogue("dialogue".to_string(), vec![], vec![]),
+        Step::Dialogue("dialogue2".to_string(), vec![], vec![]),
+        Step::Dialogue("dialogue3".to_string(), vec![], vec![]),
     ];
     assert_eq!(steps, expected);
     assert_eq!(t.next().unwrap(), Token::Word("more".to_string()));
@@ -278,14 +314,17 @@ dialogue3
     let mut extra = HashMap::new();
     extra.insert("extra".to_string(), "hi there".to_string());
     let expected = Node {
-        title: NodeName("whee hello".to_string()),
-        extra: extra,
+        title: NodeName::from("whee hello"),
+        extra,
+        source: None,
+        lazy_body: None,
+        stray_lines: vec![],
         steps: vec![
-            Step::Dialogue("dialogue".to_string(), vec![]),
-            Step::Dialogue("dialogue2".to_string(), vec![]),
-            Step::Dialogue("dialogue3".to_string(), vec![]),
+            Step::Dialogue("dialogue".to_string(), vec![], vec![]),
+            Step::Dialogue("dialogue2".to_string(), vec![], vec![]),
+            Step::Dialogue("dialogue3".to_string(), vec![], vec![]),
         ],
-        visited: false,
+        step_lines: vec![4, 5, 6],
     };
     let node = parse_node(&mut t).unwrap();
     assert_eq!(node, expected);
@@ -317,26 +356,33 @@ dialogue
     extra2.insert("extra".to_string(), "foo bar -5".to_string());
     let expected = vec![
         Node {
-            title: NodeName("whee hello".to_string()),
-            extra: extra,
+            title: NodeName::from("whee hello"),
+            extra,
+            source: None,
+            lazy_body: None,
+            stray_lines: vec![],
             steps: vec![
-                Step::Dialogue("dialogue".to_string(), vec![]),
-                Step::Dialogue("dialogue2".to_string(), vec![]),
-                Step::Dialogue("dialogue3".to_string(), vec![]),
+                Step::Dialogue("dialogue".to_string(), vec![], vec![]),
+                Step::Dialogue("dialogue2".to_string(), vec![], vec![]),
+                Step::Dialogue("dialogue3".to_string(), vec![], vec![]),
             ],
-            visited: false,
+            step_lines: vec![4, 5, 6],
         },
         Node {
-            title: NodeName("title!".to_string()),
+            title: NodeName::from("title!"),
             extra: extra2,
+            source: None,
+            lazy_body: None,
+            stray_lines: vec![],
             steps: vec![Step::Dialogue(
                 "dialogue".to_string(),
                 vec![
-                    Choice::external("option".to_string(), NodeName("whee hello".to_string())),
-                    Choice::external("option2".to_string(), NodeName("title!".to_string())),
+                    Choice::external("option".to_string(), NodeName::from("whee hello")),
+                    Choice::external("option2".to_string(), NodeName::from("title!")),
                 ],
+                vec![],
             )],
-            visited: false,
+            step_lines: vec![13],
         },
     ];
 
@@ -437,7 +483,61 @@ fn parse_jump() {
     let input = "[[SomeNode.Walk]]";
     let mut t = TokenIterator::new(input);
     let step = parse_step(&mut t).unwrap();
-    assert_eq!(step, Step::Jump(NodeName("SomeNode.Walk".to_string())));
+    assert_eq!(step, Step::Jump(NodeName::from("SomeNode.Walk")));
+}
+
+#[test]
+fn parse_dialect_syntaxes() {
+    let input = r#"Where now?
+-> Shortcut
+    <<jump Cellar>>
+* Starred
+    [[Attic]]
+[[Linked|Library]]
+<<jump  Hall >>
+[[Garden]]
+===
+"#;
+    let options = ParseOptions {
+        star_options: true,
+        ..ParseOptions::default()
+    };
+    let mut t = TokenIterator::with_options(input, options);
+    let steps = parse_node_contents(&mut t).unwrap();
+    let jump = |name: &str| Step::Jump(NodeName::from(name));
+    assert_eq!(
+        steps,
+        vec![
+            Step::Dialogue(
+                "Where now?".to_string(),
+                vec![
+                    Choice::inline("Shortcut".to_string(), vec![jump("Cellar")]),
+                    Choice::inline("Starred".to_string(), vec![jump("Attic")]),
+                    Choice::external("Linked".to_string(), NodeName::from("Library")),
+                ],
+                vec![]
+            ),
+            jump("Hall"),
+            jump("Garden"),
+        ]
+    );
+
+    // Without the dialect flag, and for links that would otherwise leak into
+    // text, parsing fails.
+    let fails = |input: &str, options: ParseOptions| {
+        parse_node_contents(&mut TokenIterator::with_options(input, options)).is_err()
+    };
+    assert!(fails("Hi\n* Starred\n===\n", ParseOptions::default()));
+    assert!(fails(
+        "Go to [[Garden]] now\n===\n",
+        ParseOptions::default()
+    ));
+    assert!(fails(
+        "Hi\n-> Yes [[Garden]]\n===\n",
+        ParseOptions::default()
+    ));
+    assert!(fails("[[Garden]] now\n===\n", ParseOptions::default()));
+    assert!(fails("<<jump>>\n===\n", ParseOptions::default()));
 }
 
 #[test]
@@ -449,7 +549,9 @@ fn parse_inline_option_with_condition() {
         line,
         Line::InlineOption(
             "This is some text".to_string(),
-            Some("$money >= 5".to_string())
+            Some("$money >= 5".to_string()),
+            None,
+            vec![]
         )
     );
 }
@@ -473,23 +575,27 @@ fn parse_inline_option_with_condition2() {
                 Choice::inline(
                     "This is some text".to_string(),
                     vec![
-                        Step::Dialogue("Some inline dialogue".to_string(), vec![]),
-                        Step::Dialogue("Some more inline dialogue".to_string(), vec![]),
+                        Step::Dialogue("Some inline dialogue".to_string(), vec![], vec![]),
+                        Step::Dialogue("Some more inline dialogue".to_string(), vec![], vec![]),
                     ],
-                    Some(Expr::Binary(
-                        BinaryOp::GreaterThanEqual,
-                        Box::new(Expr::Term(Term::Variable(VariableName(
-                            "money".to_string()
-                        )))),
-                        Box::new(Expr::Term(Term::Number(5.0)))
-                    ))
-                ),
+                )
+                .with_condition(Some(Arc::new(Expr::Binary(
+                    BinaryOp::GreaterThanEqual,
+                    Box::new(Expr::Term(Term::Variable(VariableName(
+                        "money".to_string()
+                    )))),
+                    Box::new(Expr::Term(Term::Number(5.0)))
+                )))),
                 Choice::inline(
                     "Another text".to_string(),
-                    vec![Step::Dialogue("Some inline dialogue".to_string(), vec![]),],
-                    None
+                    vec![Step::Dialogue(
+                        "Some inline dialogue".to_string(),
+                        vec![],
+                        vec![]
+                    ),],
                 ),
-            ]
+            ],
+            vec![]
         )
     );
 }
@@ -506,16 +612,16 @@ text2
     // let handler = TestHandler::default();
     // let events = handler.events.clone();
     let mut engine = YarnEngine::new();
-    engine.load_from_string(&nodes).unwrap();
+    engine.load_from_string(nodes).unwrap();
 
-    engine.activate(NodeName("1".to_string()));
+    engine.activate(NodeName::from("1"));
 
     // let f = engine.collect::<Vec<_>>();
 
     // print!("{:?}", f);
-    assert_eq!(engine.next(), Some(YarnEntry::Say("text1".to_string())));
-    assert_eq!(engine.next(), Some(YarnEntry::Say("text2".to_string())));
-    assert_eq!(engine.next(), Some(YarnEntry::EndConversation));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("text1".into())));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("text2".into())));
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
     assert_eq!(engine.next(), None);
 }
 
@@ -541,32 +647,37 @@ that's all
 "#;
     // let events = handler.events.clone();
     let mut engine = YarnEngine::new();
-    engine.load_from_string(&nodes).unwrap();
-    engine.activate(NodeName("1".to_string()));
+    engine.load_from_string(nodes).unwrap();
+    engine.activate(NodeName::from("1"));
 
     assert_eq!(
         engine.next(),
         Some(YarnEntry::Choose {
             text: "some text".to_string(),
-            choices: vec!["whee".to_string(), "whee2".to_string()]
+            choices: vec!["whee".to_string(), "whee2".to_string()],
+            timeout: None,
+            default_choice: None,
+            times_chosen: vec![0; 2],
+            matched: None
         })
     );
 
-    engine.choose(1);
+    engine.choose(1).unwrap();
     assert_eq!(
         engine.next(),
         Some(YarnEntry::Choose {
             text: "some text".to_string(),
-            choices: vec!["whee".to_string(), "whee2".to_string()]
+            choices: vec!["whee".to_string(), "whee2".to_string()],
+            timeout: None,
+            default_choice: None,
+            times_chosen: vec![0, 1],
+            matched: None
         })
     );
-    engine.choose(0);
+    engine.choose(0).unwrap();
 
-    assert_eq!(
-        engine.next(),
-        Some(YarnEntry::Say("that's all".to_string()))
-    );
-    assert_eq!(engine.next(), Some(YarnEntry::EndConversation));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("that's all".into())));
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
     assert_eq!(engine.next(), None);
 }
 
@@ -586,20 +697,6121 @@ other text
     // let events = handler.events.clone();
     let mut engine = YarnEngine::new();
     engine.set_variable(VariableName("foo".to_string()), Value::Number(5.0));
-    engine.load_from_string(&nodes).unwrap();
-    engine.activate(NodeName("1".to_string()));
+    engine.load_from_string(nodes).unwrap();
+    engine.activate(NodeName::from("1"));
 
-    assert_eq!(engine.next(), Some(YarnEntry::Say("some text".to_string())));
-    assert_eq!(engine.next(), Some(YarnEntry::EndConversation));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("some text".into())));
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
     assert_eq!(engine.next(), None);
 
     engine.set_variable(VariableName("foo".to_string()), Value::Number(6.0));
-    engine.activate(NodeName("1".to_string()));
+    engine.activate(NodeName::from("1"));
 
+    assert_eq!(engine.next(), Some(YarnEntry::Say("other text".into())));
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+    assert_eq!(engine.next(), None);
+}
+
+#[test]
+fn test_conversation_status() {
+    let nodes = r#"
+title: 1
+---
+some text
+<<wave>>
+question
+[[whee|2]]
+===
+
+title: 2
+---
+that's all
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    assert_eq!(engine.status(), ConversationStatus::Idle);
+    assert!(!engine.is_active());
+    assert_eq!(engine.next(), None);
+
+    engine.activate(NodeName::from("1"));
+    assert_eq!(engine.status(), ConversationStatus::Running);
+    assert!(engine.is_active());
+
+    assert_eq!(engine.next(), Some(YarnEntry::Say("some text".into())));
+    assert_eq!(engine.status(), ConversationStatus::WaitingForProceed);
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Command {
+            action: "wave".to_string()
+        })
+    );
+    assert_eq!(engine.status(), ConversationStatus::WaitingForProceed);
     assert_eq!(
         engine.next(),
-        Some(YarnEntry::Say("other text".to_string()))
+        Some(YarnEntry::Choose {
+            text: "question".to_string(),
+            choices: vec!["whee".to_string()],
+            timeout: None,
+            default_choice: None,
+            times_chosen: vec![0; 1],
+            matched: None
+        })
+    );
+    assert_eq!(engine.status(), ConversationStatus::WaitingForChoice);
+    assert!(engine.is_active());
+
+    engine.choose(0).unwrap();
+    assert_eq!(engine.status(), ConversationStatus::Running);
+    assert_eq!(engine.next(), Some(YarnEntry::Say("that's all".into())));
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+    assert_eq!(engine.status(), ConversationStatus::Ended);
+    assert!(engine.has_ended());
+    assert!(!engine.is_active());
+    assert_eq!(engine.next(), None);
+    assert_eq!(engine.status(), ConversationStatus::Ended);
+
+    engine.activate(NodeName::from("1"));
+    assert_eq!(engine.status(), ConversationStatus::Running);
+    assert!(!engine.has_ended());
+    assert_eq!(engine.next(), Some(YarnEntry::Say("some text".into())));
+
+    engine.stop_conversation();
+    assert_eq!(engine.status(), ConversationStatus::Idle);
+    assert!(!engine.is_active());
+    assert!(!engine.has_ended());
+    assert_eq!(engine.next(), None);
+}
+
+#[test]
+fn test_node_visited_callback() {
+    let nodes = r#"
+title: A
+---
+in a
+[[B]]
+===
+
+title: B
+---
+[[C]]
+===
+
+title: C
+---
+in c
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let visits = Rc::new(RefCell::new(vec![]));
+    let visits2 = visits.clone();
+    engine.on_node_visited(move |name, count| visits2.borrow_mut().push((name.clone(), count)));
+
+    engine.activate(NodeName::from("A"));
+    assert!(visits.borrow().is_empty());
+    assert_eq!(engine.next(), Some(YarnEntry::Say("in a".into())));
+    assert!(visits.borrow().is_empty());
+    assert_eq!(engine.next(), Some(YarnEntry::Say("in c".into())));
+    assert_eq!(
+        *visits.borrow(),
+        vec![(NodeName::from("A"), 1), (NodeName::from("B"), 1)]
+    );
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+    assert_eq!(
+        *visits.borrow(),
+        vec![
+            (NodeName::from("A"), 1),
+            (NodeName::from("B"), 1),
+            (NodeName::from("C"), 1)
+        ]
     );
-    assert_eq!(engine.next(), Some(YarnEntry::EndConversation));
     assert_eq!(engine.next(), None);
+    assert_eq!(visits.borrow().len(), 3);
+
+    visits.borrow_mut().clear();
+    engine.activate(NodeName::from("B"));
+    while engine.next().is_some() {}
+    assert_eq!(
+        *visits.borrow(),
+        vec![(NodeName::from("B"), 2), (NodeName::from("C"), 2)]
+    );
+}
+
+#[test]
+fn parse_dialogue_followed_by_jump() {
+    let input = "this is dialogue\n[[targetnode]]\n===";
+    let mut t = TokenIterator::new(input);
+    let steps = parse_node_contents(&mut t).unwrap();
+    assert_eq!(
+        steps,
+        vec![
+            Step::Dialogue("this is dialogue".to_string(), vec![], vec![]),
+            Step::Jump(NodeName::from("targetnode")),
+        ]
+    );
+}
+
+#[test]
+fn test_node_headers() {
+    let nodes = r#"
+title: 1
+priority: 3
+repeatable: true
+cooldown: soon
+---
+text
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let node = engine.node(&NodeName::from("1")).unwrap();
+
+    assert_eq!(node.header("priority"), Some("3"));
+    assert_eq!(node.header_as::<u32>("priority"), Some(Ok(3)));
+    assert_eq!(node.header_as::<bool>("repeatable"), Some(Ok(true)));
+    assert!(node.header_as::<f32>("cooldown").unwrap().is_err());
+    assert_eq!(node.header("missing"), None);
+    assert!(node.header_as::<u32>("missing").is_none());
+    assert_eq!(node.header("Priority"), Some("3"));
+    assert_eq!(node.header("title"), None);
+
+    let mut headers = node.headers().collect::<Vec<_>>();
+    headers.sort();
+    assert_eq!(
+        headers,
+        vec![
+            ("cooldown", "soon"),
+            ("priority", "3"),
+            ("repeatable", "true")
+        ]
+    );
+}
+
+#[test]
+fn parse_hashtags() {
+    let input = r#"Sally: hi there #happy #line:abc
+-> Yes please #line:def << if $money >= 5 >>
+  Some inline dialogue
+[[No thanks|nope]] #rude
+"#;
+    let mut t = TokenIterator::new(input);
+    let step = parse_step(&mut t).unwrap();
+    assert_eq!(
+        step,
+        Step::Dialogue(
+            "Sally: hi there".to_string(),
+            vec![
+                Choice::inline(
+                    "Yes please".to_string(),
+                    vec![Step::Dialogue(
+                        "Some inline dialogue".to_string(),
+                        vec![],
+                        vec![]
+                    )],
+                )
+                .with_condition(Some(Arc::new(Expr::Binary(
+                    BinaryOp::GreaterThanEqual,
+                    Box::new(Expr::Term(Term::Variable(VariableName(
+                        "money".to_string()
+                    )))),
+                    Box::new(Expr::Term(Term::Number(5.0)))
+                ))))
+                .with_tags(vec!["line:def".to_string()]),
+                Choice::external("No thanks".to_string(), NodeName::from("nope"))
+                    .with_tags(vec!["rude".to_string()]),
+            ],
+            vec!["happy".to_string(), "line:abc".to_string()]
+        )
+    );
+}
+
+#[test]
+fn test_external_option_conditions() {
+    let nodes = r#"
+title: Start
+---
+Merchant: Interested?
+[[Take the deal|Deal]] <<if $gold >= 100>> #greedy
+[[Walk away|Leave]]
+===
+title: Deal
+---
+Done.
+===
+title: Leave
+---
+Bye.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let gold = VariableName("gold".to_string());
+    let present = |engine: &mut YarnEngine, amount: f32| {
+        engine.set_variable(gold.clone(), Value::Number(amount));
+        engine.activate(NodeName::from("Start"));
+        engine.next()
+    };
+    let choose = |choices: Vec<&str>, times_chosen: Vec<u32>| YarnEntry::Choose {
+        text: "Merchant: Interested?".to_string(),
+        choices: choices.into_iter().map(|c| c.to_string()).collect(),
+        timeout: None,
+        default_choice: None,
+        times_chosen,
+        matched: None,
+    };
+
+    assert_eq!(
+        present(&mut engine, 50.),
+        Some(choose(vec!["Walk away"], vec![0]))
+    );
+    assert_eq!(engine.choose(1), Err(YarnError::InvalidChoice(1)));
+    engine.choose(0).unwrap();
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Bye.".into())));
+
+    assert_eq!(
+        present(&mut engine, 150.),
+        Some(choose(vec!["Take the deal", "Walk away"], vec![0, 1]))
+    );
+    engine.choose(0).unwrap();
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Done.".into())));
+    assert_eq!(engine.extract_lines()[1].tags, vec!["greedy".to_string()]);
+
+    // Jumps cannot be conditional.
+    let mut engine = YarnEngine::new();
+    assert!(engine
+        .load_from_string("title: A\n---\n[[B]] <<if true>>\n===\n")
+        .is_err());
+}
+
+#[test]
+fn test_lastline_tag() {
+    let nodes = r#"
+title: Start
+---
+Guard: Halt.
+Guard: Who goes there? #line:who
+-> A friend
+-> Nobody
+<<if $suspicious>>
+    Guard: I've seen you before.
+    Guard: What do you want? #lastline
+    [[Directions|Directions]]
+    [[Nothing|Start]]
+<<endif>>
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let tags = engine
+        .extract_lines()
+        .into_iter()
+        .filter(|line| line.kind == LineKind::Say)
+        .map(|line| (line.text, line.tags))
+        .collect::<Vec<_>>();
+    let line = |text: &str, tags: &[&str]| {
+        let tags = tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+        (text.to_string(), tags)
+    };
+    assert_eq!(
+        tags,
+        vec![
+            line("Guard: Halt.", &[]),
+            line("Guard: Who goes there?", &["line:who", "lastline"]),
+            line("Guard: I've seen you before.", &[]),
+            line("Guard: What do you want?", &["lastline"]),
+        ]
+    );
+}
+
+#[test]
+fn test_extract_lines() {
+    let nodes = r#"
+title: Start
+---
+Sally: Hello there. #line:hello
+How are you?
+<<if true>>
+Bob: Fine #grumpy
+<<endif>>
+Sally: Want some tea?
+-> Yes
+  Sally: Here you go.
+  Bob: Thanks.
+-> No #line:no
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let lines = engine.extract_lines();
+    let start = NodeName::from("Start");
+
+    assert_eq!(
+        lines,
+        vec![
+            LocalizableLine {
+                id: "line:hello".to_string(),
+                node: start.clone(),
+                character: Some("Sally".to_string()),
+                kind: LineKind::Say,
+                text: "Sally: Hello there.".to_string(),
+                tags: vec!["line:hello".to_string()],
+                previous: None,
+                next: Some("How are you?".to_string()),
+            },
+            LocalizableLine {
+                id: "line:Start-95eafef62f98c265".to_string(),
+                node: start.clone(),
+                character: None,
+                kind: LineKind::Say,
+                text: "How are you?".to_string(),
+                tags: vec![],
+                previous: Some("Sally: Hello there.".to_string()),
+                next: Some("Sally: Want some tea?".to_string()),
+            },
+            LocalizableLine {
+                id: "line:Start-f85c12fa9022f2b2".to_string(),
+                node: start.clone(),
+                character: Some("Bob".to_string()),
+                kind: LineKind::Say,
+                text: "Bob: Fine".to_string(),
+                tags: vec!["grumpy".to_string()],
+                previous: None,
+                next: None,
+            },
+            LocalizableLine {
+                id: "line:Start-85c370c78916f635".to_string(),
+                node: start.clone(),
+                character: Some("Sally".to_string()),
+                kind: LineKind::Say,
+                text: "Sally: Want some tea?".to_string(),
+                tags: vec!["lastline".to_string()],
+                previous: Some("How are you?".to_string()),
+                next: None,
+            },
+            LocalizableLine {
+                id: "line:Start-69d5630ba8fc389c".to_string(),
+                node: start.clone(),
+                character: None,
+                kind: LineKind::Option,
+                text: "Yes".to_string(),
+                tags: vec![],
+                previous: Some("Sally: Want some tea?".to_string()),
+                next: None,
+            },
+            LocalizableLine {
+                id: "line:Start-00429fec7a8e2cc9".to_string(),
+                node: start.clone(),
+                character: Some("Sally".to_string()),
+                kind: LineKind::Say,
+                text: "Sally: Here you go.".to_string(),
+                tags: vec![],
+                previous: None,
+                next: Some("Bob: Thanks.".to_string()),
+            },
+            LocalizableLine {
+                id: "line:Start-8dde133ccddc4ee3".to_string(),
+                node: start.clone(),
+                character: Some("Bob".to_string()),
+                kind: LineKind::Say,
+                text: "Bob: Thanks.".to_string(),
+                tags: vec![],
+                previous: Some("Sally: Here you go.".to_string()),
+                next: None,
+            },
+            LocalizableLine {
+                id: "line:no".to_string(),
+                node: start.clone(),
+                character: None,
+                kind: LineKind::Option,
+                text: "No".to_string(),
+                tags: vec!["line:no".to_string()],
+                previous: Some("Sally: Want some tea?".to_string()),
+                next: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_shared_nodes() {
+    let nodes = r#"
+title: 1
+---
+<<if visited("1")>>
+welcome back
+<<else>>
+hello
+<<endif>>
+===
+"#;
+    let mut engine1 = YarnEngine::new();
+    engine1.load_from_string(nodes).unwrap();
+    let mut engine2 = YarnEngine::with_shared_nodes(engine1.shared_nodes());
+    let name = NodeName::from("1");
+    assert!(std::ptr::eq(
+        engine1.node(&name).unwrap(),
+        engine2.node(&name).unwrap()
+    ));
+
+    engine1.activate(name.clone());
+    assert_eq!(engine1.next(), Some(YarnEntry::Say("hello".into())));
+    assert_eq!(engine1.next(), Some(YarnEntry::EndConversation(None)));
+    assert_eq!(engine1.visit_count(&name), 1);
+    assert_eq!(engine2.visit_count(&name), 0);
+
+    engine1.activate(name.clone());
+    assert_eq!(engine1.next(), Some(YarnEntry::Say("welcome back".into())));
+    engine2.activate(name.clone());
+    assert_eq!(engine2.next(), Some(YarnEntry::Say("hello".into())));
+
+    // Loading more nodes gives the engine its own copy, leaving the other untouched.
+    engine2
+        .load_from_string("title: 2\n---\nmore\n===\n")
+        .unwrap();
+    assert!(engine2.node(&NodeName::from("2")).is_some());
+    assert!(engine1.node(&NodeName::from("2")).is_none());
+    assert!(!std::ptr::eq(
+        engine1.node(&name).unwrap(),
+        engine2.node(&name).unwrap()
+    ));
+}
+
+#[test]
+fn parse_indentation_styles() {
+    let spaces = "title: 1\n---\nquestion\n-> yes\n    <<if true>>\n        answer\n    <<endif>>\n    more\n-> no\n    nope\n===\n";
+    let tabs = "title: 1\n---\nquestion\n-> yes\n\t<<if true>>\n\t\tanswer\n\t<<endif>>\n\tmore\n-> no\n\tnope\n===\n";
+    let mixed = "title: 1\n---\nquestion\n-> yes\n\t<<if true>>\n    \tanswer\n    <<endif>>\n\tmore\n-> no\n    nope\n===\n";
+
+    let permissive = ParseOptions::default();
+    let expected = parse_nodes_from_string(spaces, &permissive, &NodeNames::default()).unwrap();
+    assert_eq!(
+        parse_nodes_from_string(tabs, &permissive, &NodeNames::default()).unwrap(),
+        expected
+    );
+    assert_eq!(
+        parse_nodes_from_string(mixed, &permissive, &NodeNames::default()).unwrap(),
+        expected
+    );
+    match expected[0].steps[0] {
+        Step::Dialogue(_, ref choices, _) => assert_eq!(choices.len(), 2),
+        _ => panic!("expected dialogue"),
+    }
+
+    let strict = ParseOptions {
+        mixed_indentation: MixedIndentation::Error,
+        ..ParseOptions::default()
+    };
+    assert_eq!(
+        parse_nodes_from_string(spaces, &strict, &NodeNames::default()).unwrap(),
+        expected
+    );
+    assert_eq!(
+        parse_nodes_from_string(tabs, &strict, &NodeNames::default()).unwrap(),
+        expected
+    );
+    assert!(parse_nodes_from_string(mixed, &strict, &NodeNames::default()).is_err());
+}
+
+#[test]
+fn parse_indented_conditionals() {
+    let indented = "title: 1\n---\n<<if true>>\n  answer\n<<endif>>\n===\n";
+    let flat = "title: 1\n---\n<<if true>>\nanswer\n<<endif>>\n===\n";
+
+    let options = ParseOptions::default();
+    assert!(parse_nodes_from_string(indented, &options, &NodeNames::default()).is_ok());
+    assert!(parse_nodes_from_string(flat, &options, &NodeNames::default()).is_ok());
+
+    let options = ParseOptions {
+        indented_conditionals: true,
+        ..ParseOptions::default()
+    };
+    assert!(parse_nodes_from_string(indented, &options, &NodeNames::default()).is_ok());
+    assert!(parse_nodes_from_string(flat, &options, &NodeNames::default()).is_err());
+}
+
+#[test]
+fn parse_header_casing_and_whitespace() {
+    // As exported by the Yarn editor.
+    let yarn_editor = r#"title: Start
+tags: intro
+colorID: 0
+position: -1303,-3060
+---
+hi
+===
+"#;
+    // As written by hand in VS Code.
+    let vscode = r#"Title : Start
+TAGS:   intro
+ColorID :0
+  position  : -1303,-3060
+---
+hi
+===
+"#;
+    let options = ParseOptions::default();
+    let expected = parse_nodes_from_string(yarn_editor, &options, &NodeNames::default()).unwrap();
+    assert_eq!(
+        parse_nodes_from_string(vscode, &options, &NodeNames::default()).unwrap(),
+        expected
+    );
+
+    let node = &expected[0];
+    assert_eq!(node.title, NodeName::from("Start"));
+    assert_eq!(node.header("colorid"), Some("0"));
+    assert_eq!(node.header("ColorID"), Some("0"));
+    assert_eq!(node.header("position"), Some("-1303,-3060"));
+    assert_eq!(node.header("tags"), Some("intro"));
+
+    let duplicate = "title: Start\nTags: a\ntags: b\n---\nhi\n===\n";
+    assert!(parse_nodes_from_string(duplicate, &options, &NodeNames::default()).is_err());
+    let duplicate_title = "title: Start\nTITLE: Other\n---\nhi\n===\n";
+    assert!(parse_nodes_from_string(duplicate_title, &options, &NodeNames::default()).is_err());
+}
+
+#[test]
+fn test_command_interpolation() {
+    let nodes = r#"
+title: 1
+---
+You have {$count} things, \{literally\}.
+<<give_item $npc {$count + 1}>>
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_variable(
+        VariableName("npc".to_string()),
+        Value::String("bob".to_string()),
+    );
+    engine.set_variable(VariableName("count".to_string()), Value::Number(2.));
+    let calls = Rc::new(RefCell::new(vec![]));
+    let calls2 = calls.clone();
+    engine.register_command(
+        "give_item".to_string(),
+        Box::new(move |args| calls2.borrow_mut().push(args)),
+    );
+
+    engine.activate(NodeName::from("1"));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("You have 2 things, {literally}.".into()))
+    );
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Command {
+            action: "give_item $npc 3".to_string()
+        })
+    );
+    assert_eq!(
+        *calls.borrow(),
+        vec![vec!["$npc".to_string(), "3".to_string()]]
+    );
+
+    engine.set_substitute_bare_variables(true);
+    engine.activate(NodeName::from("1"));
+    let _ = engine.next();
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Command {
+            action: "give_item bob 3".to_string()
+        })
+    );
+    assert_eq!(calls.borrow()[1], vec!["bob".to_string(), "3".to_string()]);
+}
+
+#[test]
+fn test_visited_unknown_nodes() {
+    let nodes = r#"
+title: start
+---
+{visited("start")} {visited("other")} {visited(" DLC ")}
+{visited_count("start")} {visited_count("other")} {visited_count("DLC")}
+===
+title: other
+---
+other
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let start = NodeName::from("start");
+    engine.activate(start.clone());
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("false false false".into()))
+    );
+    assert_eq!(engine.next(), Some(YarnEntry::Say("0 0 0".into())));
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+
+    engine.activate(start);
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("true false false".into()))
+    );
+    assert_eq!(engine.next(), Some(YarnEntry::Say("1 0 0".into())));
+}
+
+#[test]
+#[should_panic]
+fn test_visited_requires_string() {
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string("title: start\n---\n{visited(5)}\n===\n")
+        .unwrap();
+    engine.activate(NodeName::from("start"));
+    engine.next();
+}
+
+#[test]
+fn test_string_functions() {
+    let nodes = r#"
+title: start
+---
+{length($name)} {upper($name)} {lower($name)}
+{substring($name, 2)} {substring($name, 1, 2)} {substring($name, 0, 99)} {substring($name, -3, 1)} [{substring($name, 10)}]
+{contains($name, "🐉b")} {contains($name, "x")}
+{upper("shout")}
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_variable(
+        VariableName("name".to_string()),
+        Value::String("a🐉bÉ".to_string()),
+    );
+    engine.activate(NodeName::from("start"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("4 A🐉BÉ a🐉bé".into())));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("bÉ 🐉b a🐉bÉ a []".into()))
+    );
+    assert_eq!(engine.next(), Some(YarnEntry::Say("true false".into())));
+
+    // Built-in functions can be replaced.
+    engine.register_function_overwriting(
+        "upper".to_string(),
+        1,
+        Box::new(|args, _| Ok(Value::String(format!("{}!", args[0].as_string())))),
+    );
+    assert_eq!(engine.next(), Some(YarnEntry::Say("shout!".into())));
+}
+
+#[test]
+fn test_format_function() {
+    let nodes = r#"
+title: start
+---
+<<set $summary to format("{0} {1}, or {0} {1} total", $count, $item)>>
+{$summary}
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_variable(VariableName("count".to_string()), 3.);
+    engine.set_variable(VariableName("item".to_string()), "apples");
+    engine.activate(NodeName::from("start"));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("3 apples, or 3 apples total".into()))
+    );
+
+    assert_eq!(
+        engine.evaluate_expression(r#"format("{{{0}}} is {1}", "x", true)"#),
+        Ok(Value::String("{x} is true".to_string()))
+    );
+    assert_eq!(
+        engine.evaluate_expression(r#"format("no placeholders")"#),
+        Ok(Value::String("no placeholders".to_string()))
+    );
+    for invalid in &[
+        r#"format("{0} and {1}", 1)"#,
+        r#"format("{name}", 1)"#,
+        r#"format("{0", 1)"#,
+        r#"format("0}", 1)"#,
+    ] {
+        assert!(
+            matches!(
+                engine.evaluate_expression(invalid),
+                Err(YarnError::FunctionFailed { ref function, .. }) if function == "format"
+            ),
+            "{}",
+            invalid
+        );
+    }
+}
+
+#[test]
+fn test_assertions() {
+    let nodes = r#"
+title: start
+---
+<<assert $act >= 1>>
+Act {$act} begins.
+<<assert $act >= 2, "Act two must have started">>
+The finale.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_variable(VariableName("act".to_string()), 1.);
+    let start = NodeName::from("start");
+
+    // Disabled assertions are skipped, even when they would fail.
+    assert_eq!(
+        engine.run_node(&start, ChoicePolicy::Fail),
+        Ok(vec![
+            YarnEntry::Say("Act 1 begins.".into()),
+            YarnEntry::Say("The finale.".into()),
+            YarnEntry::EndConversation(None),
+        ])
+    );
+
+    engine.set_assertions_enabled(true);
+    let error = engine.run_node(&start, ChoicePolicy::Fail).unwrap_err();
+    assert_eq!(
+        error,
+        YarnError::AssertionFailed {
+            node: start.clone(),
+            line: 6,
+            condition: "$act >= 2".to_string(),
+            message: Some("Act two must have started".to_string()),
+        }
+    );
+    assert_eq!(
+        error.to_string(),
+        "assertion `$act >= 2` failed in node `start` at line 6: Act two must have started"
+    );
+
+    engine.set_variable(VariableName("act".to_string()), 2.);
+    assert_eq!(
+        engine.run_node(&start, ChoicePolicy::Fail).unwrap().len(),
+        3
+    );
+
+    assert!(engine
+        .load_from_string("title: bad\n---\n<<assert $a, 1>>\n===\n")
+        .is_err());
+}
+
+#[test]
+fn test_log_steps() {
+    let nodes = r#"
+title: start
+---
+Shopkeeper: Welcome!
+<<log "gold is" $gold "after" ($gold - 5) upper("spending")>>
+Shopkeeper: Come again.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_variable(VariableName("gold".to_string()), 12.);
+    let start = NodeName::from("start");
+    let transcript = vec![
+        YarnEntry::Say("Shopkeeper: Welcome!".into()),
+        YarnEntry::Say("Shopkeeper: Come again.".into()),
+        YarnEntry::EndConversation(None),
+    ];
+
+    // Without a sink, logging is a no-op.
+    assert_eq!(
+        engine.run_node(&start, ChoicePolicy::Fail),
+        Ok(transcript.clone())
+    );
+
+    let messages = Rc::new(RefCell::new(vec![]));
+    let messages2 = messages.clone();
+    engine.on_log(move |message| messages2.borrow_mut().push(message.to_string()));
+    assert_eq!(engine.run_node(&start, ChoicePolicy::Fail), Ok(transcript));
+    assert_eq!(*messages.borrow(), vec!["gold is 12 after 7 SPENDING"]);
+
+    let texts = engine
+        .extract_lines()
+        .into_iter()
+        .map(|line| line.text)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        texts,
+        vec!["Shopkeeper: Welcome!", "Shopkeeper: Come again."]
+    );
+}
+
+#[test]
+fn test_pedantic_coercion_warnings() {
+    let nodes = r#"
+title: start
+---
+<<set $a to "x" + 1>>
+<<set $a to $gold - "2">>
+<<set $a to true * 2>>
+<<set $a to 4 / false>>
+<<set $a to $gold == "10">>
+<<set $a to 1 != true>>
+<<set $a to $gold > "10">>
+<<set $a to "a" >= "b">>
+<<set $a to 1 < true>>
+<<set $a to (1 + 1) <= "3">>
+<<set $a to -"5">>
+===
+title: typed
+---
+<<set $a to "x" + "y" == "xy" and $gold + 1 > 2 * 3 - -1>>
+<<set $a to $gold / 2 != 5 or $gold <= 10 and true == false>>
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_variable(VariableName("gold".to_string()), 10.);
+    let warnings = Rc::new(RefCell::new(vec![]));
+    let warnings2 = warnings.clone();
+    engine.on_coercion_warning(move |warning| warnings2.borrow_mut().push(warning.clone()));
+
+    // Warnings are only produced in pedantic mode.
+    let start = NodeName::from("start");
+    engine.run_node(&start, ChoicePolicy::Fail).unwrap();
+    assert!(warnings.borrow().is_empty());
+
+    engine.set_pedantic(true);
+    engine.run_node(&start, ChoicePolicy::Fail).unwrap();
+    let found = warnings
+        .borrow()
+        .iter()
+        .map(|w| (w.operator, w.operands.clone(), w.expression.clone()))
+        .collect::<Vec<_>>();
+    let expected = vec![
+        ("+", vec!["string", "number"], r#""x" + 1"#),
+        ("-", vec!["number", "string"], r#"$gold - "2""#),
+        ("*", vec!["boolean", "number"], "true * 2"),
+        ("/", vec!["number", "boolean"], "4 / false"),
+        ("==", vec!["number", "string"], r#"$gold == "10""#),
+        ("!=", vec!["number", "boolean"], "1 != true"),
+        (">", vec!["number", "string"], r#"$gold > "10""#),
+        (">=", vec!["string", "string"], r#""a" >= "b""#),
+        ("<", vec!["number", "boolean"], "1 < true"),
+        ("<=", vec!["number", "string"], r#"(1 + 1) <= "3""#),
+        ("-", vec!["string"], r#"-"5""#),
+    ];
+    let expected = expected
+        .into_iter()
+        .map(|(op, operands, expr)| (op, operands, expr.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(found, expected);
+    assert!(warnings
+        .borrow()
+        .iter()
+        .all(|w| w.node == Some(start.clone())));
+    // Results are unchanged.
+    assert_eq!(
+        engine.get_variable(&VariableName("a".to_string())),
+        Some(&Value::Number(0.))
+    );
+
+    warnings.borrow_mut().clear();
+    engine
+        .run_node(&NodeName::from("typed"), ChoicePolicy::Fail)
+        .unwrap();
+    assert!(warnings.borrow().is_empty());
+}
+
+#[test]
+fn test_type_checking_modes() {
+    let nodes = r#"
+title: start
+---
+<<declare $gold = 0>>
+<<declare $name = "hero" as String>>
+<<set $total to $gold + true>>
+Total {$total}.
+<<set $gold to "lots">>
+Gold {$gold}.
+===
+title: assign
+---
+<<set $gold to $name>>
+===
+"#;
+    let start = NodeName::from("start");
+    let gold = VariableName("gold".to_string());
+    let run = |mode: TypeChecking| {
+        let mut engine = YarnEngine::new();
+        engine.load_from_string(nodes).unwrap();
+        engine.set_type_checking(mode);
+        let warnings = Rc::new(RefCell::new(vec![]));
+        let warnings2 = warnings.clone();
+        engine.on_coercion_warning(move |w| warnings2.borrow_mut().push(w.clone()));
+        let result = engine.run_node(&start, ChoicePolicy::Fail);
+        let warnings = warnings.borrow().clone();
+        (engine, result, warnings)
+    };
+    let transcript = vec![
+        YarnEntry::Say("Total 1.".into()),
+        YarnEntry::Say("Gold lots.".into()),
+        YarnEntry::EndConversation(None),
+    ];
+    let addition = CoercionWarning {
+        node: Some(start.clone()),
+        operator: "+",
+        operands: vec!["number", "boolean"],
+        expression: "$gold + true".to_string(),
+    };
+    let assignment = CoercionWarning {
+        node: Some(start.clone()),
+        operator: "=",
+        operands: vec!["number", "string"],
+        expression: r#"$gold = "lots""#.to_string(),
+    };
+
+    let (engine, result, warnings) = run(TypeChecking::Coerce);
+    assert_eq!(result, Ok(transcript.clone()));
+    assert!(warnings.is_empty());
+    assert_eq!(
+        engine.validate(),
+        vec![
+            ValidationWarning::MismatchedOperands {
+                node: start.clone(),
+                line: 6,
+                expression: "$gold + true".to_string(),
+                operands: (YarnType::Number, YarnType::Boolean),
+            },
+            ValidationWarning::DeclaredTypeMismatch {
+                node: start.clone(),
+                line: 8,
+                variable: gold.clone(),
+                expected: YarnType::Number,
+                found: YarnType::String,
+            },
+            ValidationWarning::DeclaredTypeMismatch {
+                node: NodeName::from("assign"),
+                line: 13,
+                variable: gold.clone(),
+                expected: YarnType::Number,
+                found: YarnType::String,
+            },
+        ]
+    );
+
+    let (_, result, warnings) = run(TypeChecking::Warn);
+    assert_eq!(result, Ok(transcript));
+    assert_eq!(warnings, vec![addition.clone(), assignment]);
+
+    let (mut engine, result, warnings) = run(TypeChecking::Strict);
+    assert_eq!(result, Err(YarnError::TypeMismatch(addition)));
+    assert!(warnings.is_empty());
+    assert_eq!(engine.get_variable(&gold), Some(&Value::Number(0.)));
+    assert_eq!(
+        engine.run_node(&NodeName::from("assign"), ChoicePolicy::Fail),
+        Err(YarnError::TypeMismatch(CoercionWarning {
+            node: Some(NodeName::from("assign")),
+            operator: "=",
+            operands: vec!["number", "string"],
+            expression: "$gold = $name".to_string(),
+        }))
+    );
+
+    let mismatched = "title: bad\n---\n<<declare $x = 1 as String>>\n===\n";
+    assert!(engine.load_from_string(mismatched).is_err());
+}
+
+#[test]
+fn test_static_type_checking() {
+    let clean = r#"
+title: start
+---
+<<declare $gold = 10>>
+<<declare $name = "hero" as String>>
+<<set $gold to length(upper($name)) + 1>>
+<<if visited("start") or $gold > double(2)>>
+    <<set $name to format("{0} has {1}", $name, $gold)>>
+<<endif>>
+Shop.
+-> Buy <<if contains($name, "h")>>
+    <<set $gold to $gold - 1>>
+===
+"#;
+    let broken = r#"
+title: start
+---
+<<declare $gold = 10>>
+<<set $gold to upper("x")>>
+<<if $gold + 1>>
+    Rich.
+<<elseif visited(3)>>
+    Back.
+<<endif>>
+Shop.
+-> Buy <<if double($gold > 1)>>
+    Bought.
+===
+"#;
+    let start = NodeName::from("start");
+    let engine = |source: &str| {
+        let mut engine = YarnEngine::new();
+        engine.load_from_string(source).unwrap();
+        engine
+            .register_typed_fn("double", |x: f32| x * 2.0)
+            .unwrap();
+        engine
+            .set_function_signature("double", &[Some(YarnType::Number)], Some(YarnType::Number))
+            .unwrap();
+        engine
+    };
+    assert_eq!(engine(clean).validate(), vec![]);
+    assert_eq!(
+        engine(broken).validate(),
+        vec![
+            ValidationWarning::WrongArgumentType {
+                node: start.clone(),
+                line: 6,
+                function: "visited".to_string(),
+                index: 0,
+                expected: YarnType::String,
+                found: YarnType::Number,
+            },
+            ValidationWarning::WrongArgumentType {
+                node: start.clone(),
+                line: 11,
+                function: "double".to_string(),
+                index: 0,
+                expected: YarnType::Number,
+                found: YarnType::Boolean,
+            },
+            ValidationWarning::DeclaredTypeMismatch {
+                node: start.clone(),
+                line: 5,
+                variable: VariableName("gold".to_string()),
+                expected: YarnType::Number,
+                found: YarnType::String,
+            },
+            ValidationWarning::NonBooleanCondition {
+                node: start.clone(),
+                line: 6,
+                condition: "$gold + 1".to_string(),
+                found: YarnType::Number,
+            },
+            ValidationWarning::NonBooleanCondition {
+                node: start.clone(),
+                line: 11,
+                condition: "double($gold > 1)".to_string(),
+                found: YarnType::Number,
+            },
+        ]
+    );
+    assert_eq!(
+        engine(broken).validate()[2].to_string(),
+        "`$gold` is declared as number but assigned a string at line 5"
+    );
+    assert_eq!(
+        engine("").set_function_signature("doubel", &[], None),
+        Err(YarnError::UnknownFunction {
+            name: "doubel".to_string(),
+            suggestions: vec!["double".to_string()],
+        })
+    );
+}
+
+#[test]
+fn parse_boolean_literal_casing() {
+    for (input, value) in &[("True", true), ("FALSE", false), ("tRuE", true)] {
+        let mut t = TokenIterator::new(input);
+        assert_eq!(
+            parse_expr(&mut t).unwrap(),
+            Expr::Term(Term::Boolean(*value))
+        );
+
+        let options = ParseOptions {
+            strict_boolean_literals: true,
+            ..ParseOptions::default()
+        };
+        let mut t = TokenIterator::with_options(input, options);
+        assert!(parse_expr(&mut t).is_err());
+    }
+
+    let mut t = TokenIterator::new("true_name()");
+    assert_eq!(
+        parse_expr(&mut t).unwrap(),
+        Expr::Term(Term::Function("true_name".to_string(), vec![]))
+    );
+}
+
+#[test]
+fn parse_operator_precedence() {
+    let num = |n| Box::new(Expr::Term(Term::Number(n)));
+    let mut t = TokenIterator::new("1 + 2 * 3 - 4");
+    assert_eq!(
+        parse_expr(&mut t).unwrap(),
+        Expr::Binary(
+            BinaryOp::Minus,
+            Box::new(Expr::Binary(
+                BinaryOp::Plus,
+                num(1.0),
+                Box::new(Expr::Binary(BinaryOp::Multiply, num(2.0), num(3.0)))
+            )),
+            num(4.0)
+        )
+    );
+
+    let mut t = TokenIterator::new("(1 + 2) * 3");
+    assert_eq!(
+        parse_expr(&mut t).unwrap(),
+        Expr::Binary(
+            BinaryOp::Multiply,
+            Box::new(Expr::Parentheses(Box::new(Expr::Binary(
+                BinaryOp::Plus,
+                num(1.0),
+                num(2.0)
+            )))),
+            num(3.0)
+        )
+    );
+
+    let mut t = TokenIterator::new("$a??$b or 1");
+    assert_eq!(
+        parse_expr(&mut t).unwrap(),
+        Expr::Binary(
+            BinaryOp::Coalesce,
+            Box::new(Expr::Term(Term::Variable(VariableName("a".to_string())))),
+            Box::new(Expr::Binary(
+                BinaryOp::Or,
+                Box::new(Expr::Term(Term::Variable(VariableName("b".to_string())))),
+                num(1.0)
+            ))
+        )
+    );
+}
+
+#[test]
+fn test_coalesce_operator() {
+    let nodes = r#"
+title: start
+---
+{$nickname ?? count()}
+{$missing ?? count()}
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let calls = Rc::new(RefCell::new(0));
+    let calls2 = calls.clone();
+    engine
+        .register_function(
+            "count".to_string(),
+            0,
+            Box::new(move |_, _| {
+                *calls2.borrow_mut() += 1;
+                Ok(Value::String("Hello".to_string()))
+            }),
+        )
+        .unwrap();
+    engine.set_variable(
+        VariableName("nickname".to_string()),
+        Value::String("Sam".to_string()),
+    );
+    engine.activate(NodeName::from("start"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Sam".into())));
+    assert_eq!(*calls.borrow(), 0);
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Hello".into())));
+    assert_eq!(*calls.borrow(), 1);
+}
+
+#[test]
+fn test_coalesce_null_operand() {
+    let mut engine = YarnEngine::new();
+    engine
+        .register_function("nothing".to_string(), 0, Box::new(|_, _| Ok(Value::Null)))
+        .unwrap();
+    engine.set_variable(VariableName("nickname".to_string()), Value::Null);
+    for &coalesce in &[true, false] {
+        engine.set_coalesce_undefined_variables(coalesce);
+        let eval = |expr: &str| engine.evaluate_expression(expr);
+        assert_eq!(
+            eval("$nickname ?? \"d\""),
+            Ok(Value::String("d".to_string()))
+        );
+        assert_eq!(eval("null ?? 1"), Ok(Value::Number(1.)));
+        assert_eq!(eval("nothing() ?? null ?? 2"), Ok(Value::Number(2.)));
+        assert_eq!(eval("0 ?? 1"), Ok(Value::Number(0.)));
+        assert_eq!(eval("null ?? null"), Ok(Value::Null));
+    }
+}
+
+#[test]
+fn parse_nested_ternary() {
+    let var = |n: &str| Box::new(Expr::Term(Term::Variable(VariableName(n.to_string()))));
+    let num = |n| Box::new(Expr::Term(Term::Number(n)));
+    let mut t = TokenIterator::new("$a or $b ? 1 : $c?2:3");
+    assert_eq!(
+        parse_expr(&mut t).unwrap(),
+        Expr::Ternary(
+            Box::new(Expr::Binary(BinaryOp::Or, var("a"), var("b"))),
+            num(1.0),
+            Box::new(Expr::Ternary(var("c"), num(2.0), num(3.0)))
+        )
+    );
+}
+
+#[test]
+fn test_ternary_evaluates_taken_branch() {
+    let nodes = r#"
+title: start
+---
+Shopkeeper: {$gold >= 10 ? "Sure." : count()}
+Shopkeeper: {$gold >= 100 ? count() : "Too rich for you."}
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let calls = Rc::new(RefCell::new(0));
+    let calls2 = calls.clone();
+    engine
+        .register_function(
+            "count".to_string(),
+            0,
+            Box::new(move |_, _| {
+                *calls2.borrow_mut() += 1;
+                Ok(Value::String("counted".to_string()))
+            }),
+        )
+        .unwrap();
+    engine.set_variable(VariableName("gold".to_string()), Value::Number(20.));
+    engine.activate(NodeName::from("start"));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("Shopkeeper: Sure.".into()))
+    );
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("Shopkeeper: Too rich for you.".into()))
+    );
+    assert_eq!(*calls.borrow(), 0);
+}
+
+#[test]
+fn test_xor_operator() {
+    let nodes = r#"
+title: start
+---
+{true xor true} {true xor false} {false ^ true} {false^false}
+{true or true xor true} {true xor true and false} {1 xor 0} {2 ^ true}
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.activate(NodeName::from("start"));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("false true true false".into()))
+    );
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("true true true false".into()))
+    );
+
+    let bool_term = |b| Box::new(Expr::Term(Term::Boolean(b)));
+    let mut t = TokenIterator::new("true or true xor false and false");
+    assert_eq!(
+        parse_expr(&mut t).unwrap(),
+        Expr::Binary(
+            BinaryOp::Or,
+            bool_term(true),
+            Box::new(Expr::Binary(
+                BinaryOp::Xor,
+                bool_term(true),
+                Box::new(Expr::Binary(
+                    BinaryOp::And,
+                    bool_term(false),
+                    bool_term(false)
+                ))
+            ))
+        )
+    );
+}
+
+#[test]
+fn test_evaluate_expression() {
+    let mut engine = YarnEngine::new();
+    engine.register_function_overwriting(
+        "dice".to_string(),
+        1,
+        Box::new(|args, _| Ok(Value::Number(args[0].as_num()))),
+    );
+    engine.set_variable(VariableName("gold".to_string()), Value::Number(8.));
+    assert_eq!(
+        engine.evaluate_expression("$gold * 2 + 1"),
+        Ok(Value::Number(17.))
+    );
+    assert_eq!(
+        engine.evaluate_expression("$gold + dice(6) > 10"),
+        Ok(Value::Boolean(true))
+    );
+    assert!(matches!(
+        engine.evaluate_expression("$gold +"),
+        Err(YarnError::Parse { .. })
+    ));
+    assert!(matches!(
+        engine.evaluate_expression("$gold 5"),
+        Err(YarnError::Parse { .. })
+    ));
+    assert_eq!(
+        engine.evaluate_expression("$silver + 1"),
+        Err(YarnError::UndefinedVariable {
+            name: VariableName("silver".to_string()),
+            suggestions: vec![],
+        })
+    );
+}
+
+#[test]
+fn test_multibyte_token_boundaries() {
+    // Multibyte characters next to `<<`, `[[`, `->` and `:`, which must never
+    // split a character when the parser slices the source.
+    let bodies = [
+        "«Hello»\n-> «Да»[[Yes]]\n",
+        "«Да»<<set $x to 1>>\n",
+        "«\n<<if «>>\nx\n<<endif>>\n",
+        "->«Да»\n    Ok\n",
+        "Д:«»\n[[«|Yes]]\n",
+        "Ж: [[Yes]]\n",
+        "[[«Да»]]\n",
+        "«<<\n",
+        "«»[[\n",
+        "«:\n#«\n",
+    ];
+    for body in &bodies {
+        let source = format!(
+            "title: Start\n---\n{}===\ntitle: Yes\n---\nYes.\n===\n",
+            body
+        );
+        let mut engine = YarnEngine::new();
+        if engine.load_from_string(&source).is_ok() {
+            engine.validate();
+            engine.extract_lines();
+            let _ = engine.run_node(&NodeName::from("Start"), ChoicePolicy::FirstAvailable);
+        }
+    }
+
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string("title: Start\n---\n«Hello»\n-> «Да»\n[[«Да»|Start]]\n===\n")
+        .unwrap();
+    assert_eq!(
+        engine.run_node(&NodeName::from("Start"), ChoicePolicy::FirstAvailable),
+        Ok(vec![
+            YarnEntry::Choose {
+                text: "«Hello»".to_string(),
+                choices: vec!["«Да»".to_string(), "«Да»".to_string()],
+                timeout: None,
+                default_choice: None,
+                times_chosen: vec![0; 2],
+                matched: None,
+            },
+            YarnEntry::EndConversation(None),
+        ])
+    );
+}
+
+#[test]
+fn test_unicode_corpus() {
+    // `$cafe\u{301}` spells the variable with a combining accent.
+    let nodes = "title: 始まり
+tags: 日本語
+---
+<<declare $café_visité = false>>
+<<set $名前 to \"さくら\">>
+<<set $cafe\u{301}_count to 1>>
+さくら: こんにちは、{$名前}さん！ #line:挨拶 #😀
+Zoë: Ça va? {$cafe\u{301}_count} 👩‍👩‍👧
+<<if not $café_visité and $名前==\"さくら\">>
+    <<set $café_visité to true>>
+    カフェへようこそ ☕
+<<endif>>
+どこへ行く？
+-> 🍜 ラーメン #食べ物 #🍜
+    おいしい！
+-> Café ☕ <<if $café_visité>> #飲み物
+[[次へ|終わり]]
+===
+title: 終わり
+---
+Fin. 🎉 {length(\"日本語\")} {upper(\"éclair\")}
+[[🌸]]
+===
+title: 🌸
+---
+さようなら
+===
+";
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    assert_eq!(engine.validate(), vec![]);
+    let lines = engine.extract_lines();
+    assert_eq!(lines[0].id, "line:挨拶");
+    assert_eq!(
+        lines[0].tags,
+        vec!["line:挨拶".to_string(), "😀".to_string()]
+    );
+    assert_eq!(lines[4].tags, vec!["食べ物".to_string(), "🍜".to_string()]);
+    assert_eq!(
+        engine.run_node(&NodeName::from("始まり"), ChoicePolicy::Index(|_| 2)),
+        Ok(vec![
+            YarnEntry::Say("さくら: こんにちは、さくらさん！".into()),
+            YarnEntry::Say("Zoë: Ça va? 1 👩‍👩‍👧".into()),
+            YarnEntry::Say("カフェへようこそ ☕".into()),
+            YarnEntry::Choose {
+                text: "どこへ行く？".to_string(),
+                choices: vec![
+                    "🍜 ラーメン".to_string(),
+                    "Café ☕".to_string(),
+                    "次へ".to_string()
+                ],
+                timeout: None,
+                default_choice: None,
+                times_chosen: vec![0; 3],
+                matched: None,
+            },
+            YarnEntry::Say("Fin. 🎉 3 ÉCLAIR".into()),
+            YarnEntry::Say("さようなら".into()),
+            YarnEntry::EndConversation(None),
+        ])
+    );
+    assert_eq!(
+        engine.get_variable(&VariableName("café_visité".to_string())),
+        Some(&Value::Boolean(true))
+    );
+
+    engine.set_substitute_bare_variables(true);
+    engine
+        .load_from_string("title: Bare\n---\n<<show $cafe\u{301}_count € $5>>\n===\n")
+        .unwrap();
+    assert_eq!(
+        engine.run_node(&NodeName::from("Bare"), ChoicePolicy::Fail),
+        Ok(vec![
+            YarnEntry::Command {
+                action: "show 1 € $5".to_string()
+            },
+            YarnEntry::EndConversation(None),
+        ])
+    );
+}
+
+#[test]
+fn test_text_normalization() {
+    let text = "\u{201c}Wait\u{201d}\u{a0}\u{2014}  it\u{2019}s   late\u{2026} \u{a0}\t";
+    let normalize = |normalization: TextNormalization| normalization.apply(text);
+    assert_eq!(normalize(TextNormalization::default()), text);
+    assert_eq!(
+        normalize(TextNormalization {
+            trim_trailing_whitespace: true,
+            ..Default::default()
+        }),
+        "\u{201c}Wait\u{201d}\u{a0}\u{2014}  it\u{2019}s   late\u{2026} \u{a0}"
+    );
+    assert_eq!(
+        normalize(TextNormalization {
+            collapse_whitespace: true,
+            ..Default::default()
+        }),
+        "\u{201c}Wait\u{201d}\u{a0}\u{2014} it\u{2019}s late\u{2026} \u{a0} "
+    );
+    assert_eq!(
+        normalize(TextNormalization {
+            replace_non_breaking_spaces: true,
+            ..Default::default()
+        }),
+        "\u{201c}Wait\u{201d} \u{2014}  it\u{2019}s   late\u{2026}  \t"
+    );
+    assert_eq!(
+        normalize(TextNormalization {
+            ascii_punctuation: true,
+            ..Default::default()
+        }),
+        "\"Wait\"\u{a0}-  it's   late... \u{a0}\t"
+    );
+
+    let nodes = "title: Start
+---
+Guard:\u{a0} \u{201c}Halt!\u{201d}
+-> Run\u{2026}\u{a0}
+-> Stay
+===
+";
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let all = TextNormalization {
+        trim_trailing_whitespace: true,
+        collapse_whitespace: true,
+        replace_non_breaking_spaces: true,
+        ascii_punctuation: true,
+    };
+    engine.set_text_normalization(all);
+    assert_eq!(
+        engine.run_node(&NodeName::from("Start"), ChoicePolicy::FirstAvailable),
+        Ok(vec![
+            YarnEntry::Choose {
+                text: "Guard: \"Halt!\"".to_string(),
+                choices: vec!["Run...".to_string(), "Stay".to_string()],
+                timeout: None,
+                default_choice: None,
+                times_chosen: vec![0; 2],
+                matched: None,
+            },
+            YarnEntry::EndConversation(None),
+        ])
+    );
+    let lines = engine.extract_lines();
+    assert_eq!(lines[0].text, "Guard: \"Halt!\"");
+    assert_eq!(lines[1].text, "Run...");
+    assert_eq!(lines[1].previous.as_deref(), Some("Guard: \"Halt!\""));
+    // The loaded nodes keep the original text.
+    engine.set_text_normalization(TextNormalization::default());
+    assert_eq!(lines.len(), engine.extract_lines().len());
+    assert_eq!(engine.extract_lines()[1].text, "Run\u{2026}");
+}
+
+#[test]
+fn test_pseudo_localization() {
+    let nodes = r#"
+title: Start
+---
+Hello {$name}, \{literal\}.
+-> Yes
+-> No {$name}
+===
+"#;
+    let run = |values: bool| {
+        let mut engine = YarnEngine::new();
+        engine.load_from_string(nodes).unwrap();
+        engine.set_variable(VariableName("name".to_string()), "Bob");
+        engine.set_pseudo_localization(true);
+        engine.set_pseudo_localize_values(values);
+        engine.run_node(&NodeName::from("Start"), ChoicePolicy::FirstAvailable)
+    };
+    let choose = |text: &str, choices: &[&str]| {
+        Ok(vec![
+            YarnEntry::Choose {
+                text: text.to_string(),
+                choices: choices.iter().map(|c| c.to_string()).collect(),
+                timeout: None,
+                default_choice: None,
+                times_chosen: vec![0; choices.len()],
+                matched: None,
+            },
+            YarnEntry::EndConversation(None),
+        ])
+    };
+    assert_eq!(
+        run(false),
+        choose(
+            "[Hélló Bob, {lítérál}. ~~~~~~~]",
+            &["[Ýés ~]", "[Ñó Bob ~~]"]
+        )
+    );
+    assert_eq!(
+        run(true),
+        choose(
+            "[Hélló Bób, {lítérál}. ~~~~~~~]",
+            &["[Ýés ~]", "[Ñó Bób ~~]"]
+        )
+    );
+}
+
+#[test]
+fn test_markup_spans() {
+    let nodes = r#"
+title: Start
+---
+Oh [b]hi [wave size=2]{$name}[/wave][/b] \[ok\][pause/] [color="dark red"]end
+Pick [i]one[/i]
+-> [b]Yes[/b]
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_variable(VariableName("name".to_string()), "Zoë");
+    engine.set_markup(true);
+    engine.activate(NodeName::from("Start"));
+    let line = match engine.next() {
+        Some(YarnEntry::Say(line)) => line,
+        entry => panic!("unexpected entry {:?}", entry),
+    };
+    assert_eq!(line.plain_text(), "Oh hi Zoë [ok] end");
+    let span = |name: &str, properties: &[(&str, &str)], start, length| MarkupSpan {
+        name: name.to_string(),
+        properties: properties
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        start,
+        length,
+    };
+    assert_eq!(
+        line.spans(),
+        &[
+            span("b", &[], 3, 6),
+            span("wave", &[("size", "2")], 6, 3),
+            span("pause", &[], 14, 0),
+            span("color", &[("color", "dark red")], 15, 3),
+        ][..]
+    );
+    assert_eq!(line.spans()[1].property("size"), Some("2"));
+    engine.proceed();
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Choose {
+            text: "Pick one".to_string(),
+            choices: vec!["Yes".to_string()],
+            timeout: None,
+            default_choice: None,
+            times_chosen: vec![0; 1],
+            matched: None,
+        })
+    );
+}
+
+#[test]
+fn test_error_policy() {
+    let nodes = r#"
+title: Start
+---
+<<set $gold to missing(1)>>
+<<if missing()>>
+Broken branch
+<<else>>
+Else branch
+<<endif>>
+Hello {missing()}
+<<shout {missing()}>>
+Pick one
+-> Good
+-> Bad <<if missing()>>
+===
+"#;
+    let run = |policy: OnError| {
+        let mut engine = YarnEngine::new();
+        engine.load_from_string(nodes).unwrap();
+        engine.set_error_policy(policy);
+        let recovered = Rc::new(RefCell::new(0));
+        let counter = recovered.clone();
+        engine.on_recovered_error(move |_| *counter.borrow_mut() += 1);
+        let entries = engine.run_node(&NodeName::from("Start"), ChoicePolicy::FirstAvailable);
+        let recovered = *recovered.borrow();
+        (
+            entries,
+            recovered,
+            engine
+                .get_variable(&VariableName("gold".to_string()))
+                .cloned(),
+        )
+    };
+    let error = YarnError::UnknownFunction {
+        name: "missing".to_string(),
+        suggestions: vec![],
+    };
+    assert_eq!(run(OnError::EndConversation), (Err(error.clone()), 0, None));
+
+    let choose = YarnEntry::Choose {
+        text: "Pick one".to_string(),
+        choices: vec!["Good".to_string()],
+        timeout: None,
+        default_choice: None,
+        times_chosen: vec![0; 1],
+        matched: None,
+    };
+    let skipped = vec![
+        YarnEntry::Say("Else branch".into()),
+        choose.clone(),
+        YarnEntry::EndConversation(None),
+    ];
+    assert_eq!(run(OnError::SkipStep), (Ok(skipped), 5, None));
+
+    let emitted = vec![
+        Err(error.clone()),
+        Err(error.clone()),
+        Ok(YarnEntry::Say("Else branch".into())),
+        Err(error.clone()),
+        Err(error.clone()),
+        Err(error),
+        Ok(choose),
+        Ok(YarnEntry::EndConversation(None)),
+    ];
+    let (entries, recovered, gold) = run(OnError::Emit);
+    let entries = entries.unwrap().into_iter().map(|entry| match entry {
+        YarnEntry::Error(report) => Err(report.error),
+        entry => Ok(entry),
+    });
+    assert_eq!((entries.collect(), recovered, gold), (emitted, 5, None));
+}
+
+#[test]
+fn test_identifier_rules() {
+    let load = |line: &str| {
+        let mut engine = YarnEngine::new();
+        engine
+            .load_from_string(&format!("title: Start\n---\n{}\n===\n", line))
+            .is_ok()
+    };
+    assert!(load("<<set $_état2 to 1>>"));
+    assert!(load("<<declare $ΑΒΓ = 1>>"));
+    assert!(load("<<if $größe>1>>\n<<endif>>"));
+    assert!(!load("<<set $2fast to 1>>"));
+    assert!(!load("<<set $a.b to 1>>"));
+    assert!(!load("<<declare $🍜 = 1>>"));
+    assert!(!load("<<if $>>\n<<endif>>"));
+}
+
+#[test]
+fn test_did_you_mean_suggestions() {
+    let nodes = r#"
+title: Start
+---
+<<set $gold to 5>>
+<<set $good to true>>
+Hello.
+[[Shop|Shopkeper_Intro]]
+[[Away|Elsewhere]]
+===
+title: Shopkeeper_Intro
+---
+Welcome.
+[[Shopkeper_Intro]]
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let start = NodeName::from("Start");
+    let intro = NodeName::from("Shopkeeper_Intro");
+    assert_eq!(
+        engine.validate(),
+        vec![
+            ValidationWarning::UnknownNode {
+                node: start.clone(),
+                target: NodeName::from("Shopkeper_Intro"),
+                suggestions: vec!["Shopkeeper_Intro".to_string()],
+            },
+            ValidationWarning::UnknownNode {
+                node: start.clone(),
+                target: NodeName::from("Elsewhere"),
+                suggestions: vec![],
+            },
+            ValidationWarning::UnknownNode {
+                node: intro.clone(),
+                target: NodeName::from("Shopkeper_Intro"),
+                suggestions: vec!["Shopkeeper_Intro".to_string()],
+            },
+        ]
+    );
+
+    let err = engine.run_node(&intro, ChoicePolicy::Fail).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "unknown node `Shopkeper_Intro`; did you mean `Shopkeeper_Intro`?"
+    );
+
+    engine
+        .run_node(&start, ChoicePolicy::FirstAvailable)
+        .unwrap_err();
+    let err = engine.evaluate_expression("$glod + 1").unwrap_err();
+    assert_eq!(
+        err,
+        YarnError::UndefinedVariable {
+            name: VariableName("glod".to_string()),
+            suggestions: vec!["gold".to_string(), "good".to_string()],
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "undefined variable `$glod`; did you mean `$gold` or `$good`?"
+    );
+    assert_eq!(
+        engine
+            .evaluate_expression("visted(\"Start\")")
+            .unwrap_err()
+            .to_string(),
+        "unknown function `visted`; did you mean `visited`?"
+    );
+    assert_eq!(
+        engine
+            .evaluate_expression("$treasure")
+            .unwrap_err()
+            .to_string(),
+        "undefined variable `$treasure`"
+    );
+    assert_eq!(
+        engine.evaluate_expression("frobnicate()"),
+        Err(YarnError::UnknownFunction {
+            name: "frobnicate".to_string(),
+            suggestions: vec![],
+        })
+    );
+}
+
+#[test]
+fn test_run_node() {
+    let nodes = r#"
+title: linear
+---
+one
+<<wave>>
+two
+===
+title: choice
+---
+Pick one
+-> Left
+    went left
+-> Right
+    went right
+<<if true>>
+    inside
+<<endif>>
+done
+===
+title: loop
+---
+<<set $x 1>>
+[[loop]]
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let say = |s: &str| YarnEntry::Say(s.into());
+    let choose = |times_chosen| YarnEntry::Choose {
+        text: "Pick one".to_string(),
+        choices: vec!["Left".to_string(), "Right".to_string()],
+        timeout: None,
+        default_choice: None,
+        times_chosen,
+        matched: None,
+    };
+
+    assert_eq!(
+        engine.run_node(&NodeName::from("linear"), ChoicePolicy::Fail),
+        Ok(vec![
+            say("one"),
+            YarnEntry::Command {
+                action: "wave".to_string()
+            },
+            say("two"),
+            YarnEntry::EndConversation(None),
+        ])
+    );
+
+    let name = NodeName::from("choice");
+    assert_eq!(
+        engine.run_node(&name, ChoicePolicy::FirstAvailable),
+        Ok(vec![
+            choose(vec![0, 0]),
+            say("went left"),
+            say("inside"),
+            say("done"),
+            YarnEntry::EndConversation(None),
+        ])
+    );
+    assert_eq!(
+        engine.run_node(&name, ChoicePolicy::Index(|choices| choices.len() - 1)),
+        Ok(vec![
+            choose(vec![1, 0]),
+            say("went right"),
+            say("inside"),
+            say("done"),
+            YarnEntry::EndConversation(None),
+        ])
+    );
+    assert_eq!(
+        engine.run_node(&name, ChoicePolicy::Fail),
+        Err(YarnError::UnexpectedChoice)
+    );
+    assert!(!engine.is_active());
+
+    engine.set_step_budget(100);
+    assert_eq!(
+        engine.run_node(&NodeName::from("loop"), ChoicePolicy::Fail),
+        Err(YarnError::StepBudgetExceeded)
+    );
+    assert_eq!(
+        engine.run_node(&NodeName::from("missing"), ChoicePolicy::Fail),
+        Err(YarnError::UnknownNode(NodeName::from("missing"), vec![]))
+    );
+
+    engine.activate(NodeName::from("linear"));
+    assert_eq!(
+        engine.run_node(&name, ChoicePolicy::Fail),
+        Err(YarnError::ConversationActive)
+    );
+}
+
+#[test]
+fn test_dispatch_commands() {
+    let nodes = r#"
+title: start
+---
+hello
+<<play_sound door>>
+<<camera shake>>
+<<wait 2>>
+goodbye
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_dispatch_commands(true);
+    let calls = Rc::new(RefCell::new(vec![]));
+    let calls2 = calls.clone();
+    engine.register_command(
+        "play_sound".to_string(),
+        Box::new(move |args| calls2.borrow_mut().push(args)),
+    );
+    let calls2 = calls.clone();
+    engine.register_blocking_command(
+        "wait".to_string(),
+        Box::new(move |args| calls2.borrow_mut().push(args)),
+    );
+
+    engine.activate(NodeName::from("start"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("hello".into())));
+    assert!(calls.borrow().is_empty());
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Command {
+            action: "camera shake".to_string()
+        })
+    );
+    assert_eq!(*calls.borrow(), vec![vec!["door".to_string()]]);
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Command {
+            action: "wait 2".to_string()
+        })
+    );
+    assert_eq!(calls.borrow().len(), 2);
+    assert_eq!(engine.next(), Some(YarnEntry::Say("goodbye".into())));
+}
+
+#[test]
+fn test_run_with_handler() {
+    struct Recorder(Vec<YarnEntry>);
+    impl YarnHandler for Recorder {
+        fn say(&mut self, line: Say) {
+            self.0.push(YarnEntry::Say(line));
+        }
+        fn choose(&mut self, text: String, choices: Vec<String>) -> usize {
+            let index = choices.len() - 1;
+            self.0.push(YarnEntry::Choose {
+                text,
+                times_chosen: vec![0; choices.len()],
+                matched: None,
+                choices,
+                timeout: None,
+                default_choice: None,
+            });
+            index
+        }
+        fn command(&mut self, action: String) {
+            self.0.push(YarnEntry::Command { action });
+        }
+        fn end_conversation(&mut self) {
+            self.0.push(YarnEntry::EndConversation(None));
+        }
+    }
+
+    let nodes = r#"
+title: start
+---
+Guard: Halt!
+<<draw_sword>>
+Guard: Who goes there?
+-> A friend
+    Guard: Pass, friend.
+-> Nobody
+    [[suspicious]]
+===
+title: suspicious
+---
+Guard: Nobody, eh?
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let mut recorder = Recorder(vec![]);
+    engine
+        .run_with_handler(NodeName::from("start"), &mut recorder)
+        .unwrap();
+
+    engine.reset_times_chosen();
+    engine.activate(NodeName::from("start"));
+    let mut expected = vec![];
+    while let Some(entry) = engine.next() {
+        if let YarnEntry::Choose { ref choices, .. } = entry {
+            engine.choose(choices.len() - 1).unwrap();
+        }
+        expected.push(entry);
+    }
+    assert_eq!(recorder.0, expected);
+    assert_eq!(recorder.0.last(), Some(&YarnEntry::EndConversation(None)));
+    assert_eq!(recorder.0.len(), 5);
+}
+
+#[test]
+fn test_context_callbacks() {
+    struct World {
+        gold: f32,
+        visited: Vec<String>,
+    }
+
+    let nodes = r#"
+title: start
+---
+<<pay 5>>
+You have {gold()} gold.
+-> Continue
+    [[next]]
+===
+title: next
+---
+Now you have {gold()} gold.
+===
+"#;
+    let mut engine = YarnEngine::<World>::with_context();
+    engine.load_from_string(nodes).unwrap();
+    engine
+        .register_function_with_context(
+            "gold".to_string(),
+            0,
+            Box::new(|_, _, world: &mut World| Ok(Value::Number(world.gold))),
+        )
+        .unwrap();
+    engine.register_command_with_context(
+        "pay".to_string(),
+        Box::new(|args, world: &mut World| world.gold -= args[0].parse::<f32>().unwrap()),
+    );
+    engine.on_node_visited_with_context(|name, _, world: &mut World| {
+        world.visited.push(name.to_string())
+    });
+
+    let mut world = World {
+        gold: 12.,
+        visited: vec![],
+    };
+    engine.activate(NodeName::from("start"));
+    assert_eq!(
+        engine.next_with(&mut world),
+        Some(YarnEntry::Command {
+            action: "pay 5".to_string()
+        })
+    );
+    assert_eq!(
+        engine.next_with(&mut world),
+        Some(YarnEntry::Choose {
+            text: "You have 7 gold.".to_string(),
+            choices: vec!["Continue".to_string()],
+            timeout: None,
+            default_choice: None,
+            times_chosen: vec![0; 1],
+            matched: None
+        })
+    );
+    engine.choose_with(0, &mut world).unwrap();
+    assert_eq!(
+        engine.next_with(&mut world),
+        Some(YarnEntry::Say("Now you have 7 gold.".into()))
+    );
+    assert_eq!(
+        engine.next_with(&mut world),
+        Some(YarnEntry::EndConversation(None))
+    );
+    assert_eq!(world.gold, 7.);
+    assert_eq!(world.visited, vec!["start".to_string(), "next".to_string()]);
+}
+
+#[test]
+fn test_function_reads_variables() {
+    let nodes = r#"
+title: start
+---
+<<if total() > 5>>
+rich
+<<else>>
+poor
+<<endif>>
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine
+        .register_function(
+            "total".to_string(),
+            0,
+            Box::new(|_, state| {
+                let get = |name: &str| {
+                    state
+                        .get_variable(&VariableName(name.to_string()))
+                        .map_or(0., Value::as_num)
+                };
+                Ok(Value::Number(get("gold") + get("silver")))
+            }),
+        )
+        .unwrap();
+    engine.set_variable(VariableName("gold".to_string()), Value::Number(2.));
+    engine.set_variable(VariableName("silver".to_string()), Value::Number(3.));
+    engine.activate(NodeName::from("start"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("poor".into())));
+
+    engine.set_variable(VariableName("silver".to_string()), Value::Number(4.));
+    engine.activate(NodeName::from("start"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("rich".into())));
+}
+
+#[test]
+fn test_commands_require_proceed() {
+    let nodes = r#"
+title: start
+---
+<<pan_camera>>
+<<set $x to 5>>
+done
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_commands_require_proceed(true);
+    engine.activate(NodeName::from("start"));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Command {
+            action: "pan_camera".to_string()
+        })
+    );
+    assert_eq!(engine.status(), ConversationStatus::WaitingForCommand);
+    assert_eq!(engine.next(), None);
+    assert!(engine.evaluate_expression("$x").is_err());
+
+    engine.proceed();
+    assert_eq!(engine.next(), Some(YarnEntry::Say("done".into())));
+    assert_eq!(engine.evaluate_expression("$x"), Ok(Value::Number(5.)));
+}
+
+#[test]
+fn parse_set_forms() {
+    for input in &[
+        "set $x to 1 + 2",
+        "set $x = 1 + 2",
+        "set $x=1 + 2",
+        "set $x 1 + 2",
+    ] {
+        let source = format!("<<{}>>\n", input);
+        let mut t = TokenIterator::new(&source);
+        assert_eq!(
+            parse_step(&mut t).unwrap(),
+            Step::Assign(
+                VariableName("x".to_string()),
+                Expr::Binary(
+                    BinaryOp::Plus,
+                    Box::new(Expr::Term(Term::Number(1.0))),
+                    Box::new(Expr::Term(Term::Number(2.0)))
+                )
+            )
+        );
+    }
+    let mut t = TokenIterator::new("<<set x to 1>>\n");
+    assert!(parse_step(&mut t).is_err());
+}
+
+#[test]
+fn test_timed_choices() {
+    let nodes = r#"
+title: start
+---
+Bandit: Your money or your life! #timeout:5
+-> Fight
+    You fight.
+-> Pay up <<if $gold >= 10>> #default
+    You pay.
+-> Run
+    You run.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let name = NodeName::from("start");
+
+    engine.set_variable(VariableName("gold".to_string()), Value::Number(20.));
+    engine.activate(name.clone());
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Choose {
+            text: "Bandit: Your money or your life!".to_string(),
+            choices: vec!["Fight".to_string(), "Pay up".to_string(), "Run".to_string()],
+            timeout: Some(5.),
+            default_choice: Some(1),
+            times_chosen: vec![0; 3],
+            matched: None,
+        })
+    );
+    engine.choose_default().unwrap();
+    assert_eq!(engine.next(), Some(YarnEntry::Say("You pay.".into())));
+
+    engine.set_variable(VariableName("gold".to_string()), Value::Number(0.));
+    engine.activate(name);
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Choose {
+            text: "Bandit: Your money or your life!".to_string(),
+            choices: vec!["Fight".to_string(), "Run".to_string()],
+            timeout: Some(5.),
+            default_choice: Some(0),
+            times_chosen: vec![0; 2],
+            matched: None,
+        })
+    );
+    assert_eq!(engine.choose(2), Err(YarnError::InvalidChoice(2)));
+    engine.choose_default().unwrap();
+    assert_eq!(engine.next(), Some(YarnEntry::Say("You fight.".into())));
+}
+
+#[test]
+fn test_last_choice() {
+    let nodes = r#"
+title: start
+---
+Tea or coffee?
+-> Tea
+    Milk?
+    -> Yes
+        [[end]]
+    -> No
+        [[end]]
+-> Coffee
+    Coffee it is.
+===
+title: end
+---
+Enjoy.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let events = Rc::new(RefCell::new(vec![]));
+    let events2 = events.clone();
+    engine.on_choice_made(move |record| events2.borrow_mut().push(record.text.clone()));
+    let events2 = events.clone();
+    engine.on_node_visited(move |name, _| events2.borrow_mut().push(name.to_string()));
+
+    engine.activate(NodeName::from("start"));
+    assert_eq!(engine.last_choice(), None);
+    engine.next();
+    engine.choose(0).unwrap();
+    assert_eq!(
+        engine.last_choice(),
+        Some(&ChoiceRecord {
+            node: NodeName::from("start"),
+            prompt: "Tea or coffee?".to_string(),
+            text: "Tea".to_string(),
+            index: 0,
+        })
+    );
+    engine.next();
+    engine.choose(1).unwrap();
+    assert_eq!(
+        engine.last_choice(),
+        Some(&ChoiceRecord {
+            node: NodeName::from("start"),
+            prompt: "Milk?".to_string(),
+            text: "No".to_string(),
+            index: 1,
+        })
+    );
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Enjoy.".into())));
+    assert_eq!(
+        *events.borrow(),
+        vec!["Tea".to_string(), "No".to_string(), "start".to_string()]
+    );
+}
+
+#[test]
+fn test_node_trail() {
+    let nodes = r#"
+title: A
+---
+<<if visited("A")>>
+    [[C]]
+<<endif>>
+[[B]]
+===
+title: B
+---
+Where to?
+[[Onwards|C]]
+===
+title: C
+---
+Here.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let names = |names: &[&str]| names.iter().map(|&n| NodeName::from(n)).collect::<Vec<_>>();
+    assert!(engine.node_trail().is_empty());
+
+    engine.activate(NodeName::from("A"));
+    assert_eq!(engine.node_trail(), &names(&["A"])[..]);
+    engine.next();
+    assert_eq!(engine.node_trail(), &names(&["A", "B"])[..]);
+    engine.choose(0).unwrap();
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Here.".into())));
+    assert_eq!(engine.node_trail(), &names(&["A", "B", "C"])[..]);
+
+    engine.activate(NodeName::from("A"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Here.".into())));
+    assert_eq!(engine.node_trail(), &names(&["A", "C"])[..]);
+}
+
+#[test]
+fn test_validate_jump_cycles() {
+    let nodes = r#"
+title: Loop
+---
+<<set $x to 1>>
+[[Loop]]
+===
+title: Ping
+---
+<<if $x == 1>>
+    [[Pong]]
+<<endif>>
+Nothing to see here.
+===
+title: Pong
+---
+[[Ping]]
+===
+title: Talkative
+---
+Hello again.
+[[Talkative]]
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let name = |n: &str| NodeName::from(n);
+    assert_eq!(
+        engine.validate(),
+        vec![
+            ValidationWarning::JumpCycle(vec![name("Loop")]),
+            ValidationWarning::JumpCycle(vec![name("Ping"), name("Pong")]),
+        ]
+    );
+}
+
+#[test]
+fn test_local_variables() {
+    let nodes = r#"
+title: Start
+---
+<<set $_greeting to "Hello">>
+<<set $count to 1>>
+{$_greeting}, {$count ?? 0}.
+[[Next]]
+===
+title: Next
+---
+{$_greeting ?? "Goodbye"}.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Hello, 1.".into())));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Goodbye.".into())));
+
+    let variables = engine.variables();
+    assert_eq!(variables.len(), 1);
+    assert!(variables.contains_key(&VariableName("count".to_string())));
+}
+
+#[test]
+fn test_get_variable_as() {
+    let mut engine = YarnEngine::new();
+    let name = |n: &str| VariableName(n.to_string());
+    engine.set_variable(name("num"), 2.5);
+    engine.set_variable(name("flag"), true);
+    engine.set_variable(name("text"), "hi");
+
+    assert_eq!(engine.get_variable_as::<f32>(&name("num")), Ok(2.5));
+    assert_eq!(engine.get_variable_as::<bool>(&name("flag")), Ok(true));
+    assert_eq!(
+        engine.get_variable_as::<String>(&name("text")),
+        Ok("hi".to_string())
+    );
+    assert_eq!(
+        engine.get_variable_as::<f32>(&name("flag")),
+        Err(TypeError::Mismatch {
+            expected: "number",
+            found: Value::Boolean(true),
+        })
+    );
+    assert!(engine.get_variable_as::<bool>(&name("text")).is_err());
+    assert!(engine.get_variable_as::<String>(&name("num")).is_err());
+    assert_eq!(
+        engine.get_variable_as::<f32>(&name("missing")),
+        Err(TypeError::Undefined(name("missing")))
+    );
+
+    engine.set_lenient_conversions(true);
+    assert_eq!(engine.get_variable_as::<f32>(&name("flag")), Ok(1.));
+    assert_eq!(engine.get_variable_as::<f32>(&name("text")), Ok(0.));
+    assert_eq!(engine.get_variable_as::<bool>(&name("num")), Ok(true));
+    assert_eq!(engine.get_variable_as::<bool>(&name("text")), Ok(true));
+    assert_eq!(
+        engine.get_variable_as::<String>(&name("num")),
+        Ok("2.5".to_string())
+    );
+    assert_eq!(
+        engine.get_variable_as::<String>(&name("flag")),
+        Ok("true".to_string())
+    );
+}
+
+#[test]
+fn test_register_typed_fn() {
+    let mut engine = YarnEngine::new();
+    engine.register_typed_fn("answer", || 42.).unwrap();
+    engine
+        .register_typed_fn("shout", |s: String| format!("{}!", s.to_uppercase()))
+        .unwrap();
+    engine
+        .register_typed_fn("both", |a: bool, b: bool| a && b)
+        .unwrap();
+    engine
+        .register_typed_fn("clamp", |x: f32, lo: f32, hi: f32| x.max(lo).min(hi))
+        .unwrap();
+
+    let eval = |engine: &mut YarnEngine, expr: &str| engine.evaluate_expression(expr);
+    assert_eq!(eval(&mut engine, "answer()"), Ok(Value::Number(42.)));
+    assert_eq!(
+        eval(&mut engine, "shout(\"hey\")"),
+        Ok(Value::String("HEY!".to_string()))
+    );
+    assert_eq!(
+        eval(&mut engine, "both(true, false)"),
+        Ok(Value::Boolean(false))
+    );
+    assert_eq!(eval(&mut engine, "clamp(7, 0, 5)"), Ok(Value::Number(5.)));
+
+    assert_eq!(
+        eval(&mut engine, "clamp(\"7\", 0, 5)"),
+        Err(YarnError::FunctionFailed {
+            function: "clamp".to_string(),
+            message: "expected a number, found a string".to_string(),
+        })
+    );
+    assert_eq!(
+        eval(&mut engine, "clamp(7, 0)"),
+        Err(YarnError::WrongArgumentCount {
+            function: "clamp".to_string(),
+            args: 2,
+            expected: 3..=3,
+        })
+    );
+}
+
+#[test]
+fn test_function_registration_policy() {
+    let mut engine = YarnEngine::new();
+    let name = || "random_range".to_string();
+    assert!(!engine.has_function("random_range"));
+    assert_eq!(
+        engine.register_function(name(), 0, Box::new(|_, _| Ok(Value::Number(1.)))),
+        Ok(())
+    );
+    assert!(engine.has_function("random_range"));
+    assert_eq!(
+        engine.register_function(name(), 0, Box::new(|_, _| Ok(Value::Number(2.)))),
+        Err(RegisterError::AlreadyExists(name()))
+    );
+    assert_eq!(
+        engine.evaluate_expression("random_range()"),
+        Ok(Value::Number(1.))
+    );
+
+    engine.register_function_overwriting(name(), 0, Box::new(|_, _| Ok(Value::Number(3.))));
+    assert_eq!(
+        engine.evaluate_expression("random_range()"),
+        Ok(Value::Number(3.))
+    );
+
+    let always = || -> Box<FunctionCallback> { Box::new(|_, _| Ok(Value::Boolean(true))) };
+    let exists = Err(RegisterError::AlreadyExists("visited".to_string()));
+    assert_eq!(
+        engine.register_function("visited".to_string(), 1, always()),
+        exists
+    );
+    assert_eq!(
+        engine.register_typed_fn("visited", |_: String| true),
+        exists
+    );
+    assert_eq!(
+        engine.register_sync_function(
+            "visited".to_string(),
+            1,
+            Box::new(|_, _| Ok(Value::Boolean(true)))
+        ),
+        exists
+    );
+    engine.register_function_overwriting("visited".to_string(), 1, always());
+    assert_eq!(
+        engine.evaluate_expression("visited(\"Nowhere\")"),
+        Ok(Value::Boolean(true))
+    );
+}
+
+#[test]
+fn test_validate_function_calls() {
+    let nodes = r#"
+title: Start
+---
+<<if visited("Start") and visted("Start")>>
+    Typo.
+<<endif>>
+You have {gold(1)} gold.
+<<set $x to upper("a", "b")>>
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine
+        .register_function(
+            "gold".to_string(),
+            0,
+            Box::new(|_, _| Ok(Value::Number(5.))),
+        )
+        .unwrap();
+    engine.load_from_string(nodes).unwrap();
+    let node = NodeName::from("Start");
+    assert_eq!(
+        engine.validate(),
+        vec![
+            ValidationWarning::UnknownFunction {
+                node: node.clone(),
+                line: 4,
+                function: "visted".to_string(),
+                suggestions: vec!["visited".to_string()],
+            },
+            ValidationWarning::WrongArgumentCount {
+                node: node.clone(),
+                line: 7,
+                function: "gold".to_string(),
+                args: 1,
+                expected: 0..=0,
+            },
+            ValidationWarning::WrongArgumentCount {
+                node,
+                line: 8,
+                function: "upper".to_string(),
+                args: 2,
+                expected: 1..=1,
+            },
+        ]
+    );
+
+    engine
+        .register_function(
+            "visted".to_string(),
+            1,
+            Box::new(|_, _| Ok(Value::Boolean(true))),
+        )
+        .unwrap();
+    engine.register_function_overwriting(
+        "gold".to_string(),
+        1,
+        Box::new(|_, _| Ok(Value::Number(5.))),
+    );
+    engine.register_function_overwriting(
+        "upper".to_string(),
+        2,
+        Box::new(|_, _| Ok(Value::Boolean(true))),
+    );
+    assert_eq!(engine.validate(), vec![]);
+}
+
+#[test]
+fn test_node_sources() {
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string_named(
+            "intro.yarn",
+            "title: A\n---\nHi.\n===\ntitle: B\n---\nHo.\n===\n",
+        )
+        .unwrap();
+    engine
+        .load_from_string_named("outro.yarn", "title: C\n---\nBye.\n===\n")
+        .unwrap();
+    engine
+        .load_from_string("title: D\n---\nUnnamed.\n===\n")
+        .unwrap();
+
+    let name = |n: &str| NodeName::from(n);
+    assert_eq!(
+        engine.node(&name("B")).unwrap().source.as_deref(),
+        Some("intro.yarn")
+    );
+    assert_eq!(engine.node(&name("D")).unwrap().source, None);
+    assert_eq!(
+        engine.nodes_from_source("intro.yarn"),
+        vec![name("A"), name("B")]
+    );
+    assert_eq!(engine.nodes_from_source("outro.yarn"), vec![name("C")]);
+    assert_eq!(engine.nodes_from_source("missing.yarn"), vec![]);
+}
+
+#[test]
+fn test_node_order() {
+    let nodes = r#"
+title: Zebra
+---
+Stripes.
+===
+title: Mango
+---
+Sweet.
+===
+title: Apple
+---
+Crisp.
+===
+"#;
+    for _ in 0..2 {
+        let mut engine = YarnEngine::new();
+        engine.load_from_string(nodes).unwrap();
+        engine
+            .load_from_string("title: Mango\n---\nRipe.\n===\n")
+            .unwrap();
+        let titles = engine
+            .nodes()
+            .iter()
+            .map(|node| node.title.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(titles, vec!["Zebra", "Mango", "Apple"]);
+        let lines = engine
+            .extract_lines()
+            .into_iter()
+            .map(|line| line.text)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!["Stripes.", "Ripe.", "Crisp."]);
+    }
+}
+
+#[test]
+fn test_interned_node_names() {
+    let hub = "title: Hub\n---\nWelcome back.\n[[Again|Hub]]\n===\n";
+    let rooms = r#"
+title: Kitchen
+---
+Pots and pans.
+[[Back to the hub|Hub]]
+===
+title: Cellar
+---
+[[Hub]]
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(hub).unwrap();
+    let options = ParseOptions {
+        lazy_bodies: true,
+        ..ParseOptions::default()
+    };
+    engine
+        .load_from_string_with_options(rooms, &options)
+        .unwrap();
+
+    let title = engine.node(&NodeName::from("Hub")).unwrap().title.clone();
+    let mut references = vec![];
+    for node in engine.nodes().iter() {
+        for step in node.steps().unwrap() {
+            match step {
+                Step::Jump(name) => references.push(name.clone()),
+                Step::Dialogue(_, choices, _) => {
+                    for choice in choices {
+                        if let ChoiceKind::External(ref name) = choice.kind {
+                            references.push(name.clone());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    assert_eq!(references.len(), 3);
+    for name in references {
+        assert_eq!(name, title);
+        assert!(Arc::ptr_eq(&name.0, &title.0));
+    }
+}
+
+#[test]
+fn test_interpolated_choices_reflect_changes() {
+    let nodes = r#"
+title: Menu
+---
+You have {$gold} gold.
+-> Buy a sword for {$price}
+    <<set $gold to $gold - $price>>
+    [[Menu]]
+-> Count your coins ({counted()} times so far)
+    [[Menu]]
+-> Haggle
+    <<set $price to $price - 1>>
+    [[Menu]]
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    let counted = Rc::new(RefCell::new(0.));
+    let counted2 = counted.clone();
+    engine
+        .register_typed_fn("counted", move || {
+            *counted2.borrow_mut() += 1.;
+            *counted2.borrow()
+        })
+        .unwrap();
+    engine.set_variable(VariableName("gold".to_string()), 20.);
+    engine.set_variable(VariableName("price".to_string()), 5.);
+    engine.activate(NodeName::from("Menu"));
+
+    let present = |engine: &mut YarnEngine| match engine.next() {
+        Some(YarnEntry::Choose { text, choices, .. }) => (text, choices),
+        entry => panic!("unexpected entry {:?}", entry),
+    };
+    let (text, choices) = present(&mut engine);
+    assert_eq!(text, "You have 20 gold.");
+    assert_eq!(choices[0], "Buy a sword for 5");
+    assert_eq!(choices[1], "Count your coins (1 times so far)");
+
+    engine.choose(1).unwrap();
+    let (text, choices) = present(&mut engine);
+    assert_eq!(text, "You have 20 gold.");
+    assert_eq!(choices[1], "Count your coins (2 times so far)");
+
+    engine.choose(2).unwrap();
+    let (text, choices) = present(&mut engine);
+    assert_eq!(text, "You have 20 gold.");
+    assert_eq!(choices[0], "Buy a sword for 4");
+
+    engine.choose(0).unwrap();
+    let (text, _) = present(&mut engine);
+    assert_eq!(text, "You have 16 gold.");
+
+    engine.set_variable(VariableName("gold".to_string()), 100.);
+    engine.stop_conversation();
+    engine.activate(NodeName::from("Menu"));
+    let (text, choices) = present(&mut engine);
+    assert_eq!(text, "You have 100 gold.");
+    assert_eq!(choices[1], "Count your coins (5 times so far)");
+}
+
+#[test]
+fn test_stats() {
+    let nodes = r#"
+title: Start
+---
+<<set $gold to 5>>
+<<if $gold > 3 and visited("Start")>>
+    Rich.
+<<elseif $gold > 1>>
+    Comfortable.
+<<endif>>
+You have {$gold} gold.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Comfortable.".into())));
+    assert_eq!(engine.stats().steps(), 0);
+
+    engine.enable_stats(true);
+    engine.activate(NodeName::from("Start"));
+    let entries = engine.by_ref().collect::<Vec<_>>();
+    assert_eq!(entries.len(), 3);
+    let stats = engine.stats();
+    // The assignment, conditional, dialogue and interpolated line.
+    assert_eq!(stats.steps(), 4);
+    // The assignment, both conditions and the interpolation are each evaluated once.
+    assert_eq!(stats.expressions(), 4);
+    assert_eq!(stats.function_calls(), 1);
+    assert_eq!(stats.variable_lookups(), 3);
+    assert_eq!(engine.conversation_stats().steps(), 4);
+
+    engine.activate(NodeName::from("Start"));
+    engine.next();
+    assert_eq!(engine.stats().steps(), 7);
+    assert_eq!(engine.conversation_stats().steps(), 3);
+
+    engine.reset_stats();
+    assert_eq!(engine.stats().steps(), 0);
+    assert_eq!(engine.conversation_stats().expressions(), 0);
+}
+
+#[test]
+fn test_stats_unary_operands() {
+    let nodes = r#"
+title: Start
+---
+<<if !$rich and -$debt < 0>>
+    Poor.
+<<endif>>
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_variable(VariableName("rich".to_string()), false);
+    engine.set_variable(VariableName("debt".to_string()), 5.);
+    engine.enable_stats(true);
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Poor.".into())));
+    // The condition is a single expression, however many operators it contains.
+    assert_eq!(engine.stats().expressions(), 1);
+    assert_eq!(engine.stats().variable_lookups(), 2);
+}
+
+#[test]
+fn parse_limits() {
+    let options = ParseOptions::default();
+    let node = |body: &str| format!("title: Start\n---\nHello.\n{}\n===\n", body);
+    let limit = |source: &str, options: &ParseOptions| {
+        YarnEngine::new().load_from_string_with_options(source, options)
+    };
+
+    let parens = format!(
+        "<<if {}true{}>>\n<<endif>>",
+        "(".repeat(20_000),
+        ")".repeat(20_000)
+    );
+    assert_eq!(
+        limit(&node(&parens), &options),
+        Err(YarnError::LimitExceeded {
+            limit: ParseLimit::ExpressionDepth,
+            line: 4,
+        })
+    );
+
+    let chain = format!("<<set $x to 1{}>>", " + 1".repeat(10_000));
+    assert_eq!(
+        limit(&node(&chain), &options),
+        Err(YarnError::LimitExceeded {
+            limit: ParseLimit::ExpressionDepth,
+            line: 4,
+        })
+    );
+
+    let ifs = format!(
+        "{}Deep.\n{}",
+        "<<if true>>\n".repeat(10_000),
+        "<<endif>>\n".repeat(10_000)
+    );
+    assert_eq!(
+        limit(&node(&ifs), &options),
+        Err(YarnError::LimitExceeded {
+            limit: ParseLimit::BlockDepth,
+            line: 68,
+        })
+    );
+
+    let options_body = (0..1000)
+        .map(|depth| {
+            let indent = " ".repeat(depth * 2);
+            format!("{}Onwards?\n{}-> Deeper\n", indent, indent)
+        })
+        .collect::<String>();
+    assert!(matches!(
+        limit(&node(&options_body), &options),
+        Err(YarnError::LimitExceeded {
+            limit: ParseLimit::BlockDepth,
+            ..
+        })
+    ));
+
+    let long_line = "a".repeat(10 * 1024 * 1024);
+    assert_eq!(
+        limit(&node(&long_line), &options),
+        Err(YarnError::LimitExceeded {
+            limit: ParseLimit::LineLength,
+            line: 4,
+        })
+    );
+
+    let few_nodes = ParseOptions {
+        max_nodes: 2,
+        ..ParseOptions::default()
+    };
+    let three = "title: A\n---\nA.\n===\ntitle: B\n---\nB.\n===\ntitle: C\n---\nC.\n===\n";
+    assert_eq!(
+        limit(three, &few_nodes),
+        Err(YarnError::LimitExceeded {
+            limit: ParseLimit::Nodes,
+            line: 8,
+        })
+    );
+
+    let modest = format!("<<set $x to ((1){}) * 2>>", " + 1".repeat(100));
+    assert_eq!(limit(&node(&modest), &options), Ok(()));
+}
+
+#[test]
+fn test_lazy_bodies() {
+    let nodes = r#"
+title: Start
+tags: intro
+---
+Hello.
+[[Next]]
+===
+title: Next
+---
+Goodbye.
+===
+title: Broken
+---
+<<set missing to 1>>
+===
+"#;
+    let lazy = ParseOptions {
+        lazy_bodies: true,
+        ..ParseOptions::default()
+    };
+    assert!(YarnEngine::new()
+        .load_from_string_with_options(nodes, &ParseOptions::default())
+        .is_err());
+
+    let mut engine = YarnEngine::new();
+    engine.load_from_string_with_options(nodes, &lazy).unwrap();
+    let name = |n: &str| NodeName::from(n);
+    assert_eq!(
+        engine.node(&name("Start")).unwrap().header("tags"),
+        Some("intro")
+    );
+    assert!(engine.node(&name("Broken")).is_some());
+
+    engine.activate(name("Start"));
+    let entries = engine.by_ref().collect::<Vec<_>>();
+    assert_eq!(
+        entries,
+        vec![
+            YarnEntry::Say("Hello.".into()),
+            YarnEntry::Say("Goodbye.".into()),
+            YarnEntry::EndConversation(None),
+        ]
+    );
+
+    assert_eq!(
+        engine.parse_all(),
+        Err(YarnError::Parse {
+            line: Some(14),
+            message: "invalid statement `<<set missing to 1>>` in node `Broken`".to_string()
+        })
+    );
+    assert_eq!(
+        engine.run_node(&name("Broken"), ChoicePolicy::Fail),
+        Err(YarnError::Parse {
+            line: Some(14),
+            message: "invalid statement `<<set missing to 1>>` in node `Broken`".to_string()
+        })
+    );
+    assert_eq!(
+        engine.validate(),
+        vec![ValidationWarning::InvalidBody {
+            node: name("Broken"),
+            message:
+                "parse error at line 14: invalid statement `<<set missing to 1>>` in node `Broken`"
+                    .to_string(),
+        }]
+    );
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parse_parallel_matches_serial() {
+    use crate::parse::parse_nodes_serially;
+
+    let node = |n: usize, body: &str| {
+        format!(
+            "title: Node{}\n---\nHello {}.\n-> Option\n    <<set $x to {}>>\n<<if $x > {}>>\n    {}\n<<endif>>\n[[Node{}]]\n===\n",
+            n, n, n, n, body, n + 1
+        )
+    };
+    let corpus = |broken: &[usize], body: &str| {
+        (0..200)
+            .map(|n| node(n, if broken.contains(&n) { body } else { "Fine." }))
+            .collect::<String>()
+    };
+    let deep = format!("<<set $y to {}1{}>>", "(".repeat(300), ")".repeat(300));
+    let sources = vec![
+        corpus(&[], ""),
+        corpus(&[50, 150], "<<set y to 1>>"),
+        corpus(&[120], &deep),
+        corpus(&[10], &deep) + "title: Bad\nno colon here\n",
+        corpus(&[], "") + "title: Bad\nno colon here\n",
+    ];
+    let few_nodes = ParseOptions {
+        max_nodes: 100,
+        ..ParseOptions::default()
+    };
+    for source in &sources {
+        for options in &[ParseOptions::default(), few_nodes.clone()] {
+            assert_eq!(
+                parse_nodes_from_string(source, options, &NodeNames::default()),
+                parse_nodes_serially(source, options, &NodeNames::default())
+            );
+        }
+    }
+    assert!(
+        parse_nodes_from_string(&sources[0], &ParseOptions::default(), &NodeNames::default())
+            .is_ok()
+    );
+    for source in &sources[1..] {
+        assert!(
+            parse_nodes_from_string(source, &ParseOptions::default(), &NodeNames::default())
+                .is_err()
+        );
+    }
+}
+
+#[cfg(feature = "derive")]
+#[test]
+fn test_derive_yarn_variables() {
+    use crate::convert::YarnVariables;
+    use crate::error::TypeError;
+
+    #[derive(Debug, PartialEq, yarn_spool_derive::YarnVariables)]
+    struct Save {
+        gold: u32,
+        #[yarn(rename = "met_the_king")]
+        met_king: bool,
+        /// The name the player chose.
+        pub player_name: String,
+        #[yarn(skip)]
+        playtime: f32,
+    }
+
+    let mut engine = YarnEngine::new();
+    let save = Save {
+        gold: 12,
+        met_king: true,
+        player_name: "Zoë".to_string(),
+        playtime: 90.0,
+    };
+    save.apply_to(&mut engine);
+    let var = |name: &str| VariableName(name.to_string());
+    assert_eq!(engine.get_variable(&var("gold")), Some(&Value::Number(12.)));
+    assert_eq!(
+        engine.get_variable(&var("met_the_king")),
+        Some(&Value::Boolean(true))
+    );
+    assert_eq!(engine.get_variable(&var("met_king")), None);
+    assert_eq!(engine.get_variable(&var("playtime")), None);
+    assert_eq!(
+        Save::extract_from(&engine),
+        Ok(Save {
+            playtime: 0.,
+            ..save
+        })
+    );
+
+    engine.set_variable(var("gold"), 2.5);
+    assert_eq!(
+        Save::extract_from(&engine),
+        Err(TypeError::Mismatch {
+            expected: "whole number",
+            found: Value::Number(2.5),
+        })
+    );
+    engine.set_variable(var("gold"), -1.);
+    assert!(Save::extract_from(&engine).is_err());
+}
+
+#[test]
+fn test_temporary_variables() {
+    let nodes = r#"
+title: Start
+---
+<<set $gold to 5>>
+<<set $temp_roll to 3>>
+You rolled {$temp_roll}.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine
+        .run_node(&NodeName::from("Start"), ChoicePolicy::FirstAvailable)
+        .unwrap();
+    let var = |name: &str| VariableName(name.to_string());
+    assert_eq!(engine.variables_all().len(), 2);
+
+    // Only the persistent variable survives a save and load.
+    let saved = engine
+        .variables_persistent()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<Vec<_>>();
+    let mut restored = YarnEngine::new();
+    for (name, value) in saved {
+        restored.set_variable(name, value);
+    }
+    assert_eq!(restored.variables_all().len(), 1);
+    assert_eq!(
+        restored.get_variable(&var("gold")),
+        Some(&Value::Number(5.))
+    );
+
+    engine.clear_temporary_variables();
+    assert_eq!(engine.get_variable(&var("temp_roll")), None);
+    assert_eq!(engine.get_variable(&var("gold")), Some(&Value::Number(5.)));
+
+    engine.set_temporary_prefix("$scratch_");
+    engine.set_variable(var("scratch_x"), 1.);
+    engine.set_variable(var("temp_roll"), 1.);
+    let mut persistent = engine
+        .variables_persistent()
+        .map(|(name, _)| name.0.as_str())
+        .collect::<Vec<_>>();
+    persistent.sort();
+    assert_eq!(persistent, vec!["gold", "temp_roll"]);
+
+    engine.set_temporary_prefix("");
+    assert_eq!(engine.variables_persistent().count(), 3);
+}
+
+#[test]
+fn test_constants() {
+    let consts = r#"
+title: Config
+---
+<<const MAX_GOLD = 100>>
+<<const GREETING = "Well met">>
+===
+"#;
+    let nodes = r#"
+title: Start
+---
+<<set $gold to MAX_GOLD - 10>>
+<<if $gold < MAX_GOLD>>
+{GREETING}, you have {$gold} gold.
+<<endif>>
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(consts).unwrap();
+    engine.load_from_string(nodes).unwrap();
+    assert_eq!(engine.constants().len(), 2);
+    assert_eq!(
+        engine.constants().get("MAX_GOLD"),
+        Some(&Value::Number(100.))
+    );
+    assert_eq!(
+        engine.run_node(&NodeName::from("Start"), ChoicePolicy::Fail),
+        Ok(vec![
+            YarnEntry::Say("Well met, you have 90 gold.".into()),
+            YarnEntry::EndConversation(None),
+        ])
+    );
+
+    // Constants are shared by every load, so redefining one is an error.
+    let redefined = "title: Other\n---\n<<const MAX_GOLD = 5>>\n===\n";
+    assert_eq!(
+        engine.load_from_string_with_options(redefined, &ParseOptions::default()),
+        Err(YarnError::DuplicateConstant("MAX_GOLD".to_string()))
+    );
+    let undefined = "title: Other\n---\n<<if $gold > MIN_GOLD>>\nRich.\n<<endif>>\n===\n";
+    assert_eq!(
+        engine.load_from_string_with_options(undefined, &ParseOptions::default()),
+        Err(YarnError::UndefinedConstant("MIN_GOLD".to_string()))
+    );
+    let assigned = "title: Other\n---\n<<set $MAX_GOLD to 5>>\n===\n";
+    assert_eq!(
+        engine.load_from_string_with_options(assigned, &ParseOptions::default()),
+        Err(YarnError::AssignToConstant("MAX_GOLD".to_string()))
+    );
+    assert!(engine.node(&NodeName::from("Other")).is_none());
+}
+
+#[test]
+fn test_enums() {
+    let nodes = r#"
+title: Start
+---
+<<enum Mood>>
+<<case Happy>>
+<<case Sad>>
+<<endenum>>
+<<declare $mood = .Happy>>
+<<if $mood == Mood.Happy>>
+The guard is {$mood}.
+<<endif>>
+<<set $mood to .Sad>>
+<<if $mood == .Happy>>
+Still happy.
+<<elseif $mood != Mood.Happy>>
+Now sad.
+<<endif>>
+===
+title: Broken
+---
+<<set $mood to Mood.Angry>>
+<<set $count to $mood + 1>>
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    assert_eq!(
+        engine.enum_cases("Mood"),
+        Some(&["Happy".to_string(), "Sad".to_string()][..])
+    );
+    let mood = |case: &str| Value::Enum {
+        type_name: "Mood".to_string(),
+        case: case.to_string(),
+    };
+    let var = VariableName("mood".to_string());
+    assert_eq!(engine.get_variable(&var), Some(&mood("Happy")));
+    assert_eq!(
+        engine.run_node(&NodeName::from("Start"), ChoicePolicy::Fail),
+        Ok(vec![
+            YarnEntry::Say("The guard is Happy.".into()),
+            YarnEntry::Say("Now sad.".into()),
+            YarnEntry::EndConversation(None),
+        ])
+    );
+    assert_eq!(engine.get_variable(&var), Some(&mood("Sad")));
+
+    let warnings = engine.validate();
+    assert!(warnings.contains(&ValidationWarning::UnknownEnumCase {
+        node: NodeName::from("Broken"),
+        case: "Mood.Angry".to_string(),
+    }));
+    assert!(warnings.contains(&ValidationWarning::MismatchedOperands {
+        node: NodeName::from("Broken"),
+        line: 22,
+        expression: "$mood + 1".to_string(),
+        operands: (YarnType::Enum, YarnType::Number),
+    }));
+    assert_eq!(
+        engine
+            .evaluate_expression("$mood + 1")
+            .unwrap_err()
+            .to_string(),
+        "type mismatch: `+` applied to enum and number in `$mood + 1`"
+    );
+    assert_eq!(
+        engine.evaluate_expression("Mood.Angry"),
+        Err(YarnError::UnknownEnumCase("Mood.Angry".to_string()))
+    );
+}
+
+#[test]
+fn test_line_groups() {
+    let nodes = r#"
+title: Start
+---
+=> Hello.
+=> Good day. #line:greeting
+=> Welcome back. <<if $met>>
+=> Never shown. <<if false>>
+Bye.
+=> Skipped. <<if false>>
+=> Also skipped. <<if $met and false>>
+===
+"#;
+    let run = |engine: &mut YarnEngine| {
+        let entries = engine
+            .run_node(&NodeName::from("Start"), ChoicePolicy::Fail)
+            .unwrap();
+        entries
+            .into_iter()
+            .filter_map(|entry| match entry {
+                YarnEntry::Say(line) => Some(line.into_plain_text()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut engine = YarnEngine::new();
+    engine.set_seed(7);
+    engine.load_from_string(nodes).unwrap();
+    engine.set_variable(VariableName("met".to_string()), false);
+
+    // Unseen lines are preferred, and a line is only shown if its condition holds.
+    // The second group's conditions are all false, so it is skipped.
+    let first = run(&mut engine);
+    let second = run(&mut engine);
+    assert_eq!(first.len(), 2);
+    assert_eq!(first[1], "Bye.");
+    let mut greetings = vec![first[0].clone(), second[0].clone()];
+    greetings.sort();
+    assert_eq!(greetings, vec!["Good day.", "Hello."]);
+    engine.set_variable(VariableName("met".to_string()), true);
+    assert_eq!(run(&mut engine)[0], "Welcome back.");
+    // Once every line has been shown, the least recently shown comes next.
+    assert_eq!(run(&mut engine)[0], first[0]);
+    assert_eq!(run(&mut engine)[0], second[0]);
+
+    // The same seed makes the same selection.
+    let mut other = YarnEngine::new();
+    other.set_seed(7);
+    other.load_from_string(nodes).unwrap();
+    other.set_variable(VariableName("met".to_string()), false);
+    assert_eq!(run(&mut other), first);
+
+    // Each alternative is a localizable line.
+    assert_eq!(engine.extract_lines()[1].id, "line:greeting");
+}
+
+#[test]
+fn test_string_tables() {
+    let nodes = r#"
+title: Start
+---
+Hello, {$name}. #line:hello
+How are you?
+Goodbye.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_variable(VariableName("name".to_string()), "Ann");
+    let table = |lines: &[(&str, &str)]| {
+        lines
+            .iter()
+            .map(|&(id, text)| (id.to_string(), text.to_string()))
+            .collect::<HashMap<_, _>>()
+    };
+    engine.add_string_table(
+        "fr",
+        table(&[
+            ("line:hello", "Bonjour, {$name}."),
+            ("line:Start-95eafef62f98c265", "Comment ça va ?"),
+            ("line:Start-c9c30a70bb3392d8", "Au revoir."),
+        ]),
+    );
+    engine.add_string_table("de", table(&[("line:hello", "Hallo, {$name}.")]));
+    engine.set_default_locale("fr");
+    let missing = Rc::new(RefCell::new(vec![]));
+    let recorder = missing.clone();
+    engine.on_missing_line(move |id, locale| {
+        recorder.borrow_mut().push(format!("{} in {}", id, locale))
+    });
+
+    // Without an active locale, lines come from the default locale.
+    assert_eq!(
+        engine.run_node(&NodeName::from("Start"), ChoicePolicy::Fail),
+        Ok(vec![
+            YarnEntry::Say("Bonjour, Ann.".into()),
+            YarnEntry::Say("Comment ça va ?".into()),
+            YarnEntry::Say("Au revoir.".into()),
+            YarnEntry::EndConversation(None),
+        ])
+    );
+    assert!(missing.borrow().is_empty());
+
+    // Lines missing from the active locale fall back to the default locale.
+    engine.activate_with_locale(NodeName::from("Start"), "de");
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Hallo, Ann.".into())));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("Comment ça va ?".into()))
+    );
+    // Switching locale takes effect on the next line.
+    engine.set_active_locale("fr");
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Au revoir.".into())));
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+    assert_eq!(*missing.borrow(), vec!["line:Start-95eafef62f98c265 in de"]);
+
+    // Lines missing from every table are presented as written.
+    engine.add_string_table("fr", table(&[]));
+    engine.activate_with_locale(NodeName::from("Start"), "de");
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Hallo, Ann.".into())));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("How are you?".into())));
+    assert_eq!(
+        missing.borrow()[1..],
+        [
+            "line:Start-95eafef62f98c265 in de",
+            "line:Start-95eafef62f98c265 in fr"
+        ]
+    );
+}
+
+#[test]
+fn test_markup_nomarkup_and_character() {
+    let nodes = r#"
+title: Start
+---
+Sally: Hi [b]there[/b]
+Oh [nomarkup][b]{$name} #not-a-tag[/nomarkup] [i]ok[/i] #tag
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string_with_options(nodes, &ParseOptions::default())
+        .unwrap();
+    engine.set_variable(VariableName("name".to_string()), "Zoë");
+    engine.set_markup(true);
+    engine.activate(NodeName::from("Start"));
+    let mut next_line = || match engine.next() {
+        Some(YarnEntry::Say(line)) => {
+            engine.proceed();
+            line
+        }
+        entry => panic!("unexpected entry {:?}", entry),
+    };
+
+    let line = next_line();
+    assert_eq!(line.plain_text(), "Sally: Hi there");
+    let character = &line.spans()[0];
+    assert_eq!(character.name, "character");
+    assert_eq!(character.property("name"), Some("Sally"));
+    assert_eq!((character.start, character.length), (0, 7));
+    assert_eq!(line.spans()[1].name, "b");
+
+    // Nothing within nomarkup is interpreted, including interpolations and tags.
+    let line = next_line();
+    assert_eq!(line.plain_text(), "Oh [b]{$name} #not-a-tag ok");
+    assert_eq!(line.spans().len(), 1);
+    assert_eq!((line.spans()[0].start, line.spans()[0].length), (25, 2));
+}
+
+#[test]
+fn test_load_sources() {
+    let sources = || {
+        vec![
+            (
+                "intro.yarn".to_string(),
+                "title: Start\n---\nHi.\n<<jump Shop>>\n===\n".to_string(),
+            ),
+            (
+                "shop.yarn".to_string(),
+                "title: Shop\n---\n<<set $gold to>>\n===\n".to_string(),
+            ),
+        ]
+    };
+    let start = NodeName::from("Start");
+
+    let mut engine: YarnEngine = YarnEngine::new();
+    let report = engine.load_sources(sources(), &LoadOptions::default());
+    assert!(report.loaded.is_empty());
+    assert_eq!(report.errors.len(), 1);
+    assert_eq!(report.errors[0].0, "shop.yarn");
+    assert!(engine.node(&start).is_none());
+
+    let options = LoadOptions {
+        atomic: false,
+        ..LoadOptions::default()
+    };
+    let report = engine.load_sources(sources(), &options);
+    assert_eq!(report.loaded, vec!["intro.yarn".to_string()]);
+    assert_eq!(report.errors[0].0, "shop.yarn");
+    assert_eq!(engine.nodes_from_source("intro.yarn"), vec![start]);
+}
+
+#[test]
+fn test_load_sources_duplicate_node() {
+    let mut engine: YarnEngine = YarnEngine::new();
+    let report = engine.load_sources(
+        vec![
+            (
+                "a.yarn".to_string(),
+                "title: Start\n---\nA\n===\n".to_string(),
+            ),
+            (
+                "b.yarn".to_string(),
+                "title: Start\n---\nB\n===\n".to_string(),
+            ),
+        ],
+        &LoadOptions::default(),
+    );
+    let err = YarnError::DuplicateNode {
+        name: NodeName::from("Start"),
+        first: "a.yarn".to_string(),
+        second: "b.yarn".to_string(),
+    };
+    assert_eq!(
+        err.to_string(),
+        "node `Start` in `b.yarn` is already defined in `a.yarn`"
+    );
+    assert_eq!(report.errors, vec![("b.yarn".to_string(), err)]);
+    assert!(engine.nodes().is_empty());
+}
+
+#[test]
+fn test_checkpoints() {
+    let nodes = r#"
+title: One
+---
+<<set $gold to 1>>
+One.
+<<jump Two>>
+===
+title: Two
+---
+<<set $gold to $gold + 1>>
+Two.
+<<jump Three>>
+===
+title: Three
+---
+Three with {$gold} gold, {visited_count("Two")} visit.
+===
+"#;
+    let gold = VariableName("gold".to_string());
+    let run = |engine: &mut YarnEngine| {
+        let mut lines = vec![];
+        for entry in engine.by_ref() {
+            match entry {
+                YarnEntry::Say(line) => lines.push(line.plain_text().to_string()),
+                YarnEntry::EndConversation(None) => break,
+                entry => panic!("unexpected entry {:?}", entry),
+            }
+        }
+        lines
+    };
+
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_checkpoint_limit(2);
+    engine.activate(NodeName::from("One"));
+    assert_eq!(run(&mut engine).len(), 3);
+    // The checkpoint of the first node was discarded.
+    let checkpoints = engine.checkpoints().to_vec();
+    assert_eq!(checkpoints.len(), 2);
+    assert_eq!(checkpoints[0].sequence, 2);
+    assert_eq!(checkpoints[0].node, NodeName::from("Two"));
+    assert_eq!(checkpoints[0].next, Some(NodeName::from("Three")));
+    assert_eq!(checkpoints[1].next, None);
+
+    let mut restored = YarnEngine::new();
+    restored.load_from_string(nodes).unwrap();
+    restored.restore_checkpoint(&checkpoints[0]).unwrap();
+    assert_eq!(restored.get_variable_as::<f32>(&gold), Ok(2.));
+    assert_eq!(run(&mut restored), vec!["Three with 2 gold, 1 visit."]);
+
+    let mut empty: YarnEngine = YarnEngine::new();
+    assert!(empty.restore_checkpoint(&checkpoints[0]).is_err());
+}
+
+#[test]
+fn test_checkpoints_skip_temporary_variables() {
+    let nodes = r#"
+title: One
+---
+<<set $gold to 1>>
+<<set $temp_mood to "happy">>
+<<jump Two>>
+===
+title: Two
+---
+Two.
+===
+"#;
+    let gold = VariableName("gold".to_string());
+    let mood = VariableName("temp_mood".to_string());
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_checkpoint_limit(1);
+    engine.activate(NodeName::from("One"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Two.".into())));
+    assert!(engine.get_variable(&mood).is_some());
+    let checkpoint = engine.checkpoints()[0].clone();
+
+    let mut restored = YarnEngine::new();
+    restored.load_from_string(nodes).unwrap();
+    restored.restore_checkpoint(&checkpoint).unwrap();
+    assert_eq!(restored.get_variable_as::<f32>(&gold), Ok(1.));
+    assert_eq!(restored.get_variable(&mood), None);
+
+    engine.restore_checkpoint(&checkpoint).unwrap();
+    assert_eq!(engine.get_variable(&mood), None);
+}
+
+#[test]
+fn test_reentrant_callback() {
+    let engine = Rc::new(RefCell::new(YarnEngine::new()));
+    engine
+        .borrow_mut()
+        .load_from_string("title: Start\n---\n<<restart>>\nStill here.\n===\n")
+        .unwrap();
+    let results = Rc::new(RefCell::new(vec![]));
+    let (inner, inner_results) = (Rc::downgrade(&engine), results.clone());
+    engine.borrow_mut().register_command(
+        "restart".to_string(),
+        Box::new(move |_| {
+            let engine = inner.upgrade().unwrap();
+            let activated = engine
+                .try_borrow_mut()
+                .map(|mut engine| engine.activate(NodeName::from("Start")));
+            inner_results.borrow_mut().push(activated.is_ok());
+        }),
+    );
+    engine.borrow_mut().set_dispatch_commands(true);
+    engine.borrow_mut().activate(NodeName::from("Start"));
+    let entry = engine.borrow_mut().next();
+    match entry {
+        Some(YarnEntry::Say(line)) => assert_eq!(line.plain_text(), "Still here."),
+        entry => panic!("unexpected entry {:?}", entry),
+    }
+    assert_eq!(*results.borrow(), vec![false]);
+}
+
+#[test]
+fn test_weighted_autochoice() {
+    let nodes = r#"
+title: Bark
+autochoice: weighted
+---
+<<set $rare to false>>
+Guard:
+-> Common #weight:3
+    Nice weather.
+-> Uncommon
+    Quiet today.
+-> Rare <<if $rare>> #weight:100
+    Dragons!
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_seed(7);
+    let mut counts = HashMap::new();
+    for _ in 0..2000 {
+        engine.activate(NodeName::from("Bark"));
+        match engine.next() {
+            Some(YarnEntry::Say(line)) => assert_eq!(line.plain_text(), "Guard:"),
+            entry => panic!("unexpected entry {:?}", entry),
+        }
+        engine.proceed();
+        match engine.next() {
+            Some(YarnEntry::Say(line)) => {
+                *counts.entry(line.plain_text().to_string()).or_insert(0) += 1
+            }
+            entry => panic!("unexpected entry {:?}", entry),
+        }
+        assert_eq!(engine.last_choice().unwrap().prompt, "Guard:");
+    }
+    assert_eq!(counts.len(), 2);
+    let common = counts["Nice weather."];
+    assert!((1400..1600).contains(&common), "{:?}", counts);
+}
+
+#[test]
+fn test_forward_compatible() {
+    let unknown = ["<<set $gold to {1, 2}>>", "<<set $mood = @happy>>"];
+    let source = format!(
+        "title: Start\n---\n{}\n<<once>>\nHi [wave amp=2/]there.\n{}\n<<set $seen to true>>\n===\n",
+        unknown[0], unknown[1]
+    );
+    let mut engine = YarnEngine::new();
+    assert!(engine
+        .load_from_string_with_options(&source, &ParseOptions::default())
+        .is_err());
+    let options = ParseOptions {
+        forward_compatible: true,
+        ..ParseOptions::default()
+    };
+    engine
+        .load_from_string_with_options(&source, &options)
+        .unwrap();
+
+    // Each unknown statement is reported, as written.
+    let statements = engine
+        .validate()
+        .into_iter()
+        .filter_map(|warning| match warning {
+            ValidationWarning::UnknownStatement { node, source } => {
+                assert_eq!(node, NodeName::from("Start"));
+                Some(format!("<<{}>>", source))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(statements, unknown);
+
+    // Known statements and text still run, and unknown ones are skipped.
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Command {
+            action: "once".to_string()
+        })
+    );
+    engine.proceed();
+    match engine.next() {
+        Some(YarnEntry::Say(line)) => assert_eq!(line.plain_text(), "Hi [wave amp=2/]there."),
+        entry => panic!("unexpected entry {:?}", entry),
+    }
+    engine.proceed();
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+    let seen = VariableName("seen".to_string());
+    assert_eq!(engine.get_variable_as::<bool>(&seen), Ok(true));
+    assert_eq!(engine.get_variable(&VariableName("gold".to_string())), None);
+}
+
+#[test]
+fn test_step() {
+    let nodes = r#"
+title: Start
+---
+<<set $gold to 5>>
+<<if $gold > 3>>
+    Rich.
+<<endif>>
+<<jump End>>
+===
+title: End
+---
+Bye.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    assert_eq!(engine.step(), Ok(StepResult::Paused));
+    engine.activate(NodeName::from("Start"));
+    let start = NodeName::from("Start");
+    let executed = |kind| StepResult::Executed {
+        node: start.clone(),
+        kind,
+    };
+    let say = |text: &str| StepResult::Entry(YarnEntry::Say(Say::from(text)));
+    let expected = vec![
+        executed(StepKind::Assign {
+            variable: VariableName("gold".to_string()),
+            value: Some(Value::Number(5.)),
+        }),
+        executed(StepKind::Conditional),
+        say("Rich."),
+        executed(StepKind::Jump(NodeName::from("End"))),
+        say("Bye."),
+        StepResult::Entry(YarnEntry::EndConversation(None)),
+        StepResult::Paused,
+    ];
+    for expected in expected {
+        assert_eq!(engine.step(), Ok(expected));
+    }
+}
+
+#[test]
+fn test_return() {
+    let nodes = r#"
+title: ShouldGreet
+---
+<<return $mood == "happy" and visited("Intro")>>
+Never reached.
+===
+title: Intro
+---
+<<if $mood == "happy">>
+    <<return "wave">>
+<<endif>>
+Hello.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine.set_variable(VariableName("mood".to_string()), "happy");
+    let run = |engine: &mut YarnEngine, node: &str| {
+        engine
+            .run_node(&NodeName::from(node), ChoicePolicy::Fail)
+            .unwrap()
+    };
+    assert_eq!(
+        run(&mut engine, "ShouldGreet"),
+        vec![YarnEntry::EndConversation(Some(Value::Boolean(false)))]
+    );
+    assert_eq!(
+        run(&mut engine, "Intro"),
+        vec![YarnEntry::EndConversation(Some(Value::String(
+            "wave".to_string()
+        )))]
+    );
+    assert_eq!(
+        run(&mut engine, "ShouldGreet"),
+        vec![YarnEntry::EndConversation(Some(Value::Boolean(true)))]
+    );
+
+    // A node that ends without returning has no value.
+    engine.set_variable(VariableName("mood".to_string()), "grumpy");
+    assert_eq!(
+        run(&mut engine, "Intro"),
+        vec![
+            YarnEntry::Say(Say::from("Hello.")),
+            YarnEntry::EndConversation(None)
+        ]
+    );
+}
+
+#[test]
+fn test_diff() {
+    let old = r#"
+title: Start
+tags: intro
+---
+Hello. #line:hello
+How are you?
+-> Fine
+-> Bad
+===
+title: Unchanged
+---
+Same.
+===
+title: Old
+---
+Gone soon.
+===
+"#;
+    let new = r#"
+title: Start
+tags: intro draft
+---
+Welcome!
+Hello.   #line:hello
+How are you today?
+-> Fine
+===
+title: Unchanged
+---
+    Same.
+===
+title: New
+---
+Brand new.
+===
+"#;
+    let load = |source| {
+        let mut engine = YarnEngine::new();
+        engine.load_from_string(source).unwrap();
+        engine
+    };
+    let (old, new) = (load(old), load(new));
+    let diff = crate::diff::diff(old.nodes(), new.nodes());
+    let summary = diff
+        .nodes
+        .iter()
+        .map(|node| (node.node.as_str(), node.status))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![
+            ("Start", NodeStatus::Modified),
+            ("New", NodeStatus::Added),
+            ("Old", NodeStatus::Removed),
+        ]
+    );
+
+    let start = &diff.nodes[0];
+    assert_eq!(
+        start.headers,
+        vec![HeaderChange {
+            name: "tags".to_string(),
+            old: Some("intro".to_string()),
+            new: Some("intro draft".to_string()),
+        }]
+    );
+    // The tagged line moved but is unchanged; untagged lines are matched by position.
+    let text = |line: &Option<LocalizableLine>| line.as_ref().map(|line| line.text.clone());
+    let lines = start
+        .lines
+        .iter()
+        .map(|change| (change.id.as_str(), text(&change.old), text(&change.new)))
+        .collect::<Vec<_>>();
+    let some = |s: &str| Some(s.to_string());
+    assert_eq!(
+        lines,
+        vec![
+            ("line:Start-e0ed4a6bf61d6f90", None, some("Welcome!")),
+            (
+                "line:Start-c580a73a753978b4",
+                some("Fine"),
+                some("How are you today?")
+            ),
+            ("line:Start-56b8d0ade2684cf5", some("Bad"), some("Fine")),
+            ("line:Start-95eafef62f98c265", some("How are you?"), None),
+        ]
+    );
+
+    let json = diff.to_json();
+    assert!(json.starts_with(r#"{"nodes":[{"node":"Start","status":"modified","headers":[{"name":"tags","old":"intro","new":"intro draft"}],"lines":[{"id":"line:Start-e0ed4a6bf61d6f90","old":null,"new":{"kind":"say","text":"Welcome!","tags":[]}}"#));
+    assert!(json.ends_with(r#"{"node":"Old","status":"removed","headers":[],"lines":[{"id":"line:Old-65cddd2f3bf9d438","old":{"kind":"say","text":"Gone soon.","tags":[]},"new":null}]}]}"#));
+}
+
+#[test]
+fn test_incremental_load() {
+    let source = r#"title: Start
+---
+Hello.
+<<jump Middle>>
+===
+title: Middle
+---
+Still here.
+<<jump End>>
+===
+title: End
+---
+Goodbye.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    let mut session = engine.begin_load(source, &ParseOptions::default());
+    let progress = session.work(1).unwrap();
+    assert_eq!(progress.nodes, 1);
+    assert!(!progress.done);
+    assert!(progress.bytes > 0 && progress.bytes < progress.total_bytes);
+    let progress = session.work(1).unwrap();
+    assert_eq!(progress.nodes, 2);
+    assert!(!progress.done);
+    assert!(engine.node(&NodeName::from("Start")).is_none());
+    engine.finish_load(session).unwrap();
+    assert!(engine.node(&NodeName::from("End")).is_some());
+
+    engine.activate(NodeName::from("Start"));
+    let lines = engine
+        .by_ref()
+        .filter_map(|entry| match entry {
+            YarnEntry::Say(line) => Some(line.plain_text().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(lines, vec!["Hello.", "Still here.", "Goodbye."]);
+}
+
+#[test]
+fn test_incremental_load_error() {
+    let source = "title: Start\n---\nHello.\n===\ntitle: Broken\nHello.\n===\n";
+    let mut engine = YarnEngine::new();
+    let mut session = engine.begin_load(source, &ParseOptions::default());
+    assert_eq!(session.work(1).unwrap().nodes, 1);
+    assert!(session.work(1).is_err());
+    assert!(session.work(1).is_err());
+    assert!(engine.finish_load(session).is_err());
+    assert!(engine.node(&NodeName::from("Start")).is_none());
+}
+
+#[test]
+fn test_flags() {
+    let source = r#"title: Start
+---
+<<flag met_king>>
+<<if flag("met_king") and not flag("saw_cutscene")>>
+    The king remembers you.
+<<endif>>
+<<flag saw_cutscene>>
+{flag("saw_cutscene")}
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    assert_eq!(engine.flags().count(), 0);
+    engine.activate(NodeName::from("Start"));
+    let lines = engine
+        .by_ref()
+        .filter_map(|entry| match entry {
+            YarnEntry::Say(line) => Some(line.plain_text().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(lines, vec!["The king remembers you.", "true"]);
+
+    let mut flags = engine.flags().collect::<Vec<_>>();
+    flags.sort();
+    assert_eq!(flags, vec!["met_king", "saw_cutscene"]);
+    let saved = engine
+        .variables_persistent()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<Vec<_>>();
+    let mut restored = YarnEngine::new();
+    restored.load_from_string(source).unwrap();
+    for (name, value) in saved {
+        restored.set_variable(name, value);
+    }
+    assert_eq!(restored.flags().count(), 2);
+    assert!(engine.validate().is_empty());
+}
+
+#[test]
+fn test_flag_warnings() {
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string(
+            r#"title: Start
+---
+<<flag met_king>>
+<<flag met_king>>
+<<if flag("met_queen")>>
+    Hello again.
+<<endif>>
+===
+"#,
+        )
+        .unwrap();
+    assert_eq!(
+        engine.validate(),
+        vec![
+            ValidationWarning::FlagNeverChecked {
+                node: NodeName::from("Start"),
+                flag: "met_king".to_string(),
+            },
+            ValidationWarning::FlagNeverSet {
+                node: NodeName::from("Start"),
+                flag: "met_queen".to_string(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_times_chosen() {
+    let source = r#"title: Hub
+---
+What now?
+-> Ask about the king #line:ask_king
+    <<if chosen("Hub", "ask_king") > 1>>
+        You already asked.
+    <<endif>>
+-> Leave
+    <<stop>>
+<<if chosen("Hub", 0) >= 2>>
+    Enough about the king.
+<<else>>
+    [[Hub]]
+<<endif>>
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.set_checkpoint_limit(10);
+    engine.load_from_string(source).unwrap();
+    let hub = NodeName::from("Hub");
+    let times_chosen = |engine: &mut YarnEngine| loop {
+        match engine.next() {
+            Some(YarnEntry::Choose { times_chosen, .. }) => break times_chosen,
+            Some(_) => {}
+            None => panic!("expected options"),
+        }
+    };
+
+    engine.activate(hub.clone());
+    assert_eq!(times_chosen(&mut engine), vec![0, 0]);
+    engine.choose(0).unwrap();
+    assert_eq!(times_chosen(&mut engine), vec![1, 0]);
+    assert_eq!(engine.times_chosen(&hub, 0), 1);
+    engine.choose(0).unwrap();
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("You already asked.".into()))
+    );
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("Enough about the king.".into()))
+    );
+    assert_eq!(engine.times_chosen(&hub, 0), 2);
+    assert_eq!(engine.times_chosen(&hub, 1), 0);
+
+    let checkpoint = engine.checkpoints()[0].clone();
+    engine.reset_times_chosen();
+    assert_eq!(engine.times_chosen(&hub, 0), 0);
+    engine.activate(hub.clone());
+    assert_eq!(times_chosen(&mut engine), vec![0, 0]);
+
+    engine.restore_checkpoint(&checkpoint).unwrap();
+    assert_eq!(engine.times_chosen(&hub, 0), 1);
+    assert_eq!(times_chosen(&mut engine), vec![1, 0]);
+}
+
+#[test]
+fn test_runtime_limits() {
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string(
+            "title: Ping\n---\n<<jump Pong>>\n===\ntitle: Pong\n---\n<<jump Ping>>\n===\n",
+        )
+        .unwrap();
+    engine.set_jump_limit(10);
+    engine.activate(NodeName::from("Ping"));
+    let err = engine.try_next_with(&mut ()).unwrap_err();
+    match err {
+        YarnError::RuntimeLimitExceeded {
+            limit: RuntimeLimit::Jumps,
+            max: 10,
+            ref node,
+            ref trail,
+        } => {
+            assert_eq!(*node, NodeName::from("Ping"));
+            assert_eq!(trail.len(), 11);
+        }
+        ref err => panic!("unexpected error: {:?}", err),
+    }
+    assert!(err.to_string().starts_with(
+        "maximum consecutive jumps of 10 exceeded in node `Ping` (trail: Ping -> Pong"
+    ));
+
+    // Jumps are only counted until an entry is produced.
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string(
+            "title: Ping\n---\nPing.\n<<jump Pong>>\n===\ntitle: Pong\n---\n<<jump Ping>>\n===\n",
+        )
+        .unwrap();
+    engine.set_jump_limit(2);
+    engine.activate(NodeName::from("Ping"));
+    for _ in 0..5 {
+        assert_eq!(
+            engine.try_next_with(&mut ()),
+            Ok(Some(YarnEntry::Say("Ping.".into())))
+        );
+    }
+
+    let depth = 10;
+    let mut source = "title: Deep\n---\n".to_string();
+    for level in 0..depth {
+        source.push_str(&format!("{}<<if true>>\n", "    ".repeat(level)));
+    }
+    source.push_str(&format!("{}Deep enough.\n", "    ".repeat(depth)));
+    for level in (0..depth).rev() {
+        source.push_str(&format!("{}<<endif>>\n", "    ".repeat(level)));
+    }
+    source.push_str("===\n");
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(&source).unwrap();
+    engine.activate(NodeName::from("Deep"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Deep enough.".into())));
+    engine.set_nesting_limit(5);
+    engine.activate(NodeName::from("Deep"));
+    assert_eq!(
+        engine.try_next_with(&mut ()),
+        Err(YarnError::RuntimeLimitExceeded {
+            limit: RuntimeLimit::Nesting,
+            max: 5,
+            node: NodeName::from("Deep"),
+            trail: vec![NodeName::from("Deep")],
+        })
+    );
+}
+
+#[test]
+fn test_interjections() {
+    let source = r#"title: Start
+---
+Guard: Halt!
+Guard: State your business.
+-> Trade
+    Guard: Go on through.
+-> Leave
+===
+title: Radio
+---
+Radio: Come in, over.
+<<jump Static>>
+===
+title: Static
+---
+Radio: Kzzt.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    let say = |text: &str| Some(YarnEntry::Say(text.into()));
+    let choose = Some(YarnEntry::Choose {
+        text: "Guard: State your business.".to_string(),
+        choices: vec!["Trade".to_string(), "Leave".to_string()],
+        timeout: None,
+        default_choice: None,
+        times_chosen: vec![0, 0],
+        matched: None,
+    });
+
+    assert_eq!(
+        engine.interject_line("Too early."),
+        Err(YarnError::NoConversation)
+    );
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(engine.next(), say("Guard: Halt!"));
+    engine.interject_line("Your radio crackles...").unwrap();
+    assert_eq!(engine.next(), say("Your radio crackles..."));
+    engine.interject_node(NodeName::from("Static")).unwrap();
+    assert_eq!(engine.next(), say("Radio: Kzzt."));
+    assert_eq!(engine.next(), choose);
+
+    // Interjections at a choice point present the same options again.
+    engine.interject_node(NodeName::from("Radio")).unwrap();
+    assert_eq!(engine.next(), say("Radio: Come in, over."));
+    engine.interject_line("Static fills the air.").unwrap();
+    assert_eq!(engine.next(), say("Static fills the air."));
+    assert_eq!(engine.next(), say("Radio: Kzzt."));
+    assert_eq!(engine.next(), choose);
+    assert!(engine.node_trail().ends_with(&[NodeName::from("Start")]));
+    engine.interject_line("The guard waits.").unwrap();
+    assert_eq!(engine.next(), say("The guard waits."));
+    assert_eq!(engine.next(), choose);
+    engine.choose(0).unwrap();
+    assert_eq!(engine.next(), say("Guard: Go on through."));
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+    assert_eq!(engine.visit_count(&NodeName::from("Static")), 2);
+
+    assert_eq!(
+        engine.interject_node(NodeName::from("Radio")),
+        Err(YarnError::NoConversation)
+    );
+    engine.activate(NodeName::from("Start"));
+    assert!(matches!(
+        engine.interject_node(NodeName::from("Radoi")),
+        Err(YarnError::UnknownNode(..))
+    ));
+}
+
+#[test]
+fn test_shared_conditions() {
+    let mut source = String::new();
+    for i in 0..100 {
+        source.push_str(&format!(
+            "title: Room{i}\n---\n<<if $chapter >= 2>>\n    Later.\n<<elseif $chapter==1>>\n    Early.\n<<endif>>\nWhere to?\n-> Stay <<if $chapter >= 2>>\n    Staying.\n-> Room {i} <<if visited(\"Room{i}\")>>\n    Again.\n-> Leave\n===\n"
+        ));
+    }
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(&source).unwrap();
+
+    let mut conditions: Vec<Arc<Expr>> = vec![];
+    for i in 0..100 {
+        let node = engine.node(&NodeName::from(format!("Room{}", i))).unwrap();
+        for step in node.steps().unwrap() {
+            match step {
+                Step::Conditional(condition, _, else_ifs, _) => {
+                    conditions.push(condition.clone());
+                    conditions.extend(else_ifs.iter().map(|(c, _)| c.clone()));
+                }
+                Step::Dialogue(_, choices, _) => {
+                    conditions.extend(choices.iter().filter_map(|c| c.condition.clone()));
+                }
+                _ => {}
+            }
+        }
+    }
+    let mut distinct = conditions.clone();
+    distinct.sort_by_key(Arc::as_ptr);
+    distinct.dedup_by(|a, b| Arc::ptr_eq(a, b));
+    // `$chapter >= 2` and `$chapter == 1` are each shared by every node, while
+    // each node's `visited` condition is its own.
+    assert_eq!(conditions.len(), 400);
+    assert_eq!(distinct.len(), 102);
+
+    let said = |engine: &mut YarnEngine, chapter: f32| {
+        engine.set_variable(VariableName("chapter".to_string()), chapter);
+        engine.activate(NodeName::from("Room7"));
+        let first = engine.next();
+        let choices = match engine.next() {
+            Some(YarnEntry::Choose { choices, .. }) => choices,
+            entry => panic!("expected options, found {:?}", entry),
+        };
+        (first, choices)
+    };
+    assert_eq!(
+        said(&mut engine, 2.),
+        (
+            Some(YarnEntry::Say("Later.".into())),
+            vec!["Stay".to_string(), "Leave".to_string()]
+        )
+    );
+    assert_eq!(
+        said(&mut engine, 1.),
+        (
+            Some(YarnEntry::Say("Early.".into())),
+            vec!["Leave".to_string()]
+        )
+    );
+}
+
+#[test]
+fn test_skim() {
+    let source = r#"title: Start
+---
+Narrator: The tavern is quiet.
+Narrator: What will you order?
+-> Ale
+    Barkeep: One ale.
+-> Wine
+    Barkeep: Red or white?
+Narrator: You drink in silence.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    let play = |engine: &mut YarnEngine, choice: usize| {
+        engine.activate(NodeName::from("Start"));
+        let mut lines = vec![];
+        while let Some(entry) = engine.next() {
+            match entry {
+                YarnEntry::Say(say) => lines.push((say.plain_text().to_string(), say.seen())),
+                YarnEntry::Choose { .. } => engine.choose(choice).unwrap(),
+                YarnEntry::EndConversation(_) => break,
+                _ => {}
+            }
+        }
+        lines
+    };
+    let lines = |lines: &[(&str, bool)]| {
+        lines
+            .iter()
+            .map(|&(text, seen)| (text.to_string(), seen))
+            .collect::<Vec<_>>()
+    };
+
+    // Lines are tracked before skimming is enabled, but only flagged while skimming.
+    assert_eq!(
+        play(&mut engine, 0),
+        lines(&[
+            ("Narrator: The tavern is quiet.", false),
+            ("Barkeep: One ale.", false),
+            ("Narrator: You drink in silence.", false),
+        ])
+    );
+    engine.set_skim(true);
+    assert_eq!(
+        play(&mut engine, 1),
+        lines(&[
+            ("Narrator: The tavern is quiet.", true),
+            ("Barkeep: Red or white?", false),
+            ("Narrator: You drink in silence.", true),
+        ])
+    );
+    engine.set_skim_skips_seen(true);
+    assert_eq!(play(&mut engine, 0), vec![]);
+
+    // The seen lines can be saved and restored into another engine.
+    let seen = engine.seen_lines().map(str::to_string).collect::<Vec<_>>();
+    assert_eq!(seen.len(), 5);
+    let mut restored = YarnEngine::new();
+    restored.load_from_string(source).unwrap();
+    restored.set_skim(true);
+    restored.mark_lines_seen(seen);
+    assert!(play(&mut restored, 1).iter().all(|(_, seen)| *seen));
+    restored.reset_seen_lines();
+    assert_eq!(restored.seen_lines().count(), 0);
+}
+
+#[test]
+fn test_line_length_warnings() {
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string(
+            r#"title: Start
+---
+Narrator: [b]Careful[/b], {$name}! #line:warn
+Guard: Halt! | Who goes there at this hour of night?
+-> Ask about the \{braces\} on the door
+-> Leave
+===
+"#,
+        )
+        .unwrap();
+    assert_eq!(engine.validate(), vec![]);
+
+    let too_long = |line: &str, segment, length, limit| ValidationWarning::LineTooLong {
+        node: NodeName::from("Start"),
+        line: line.to_string(),
+        segment,
+        length,
+        limit,
+    };
+    // Markup is not counted, interpolations count as the estimated width and
+    // escaped braces count as themselves.
+    engine.set_line_length_limit(Some(LineLengthLimit {
+        max: 20,
+        segments: Some(("|".to_string(), 30)),
+        interpolation_width: 4,
+    }));
+    assert_eq!(
+        engine.validate(),
+        vec![
+            too_long("line:warn", None, 24, 20),
+            too_long("line:Start-999591c002c42e29", None, 52, 20),
+            too_long("line:Start-999591c002c42e29", Some(1), 37, 30),
+            too_long("line:Start-4606c4ed2e79ce6e", None, 34, 20),
+        ]
+    );
+    engine.set_line_length_limit(Some(LineLengthLimit::new(30)));
+    assert_eq!(
+        engine.validate(),
+        vec![
+            too_long("line:Start-999591c002c42e29", None, 52, 30),
+            too_long("line:Start-4606c4ed2e79ce6e", None, 34, 30),
+        ]
+    );
+    engine.set_line_length_limit(Some(LineLengthLimit::new(29)));
+    assert_eq!(engine.validate()[0], too_long("line:warn", None, 30, 29));
+}
+
+#[test]
+fn test_lint_rules() {
+    struct RequireSpeaker;
+    impl LintRule for RequireSpeaker {
+        fn id(&self) -> &str {
+            "require-speaker"
+        }
+        fn check_line(&self, _node: &Node, line: &LocalizableLine, sink: &mut LintSink<'_>) {
+            if line.kind == LineKind::Say && line.character.is_none() {
+                sink.report_line(&line.id, "line has no speaker");
+            }
+        }
+    }
+    struct RequireArea;
+    impl LintRule for RequireArea {
+        fn id(&self) -> &str {
+            "require-area"
+        }
+        fn check_node(&self, node: &Node, sink: &mut LintSink<'_>) {
+            if node.header("area").is_none() {
+                sink.report("node has no `area` header");
+            }
+        }
+        fn check_command(&self, _node: &Node, command: &str, sink: &mut LintSink<'_>) {
+            if command.contains("TODO") {
+                sink.report(format!("TODO in `<<{}>>`", command));
+            }
+        }
+    }
+
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string(
+            r#"title: Start
+area: docks
+---
+The waves lap at the pier.
+Sailor: Ahoy!
+<<jump Harbour>>
+===
+title: Market
+---
+<<fanfare TODO>>
+Merchant: Fresh fish!
+<<jump Harbor>>
+===
+title: Cutscene
+lint-disable: require-speaker, unknown-node require-area
+---
+The sun sets.
+<<jump Harbor>>
+===
+"#,
+        )
+        .unwrap();
+    engine.add_lint_rule(Box::new(RequireSpeaker));
+    engine.add_lint_rule(Box::new(RequireArea));
+    let diagnostic = |rule: &str, node: &str, line: Option<&str>, message: &str| LintDiagnostic {
+        rule: rule.to_string(),
+        node: NodeName::from(node),
+        line: line.map(|line| line.to_string()),
+        message: message.to_string(),
+    };
+    assert_eq!(
+        engine.lint(),
+        vec![
+            diagnostic("unknown-node", "Start", None, "unknown node `Harbour`"),
+            diagnostic(
+                "require-speaker",
+                "Start",
+                Some("line:Start-4d554f2e7837575d"),
+                "line has no speaker"
+            ),
+            diagnostic("unknown-node", "Market", None, "unknown node `Harbor`"),
+            diagnostic("require-area", "Market", None, "node has no `area` header"),
+            diagnostic("require-area", "Market", None, "TODO in `<<fanfare TODO>>`"),
+        ]
+    );
+}
+
+#[test]
+fn test_stable_line_ids() {
+    let source = r#"title: Start
+---
+Sally: Hello there.
+How are you?
+How are you?
+-> Fine #line:fine
+-> Bad
+===
+"#;
+    let ids = |source: &str| {
+        let mut engine = YarnEngine::new();
+        engine.load_from_string(source).unwrap();
+        engine
+            .extract_lines()
+            .into_iter()
+            .map(|line| line.id)
+            .collect::<Vec<_>>()
+    };
+    // IDs are the same every time, on every platform, and repeated lines are
+    // numbered.
+    let expected = vec![
+        "line:Start-9a97ad563ed67892",
+        "line:Start-95eafef62f98c265",
+        "line:Start-95eafef62f98c265-2",
+        "line:fine",
+        "line:Start-f928564be07c71e4",
+    ];
+    assert_eq!(ids(source), expected);
+    assert_eq!(ids(source), ids(source));
+
+    // Adding a line leaves the IDs of the other lines as they were.
+    let inserted = source.replace("---\n", "---\nBob: Morning.\n");
+    assert_eq!(ids(&inserted)[1..], expected[..]);
+
+    let tagged = crate::localize::assign_line_ids(source).unwrap();
+    assert_eq!(
+        tagged,
+        r#"title: Start
+---
+Sally: Hello there. #line:Start-9a97ad563ed67892
+How are you? #line:Start-95eafef62f98c265
+How are you? #line:Start-95eafef62f98c265-2
+-> Fine #line:fine
+-> Bad #line:Start-f928564be07c71e4
+===
+"#
+    );
+    assert_eq!(ids(&tagged), expected);
+    assert_eq!(crate::localize::assign_line_ids(&tagged).unwrap(), tagged);
+
+    // Titles with spaces still give IDs that parse back as tags.
+    let spaced = "title: My Node\n---\nHello.\n===\n";
+    let tagged = crate::localize::assign_line_ids(spaced).unwrap();
+    assert_eq!(
+        tagged,
+        "title: My Node\n---\nHello. #line:My_Node-1a40fbecd85b9461\n===\n"
+    );
+    assert_eq!(crate::localize::assign_line_ids(&tagged).unwrap(), tagged);
+}
+
+#[test]
+fn test_content_filter() {
+    let source = r#"title: Start
+---
+Host: Welcome back.
+Host: Look at that wound. #gore
+Host: That was brutal. #gore
+-> Keep watching
+    Host: Next round!
+-> Watch the replay #gore
+-> Skip ahead
+    <<jump Finale>>
+===
+title: Finale
+tags: mature
+fallback: FamilyFinale
+---
+Host: Blood everywhere!
+===
+title: FamilyFinale
+---
+Host: What a finish!
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    engine.set_content_filter(|content| match content {
+        ContentRef::Node(node) if node.header("tags") == Some("mature") => FilterDecision::Fallback,
+        ContentRef::Line { tags, .. } | ContentRef::Option { tags, .. }
+            if tags.iter().any(|tag| tag == "gore") =>
+        {
+            FilterDecision::Drop
+        }
+        _ => FilterDecision::Allow,
+    });
+    let say = |text: &str| Some(YarnEntry::Say(text.into()));
+
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(engine.next(), say("Host: Welcome back."));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Choose {
+            text: "".to_string(),
+            choices: vec!["Keep watching".to_string(), "Skip ahead".to_string()],
+            timeout: None,
+            default_choice: None,
+            times_chosen: vec![0, 0],
+            matched: None,
+        })
+    );
+    engine.choose(1).unwrap();
+    assert_eq!(engine.next(), say("Host: What a finish!"));
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+    assert_eq!(engine.visit_count(&NodeName::from("Finale")), 0);
+
+    // Redacted content is presented with the replacement text, and blocked nodes
+    // without a fallback end the conversation.
+    engine.set_content_filter(|content| match content {
+        ContentRef::Node(node) if node.title.as_str() == "FamilyFinale" => FilterDecision::Fallback,
+        ContentRef::Line { tags, .. } if tags.iter().any(|tag| tag == "gore") => {
+            FilterDecision::Redact("[redacted]".to_string())
+        }
+        _ => FilterDecision::Allow,
+    });
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(engine.next(), say("Host: Welcome back."));
+    assert_eq!(engine.next(), say("[redacted]"));
+    match engine.next() {
+        Some(YarnEntry::Choose { text, choices, .. }) => {
+            assert_eq!(text, "[redacted]");
+            assert_eq!(choices.len(), 3);
+        }
+        entry => panic!("expected options, found {:?}", entry),
+    }
+    engine.choose(2).unwrap();
+    assert_eq!(engine.next(), say("Host: Blood everywhere!"));
+    engine.activate(NodeName::from("FamilyFinale"));
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+}
+
+#[test]
+fn test_story_progress() {
+    let source = r#"title: Start
+---
+Guide: Welcome.
+=> Guide: Nice day.
+=> Guide: Lovely weather.
+Guide: Where to?
+-> The cave #line:cave
+    <<jump Cave>>
+-> Home
+===
+title: Cave
+---
+It is dark.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    engine.set_seed(7);
+    for choice in [0, 1, 0] {
+        engine.activate(NodeName::from("Start"));
+        while let Some(entry) = engine.next() {
+            match entry {
+                YarnEntry::Choose { .. } => engine.choose(choice).unwrap(),
+                YarnEntry::EndConversation(_) => break,
+                _ => {}
+            }
+        }
+    }
+
+    let saved = engine.export_progress().to_string();
+    assert!(saved.starts_with("yarn-spool progress 2\ntime\t0\n"));
+    let progress = saved.parse::<StoryProgress>().unwrap();
+    assert_eq!(progress, engine.export_progress());
+    let mut restored = YarnEngine::new();
+    restored.load_from_string(source).unwrap();
+    restored.import_progress(&progress);
+    assert_eq!(restored.export_progress(), engine.export_progress());
+
+    for expr in [
+        r#"visited("Cave")"#,
+        r#"visited_count("Start")"#,
+        r#"visited_count("Cave")"#,
+        r#"chosen("Start", "cave")"#,
+        r#"chosen("Start", 1)"#,
+    ] {
+        assert_eq!(
+            restored.evaluate_expression(expr),
+            engine.evaluate_expression(expr),
+            "{}",
+            expr
+        );
+    }
+    assert_eq!(
+        restored.evaluate_expression(r#"visited_count("Cave")"#),
+        Ok(Value::Number(2.))
+    );
+
+    // Both engines present the same line group line next, and flag the same lines
+    // as seen.
+    for engine in [&mut engine, &mut restored] {
+        engine.set_seed(7);
+        engine.set_skim(true);
+        engine.activate(NodeName::from("Start"));
+    }
+    for _ in 0..3 {
+        assert_eq!(restored.next(), engine.next());
+    }
+
+    // Importing on top of existing progress sums the counters.
+    restored.import_progress(&progress);
+    assert_eq!(
+        restored.evaluate_expression(r#"visited_count("Cave")"#),
+        Ok(Value::Number(4.))
+    );
+    assert_eq!(
+        "yarn-spool progress 3\n"
+            .parse::<StoryProgress>()
+            .map(|_| ()),
+        Err(YarnError::Parse {
+            line: Some(1),
+            message: "story progress version 3 is newer than supported version 2".to_string()
+        })
+    );
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&progress).unwrap();
+        assert_eq!(
+            serde_json::from_str::<StoryProgress>(&json).unwrap(),
+            progress
+        );
+    }
+    let old = "yarn-spool progress 1\nvisit\tCave\t2\n"
+        .parse::<StoryProgress>()
+        .unwrap();
+    assert_eq!(old.visits[&NodeName::from("Cave")], 2);
+    assert_eq!(old.time, 0.);
+}
+
+#[test]
+fn test_single_end_conversation() {
+    let source = r#"title: Start
+---
+Guide: Hello.
+<<wave>>
+=> Guide: Nice day.
+=> Guide: Lovely weather.
+Guide: Where to?
+-> The cave
+    <<jump Cave>>
+-> The shop
+    Guide: Closed.
+    <<stop>>
+-> Nowhere
+===
+title: Cave
+---
+Guide: It is dark.
+-> Go back
+    <<jump Start>>
+-> Stay
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    engine.set_commands_require_proceed(true);
+    engine.register_command("wave".to_string(), Box::new(|_| {}));
+
+    // A fixed linear congruential generator keeps the sequence reproducible.
+    let mut seed = 0x2545_f491_u64;
+    let mut random = |n: u64| {
+        seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (seed >> 33) % n
+    };
+    // Whether the active conversation has ended, or `None` before the first.
+    let mut ended = None;
+    let (mut conversations, mut ends) = (0, 0);
+    for _ in 0..20_000 {
+        match random(16) {
+            0..=10 => match engine.next() {
+                Some(YarnEntry::EndConversation(_)) => {
+                    assert_eq!(ended, Some(false), "a second EndConversation");
+                    ended = Some(true);
+                    ends += 1;
+                }
+                Some(_) => assert_eq!(ended, Some(false), "an entry after the end"),
+                None => {}
+            },
+            11 | 12 => {
+                let result = engine.choose(random(4) as usize);
+                if ended != Some(false) {
+                    assert_eq!(result, Err(YarnError::NoConversation));
+                }
+            }
+            13 => {
+                let _ = engine.choose_default();
+            }
+            14 => engine.proceed(),
+            _ => {
+                engine.activate(NodeName::from("Start"));
+                ended = Some(false);
+                conversations += 1;
+            }
+        }
+        if ended == Some(true) {
+            assert!(engine.has_ended());
+            assert_eq!(engine.next(), None);
+        }
+    }
+    assert!(conversations > 1000 && ends > 100);
+
+    // Neither choosing nor proceeding revives an ended conversation.
+    engine.activate(NodeName::from("Cave"));
+    assert!(matches!(engine.next(), Some(YarnEntry::Choose { .. })));
+    engine.choose(1).unwrap();
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+    assert_eq!(engine.choose(0), Err(YarnError::NoConversation));
+    engine.proceed();
+    assert_eq!(engine.next(), None);
+}
+
+#[test]
+fn test_available_nodes() {
+    let source = r#"title: Market
+tags: storylet
+priority: 2
+precondition: $day > 1
+---
+The market is busy.
+===
+title: Storm
+tags: storylet weather
+precondition: $day > 2 and visited("Market")
+---
+Thunder rolls.
+===
+title: Harvest
+tags: storylet
+priority: 1
+precondition: not $harvested
+---
+The fields are ready.
+===
+title: Broken
+tags: storylet
+precondition: $day >
+---
+Never shown.
+===
+title: Start
+---
+Good morning.
+===
+"#;
+    let say = |text: &str| Some(YarnEntry::Say(text.into()));
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    engine.set_variable(VariableName("day".to_string()), 1.0);
+    engine.set_variable(VariableName("harvested".to_string()), false);
+    let available = |engine: &YarnEngine| {
+        engine
+            .available_nodes(Some("storylet"))
+            .into_iter()
+            .filter(AvailableNode::is_available)
+            .map(|node| node.name.as_str().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let nodes = engine.available_nodes(Some("storylet"));
+    assert_eq!(nodes.len(), 4);
+    assert_eq!(
+        nodes[0],
+        AvailableNode {
+            name: NodeName::from("Market"),
+            available: Ok(false),
+            priority: Some(2.0),
+            visits: 0,
+        }
+    );
+    assert_eq!(nodes[2].priority, Some(1.0));
+    assert_eq!(nodes[3].name.as_str(), "Broken");
+    assert!(matches!(nodes[3].available, Err(YarnError::Parse { .. })));
+    assert_eq!(available(&engine), ["Harvest"]);
+
+    engine.set_variable(VariableName("day".to_string()), 3.0);
+    assert_eq!(available(&engine), ["Market", "Harvest"]);
+    engine.activate(NodeName::from("Market"));
+    assert_eq!(engine.next(), say("The market is busy."));
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+    engine.set_variable(VariableName("harvested".to_string()), true);
+    assert_eq!(available(&engine), ["Market", "Storm"]);
+    assert_eq!(engine.available_nodes(Some("storylet"))[0].visits, 1);
+
+    // Listing nodes leaves the conversation and variables alone.
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(engine.available_nodes(None).len(), 5);
+    assert_eq!(engine.available_nodes(Some("weather")).len(), 1);
+    assert_eq!(engine.next(), say("Good morning."));
+    assert_eq!(
+        engine.get_variable(&VariableName("day".to_string())),
+        Some(&Value::Number(3.0))
+    );
+}
+
+#[test]
+fn test_time_functions() {
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string(
+            r#"title: Bark
+---
+<<if elapsed_since($last_bark) < 30>>
+    Guard: ...
+<<else>>
+    Guard: Move along.
+    <<set $last_bark to time()>>
+<<endif>>
+===
+"#,
+        )
+        .unwrap();
+    let bark = |engine: &mut YarnEngine| {
+        engine.activate(NodeName::from("Bark"));
+        let line = engine.next();
+        assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+        line
+    };
+    let say = |text: &str| Some(YarnEntry::Say(text.into()));
+
+    engine.set_variable(VariableName("last_bark".to_string()), -1000.0);
+    engine.set_time(100.0);
+    assert_eq!(bark(&mut engine), say("Guard: Move along."));
+    assert_eq!(
+        engine.get_variable(&VariableName("last_bark".to_string())),
+        Some(&Value::Number(100.0))
+    );
+    engine.set_time(120.0);
+    assert_eq!(bark(&mut engine), say("Guard: ..."));
+    assert_eq!(
+        engine.evaluate_expression("elapsed_since(100)"),
+        Ok(Value::Number(20.0))
+    );
+    engine.set_time(130.5);
+    assert_eq!(bark(&mut engine), say("Guard: Move along."));
+
+    // Saved progress keeps the time.
+    let progress = engine.export_progress().to_string();
+    let mut restored = YarnEngine::new();
+    restored.import_progress(&progress.parse().unwrap());
+    assert_eq!(restored.time(), 130.5);
+    assert_eq!(
+        restored.evaluate_expression("time()"),
+        Ok(Value::Number(130.5))
+    );
+}
+
+#[test]
+fn test_options_match() {
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string(
+            r#"title: Gate
+---
+<<options match reputation()>>
+Guard: What do you want?
+-> Flash your badge <<case >= 50>> <<if chosen("Gate", "badge") == 0>> #line:badge
+    Guard: Sir!
+-> Ask politely <<case >= 10>>
+-> Beg for entry <<case else>>
+-> Leave
+===
+"#,
+        )
+        .unwrap();
+    let calls = Rc::new(RefCell::new(0));
+    let reputation = Rc::new(RefCell::new(60.0));
+    let (counter, value) = (calls.clone(), reputation.clone());
+    engine
+        .register_function(
+            "reputation".to_string(),
+            0,
+            Box::new(move |_, _| {
+                *counter.borrow_mut() += 1;
+                Ok(Value::Number(*value.borrow()))
+            }),
+        )
+        .unwrap();
+    let present = |engine: &mut YarnEngine| {
+        engine.activate(NodeName::from("Gate"));
+        match engine.next() {
+            Some(YarnEntry::Choose {
+                choices, matched, ..
+            }) => (choices, matched.unwrap()),
+            entry => panic!("expected options, found {:?}", entry),
+        }
+    };
+
+    // The first matching case wins, and the selector is evaluated once.
+    let (choices, matched) = present(&mut engine);
+    assert_eq!(choices, ["Flash your badge", "Leave"]);
+    assert_eq!(
+        matched,
+        OptionMatch {
+            value: Some(Value::Number(60.)),
+            choice: Some(0),
+            case: Some(">= 50".to_string()),
+            unmatched: vec!["Ask politely".to_string(), "Beg for entry".to_string()],
+        }
+    );
+    assert_eq!(*calls.borrow(), 1);
+    engine.choose(0).unwrap();
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Guard: Sir!".into())));
+
+    // An option offered only once falls through to the next matching case.
+    let (choices, matched) = present(&mut engine);
+    assert_eq!(choices, ["Ask politely", "Leave"]);
+    assert_eq!(matched.case.as_deref(), Some(">= 10"));
+    assert_eq!(matched.unmatched, ["Flash your badge", "Beg for entry"]);
+
+    // Without a match, the fallback is presented.
+    *reputation.borrow_mut() = 5.;
+    let (choices, matched) = present(&mut engine);
+    assert_eq!(choices, ["Beg for entry", "Leave"]);
+    assert_eq!(
+        (matched.choice, matched.case),
+        (Some(0), Some("else".to_string()))
+    );
+
+    // Cases require a selector.
+    let mut engine = YarnEngine::new();
+    assert!(engine
+        .load_from_string("title: A\n---\nHi\n-> Yes <<case 1>>\n===\n")
+        .is_err());
+}
+
+#[test]
+fn test_error_reports() {
+    let source = r#"title: Start
+---
+<<jump Middle>>
+===
+title: Middle
+---
+<<jump Deep>>
+===
+title: Deep
+---
+<<set $total to $gold + 1>>
+Done.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    engine.set_variable(VariableName("golds".to_string()), 3.);
+    engine.set_error_policy(OnError::Emit);
+    let reports = Rc::new(RefCell::new(vec![]));
+    let recovered = reports.clone();
+    engine.on_recovered_error(move |report| recovered.borrow_mut().push(report.clone()));
+    engine.activate(NodeName::from("Start"));
+
+    let expected = ErrorReport {
+        error: YarnError::UndefinedVariable {
+            name: VariableName("gold".to_string()),
+            suggestions: vec!["golds".to_string()],
+        },
+        kind: "undefined-variable",
+        message: "undefined variable `$gold`; did you mean `$golds`?".to_string(),
+        node: Some(NodeName::from("Deep")),
+        line: Some(11),
+        trail: vec![
+            NodeName::from("Start"),
+            NodeName::from("Middle"),
+            NodeName::from("Deep"),
+        ],
+        expression: Some("$gold + 1".to_string()),
+        name: Some("gold".to_string()),
+        engine_version: env!("CARGO_PKG_VERSION"),
+    };
+    assert_eq!(engine.next(), Some(YarnEntry::Error(expected.clone())));
+    assert_eq!(reports.borrow().as_slice(), std::slice::from_ref(&expected));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Done.".into())));
+    #[cfg(feature = "serde")]
+    assert_eq!(
+        serde_json::to_string(&expected).unwrap(),
+        format!(
+            r#"{{"kind":"undefined-variable","message":"undefined variable `$gold`; did you mean `$golds`?","node":"Deep","line":11,"trail":["Start","Middle","Deep"],"expression":"$gold + 1","name":"gold","engine_version":"{}"}}"#,
+            env!("CARGO_PKG_VERSION")
+        )
+    );
+
+    // Errors that stop the conversation are reported the same way.
+    engine.set_error_policy(OnError::EndConversation);
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(engine.try_next_with(&mut ()), Err(expected.error.clone()));
+    assert_eq!(engine.last_error(), Some(&expected));
+}
+
+#[test]
+fn test_can_activate() {
+    let source = r#"title: Shop
+precondition: $gold > 5
+---
+Welcome in.
+<<jump Counter>>
+===
+title: Counter
+---
+The price is {price(3)}.
+-> Leave
+    <<jump Street>>
+===
+title: Start
+---
+Hello.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    engine.set_variable(VariableName("gold".to_string()), 10.0);
+    assert_eq!(engine.can_activate(&NodeName::from("Start")), Ok(()));
+
+    let problems = engine.can_activate(&NodeName::from("Shop")).unwrap_err();
+    assert_eq!(problems.len(), 2);
+    assert!(matches!(
+        &problems[0],
+        ActivationProblem::InReachableNode(ValidationWarning::UnknownFunction { node, function, .. })
+            if node.as_str() == "Counter" && function == "price"
+    ));
+    assert!(matches!(
+        &problems[1],
+        ActivationProblem::InReachableNode(ValidationWarning::UnknownNode { node, target, .. })
+            if node.as_str() == "Counter" && target.as_str() == "Street"
+    ));
+
+    engine
+        .register_function(
+            "price".to_string(),
+            1,
+            Box::new(|args, _| Ok(Value::Number(args[0].as_num() * 2.0))),
+        )
+        .unwrap();
+    engine
+        .load_from_string("title: Street\n---\nOutside.\n===\n")
+        .unwrap();
+    assert_eq!(engine.can_activate(&NodeName::from("Shop")), Ok(()));
+
+    engine.set_variable(VariableName("gold".to_string()), 1.0);
+    assert_eq!(
+        engine.can_activate(&NodeName::from("Shop")),
+        Err(vec![ActivationProblem::PreconditionFailed(
+            "$gold > 5".to_string()
+        )])
+    );
+    let problems = engine.can_activate(&NodeName::from("Shopp")).unwrap_err();
+    assert_eq!(
+        problems,
+        [ActivationProblem::UnknownNode(
+            NodeName::from("Shopp"),
+            vec!["Shop".to_string()]
+        )]
+    );
+    // Checking changes nothing.
+    assert_eq!(engine.visit_count(&NodeName::from("Shop")), 0);
+}
+
+#[test]
+fn test_activate_start() {
+    let source = r#"title: Start
+---
+Hello.
+===
+title: Intro
+tags: entry tutorial
+---
+Welcome.
+===
+title: Epilogue
+tags: entry
+chapter: 2
+---
+Goodbye.
+===
+title: Aside
+---
+Psst.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    assert!(matches!(
+        engine.activate_start(),
+        Err(YarnError::UnknownNode(..))
+    ));
+    engine.load_from_string(source).unwrap();
+    engine.activate_start().unwrap();
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Hello.".into())));
+
+    engine.set_start_node_name(NodeName::from("Intro"));
+    assert_eq!(engine.start_node_name().as_str(), "Intro");
+    engine.activate_start().unwrap();
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Welcome.".into())));
+    engine.set_start_node_name(NodeName::from("Intr"));
+    assert_eq!(
+        engine.activate_start(),
+        Err(YarnError::UnknownNode(
+            NodeName::from("Intr"),
+            vec!["Intro".to_string()]
+        ))
+    );
+
+    let names = |engine: &YarnEngine| {
+        engine
+            .entry_points()
+            .into_iter()
+            .map(|name| name.as_str().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(&engine), ["Intro", "Epilogue"]);
+    engine.set_entry_point_predicate(|node| node.header("chapter").is_some());
+    assert_eq!(names(&engine), ["Epilogue"]);
+}
+
+#[test]
+fn test_pagination() {
+    let source = r#"title: Start
+---
+The road is long. It winds through the hills! Are we there yet? No.
+Supercalifragilistic indeed.
+The [b]bold claim[/b] stands.
+Short.
+===
+title: Words
+---
+Supercalifragilistic indeed.
+The [b]bold claim[/b] stands.
+Short.
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    engine.set_markup(true);
+    engine.set_pagination(Some(Pagination::new(30)));
+    engine.activate(NodeName::from("Start"));
+    let mut pages = vec![];
+    while let Some(YarnEntry::Say(say)) = engine.next() {
+        pages.push(say);
+    }
+    let texts = pages
+        .iter()
+        .map(|say| (say.plain_text(), say.page()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        texts,
+        [
+            ("The road is long.", Some((0, 3))),
+            ("It winds through the hills!", Some((1, 3))),
+            ("Are we there yet? No.", Some((2, 3))),
+            ("Supercalifragilistic indeed.", None),
+            ("The bold claim stands.", None),
+            ("Short.", None),
+        ]
+    );
+
+    // A word longer than a page is split within it, and markup straddling a page
+    // break is cut into a span on each page.
+    engine.set_pagination(Some(Pagination {
+        max_chars: 8,
+        split_on: SplitOn::Word,
+    }));
+    engine.activate(NodeName::from("Words"));
+    let mut pages = vec![];
+    while let Some(YarnEntry::Say(say)) = engine.next() {
+        pages.push(say);
+    }
+    let texts = pages.iter().map(|say| say.plain_text()).collect::<Vec<_>>();
+    assert_eq!(
+        texts,
+        ["Supercal", "ifragili", "stic", "indeed.", "The bold", "claim", "stands.", "Short."]
+    );
+    let bold = |say: &Say| {
+        say.spans()
+            .iter()
+            .map(|span| (span.name.clone(), span.start, span.length))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(bold(&pages[4]), [("b".to_string(), 4, 4)]);
+    assert_eq!(bold(&pages[5]), [("b".to_string(), 0, 5)]);
+    assert_eq!(pages[4].page(), Some((0, 3)));
+}
+
+#[test]
+fn test_display_formatter() {
+    let source = r#"title: Start
+---
+You have {$gold} gold. #line:gold
+{"Total: " + $gold}
+<<pay {$gold}>>
+Spend it?
+-> Buy for {$gold}
+-> Keep {$gold}
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    engine.set_variable(VariableName("gold".to_string()), 1234.5);
+    engine.add_string_table(
+        "fr",
+        vec![(
+            "line:gold".to_string(),
+            "Vous avez {$gold} pièces.".to_string(),
+        )]
+        .into_iter()
+        .collect(),
+    );
+    engine.set_display_formatter(|value| match value {
+        Value::Number(n) => {
+            let number = n.to_string();
+            let (whole, fraction) = number.split_at(number.find('.').unwrap_or(number.len()));
+            let mut grouped = String::new();
+            for (idx, digit) in whole.chars().enumerate() {
+                if idx > 0 && (whole.len() - idx).is_multiple_of(3) {
+                    grouped.push(',');
+                }
+                grouped.push(digit);
+            }
+            grouped + fraction
+        }
+        value => value.as_string(),
+    });
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("You have 1,234.5 gold.".into()))
+    );
+    // Expressions and commands still convert values as usual.
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Total: 1234.5".into())));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Command {
+            action: "pay 1234.5".to_string()
+        })
+    );
+    match engine.next() {
+        Some(YarnEntry::Choose { choices, .. }) => {
+            assert_eq!(choices, ["Buy for 1,234.5", "Keep 1,234.5"])
+        }
+        entry => panic!("unexpected entry {:?}", entry),
+    }
+
+    // Translated lines are formatted the same way.
+    engine.activate_with_locale(NodeName::from("Start"), "fr");
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("Vous avez 1,234.5 pièces.".into()))
+    );
+}
+
+#[test]
+fn test_on_abort() {
+    let source = r#"title: Talk
+on_abort: Cleanup
+---
+<<set $talking to true>>
+Hello there.
+Nice weather.
+===
+title: Cleanup
+---
+<<set $talking to false>>
+<<reset_camera>>
+===
+title: Ask
+on_abort: BadCleanup
+---
+Well?
+===
+title: BadCleanup
+---
+Before you go...
+-> Stay
+-> Leave
+===
+"#;
+    let talking = |engine: &YarnEngine| {
+        engine
+            .get_variable(&VariableName("talking".to_string()))
+            .cloned()
+    };
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    engine.activate(NodeName::from("Talk"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Hello there.".into())));
+    assert_eq!(talking(&engine), Some(Value::Boolean(true)));
+    engine.stop_conversation();
+    assert!(engine.is_active());
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Command {
+            action: "reset_camera".to_string()
+        })
+    );
+    engine.proceed();
+    assert_eq!(engine.next(), Some(YarnEntry::EndConversation(None)));
+    assert_eq!(talking(&engine), Some(Value::Boolean(false)));
+    assert_eq!(
+        engine.node_trail().last().map(|n| n.as_str()),
+        Some("Cleanup")
+    );
+
+    // Completing the node does not clean up.
+    engine.activate(NodeName::from("Talk"));
+    let entries = engine.by_ref().collect::<Vec<_>>();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(talking(&engine), Some(Value::Boolean(true)));
+    engine.stop_conversation();
+    assert_eq!(engine.status(), ConversationStatus::Idle);
+    assert_eq!(engine.next(), None);
+
+    // A cleanup cannot present options, and stopping it abandons it.
+    engine.activate(NodeName::from("Ask"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Well?".into())));
+    engine.stop_conversation();
+    assert_eq!(
+        engine.try_next_with(&mut ()),
+        Err(YarnError::UnexpectedChoice)
+    );
+    engine.stop_conversation();
+    assert_eq!(engine.status(), ConversationStatus::Idle);
+}
+
+#[test]
+fn test_rng_mode() {
+    let source = r#"title: Warmup
+---
+You roll {dice(6)} and {dice(6)}.
+===
+title: Roll
+---
+{dice(1000)} {dice(1000)} {random()}
+===
+"#;
+    let rolls = |mode: RngMode, warm_up: bool| {
+        let mut engine = YarnEngine::new();
+        engine.load_from_string(source).unwrap();
+        engine.set_seed(7);
+        engine.set_rng_mode(mode);
+        if warm_up {
+            engine.activate(NodeName::from("Warmup"));
+            engine.by_ref().for_each(drop);
+        }
+        let mut rolls = vec![];
+        for _ in 0..2 {
+            engine.activate(NodeName::from("Roll"));
+            rolls.push(engine.next().unwrap());
+            engine.by_ref().for_each(drop);
+        }
+        rolls
+    };
+
+    let salted = rolls(RngMode::PerNodeSalted, false);
+    assert_eq!(salted, rolls(RngMode::PerNodeSalted, true));
+    // Each visit has its own stream.
+    assert_ne!(salted[0], salted[1]);
+    assert_ne!(
+        rolls(RngMode::GlobalStream, false),
+        rolls(RngMode::GlobalStream, true)
+    );
+}
+
+#[test]
+fn test_parse_metrics() {
+    let sources = vec![
+        (
+            "start.yarn".to_string(),
+            r#"title: Start
+---
+Hello, {$name}.
+<<set $gold to 5>>
+<<if $gold > 3>>
+    Rich.
+<<endif>>
+<<jump Shop>>
+===
+"#
+            .to_string(),
+        ),
+        (
+            "shop.yarn".to_string(),
+            r#"title: Shop
+---
+Buy something?
+-> Sword <<if $gold >= 4>>
+    <<set $gold to $gold - 4>>
+-> Leave
+===
+"#
+            .to_string(),
+        ),
+        (
+            "broken.yarn".to_string(),
+            "title: Broken\n---\n<<if $x>>\nNo end.\n===\n".to_string(),
+        ),
+    ];
+    let mut engine: YarnEngine = YarnEngine::new();
+    let options = LoadOptions {
+        parse: ParseOptions {
+            collect_metrics: true,
+            ..ParseOptions::default()
+        },
+        atomic: false,
+    };
+    let report = engine.load_sources(sources.clone(), &options);
+    assert_eq!(report.loaded.len(), 2);
+    let metrics: ParseMetrics = report.metrics.unwrap();
+    let names = metrics
+        .sources
+        .iter()
+        .map(|source| source.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["start.yarn", "shop.yarn", "broken.yarn"]);
+    let counts = metrics.sources[..2]
+        .iter()
+        .map(|source| {
+            let node = &source.nodes[0];
+            (node.name.as_str(), node.steps, node.expressions)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(counts, [("Start", 5, 3), ("Shop", 2, 2)]);
+    assert!(metrics.sources[2].nodes.is_empty());
+    assert_eq!(
+        (
+            metrics.node_count(),
+            metrics.step_count(),
+            metrics.expression_count()
+        ),
+        (2, 7, 5)
+    );
+    assert_eq!(metrics.slowest_nodes(5).len(), 2);
+    let table = metrics.to_table_string();
+    assert!(names.iter().all(|name| table.contains(name)));
+    assert!(table.contains("total"));
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_value(&metrics).unwrap();
+        assert_eq!(json["sources"][0]["nodes"][0]["name"], "Start");
+        assert_eq!(json["sources"][0]["nodes"][0]["steps"], 5);
+        assert!(json["sources"][0]["duration"].is_f64());
+    }
+
+    // Metrics are only collected when asked for.
+    let mut engine: YarnEngine = YarnEngine::new();
+    let report = engine.load_sources(sources, &LoadOptions::default());
+    assert_eq!(report.metrics, None);
+}
+
+#[test]
+fn test_error_variants() {
+    let mut engine = YarnEngine::new();
+    engine
+        .register_function(
+            "stock".to_string(),
+            1,
+            Box::new(|args, _| match args[0].as_string().as_str() {
+                "apple" => Ok(Value::Number(3.)),
+                item => Err(format!("no such item `{}`", item)),
+            }),
+        )
+        .unwrap();
+    assert_eq!(
+        engine.evaluate_expression("stock(\"pear\")"),
+        Err(YarnError::FunctionFailed {
+            function: "stock".to_string(),
+            message: "no such item `pear`".to_string(),
+        })
+    );
+    assert_eq!(
+        engine
+            .evaluate_expression("stock()")
+            .unwrap_err()
+            .to_string(),
+        "`stock` called with 0 arguments but accepts 1 to 1"
+    );
+
+    assert!(matches!(
+        engine.load_from_string("title: Start\n---\n<<if true>>\n===\n"),
+        Err(YarnError::Parse { .. })
+    ));
+    engine
+        .load_from_string("title: Start\n---\nHello.\n===\n")
+        .unwrap();
+    assert_eq!(engine.choose(0), Err(YarnError::NoConversation));
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Hello.".into())));
+    assert_eq!(engine.choose_default(), Err(YarnError::NoChoices));
+}
+
+#[test]
+fn test_read_view() {
+    let nodes = r#"
+title: Start
+tags: bark
+precondition: visited("Intro") and $mood > threshold($rank)
+---
+Hello.
+===
+title: Intro
+tags: bark
+---
+<<const MAX = 3>>
+<<enum Rank>>
+<<case Low>>
+<<case High>>
+<<endenum>>
+Welcome.
+===
+title: Quiet
+tags: bark
+precondition: $mood < 0
+---
+...
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine
+        .register_sync_function(
+            "threshold".to_string(),
+            1,
+            Box::new(|args, _| Ok(Value::Number(args[0].as_num() * 10.))),
+        )
+        .unwrap();
+    engine
+        .register_function("local".to_string(), 0, Box::new(|_, _| Ok(1.0.into())))
+        .unwrap();
+    engine.set_variable(VariableName("mood".to_string()), 25.);
+    engine.set_variable(VariableName("rank".to_string()), 2.);
+    engine.activate(NodeName::from("Intro"));
+    while engine.next().is_some() {}
+
+    let view = engine.read_view();
+    let expected = engine.available_nodes(Some("bark"));
+    let available = expected.iter().map(AvailableNode::is_available);
+    assert_eq!(available.collect::<Vec<_>>(), [true, true, false]);
+    assert_eq!(view.available_nodes(Some("bark")), expected);
+    assert_eq!(view.visit_count(&NodeName::from("Intro")), 1);
+    assert_eq!(
+        view.get_variable(&VariableName("mood".to_string())),
+        Some(&Value::Number(25.))
+    );
+    assert!(matches!(
+        view.evaluate_expression("local()"),
+        Err(YarnError::UnknownFunction { .. })
+    ));
+    assert_eq!(
+        view.evaluate_expression("MAX + threshold(1)"),
+        Ok(Value::Number(13.))
+    );
+    assert_eq!(
+        view.evaluate_expression("Rank.High == .High"),
+        Ok(Value::Boolean(true))
+    );
+
+    std::thread::scope(|scope| {
+        for thread in 0..8 {
+            let (view, expected) = (&view, &expected);
+            scope.spawn(move || {
+                for i in 0..200 {
+                    assert_eq!(&view.available_nodes(Some("bark")), expected);
+                    let expr = format!("$mood + {} > threshold({})", i, thread);
+                    let value = view.evaluate_expression(&expr).unwrap();
+                    assert_eq!(value, Value::Boolean(25 + i > thread * 10));
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn test_null() {
+    let nodes = r#"
+title: Start
+---
+<<set $item to find("key")>>
+<<if $item == null>>
+No {$item} here.
+<<endif>>
+<<set $sum to $item + 1>>
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(nodes).unwrap();
+    engine
+        .register_function("find".to_string(), 1, Box::new(|_, _| Ok(Value::Null)))
+        .unwrap();
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(engine.next(), Some(YarnEntry::Say("No null here.".into())));
+    assert_eq!(
+        engine.get_variable(&VariableName("item".to_string())),
+        Some(&Value::Null)
+    );
+    assert!(matches!(
+        engine.try_next_with(&mut ()),
+        Err(YarnError::TypeMismatch(_))
+    ));
+
+    let eval = |expr: &str| engine.evaluate_expression(expr);
+    assert_eq!(eval("null"), Ok(Value::Null));
+    assert_eq!(eval("null == null"), Ok(Value::Boolean(true)));
+    for other in &["0", "false", "\"\"", "\"null\""] {
+        assert_eq!(
+            eval(&format!("null == {}", other)),
+            Ok(Value::Boolean(false))
+        );
+        assert_eq!(
+            eval(&format!("{} != null", other)),
+            Ok(Value::Boolean(true))
+        );
+    }
+    assert_eq!(eval("!null or null"), Ok(Value::Boolean(true)));
+    assert!(matches!(eval("null * 2"), Err(YarnError::TypeMismatch(_))));
+    assert!(matches!(eval("-null"), Err(YarnError::TypeMismatch(_))));
+    assert!(matches!(eval("null < 1"), Err(YarnError::TypeMismatch(_))));
+    assert_eq!(
+        (
+            Value::Null.as_bool(),
+            Value::Null.as_num(),
+            Value::Null.as_string()
+        ),
+        (false, 0., "null".to_string())
+    );
+}
+
+#[test]
+fn test_stray_content() {
+    let node = |title: &str| format!("title: {}\n---\nHi.\n===\n", title);
+    let leading = format!("// notes\nSome text\n\n{}", node("A"));
+    let between = format!("{}\nBob: hello\nmore\n{}", node("A"), node("B"));
+    let trailing = format!("{}{}\n  stray\n// end\n", node("A"), node("B"));
+    let speaker = format!("{}\nNarrator: hello\n\n{}", node("A"), node("B"));
+
+    let mut engine = YarnEngine::new();
+    let sources = [(&leading, 2), (&between, 6), (&trailing, 10), (&speaker, 6)];
+    for (source, line) in &sources {
+        assert_eq!(
+            engine.load_from_string(source),
+            Err(YarnError::Parse {
+                line: Some(*line),
+                message: "content outside a node".to_string()
+            })
+        );
+    }
+
+    let lenient = ParseOptions {
+        stray_content: StrayContent::Warn,
+        ..ParseOptions::default()
+    };
+    let mut stray = vec![];
+    for (source, _) in &sources {
+        let mut engine = YarnEngine::new();
+        engine
+            .load_from_string_with_options(source, &lenient)
+            .unwrap();
+        if let Some(node) = engine.node(&NodeName::from("B")) {
+            assert_eq!(node.headers().count(), 0);
+        }
+        engine.activate(NodeName::from("A"));
+        assert_eq!(engine.next(), Some(YarnEntry::Say("Hi.".into())));
+        stray.push(
+            engine
+                .validate()
+                .into_iter()
+                .filter_map(|warning| match warning {
+                    ValidationWarning::StrayContent { node, line } => Some((node, line)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+        );
+    }
+    let (a, b) = (NodeName::from("A"), NodeName::from("B"));
+    assert_eq!(
+        stray,
+        vec![
+            vec![(a.clone(), 2)],
+            vec![(a.clone(), 6), (a.clone(), 7)],
+            vec![(b, 10)],
+            vec![(a, 6)],
+        ]
+    );
+}
+
+#[cfg(feature = "bevy")]
+#[test]
+fn test_bevy_dialogue() {
+    use crate::bevy_plugin::{
+        ChooseEvent, ChooseSelection, EndEvent, SayEvent, YarnDialogue, YarnDialoguePlugin,
+        YarnFileAsset,
+    };
+    use bevy::app::{App, Update};
+    use bevy::asset::{AssetPlugin, AssetServer, Assets};
+    use bevy::ecs::event::{EventReader, EventWriter};
+    use bevy::ecs::resource::Resource;
+    use bevy::ecs::system::{NonSendMut, ResMut};
+    use bevy::MinimalPlugins;
+
+    #[derive(Resource, Default)]
+    struct Transcript(Vec<String>);
+
+    fn respond(
+        mut dialogue: NonSendMut<YarnDialogue>,
+        mut say: EventReader<SayEvent>,
+        mut choose: EventReader<ChooseEvent>,
+        mut end: EventReader<EndEvent>,
+        mut selections: EventWriter<ChooseSelection>,
+        mut transcript: ResMut<Transcript>,
+    ) {
+        for SayEvent(line) in say.read() {
+            transcript.0.push(line.plain_text().to_string());
+            dialogue.proceed();
+        }
+        for event in choose.read() {
+            transcript
+                .0
+                .push(format!("{} {:?}", event.text, event.choices));
+            selections.write(ChooseSelection(1));
+        }
+        for EndEvent(value) in end.read() {
+            transcript.0.push(format!("end {:?}", value));
+        }
+    }
+
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        AssetPlugin {
+            file_path: "examples".to_string(),
+            ..AssetPlugin::default()
+        },
+        YarnDialoguePlugin,
+    ))
+    .init_resource::<Transcript>()
+    .add_systems(Update, respond);
+
+    let handle = app
+        .world()
+        .resource::<AssetServer>()
+        .load::<YarnFileAsset>("simple.yarn");
+    let mut file = None;
+    for _ in 0..1000 {
+        app.update();
+        file = app
+            .world()
+            .resource::<Assets<YarnFileAsset>>()
+            .get(&handle)
+            .cloned();
+        if file.is_some() {
+            break;
+        }
+    }
+    let mut dialogue = app.world_mut().non_send_resource_mut::<YarnDialogue>();
+    dialogue.load(&file.unwrap()).unwrap();
+    dialogue.activate(NodeName::from("dwarf"));
+    for _ in 0..10 {
+        app.update();
+    }
+    assert_eq!(
+        app.world().resource::<Transcript>().0,
+        vec![
+            "Dwarf: What do you think you're doing?",
+            "You: Oh, sorry. I didn't see you there.",
+            "Dwarf: Are you saying I'm short? [\" yes: \", \" no: \"]",
+            "Dwarf: You know it. ",
+            "Dwarf: Now get lost.",
+            "end None",
+        ]
+    );
+}
+
+#[test]
+fn test_lazy_line_ids() {
+    let lazy = ParseOptions {
+        lazy_bodies: true,
+        ..ParseOptions::default()
+    };
+    let mut engine = YarnEngine::new();
+    engine
+        .load_from_string_with_options("title: Start\n---\nHello.\n===\n", &lazy)
+        .unwrap();
+    let id = "line:Start-c338889184485907".to_string();
+    engine.add_string_table("fr", [(id, "Bonjour.".to_string())].into());
+    engine.set_active_locale("fr");
+    let start = NodeName::from("Start");
+    assert_eq!(
+        engine.run_node(&start, ChoicePolicy::Fail),
+        Ok(vec![
+            YarnEntry::Say("Bonjour.".into()),
+            YarnEntry::EndConversation(None),
+        ])
+    );
+
+    // Replacing the node indexes its lines afresh when they are presented.
+    engine
+        .load_from_string_with_options("title: Start\n---\nGoodbye.\n===\n", &lazy)
+        .unwrap();
+    assert_eq!(
+        engine.run_node(&start, ChoicePolicy::Fail),
+        Ok(vec![
+            YarnEntry::Say("Goodbye.".into()),
+            YarnEntry::EndConversation(None),
+        ])
+    );
+}
+
+#[test]
+fn test_nested_line_ids() {
+    let source = r#"title: Start
+---
+<<if true>>
+    Guard: Halt, {$name}! #line:halt
+<<endif>>
+Guard: Well? #line:well
+-> Wait #line:wait
+    Guard: Halt, {$name}! #line:again
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    engine.set_variable(VariableName("name".to_string()), "Ann");
+    let table = [
+        ("line:halt", "Wache: Halt, {$name}!"),
+        ("line:wait", "Warten"),
+        ("line:again", "Wache: Stehen bleiben, {$name}!"),
+    ];
+    let table = table
+        .iter()
+        .map(|&(id, text)| (id.to_string(), text.to_string()))
+        .collect();
+    engine.add_string_table("de", table);
+    engine.set_active_locale("de");
+
+    // Lines are found by where they are, however deeply they are nested, and the
+    // same text in two places is rendered for each.
+    engine.activate(NodeName::from("Start"));
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("Wache: Halt, Ann!".into()))
+    );
+    engine.proceed();
+    assert!(matches!(
+        engine.next(),
+        Some(YarnEntry::Choose { choices, .. }) if choices == ["Warten"]
+    ));
+    engine.choose(0).unwrap();
+    assert_eq!(
+        engine.next(),
+        Some(YarnEntry::Say("Wache: Stehen bleiben, Ann!".into()))
+    );
+    let mut seen = engine.seen_lines().collect::<Vec<_>>();
+    seen.sort();
+    assert_eq!(seen, ["line:again", "line:halt", "line:well"]);
+}
+
+#[test]
+fn test_parse_error_lines() {
+    let error = |source: &str| match YarnEngine::new().load_from_string(source) {
+        Err(YarnError::Parse { line, message }) => (line, message),
+        result => panic!("expected a parse error, found {:?}", result),
+    };
+    let cases = [
+        (
+            "title: Start\n---\nHello.\n<<endif>>\n===\n",
+            4,
+            "`<<endif>>` without `<<if>>`",
+        ),
+        (
+            "title: Start\n---\n<<if $a ==>>\nHello.\n<<endif>>\n===\n",
+            3,
+            "invalid condition `$a ==`",
+        ),
+        (
+            "title: Start\n---\n<<if true>>\nHello.\n===\n",
+            3,
+            "`<<if>>` without `<<endif>>`",
+        ),
+        ("title: Start\n---\nHello.\n", 3, "node without `===`"),
+        (
+            "title: Start\ntitle: Again\n---\n===\n",
+            2,
+            "duplicate `title` header",
+        ),
+        (
+            "title: Start\n---\nHello.\n<<set x to 1>>\n===\n",
+            4,
+            "invalid statement `<<set x to 1>>`",
+        ),
+    ];
+    for (source, line, message) in cases {
+        assert_eq!(error(source), (Some(line), message.to_string()));
+    }
+
+    // Loading a file keeps the error as it was found.
+    let path = std::env::temp_dir().join(format!("yarn-spool-{}.yarn", std::process::id()));
+    std::fs::write(&path, cases[0].0).unwrap();
+    let loaded = YarnEngine::new().load_from_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        loaded.map_err(|err| err.to_string()),
+        Err("parse error at line 4: `<<endif>>` without `<<if>>`".to_string())
+    );
+}
+
+#[test]
+fn test_validate_function_call_lines() {
+    let nodes = r#"title: Start
+---
+[[Choose]]
+===
+title: Choose
+---
+Pick one.
+-> First
+    <<log missing_a()>>
+-> Second
+<<if missing_b()>>
+    Then {missing_c()}.
+<<endif>>
+===
+"#;
+    let lines = |options: &ParseOptions| {
+        let mut engine = YarnEngine::new();
+        engine
+            .load_from_string_with_options(nodes, options)
+            .unwrap();
+        engine
+            .validate()
+            .into_iter()
+            .map(|warning| match warning {
+                ValidationWarning::UnknownFunction { function, line, .. } => (function, line),
+                other => panic!("unexpected warning {:?}", other),
+            })
+            .collect::<Vec<_>>()
+    };
+    let expected = vec![
+        ("missing_a".to_string(), 9),
+        ("missing_b".to_string(), 11),
+        ("missing_c".to_string(), 12),
+    ];
+    assert_eq!(lines(&ParseOptions::default()), expected);
+    let lazy = ParseOptions {
+        lazy_bodies: true,
+        ..ParseOptions::default()
+    };
+    assert_eq!(lines(&lazy), expected);
+}
+
+#[test]
+fn test_error_report_lines_in_nested_steps() {
+    let source = r#"title: Start
+---
+Pick.
+-> Skip
+    Skipped.
+-> Fail
+    <<if false>>
+        Never.
+    <<elseif true>>
+        <<set $x to $missing>>
+        Went on.
+    <<endif>>
+===
+"#;
+    let mut engine = YarnEngine::new();
+    engine.load_from_string(source).unwrap();
+    engine.set_error_policy(OnError::SkipStep);
+    let lines = Rc::new(RefCell::new(vec![]));
+    let recovered = lines.clone();
+    engine.on_recovered_error(move |report| recovered.borrow_mut().push(report.line));
+    engine.activate(NodeName::from("Start"));
+    assert!(matches!(engine.next(), Some(YarnEntry::Choose { .. })));
+    engine.choose(1).unwrap();
+    assert_eq!(engine.next(), Some(YarnEntry::Say("Went on.".into())));
+    assert_eq!(*lines.borrow(), vec![Some(10)]);
+
+    engine.set_error_policy(OnError::EndConversation);
+    engine.activate(NodeName::from("Start"));
+    engine.next();
+    engine.choose(1).unwrap();
+    assert!(engine.try_next_with(&mut ()).is_err());
+    assert_eq!(engine.last_error().and_then(|report| report.line), Some(10));
 }
@@ -0,0 +1,1154 @@
+use crate::engine::{
+    self, BinaryOp, ChoiceKind, Expr, NodeName, Nodes, OptionCase, Step, Term, UnaryOp, Value,
+    VariableName, YarnType,
+};
+use crate::enums::Enums;
+use crate::error::{self, YarnError};
+use crate::flags;
+use crate::localize::{self, LocalizableLine};
+use crate::markup;
+use crate::normalize::TextNormalization;
+use crate::parse;
+use crate::suggest;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// A potential problem in the loaded nodes that does not prevent them from running.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationWarning {
+    /// The listed nodes jump to one another in a cycle without presenting any
+    /// dialogue, options or commands, so entering any of them never yields.
+    JumpCycle(Vec<NodeName>),
+    /// The body of a node loaded lazily could not be parsed.
+    InvalidBody {
+        /// The node whose body is invalid.
+        node: NodeName,
+        /// A description of the problem.
+        message: String,
+    },
+    /// The node calls a function that is not registered.
+    UnknownFunction {
+        /// The node containing the call.
+        node: NodeName,
+        /// The line number of the step containing the call.
+        line: usize,
+        /// The name of the function.
+        function: String,
+        /// Similarly named registered functions.
+        suggestions: Vec<String>,
+    },
+    /// The node jumps or links to a node that is not loaded.
+    UnknownNode {
+        /// The node containing the jump or option.
+        node: NodeName,
+        /// The missing node.
+        target: NodeName,
+        /// The titles of similarly named nodes.
+        suggestions: Vec<String>,
+    },
+    /// The node calls a function with a number of arguments it does not accept.
+    WrongArgumentCount {
+        /// The node containing the call.
+        node: NodeName,
+        /// The line number of the step containing the call.
+        line: usize,
+        /// The name of the function.
+        function: String,
+        /// The number of arguments passed.
+        args: usize,
+        /// The numbers of arguments the function accepts.
+        expected: RangeInclusive<usize>,
+    },
+    /// The node assigns a value to a declared variable that always has a different
+    /// type than the declaration.
+    DeclaredTypeMismatch {
+        /// The node containing the assignment.
+        node: NodeName,
+        /// The line number of the assignment.
+        line: usize,
+        /// The variable assigned.
+        variable: VariableName,
+        /// The declared type.
+        expected: YarnType,
+        /// The type of the assigned value.
+        found: YarnType,
+    },
+    /// The condition of an `<<if>>`, `<<elseif>>` or option never produces a
+    /// boolean.
+    NonBooleanCondition {
+        /// The node containing the condition.
+        node: NodeName,
+        /// The line number of the step with the condition. The conditions of
+        /// `<<elseif>>` and options are reported at their `<<if>>` and line of
+        /// dialogue.
+        line: usize,
+        /// The condition, as Yarn source.
+        condition: String,
+        /// The type of the condition's value.
+        found: YarnType,
+    },
+    /// The node passes an argument whose type never matches the function's
+    /// signature.
+    WrongArgumentType {
+        /// The node containing the call.
+        node: NodeName,
+        /// The line number of the step containing the call.
+        line: usize,
+        /// The name of the function.
+        function: String,
+        /// The position of the argument, starting from 0.
+        index: usize,
+        /// The type of the parameter.
+        expected: YarnType,
+        /// The type of the argument.
+        found: YarnType,
+    },
+    /// The node applies an operator to operands whose types always require one of
+    /// them to be converted, such as adding a number to a boolean.
+    MismatchedOperands {
+        /// The node containing the expression.
+        node: NodeName,
+        /// The line number of the step containing the expression.
+        line: usize,
+        /// The expression, as Yarn source.
+        expression: String,
+        /// The types of the left and right operands.
+        operands: (YarnType, YarnType),
+    },
+    /// The node refers to an enum case that is not declared, such as `Mood.Angry`
+    /// or a shorthand `.Angry` that no enum has.
+    UnknownEnumCase {
+        /// The node containing the case.
+        node: NodeName,
+        /// The case, as Yarn source.
+        case: String,
+    },
+    /// The node contains a `<<statement>>` that could not be parsed and was kept
+    /// because of `ParseOptions::forward_compatible`.
+    UnknownStatement {
+        /// The node containing the statement.
+        node: NodeName,
+        /// The statement between `<<` and `>>`, without surrounding whitespace.
+        source: String,
+    },
+    /// Content outside any node next to this one was skipped because of
+    /// `ParseOptions::stray_content`.
+    StrayContent {
+        /// The node before the content, or after it for content above the first node.
+        node: NodeName,
+        /// The line number of the content in its source.
+        line: usize,
+    },
+    /// The node sets a flag with `<<flag>>` that no node checks with `flag()`.
+    FlagNeverChecked {
+        /// The node setting the flag.
+        node: NodeName,
+        /// The name of the flag.
+        flag: String,
+    },
+    /// The node checks a flag with `flag()` that no node sets with `<<flag>>`.
+    FlagNeverSet {
+        /// The node checking the flag.
+        node: NodeName,
+        /// The name of the flag.
+        flag: String,
+    },
+    /// A line of dialogue or option text is longer than the `LineLengthLimit` set
+    /// with `YarnEngine::set_line_length_limit`.
+    LineTooLong {
+        /// The node containing the line.
+        node: NodeName,
+        /// The line's ID, as given by `YarnEngine::extract_lines`.
+        line: String,
+        /// The position of the segment that is too long, starting from 0, or `None`
+        /// if the whole line is.
+        segment: Option<usize>,
+        /// The measured length in characters.
+        length: usize,
+        /// The maximum length.
+        limit: usize,
+    },
+}
+
+impl ValidationWarning {
+    /// The ID of the lint rule reporting this warning, such as `unknown-node`, which
+    /// a node can suppress with a `lint-disable` header.
+    pub fn rule(&self) -> &'static str {
+        match self {
+            ValidationWarning::JumpCycle(_) => "jump-cycle",
+            ValidationWarning::InvalidBody { .. } => "invalid-body",
+            ValidationWarning::UnknownFunction { .. } => "unknown-function",
+            ValidationWarning::UnknownNode { .. } => "unknown-node",
+            ValidationWarning::WrongArgumentCount { .. } => "wrong-argument-count",
+            ValidationWarning::DeclaredTypeMismatch { .. } => "declared-type-mismatch",
+            ValidationWarning::NonBooleanCondition { .. } => "non-boolean-condition",
+            ValidationWarning::WrongArgumentType { .. } => "wrong-argument-type",
+            ValidationWarning::MismatchedOperands { .. } => "mismatched-operands",
+            ValidationWarning::UnknownEnumCase { .. } => "unknown-enum-case",
+            ValidationWarning::UnknownStatement { .. } => "unknown-statement",
+            ValidationWarning::StrayContent { .. } => "stray-content",
+            ValidationWarning::FlagNeverChecked { .. } => "flag-never-checked",
+            ValidationWarning::FlagNeverSet { .. } => "flag-never-set",
+            ValidationWarning::LineTooLong { .. } => "line-too-long",
+        }
+    }
+
+    /// The node the warning was found in. For a cycle, this is its first node.
+    pub fn node(&self) -> &NodeName {
+        match self {
+            ValidationWarning::JumpCycle(nodes) => &nodes[0],
+            ValidationWarning::InvalidBody { node, .. }
+            | ValidationWarning::UnknownFunction { node, .. }
+            | ValidationWarning::UnknownNode { node, .. }
+            | ValidationWarning::WrongArgumentCount { node, .. }
+            | ValidationWarning::DeclaredTypeMismatch { node, .. }
+            | ValidationWarning::NonBooleanCondition { node, .. }
+            | ValidationWarning::WrongArgumentType { node, .. }
+            | ValidationWarning::MismatchedOperands { node, .. }
+            | ValidationWarning::UnknownEnumCase { node, .. }
+            | ValidationWarning::UnknownStatement { node, .. }
+            | ValidationWarning::StrayContent { node, .. }
+            | ValidationWarning::FlagNeverChecked { node, .. }
+            | ValidationWarning::FlagNeverSet { node, .. }
+            | ValidationWarning::LineTooLong { node, .. } => node,
+        }
+    }
+}
+
+impl fmt::Display for ValidationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationWarning::JumpCycle(nodes) => {
+                let nodes = nodes.iter().map(|n| n.as_str()).collect::<Vec<_>>();
+                write!(
+                    f,
+                    "nodes jump in a cycle without yielding: {}",
+                    nodes.join(" -> ")
+                )
+            }
+            ValidationWarning::InvalidBody { message, .. } => write!(f, "{}", message),
+            ValidationWarning::UnknownFunction {
+                line,
+                function,
+                suggestions,
+                ..
+            } => {
+                write!(f, "unknown function `{}` at line {}", function, line)?;
+                error::did_you_mean(f, "", suggestions)
+            }
+            ValidationWarning::UnknownNode {
+                target,
+                suggestions,
+                ..
+            } => {
+                write!(f, "unknown node `{}`", target)?;
+                error::did_you_mean(f, "", suggestions)
+            }
+            ValidationWarning::WrongArgumentCount {
+                line,
+                function,
+                args,
+                expected,
+                ..
+            } => write!(
+                f,
+                "`{}` called with {} arguments at line {} but accepts {} to {}",
+                function,
+                args,
+                line,
+                expected.start(),
+                expected.end()
+            ),
+            ValidationWarning::DeclaredTypeMismatch {
+                line,
+                variable,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "`${}` is declared as {} but assigned a {} at line {}",
+                variable.0, expected, found, line
+            ),
+            ValidationWarning::NonBooleanCondition {
+                line,
+                condition,
+                found,
+                ..
+            } => write!(
+                f,
+                "condition `{}` at line {} is a {}, not a bool",
+                condition, line, found
+            ),
+            ValidationWarning::WrongArgumentType {
+                line,
+                function,
+                index,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "argument {} of `{}` at line {} should be a {} but is a {}",
+                index, function, line, expected, found
+            ),
+            ValidationWarning::MismatchedOperands {
+                line,
+                expression,
+                operands,
+                ..
+            } => write!(
+                f,
+                "`{}` at line {} converts between {} and {}",
+                expression, line, operands.0, operands.1
+            ),
+            ValidationWarning::UnknownEnumCase { case, .. } => {
+                write!(f, "unknown enum case `{}`", case)
+            }
+            ValidationWarning::UnknownStatement { source, .. } => {
+                write!(f, "unknown statement `<<{}>>`", source)
+            }
+            ValidationWarning::StrayContent { line, .. } => {
+                write!(f, "skipped content outside a node at line {}", line)
+            }
+            ValidationWarning::FlagNeverChecked { flag, .. } => {
+                write!(f, "flag `{}` is set but never checked", flag)
+            }
+            ValidationWarning::FlagNeverSet { flag, .. } => {
+                write!(f, "flag `{}` is checked but never set", flag)
+            }
+            ValidationWarning::LineTooLong {
+                line,
+                segment,
+                length,
+                limit,
+                ..
+            } => {
+                write!(f, "line `{}` ", line)?;
+                if let Some(segment) = segment {
+                    write!(f, "segment {} ", segment)?;
+                }
+                write!(
+                    f,
+                    "is {} characters long, over the limit of {}",
+                    length, limit
+                )
+            }
+        }
+    }
+}
+
+/// The longest text that fits in a dialogue box, checked by `YarnEngine::validate`.
+/// Lengths are measured in characters of plain text, after removing markup, with
+/// each `{expression}` counted as `interpolation_width` characters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineLengthLimit {
+    /// The maximum length of a line.
+    pub max: usize,
+    /// A delimiter that writers use to split a line across several boxes, such as
+    /// `|`, and the maximum length of each part.
+    pub segments: Option<(String, usize)>,
+    /// The estimated length of an interpolated value. Defaults to 10.
+    pub interpolation_width: usize,
+}
+
+impl LineLengthLimit {
+    /// A limit of the given number of characters per line.
+    pub fn new(max: usize) -> LineLengthLimit {
+        LineLengthLimit {
+            max,
+            segments: None,
+            interpolation_width: 10,
+        }
+    }
+
+    /// The length of the given text, measured as described above.
+    fn measure(&self, text: &str) -> usize {
+        // Interpolations are replaced by a placeholder before markup is removed, so
+        // that brackets within expressions are not taken for markup.
+        let mut replaced = String::with_capacity(text.len());
+        let mut chars = text.chars().peekable();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' if matches!(chars.peek(), Some('{') | Some('}')) => {
+                    replaced.extend(chars.next());
+                }
+                '{' => {
+                    chars.by_ref().find(|&ch| ch == '}');
+                    replaced.push(PLACEHOLDER);
+                }
+                ch => replaced.push(ch),
+            }
+        }
+        let (plain, _) = markup::parse_markup(&replaced);
+        plain
+            .chars()
+            .map(|ch| match ch {
+                PLACEHOLDER => self.interpolation_width,
+                _ => 1,
+            })
+            .sum()
+    }
+}
+
+/// Stands for an interpolated value while a line is measured.
+const PLACEHOLDER: char = '\u{fffc}';
+
+/// What validation knows about a registered function.
+pub(crate) struct FunctionInfo {
+    /// The accepted argument counts.
+    pub(crate) arity: RangeInclusive<usize>,
+    /// The type of each parameter, where known.
+    pub(crate) params: Vec<Option<YarnType>>,
+    /// The type of the return value, if known.
+    pub(crate) returns: Option<YarnType>,
+}
+
+/// What is known about the script without running it.
+struct Types<'a> {
+    nodes: &'a Nodes,
+    declarations: HashMap<VariableName, YarnType>,
+    functions: HashMap<String, FunctionInfo>,
+    enums: &'a Enums,
+    /// The flags set and checked anywhere in the script.
+    flags_set: HashSet<String>,
+    flags_checked: HashSet<String>,
+}
+
+/// A reason `YarnEngine::can_activate` expects a conversation starting at a node to
+/// fail.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ActivationProblem {
+    /// The node is not loaded. Also contains the titles of similarly named nodes.
+    UnknownNode(NodeName, Vec<String>),
+    /// A node reachable from the node by jumps and options calls a function that is
+    /// not registered or with the wrong number of arguments, jumps or links to a
+    /// node that is not loaded, or has a body that cannot be parsed.
+    InReachableNode(ValidationWarning),
+    /// The node's `precondition` header, as written, evaluated to false.
+    PreconditionFailed(String),
+    /// The node's `precondition` header could not be evaluated.
+    PreconditionError(YarnError),
+}
+
+impl fmt::Display for ActivationProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActivationProblem::UnknownNode(name, suggestions) => {
+                write!(f, "unknown node `{}`", name)?;
+                error::did_you_mean(f, "", suggestions)
+            }
+            ActivationProblem::InReachableNode(warning) => {
+                write!(f, "in node `{}`: {}", warning.node(), warning)
+            }
+            ActivationProblem::PreconditionFailed(precondition) => {
+                write!(f, "precondition `{}` is false", precondition)
+            }
+            ActivationProblem::PreconditionError(err) => {
+                write!(f, "precondition could not be evaluated: {}", err)
+            }
+        }
+    }
+}
+
+/// Check the nodes reachable from the given node by jumps and options, in the order
+/// they are reached, for the problems that would stop a conversation: calls to
+/// functions that are not registered or with the wrong number of arguments, jumps
+/// and options to nodes that are not loaded, and bodies that cannot be parsed.
+pub(crate) fn check_reachable(
+    nodes: &Nodes,
+    functions: HashMap<String, FunctionInfo>,
+    enums: &Enums,
+    start: &NodeName,
+) -> Vec<ValidationWarning> {
+    // Declarations and flags only matter to warnings that are left out.
+    let types = Types {
+        nodes,
+        declarations: HashMap::new(),
+        functions,
+        enums,
+        flags_set: HashSet::new(),
+        flags_checked: HashSet::new(),
+    };
+    let mut warnings = vec![];
+    let mut reached = vec![start.clone()];
+    let mut idx = 0;
+    while let Some(name) = reached.get(idx).cloned() {
+        idx += 1;
+        let node = match nodes.get(&name) {
+            Some(node) => node,
+            None => continue,
+        };
+        let steps = match node.steps() {
+            Ok(steps) => steps,
+            Err(err) => {
+                warnings.push(ValidationWarning::InvalidBody {
+                    node: name,
+                    message: err.to_string(),
+                });
+                continue;
+            }
+        };
+        check_exprs(node, steps, &types, &mut warnings);
+        check_steps(node, steps, &types, &mut warnings);
+        let mut targets = vec![];
+        collect_targets(steps, &mut targets);
+        for target in targets {
+            if !reached.contains(&target) {
+                reached.push(target);
+            }
+        }
+    }
+    warnings.retain(|warning| {
+        matches!(
+            warning,
+            ValidationWarning::UnknownFunction { .. }
+                | ValidationWarning::WrongArgumentCount { .. }
+                | ValidationWarning::UnknownNode { .. }
+                | ValidationWarning::InvalidBody { .. }
+        )
+    });
+    warnings
+}
+
+/// Collect the targets of the jumps and options in the given steps.
+fn collect_targets(steps: &[Step], targets: &mut Vec<NodeName>) {
+    for step in steps {
+        match step {
+            Step::Jump(target) => targets.push(target.clone()),
+            Step::Dialogue(_, choices, _) => {
+                for choice in choices {
+                    match choice.kind {
+                        ChoiceKind::Inline(ref steps) => collect_targets(steps, targets),
+                        ChoiceKind::External(ref target) => targets.push(target.clone()),
+                    }
+                }
+            }
+            Step::Conditional(_, if_steps, else_ifs, else_steps) => {
+                collect_targets(if_steps, targets);
+                for (_, steps) in else_ifs {
+                    collect_targets(steps, targets);
+                }
+                collect_targets(else_steps, targets);
+            }
+            Step::Command(..)
+            | Step::Assign(..)
+            | Step::Assert(..)
+            | Step::Log(..)
+            | Step::Declare(..)
+            | Step::Const(..)
+            | Step::Enum(..)
+            | Step::LineGroup(..)
+            | Step::Return(..)
+            | Step::Unknown(..) => {}
+        }
+    }
+}
+
+/// Check the given nodes for problems that are cheap to detect statically, ordered by
+/// node. `functions` describes each registered function.
+pub(crate) fn validate(
+    nodes: &Nodes,
+    functions: HashMap<String, FunctionInfo>,
+    enums: &Enums,
+    line_length: Option<(&LineLengthLimit, &TextNormalization)>,
+) -> Vec<ValidationWarning> {
+    let mut warnings = vec![];
+    let mut declarations = vec![];
+    let (mut flags_set, mut flags_checked) = (vec![], vec![]);
+    for node in nodes.iter() {
+        if let Ok(steps) = node.steps() {
+            collect_declarations(steps, &mut declarations);
+            flags::collect_set_flags(steps, &mut flags_set);
+            let mut exprs = vec![];
+            collect_exprs(steps, &mut exprs);
+            for expr in &exprs {
+                flags::collect_checked_flags(expr, &mut flags_checked);
+            }
+        }
+    }
+    let types = Types {
+        nodes,
+        declarations: declarations
+            .into_iter()
+            .map(|(name, _, ty)| (name, ty))
+            .collect(),
+        functions,
+        enums,
+        flags_set: flags_set.into_iter().collect(),
+        flags_checked: flags_checked.into_iter().collect(),
+    };
+    let mut lines: HashMap<NodeName, Vec<LocalizableLine>> = HashMap::new();
+    if let Some((_, normalization)) = line_length {
+        for line in localize::extract_lines(nodes, normalization) {
+            lines.entry(line.node.clone()).or_default().push(line);
+        }
+    }
+
+    for node in nodes.iter() {
+        for &line in &node.stray_lines {
+            warnings.push(ValidationWarning::StrayContent {
+                node: node.title.clone(),
+                line,
+            });
+        }
+        let steps = match node.steps() {
+            Ok(steps) => steps,
+            Err(err) => {
+                warnings.push(ValidationWarning::InvalidBody {
+                    node: node.title.clone(),
+                    message: err.to_string(),
+                });
+                continue;
+            }
+        };
+        let targets = silent_jumps(steps);
+        if targets.contains(&node.title) {
+            warnings.push(ValidationWarning::JumpCycle(vec![node.title.clone()]));
+        }
+        for target in targets {
+            if target.0 <= node.title.0 {
+                continue;
+            }
+            let returns = nodes
+                .get(&target)
+                .and_then(|other| other.steps().ok())
+                .is_some_and(|steps| silent_jumps(steps).contains(&node.title));
+            if returns {
+                warnings.push(ValidationWarning::JumpCycle(vec![
+                    node.title.clone(),
+                    target,
+                ]));
+            }
+        }
+
+        let exprs = check_exprs(node, steps, &types, &mut warnings);
+        check_steps(node, steps, &types, &mut warnings);
+        check_flags(&node.title, steps, &exprs, &types, &mut warnings);
+        if let (Some((limit, _)), Some(lines)) = (line_length, lines.get(&node.title)) {
+            check_line_lengths(lines, limit, &mut warnings);
+        }
+    }
+    warnings
+}
+
+/// Check every expression in the node's steps with `check_expr`, returning them.
+fn check_exprs(
+    node: &engine::Node,
+    steps: &[Step],
+    types: &Types,
+    warnings: &mut Vec<ValidationWarning>,
+) -> Vec<Expr> {
+    let lines = node.step_lines();
+    let mut exprs = vec![];
+    for (idx, step) in flatten(steps).into_iter().enumerate() {
+        let start = exprs.len();
+        step_exprs(step, &mut exprs);
+        let line = lines.get(idx).copied().unwrap_or_default();
+        for expr in &exprs[start..] {
+            check_expr(&node.title, line, expr, types, warnings);
+        }
+    }
+    exprs
+}
+
+/// Collect the `<<declare>>` steps in the given steps: each variable, its default
+/// value and its type.
+pub(crate) fn collect_declarations(
+    steps: &[Step],
+    declarations: &mut Vec<(VariableName, Value, YarnType)>,
+) {
+    for step in steps {
+        match step {
+            Step::Declare(name, value, ty) => declarations.push((name.clone(), value.clone(), *ty)),
+            Step::Dialogue(_, choices, _) => {
+                for choice in choices {
+                    if let ChoiceKind::Inline(ref steps) = choice.kind {
+                        collect_declarations(steps, declarations);
+                    }
+                }
+            }
+            Step::Conditional(_, if_steps, else_ifs, else_steps) => {
+                collect_declarations(if_steps, declarations);
+                for (_, steps) in else_ifs {
+                    collect_declarations(steps, declarations);
+                }
+                collect_declarations(else_steps, declarations);
+            }
+            Step::Command(..)
+            | Step::Assign(..)
+            | Step::Jump(..)
+            | Step::Assert(..)
+            | Step::Log(..)
+            | Step::Const(..)
+            | Step::Enum(..)
+            | Step::LineGroup(..)
+            | Step::Return(..)
+            | Step::Unknown(..) => {}
+        }
+    }
+}
+
+/// Check each jump and option target, each assignment to a declared variable and
+/// each condition whose type is known without running the script.
+fn check_steps(
+    node: &engine::Node,
+    steps: &[Step],
+    types: &Types,
+    warnings: &mut Vec<ValidationWarning>,
+) {
+    let lines = node.step_lines();
+    for (idx, step) in flatten(steps).into_iter().enumerate() {
+        let line = lines.get(idx).copied().unwrap_or_default();
+        check_step(&node.title, line, step, types, warnings);
+    }
+}
+
+/// Check a single step, without the steps nested in it.
+fn check_step(
+    node: &NodeName,
+    line: usize,
+    step: &Step,
+    types: &Types,
+    warnings: &mut Vec<ValidationWarning>,
+) {
+    match step {
+        Step::Assign(name, expr) => {
+            let expected = match types.declarations.get(name) {
+                Some(&expected) => expected,
+                None => return,
+            };
+            match types.of(expr) {
+                Some(found) if found != expected => {
+                    warnings.push(ValidationWarning::DeclaredTypeMismatch {
+                        node: node.clone(),
+                        line,
+                        variable: name.clone(),
+                        expected,
+                        found,
+                    })
+                }
+                _ => {}
+            }
+        }
+        Step::Dialogue(_, choices, _) => {
+            for choice in choices {
+                if let Some(ref condition) = choice.condition {
+                    check_condition(node, line, condition, types, warnings);
+                }
+                if let ChoiceKind::External(ref target) = choice.kind {
+                    check_target(node, target, types, warnings);
+                }
+            }
+        }
+        Step::LineGroup(group) => {
+            for condition in group.lines.iter().filter_map(|l| l.condition.as_ref()) {
+                check_condition(node, line, condition, types, warnings);
+            }
+        }
+        Step::Jump(target) => check_target(node, target, types, warnings),
+        Step::Conditional(condition, _, else_ifs, _) => {
+            check_condition(node, line, condition, types, warnings);
+            for (condition, _) in else_ifs {
+                check_condition(node, line, condition, types, warnings);
+            }
+        }
+        Step::Unknown(source) => warnings.push(ValidationWarning::UnknownStatement {
+            node: node.clone(),
+            source: source.clone(),
+        }),
+        Step::Command(..)
+        | Step::Assert(..)
+        | Step::Log(..)
+        | Step::Declare(..)
+        | Step::Const(..)
+        | Step::Enum(..)
+        | Step::Return(..) => {}
+    }
+}
+
+/// Check that each flag the node sets is checked somewhere, and that each flag it
+/// checks is set somewhere.
+fn check_flags(
+    node: &NodeName,
+    steps: &[Step],
+    exprs: &[Expr],
+    types: &Types,
+    warnings: &mut Vec<ValidationWarning>,
+) {
+    let mut set = vec![];
+    flags::collect_set_flags(steps, &mut set);
+    let mut checked = vec![];
+    for expr in exprs {
+        flags::collect_checked_flags(expr, &mut checked);
+    }
+    let mut seen = HashSet::new();
+    for flag in set {
+        if !types.flags_checked.contains(&flag) && seen.insert(flag.clone()) {
+            warnings.push(ValidationWarning::FlagNeverChecked {
+                node: node.clone(),
+                flag,
+            });
+        }
+    }
+    let mut seen = HashSet::new();
+    for flag in checked {
+        if !types.flags_set.contains(&flag) && seen.insert(flag.clone()) {
+            warnings.push(ValidationWarning::FlagNeverSet {
+                node: node.clone(),
+                flag,
+            });
+        }
+    }
+}
+
+/// Check that each line, and each segment of it, fits within the limit.
+fn check_line_lengths(
+    lines: &[LocalizableLine],
+    limit: &LineLengthLimit,
+    warnings: &mut Vec<ValidationWarning>,
+) {
+    for line in lines {
+        let mut too_long = |segment, length, max| {
+            if length > max {
+                warnings.push(ValidationWarning::LineTooLong {
+                    node: line.node.clone(),
+                    line: line.id.clone(),
+                    segment,
+                    length,
+                    limit: max,
+                });
+            }
+        };
+        too_long(None, limit.measure(&line.text), limit.max);
+        if let Some((ref delimiter, max)) = limit.segments {
+            if delimiter.is_empty() || !line.text.contains(delimiter.as_str()) {
+                continue;
+            }
+            for (idx, segment) in line.text.split(delimiter.as_str()).enumerate() {
+                too_long(Some(idx), limit.measure(segment.trim()), max);
+            }
+        }
+    }
+}
+
+fn check_target(
+    node: &NodeName,
+    target: &NodeName,
+    types: &Types,
+    warnings: &mut Vec<ValidationWarning>,
+) {
+    if types.nodes.get(target).is_none() {
+        warnings.push(ValidationWarning::UnknownNode {
+            node: node.clone(),
+            target: target.clone(),
+            suggestions: types.nodes.similar_titles(target),
+        });
+    }
+}
+
+fn check_condition(
+    node: &NodeName,
+    line: usize,
+    condition: &Expr,
+    types: &Types,
+    warnings: &mut Vec<ValidationWarning>,
+) {
+    match types.of(condition) {
+        Some(found) if found != YarnType::Boolean => {
+            warnings.push(ValidationWarning::NonBooleanCondition {
+                node: node.clone(),
+                line,
+                condition: condition.to_string(),
+                found,
+            })
+        }
+        _ => {}
+    }
+}
+
+impl Types<'_> {
+    /// The type of the expression's value, if it is known without running the
+    /// script.
+    fn of(&self, expr: &Expr) -> Option<YarnType> {
+        match expr {
+            Expr::Term(Term::Number(_)) => Some(YarnType::Number),
+            Expr::Term(Term::String(_)) => Some(YarnType::String),
+            Expr::Term(Term::Boolean(_)) => Some(YarnType::Boolean),
+            Expr::Term(Term::Null) => Some(YarnType::Null),
+            Expr::Term(Term::Variable(name)) => self.declarations.get(name).cloned(),
+            Expr::Term(Term::Constant(_)) => None,
+            Expr::Term(Term::EnumCase(..)) => Some(YarnType::Enum),
+            Expr::Term(Term::Function(name, _)) => self.functions.get(name)?.returns,
+            Expr::Ternary(_, if_true, if_false) => {
+                let ty = self.of(if_true)?;
+                if self.of(if_false)? == ty {
+                    Some(ty)
+                } else {
+                    None
+                }
+            }
+            Expr::Parentheses(expr) => self.of(expr),
+            Expr::Unary(UnaryOp::Not, _) => Some(YarnType::Boolean),
+            Expr::Unary(UnaryOp::Negate, _) => Some(YarnType::Number),
+            Expr::Binary(op, left, right) => match op {
+                BinaryOp::Coalesce => None,
+                BinaryOp::Plus => match (self.of(left)?, self.of(right)?) {
+                    (YarnType::String, _) | (_, YarnType::String) => Some(YarnType::String),
+                    _ => Some(YarnType::Number),
+                },
+                BinaryOp::Minus | BinaryOp::Multiply | BinaryOp::Divide => Some(YarnType::Number),
+                _ => Some(YarnType::Boolean),
+            },
+        }
+    }
+}
+
+/// The given steps and every step nested in them, each before the steps nested in it
+/// and in the order they appear in the source, which is the order of
+/// `Node::step_lines`.
+pub(crate) fn flatten(steps: &[Step]) -> Vec<&Step> {
+    let mut flat = vec![];
+    flatten_into(steps, &mut flat);
+    flat
+}
+
+fn flatten_into<'a>(steps: &'a [Step], flat: &mut Vec<&'a Step>) {
+    for step in steps {
+        flat.push(step);
+        match step {
+            Step::Dialogue(_, choices, _) => {
+                for choice in choices {
+                    if let ChoiceKind::Inline(ref steps) = choice.kind {
+                        flatten_into(steps, flat);
+                    }
+                }
+            }
+            Step::Conditional(_, if_steps, else_ifs, else_steps) => {
+                flatten_into(if_steps, flat);
+                for (_, steps) in else_ifs {
+                    flatten_into(steps, flat);
+                }
+                flatten_into(else_steps, flat);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Collect every expression in the given steps, including those interpolated into
+/// dialogue, options and commands.
+pub(crate) fn collect_exprs(steps: &[Step], exprs: &mut Vec<Expr>) {
+    for step in flatten(steps) {
+        step_exprs(step, exprs);
+    }
+}
+
+/// Collect the expressions of a single step, without those of the steps nested in it.
+fn step_exprs(step: &Step, exprs: &mut Vec<Expr>) {
+    match step {
+        Step::Dialogue(text, choices, _) => {
+            interpolated_exprs(text, exprs);
+            let mut selector_pending = true;
+            for choice in choices {
+                interpolated_exprs(&choice.text, exprs);
+                exprs.extend(choice.condition.as_deref().cloned());
+                if let Some(OptionCase::Compare {
+                    ref selector,
+                    ref operand,
+                    ..
+                }) = choice.case
+                {
+                    // The options of a block share their selector.
+                    if selector_pending {
+                        exprs.push((**selector).clone());
+                        selector_pending = false;
+                    }
+                    exprs.push(operand.clone());
+                }
+            }
+        }
+        Step::LineGroup(group) => {
+            for line in &group.lines {
+                interpolated_exprs(&line.text, exprs);
+                exprs.extend(line.condition.as_deref().cloned());
+            }
+        }
+        Step::Command(text) => interpolated_exprs(text, exprs),
+        Step::Assign(_, expr) => exprs.push(expr.clone()),
+        Step::Declare(..) | Step::Const(..) | Step::Enum(..) | Step::Unknown(..) => {}
+        Step::Assert(assertion) => exprs.push(assertion.condition.clone()),
+        Step::Log(args) => exprs.extend(args.iter().cloned()),
+        Step::Return(value) => exprs.extend(value.iter().cloned()),
+        Step::Jump(..) => {}
+        Step::Conditional(expr, _, else_ifs, _) => {
+            exprs.push((**expr).clone());
+            exprs.extend(else_ifs.iter().map(|(expr, _)| (**expr).clone()));
+        }
+    }
+}
+
+/// Parse each `{expression}` in the given text. Escaped braces and expressions that
+/// fail to parse are skipped.
+pub(crate) fn interpolated_exprs(text: &str, exprs: &mut Vec<Expr>) {
+    let mut chars = text.char_indices();
+    while let Some((idx, ch)) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            '{' => {
+                let end = chars.by_ref().find(|&(_, ch)| ch == '}');
+                if let Some((end, _)) = end {
+                    exprs.extend(parse::parse_complete_expr(&text[idx + 1..end]).ok());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Check every function call in the expression against the registered functions,
+/// and every operator whose operands' types are known.
+fn check_expr(
+    node: &NodeName,
+    line: usize,
+    expr: &Expr,
+    types: &Types,
+    warnings: &mut Vec<ValidationWarning>,
+) {
+    match expr {
+        Expr::Term(Term::Function(name, args)) => {
+            match types.functions.get(name) {
+                None => warnings.push(ValidationWarning::UnknownFunction {
+                    node: node.clone(),
+                    line,
+                    function: name.clone(),
+                    suggestions: suggest::similar_names(
+                        name,
+                        types.functions.keys().map(|name| name.as_str()),
+                    ),
+                }),
+                Some(info) => {
+                    if !info.arity.contains(&args.len()) {
+                        warnings.push(ValidationWarning::WrongArgumentCount {
+                            node: node.clone(),
+                            line,
+                            function: name.clone(),
+                            args: args.len(),
+                            expected: info.arity.clone(),
+                        })
+                    }
+                    for (index, (arg, expected)) in args.iter().zip(&info.params).enumerate() {
+                        match (*expected, types.of(arg)) {
+                            (Some(expected), Some(found)) if found != expected => {
+                                warnings.push(ValidationWarning::WrongArgumentType {
+                                    node: node.clone(),
+                                    line,
+                                    function: name.clone(),
+                                    index,
+                                    expected,
+                                    found,
+                                })
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            for arg in args {
+                check_expr(node, line, arg, types, warnings);
+            }
+        }
+        Expr::Term(Term::EnumCase(type_name, case)) => {
+            let has_case = |cases: &Vec<String>| cases.contains(case);
+            let known = match type_name {
+                Some(name) => types.enums.get(name).is_some_and(has_case),
+                None => types.enums.values().any(has_case),
+            };
+            if !known {
+                warnings.push(ValidationWarning::UnknownEnumCase {
+                    node: node.clone(),
+                    case: expr.to_string(),
+                });
+            }
+        }
+        Expr::Term(_) => {}
+        Expr::Unary(_, expr) | Expr::Parentheses(expr) => {
+            check_expr(node, line, expr, types, warnings)
+        }
+        Expr::Binary(op, left, right) => {
+            let arithmetic = !matches!(
+                op,
+                BinaryOp::And | BinaryOp::Or | BinaryOp::Xor | BinaryOp::Coalesce
+            );
+            if arithmetic {
+                if let (Some(l), Some(r)) = (types.of(left), types.of(right)) {
+                    if engine::coerces(op, l, r) {
+                        warnings.push(ValidationWarning::MismatchedOperands {
+                            node: node.clone(),
+                            line,
+                            expression: expr.to_string(),
+                            operands: (l, r),
+                        });
+                    }
+                }
+            }
+            check_expr(node, line, left, types, warnings);
+            check_expr(node, line, right, types, warnings);
+        }
+        Expr::Ternary(condition, if_true, if_false) => {
+            check_expr(node, line, condition, types, warnings);
+            check_expr(node, line, if_true, types, warnings);
+            check_expr(node, line, if_false, types, warnings);
+        }
+    }
+}
+
+/// The jump targets that can be reached from the start of the given steps without
+/// passing a dialogue line, a set of options or a command.
+fn silent_jumps(steps: &[Step]) -> Vec<NodeName> {
+    let mut targets = vec![];
+    collect_silent_jumps(steps, &mut targets);
+    targets
+}
+
+/// Record reachable silent jump targets, returning whether execution can fall
+/// through to the end of the block.
+fn collect_silent_jumps(steps: &[Step], targets: &mut Vec<NodeName>) -> bool {
+    for step in steps {
+        match step {
+            Step::Dialogue(..) | Step::Command(..) | Step::Return(..) => return false,
+            // A group yields unless every line has a condition that may be false.
+            Step::LineGroup(group) if group.lines.iter().any(|l| l.condition.is_none()) => {
+                return false
+            }
+            Step::LineGroup(..)
+            | Step::Assign(..)
+            | Step::Assert(..)
+            | Step::Log(..)
+            | Step::Declare(..)
+            | Step::Const(..)
+            | Step::Enum(..)
+            | Step::Unknown(..) => {}
+            Step::Jump(name) => {
+                if !targets.contains(name) {
+                    targets.push(name.clone());
+                }
+                return false;
+            }
+            Step::Conditional(_, if_steps, else_ifs, else_steps) => {
+                let mut falls_through = collect_silent_jumps(if_steps, targets);
+                for (_, steps) in else_ifs {
+                    falls_through |= collect_silent_jumps(steps, targets);
+                }
+                falls_through |= collect_silent_jumps(else_steps, targets);
+                if !falls_through {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,92 @@
+use crate::chosen::ChoiceCounts;
+use crate::engine::{
+    EngineState, EvalContext, NodeName, Nodes, TypeChecking, Value, VariableName, Variables,
+};
+use crate::enums::Enums;
+use crate::error::YarnError;
+use crate::random::Randomness;
+use crate::storylet::{self, AvailableNode};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+/// A function callback that can be called from several threads at once, registered
+/// with `YarnEngine::register_sync_function`.
+pub type SyncFunctionCallback =
+    dyn Fn(Vec<Value>, &EvalContext) -> Result<Value, String> + Send + Sync;
+
+#[derive(Clone)]
+pub(crate) struct SyncFunction {
+    pub(crate) num_args: RangeInclusive<usize>,
+    pub(crate) callback: Arc<SyncFunctionCallback>,
+}
+
+/// A read-only view of an engine's story state, given by `YarnEngine::read_view`,
+/// that can be shared between threads to evaluate conditions in parallel. The
+/// engine cannot change while the view exists.
+///
+/// Only the built-in functions and those registered with
+/// `YarnEngine::register_sync_function` can be called through the view. Random
+/// functions draw from a copy of the engine's generators taken when the view was
+/// created, so the engine's own choices are unaffected.
+pub struct EvalView<'a> {
+    pub(crate) node: Option<&'a NodeName>,
+    pub(crate) nodes: &'a Nodes,
+    pub(crate) visits: &'a HashMap<NodeName, u32>,
+    pub(crate) chosen: &'a ChoiceCounts,
+    pub(crate) variables: &'a Variables,
+    pub(crate) locals: Option<&'a Variables>,
+    pub(crate) time: f64,
+    pub(crate) random: Randomness,
+    pub(crate) functions: &'a Arc<HashMap<String, SyncFunction>>,
+    pub(crate) constants: &'a Arc<HashMap<String, Value>>,
+    pub(crate) enums: &'a Arc<Enums>,
+    pub(crate) type_checking: TypeChecking,
+    pub(crate) coalesce_undefined_variables: bool,
+}
+
+impl<'a> EvalView<'a> {
+    /// Parse and evaluate a single Yarn expression, as with
+    /// `YarnEngine::evaluate_expression`. Evaluations share the engine's functions,
+    /// constants and enums rather than copying them.
+    pub fn evaluate_expression(&self, expr: &str) -> Result<Value, YarnError> {
+        let random = RefCell::new(self.random.clone());
+        EngineState::for_view(self).evaluate_source(expr, &self.context(&random), &mut ())
+    }
+
+    /// The current value of the given variable, if it has been set.
+    pub fn get_variable(&self, name: &VariableName) -> Option<&'a Value> {
+        if name.is_local() {
+            self.locals.and_then(|locals| locals.values.get(name))
+        } else {
+            self.variables.values.get(name)
+        }
+    }
+
+    /// The number of times the given node has been visited.
+    pub fn visit_count(&self, name: &NodeName) -> u32 {
+        self.visits.get(name).cloned().unwrap_or(0)
+    }
+
+    /// List the loaded nodes with their preconditions evaluated, as with
+    /// `YarnEngine::available_nodes`.
+    pub fn available_nodes(&self, filter_tag: Option<&str>) -> Vec<AvailableNode> {
+        storylet::available_nodes(self.nodes, self.visits, filter_tag, |expr| {
+            self.evaluate_expression(expr)
+        })
+    }
+
+    fn context<'b>(&'b self, random: &'b RefCell<Randomness>) -> EvalContext<'b> {
+        EvalContext {
+            node: self.node,
+            nodes: self.nodes,
+            visits: self.visits,
+            chosen: self.chosen,
+            variables: self.variables,
+            locals: self.locals,
+            time: self.time,
+            random,
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+fn play(args: &[&str], input: &str) -> Output {
+    let script = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/play.yarn");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_yarn-play"))
+        .arg(script)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn plays_script_from_stdin() {
+    let transcript = std::env::temp_dir().join(format!("yarn-play-{}.txt", std::process::id()));
+    let output = play(
+        &[
+            "--var",
+            "gold=12",
+            "--seed",
+            "7",
+            "--transcript",
+            transcript.to_str().unwrap(),
+        ],
+        "3\n1\n",
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Welcome, traveller. You carry 12 gold.\n\
+         [wave]\n\
+         Where to?\n  1. Market\n  2. Home\n\
+         > enter a number from 1 to 2\n\
+         > The merchant grins at your purse.\n\
+         You leave with 2 gold.\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&transcript).unwrap(),
+        "Welcome, traveller. You carry 12 gold.\n\
+         [wave]\n\
+         Where to?\n  1. Market\n  2. Home\n\
+         > 1\n\
+         The merchant grins at your purse.\n\
+         You leave with 2 gold.\n"
+    );
+    let _ = std::fs::remove_file(transcript);
+}
+
+#[test]
+fn reports_errors_with_node() {
+    let output = play(&["Broken"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Before the break.\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "error: in node `Broken`: unknown function `missing`\n"
+    );
+
+    let output = play(&["Nowhere"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("unknown node `Nowhere`"));
+}